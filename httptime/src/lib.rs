@@ -1,6 +1,9 @@
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+
+/// The `Date` header has a resolution of one second.
+const DATE_RESOLUTION: Duration = Duration::from_secs(1);
 
 /// Get the current time from an HTTP server where the time is in the `Date` header in the response.
 pub async fn get_time(url: &str, timeout: Duration) -> Result<SystemTime> {
@@ -16,6 +19,125 @@ pub async fn get_time(url: &str, timeout: Duration) -> Result<SystemTime> {
     httpdate::parse_http_date(value).context("failed to parse date")
 }
 
+/// The time agreed by multiple HTTP servers.
+#[derive(Debug, Clone, Copy)]
+pub struct ConsensusTime {
+    /// The agreed time at `instant`.
+    pub time: SystemTime,
+    /// The local monotonic time when `time` was taken.
+    pub instant: Instant,
+    /// The maximum error of `time`.
+    pub error_bound: Duration,
+    /// Number of servers that agreed on the time.
+    pub agreed: usize,
+    /// Number of servers that failed to respond or were rejected as outliers.
+    pub rejected: usize,
+}
+
+impl ConsensusTime {
+    /// Returns the agreed time extrapolated to now using the local monotonic clock.
+    pub fn now(&self) -> SystemTime {
+        self.time + self.instant.elapsed()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    /// The estimated server time at `instant`.
+    time: SystemTime,
+    instant: Instant,
+    /// The half width of the interval the server time falls in.
+    error: Duration,
+}
+
+impl Sample {
+    fn new(date: SystemTime, start: Instant, end: Instant) -> Self {
+        // The truncated date was generated somewhere in the round trip, so the server time at
+        // `end` falls in [date, date + resolution + rtt].
+        let error = (DATE_RESOLUTION + (end - start)) / 2;
+        Self {
+            time: date + error,
+            instant: end,
+            error,
+        }
+    }
+}
+
+/// Get the current time agreed by at least `quorum` of the given HTTP servers.
+///
+/// The servers are queried concurrently, each within `timeout`. Each response is corrected with
+/// the measured round trip time, and the responses deviating more than `tolerance` from the
+/// median are rejected as outliers. Returns the median of the rest with an error bound.
+pub async fn get_consensus_time(
+    urls: &[impl AsRef<str>],
+    quorum: usize,
+    tolerance: Duration,
+    timeout: Duration,
+) -> Result<ConsensusTime> {
+    let requests = urls.iter().map(|url| async move {
+        let start = Instant::now();
+        let result = get_time(url.as_ref(), timeout).await;
+        result.map(|date| Sample::new(date, start, Instant::now()))
+    });
+    let samples: Vec<_> = futures::future::join_all(requests)
+        .await
+        .into_iter()
+        .filter_map(Result::ok)
+        .collect();
+    let mut consensus = consensus(&samples, quorum, tolerance, Instant::now())?;
+    consensus.rejected = urls.len() - consensus.agreed;
+    Ok(consensus)
+}
+
+fn consensus(
+    samples: &[Sample],
+    quorum: usize,
+    tolerance: Duration,
+    instant: Instant,
+) -> Result<ConsensusTime> {
+    let quorum = quorum.max(1);
+    if samples.len() < quorum {
+        bail!("only {} servers responded, quorum={quorum}", samples.len());
+    }
+    // Align all the samples to the same instant.
+    let mut samples: Vec<_> = samples
+        .iter()
+        .map(|s| Sample {
+            time: s.time + instant.saturating_duration_since(s.instant),
+            instant,
+            error: s.error,
+        })
+        .collect();
+    samples.sort_by_key(|s| s.time);
+    let median = samples[samples.len() / 2].time;
+    samples.retain(|s| abs_diff(s.time, median) <= tolerance);
+    if samples.len() < quorum {
+        bail!(
+            "only {} servers agree on the time, quorum={quorum}",
+            samples.len()
+        );
+    }
+    let time = samples[samples.len() / 2].time;
+    let error_bound = samples
+        .iter()
+        .map(|s| abs_diff(s.time, time) + s.error)
+        .max()
+        .unwrap_or_default();
+    Ok(ConsensusTime {
+        time,
+        instant,
+        error_bound,
+        agreed: samples.len(),
+        rejected: 0,
+    })
+}
+
+fn abs_diff(a: SystemTime, b: SystemTime) -> Duration {
+    a.duration_since(b)
+        .or_else(|_| b.duration_since(a))
+        .unwrap_or_default()
+}
+
 #[cfg(not(target_os = "wasi"))]
 mod runtime {
     use anyhow::Result;
@@ -79,6 +201,52 @@ mod test {
 
     use super::*;
 
+    #[test]
+    fn consensus_rejects_outliers() {
+        let now = Instant::now();
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let rtt = Duration::from_millis(100);
+        let sample = |offset_ms: i64| {
+            let date = if offset_ms >= 0 {
+                t0 + Duration::from_millis(offset_ms as u64)
+            } else {
+                t0 - Duration::from_millis(-offset_ms as u64)
+            };
+            Sample::new(date, now - rtt, now)
+        };
+        let samples = [sample(0), sample(1000), sample(-1000), sample(3_600_000)];
+
+        let result = consensus(&samples, 3, Duration::from_secs(2), now).unwrap();
+        assert_eq!(result.agreed, 3);
+        assert_eq!(result.time, sample(0).time);
+        assert_eq!(result.error_bound, Duration::from_millis(1000 + 550));
+
+        assert!(consensus(&samples, 4, Duration::from_secs(2), now).is_err());
+        assert!(consensus(&samples[..2], 3, Duration::from_secs(2), now).is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "requires network access"]
+    async fn test_get_consensus_time() {
+        let servers = [
+            "https://www.cloudflare.com",
+            "https://www.apple.com",
+            "https://www.baidu.com",
+            "https://kernel.org/",
+        ];
+        let timeout = Duration::from_secs(5);
+        let result = get_consensus_time(&servers, 3, Duration::from_secs(2), timeout)
+            .await
+            .unwrap();
+        assert!(result.agreed >= 3);
+        assert_eq!(result.agreed + result.rejected, servers.len());
+        let local = abs_diff(result.now(), SystemTime::now());
+        assert!(
+            local < Duration::from_secs(60),
+            "local clock off by {local:?}"
+        );
+    }
+
     #[tokio::test]
     async fn test_get_time() {
         let servers = [
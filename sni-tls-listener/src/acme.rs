@@ -0,0 +1,311 @@
+//! Certificates issued by an ACME CA, validated with TLS-ALPN-01 (RFC 8737) on the SNI port.
+//!
+//! The account key and the certificate keys are generated in the worker and only leave it
+//! through the [`AcmeHost`], which keeps them across restarts so that certificates still valid
+//! are served again rather than ordered again. Each owner, e.g. an app, only gets certificates
+//! for the names the host allows it.
+//!
+//! The names of a subscription are served with a self-signed placeholder certificate until their
+//! first certificate is issued, and the certificate is renewed for as long as they are
+//! subscribed. One certificate covers all the names of a subscription.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use rustls::sign::CertifiedKey;
+use rustls_pki_types::CertificateDer;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{oneshot, OnceCell};
+use tracing::{info, warn};
+
+use crate::listener::WeakSniTlsListener;
+
+pub(crate) use x509::placeholder_key;
+
+mod client;
+mod x509;
+
+#[cfg(all(test, feature = "ring"))]
+mod tests;
+
+const MIN_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(3600 * 6);
+/// The key the account key is saved under in the host.
+const ACCOUNT_KEY: &str = "account";
+
+/// What the ACME issuer needs from the embedder of the listener.
+pub trait AcmeHost: Send + Sync + 'static {
+    /// Whether the owner can get certificates for the name.
+    fn is_allowed(&self, owner: &str, name: &str) -> bool;
+    /// Loads the data last saved under the key.
+    fn load(&self, key: &str) -> Option<Vec<u8>>;
+    /// Saves the data to be loaded after a restart. It holds private keys.
+    fn save(&self, key: &str, data: &[u8]);
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AcmeConfig {
+    /// The directory URL of the ACME CA.
+    pub directory_url: String,
+    /// Contact URLs of the account, e.g. `mailto:admin@example.com`.
+    pub contact: Vec<String>,
+    /// PEM file of an extra root CA to trust for the ACME API, e.g. the one of a local Pebble.
+    pub ca_cert: Option<String>,
+    /// Interval to renew the certificates, in seconds.
+    pub renew_interval_secs: u64,
+}
+
+impl Default for AcmeConfig {
+    fn default() -> Self {
+        Self {
+            directory_url: "https://acme-v02.api.letsencrypt.org/directory".into(),
+            contact: vec![],
+            ca_cert: None,
+            renew_interval_secs: 3600 * 24 * 60,
+        }
+    }
+}
+
+/// A certificate saved in the host.
+#[derive(Serialize, Deserialize)]
+struct SavedCert {
+    /// When the certificate was issued, in seconds since the UNIX epoch.
+    issued_at: u64,
+    /// The PKCS #8 document of the key, in base64.
+    key: String,
+    /// The DER certificate chain in base64, the leaf first.
+    chain: Vec<String>,
+}
+
+fn cert_key(names: &[String]) -> String {
+    format!("cert/{}", names.join(","))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub(crate) struct AcmeIssuer {
+    config: AcmeConfig,
+    host: Arc<dyn AcmeHost>,
+    http: reqwest::Client,
+    account: OnceCell<client::Account>,
+}
+
+impl AcmeIssuer {
+    pub fn new(config: AcmeConfig, host: Arc<dyn AcmeHost>) -> Result<Self> {
+        let mut http = reqwest::Client::builder().timeout(Duration::from_secs(30));
+        if let Some(path) = &config.ca_cert {
+            let pem = std::fs::read(path).context("failed to read the ACME CA certificate")?;
+            let cert =
+                reqwest::Certificate::from_pem(&pem).context("invalid ACME CA certificate")?;
+            http = http.add_root_certificate(cert);
+        }
+        Ok(Self {
+            http: http
+                .build()
+                .context("failed to create the ACME http client")?,
+            config,
+            host,
+            account: OnceCell::new(),
+        })
+    }
+
+    /// Whether the owner can get certificates for all the names.
+    pub fn is_allowed(&self, owner: &str, names: &[String]) -> bool {
+        names.iter().all(|name| self.host.is_allowed(owner, name))
+    }
+
+    async fn account(&self) -> Result<&client::Account> {
+        self.account
+            .get_or_try_init(|| async {
+                let pkcs8 = match self.host.load(ACCOUNT_KEY) {
+                    Some(pkcs8) => pkcs8,
+                    None => {
+                        let pkcs8 = client::generate_account_key()?;
+                        self.host.save(ACCOUNT_KEY, &pkcs8);
+                        pkcs8
+                    }
+                };
+                client::Account::register(
+                    self.http.clone(),
+                    &self.config.directory_url,
+                    &self.config.contact,
+                    &pkcs8,
+                )
+                .await
+            })
+            .await
+    }
+
+    /// The saved certificate of the names if it is not due for renewal yet, along with the time
+    /// left until it is.
+    pub fn saved_cert(&self, names: &[String]) -> Result<Option<(Arc<CertifiedKey>, Duration)>> {
+        let Some(data) = self.host.load(&cert_key(names)) else {
+            return Ok(None);
+        };
+        let saved: SavedCert =
+            serde_json::from_slice(&data).context("invalid saved certificate")?;
+        let renew_at = saved
+            .issued_at
+            .saturating_add(self.config.renew_interval_secs);
+        let Some(renew_in) = renew_at.checked_sub(now_secs()).filter(|secs| *secs > 0) else {
+            return Ok(None);
+        };
+        let key = x509::KeyPairDoc::from_pkcs8(STANDARD.decode(saved.key)?)?;
+        let chain = saved
+            .chain
+            .iter()
+            .map(|der| Ok(CertificateDer::from(STANDARD.decode(der)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some((key.certified(chain)?, Duration::from_secs(renew_in))))
+    }
+
+    fn save_cert(&self, names: &[String], key: &x509::KeyPairDoc, chain: &[CertificateDer]) {
+        let saved = SavedCert {
+            issued_at: now_secs(),
+            key: STANDARD.encode(key.pkcs8()),
+            chain: chain.iter().map(|der| STANDARD.encode(der)).collect(),
+        };
+        match serde_json::to_vec(&saved) {
+            Ok(data) => self.host.save(&cert_key(names), &data),
+            Err(err) => warn!(target: "wapo::tls", "failed to encode the certificate: {err}"),
+        }
+    }
+
+    /// Orders a certificate for the names, answering the challenges through the listener.
+    async fn issue(
+        &self,
+        names: &[String],
+        listener: &WeakSniTlsListener,
+    ) -> Result<Arc<CertifiedKey>> {
+        let account = self.account().await?;
+        let (order_url, order) = account.new_order(names).await?;
+        for url in &order.authorizations {
+            let authorization = account.authorization(url).await?;
+            if authorization.status == "valid" {
+                continue;
+            }
+            let challenge = authorization
+                .challenges
+                .iter()
+                .find(|challenge| challenge.kind == "tls-alpn-01")
+                .context("the CA offers no tls-alpn-01 challenge")?;
+            let domain = &authorization.identifier.value;
+            let key_authorization = account.key_authorization(&challenge.token)?;
+            let challenge_key = x509::KeyPairDoc::generate()?;
+            let cert = challenge_key
+                .self_signed(std::slice::from_ref(domain), Some(&key_authorization))?;
+            let challenge_key = challenge_key.certified(vec![cert.into()])?;
+            let listener = listener.upgrade().context("listener dropped")?;
+            listener.set_challenge(domain, Some(challenge_key));
+            let validated = async {
+                account.respond(challenge).await?;
+                account.wait_authorization(url).await
+            }
+            .await;
+            listener.set_challenge(domain, None);
+            let authorization = validated?;
+            if authorization.status != "valid" {
+                bail!(
+                    "authorization of {} is {}",
+                    authorization.identifier.value,
+                    authorization.status
+                );
+            }
+        }
+        let key = x509::KeyPairDoc::generate()?;
+        account.finalize(&order, &key.csr(names)?).await?;
+        let order = account.wait_order(&order_url).await?;
+        if order.status != "valid" {
+            bail!("order is {}", order.status);
+        }
+        let pem = account.certificate(&order).await?;
+        let chain = rustls_pemfile::certs(&mut pem.as_bytes())
+            .collect::<Result<Vec<CertificateDer>, _>>()
+            .context("invalid certificate chain")?;
+        if chain.is_empty() {
+            bail!("empty certificate chain");
+        }
+        self.save_cert(names, &key, &chain);
+        key.certified(chain)
+    }
+}
+
+/// Issues and renews the certificate of the names of the owner until they are no longer
+/// subscribed, or `cancel_rx` is dropped. The first one is issued after `renew_in`.
+pub(crate) async fn maintain(
+    issuer: Arc<AcmeIssuer>,
+    owner: String,
+    names: Vec<String>,
+    listener: WeakSniTlsListener,
+    renew_in: Duration,
+    cancel_rx: oneshot::Receiver<()>,
+) {
+    let domains = names.join(",");
+    let domains = domains.as_str();
+    tokio::select! {
+        _ = renew(&issuer, &owner, &names, &listener, renew_in) => {}
+        _ = cancel_rx => {
+            info!(target: "wapo::tls", domains, "names unsubscribed, stop renewing");
+        }
+    }
+}
+
+async fn renew(
+    issuer: &AcmeIssuer,
+    owner: &str,
+    names: &[String],
+    listener: &WeakSniTlsListener,
+    renew_in: Duration,
+) {
+    let domains = names.join(",");
+    let domains = domains.as_str();
+    let released = || {
+        let released = listener
+            .upgrade()
+            .is_none_or(|listener| listener.release_managed_key(owner, names));
+        if released {
+            info!(target: "wapo::tls", domains, "names unsubscribed, stop renewing");
+        }
+        released
+    };
+    if !renew_in.is_zero() {
+        tokio::time::sleep(renew_in).await;
+        if released() {
+            return;
+        }
+    }
+    let mut retry_interval = MIN_RETRY_INTERVAL;
+    loop {
+        let delay = match issuer.issue(names, listener).await {
+            Ok(key) => {
+                info!(target: "wapo::tls", domains, "certificate issued");
+                let Some(listener) = listener.upgrade() else {
+                    break;
+                };
+                listener.install_managed_key(owner, names, key);
+                retry_interval = MIN_RETRY_INTERVAL;
+                Duration::from_secs(issuer.config.renew_interval_secs)
+            }
+            Err(err) => {
+                warn!(target: "wapo::tls", domains, "failed to issue certificate: {err:?}");
+                let delay = retry_interval;
+                retry_interval = (retry_interval * 2).min(MAX_RETRY_INTERVAL);
+                delay
+            }
+        };
+        tokio::time::sleep(delay).await;
+        if released() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,343 @@
+//! The subset of the ACME protocol (RFC 8555) needed to order certificates for DNS names.
+
+use std::{sync::Mutex, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use reqwest::{header::HeaderValue, Response, StatusCode};
+use ring_crypto::{
+    digest,
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+const REPLAY_NONCE: &str = "Replay-Nonce";
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+/// How many times an order or authorization is polled before giving up.
+const MAX_POLLS: usize = 30;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Serialize)]
+struct Jwk {
+    crv: &'static str,
+    kty: &'static str,
+    x: String,
+    y: String,
+}
+
+#[derive(Serialize)]
+struct Protected<'a> {
+    alg: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jwk: Option<Jwk>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kid: Option<&'a str>,
+    nonce: String,
+    url: &'a str,
+}
+
+#[derive(Serialize)]
+struct Jws {
+    protected: String,
+    payload: String,
+    signature: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NewAccount<'a> {
+    terms_of_service_agreed: bool,
+    contact: &'a [String],
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct Identifier {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub value: String,
+}
+
+#[derive(Serialize)]
+struct NewOrder {
+    identifiers: Vec<Identifier>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(super) struct Order {
+    pub status: String,
+    #[serde(default)]
+    pub authorizations: Vec<String>,
+    pub finalize: String,
+    pub certificate: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(super) struct Authorization {
+    pub status: String,
+    pub identifier: Identifier,
+    #[serde(default)]
+    pub challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(super) struct Challenge {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub url: String,
+    #[serde(default)]
+    pub token: String,
+}
+
+#[derive(Serialize)]
+struct Finalize {
+    csr: String,
+}
+
+#[derive(Serialize)]
+struct Empty {}
+
+fn b64(data: impl AsRef<[u8]>) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+/// Generates the PKCS #8 document of a new account key.
+pub(super) fn generate_account_key() -> Result<Vec<u8>> {
+    let pkcs8 =
+        EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+            .or(Err(anyhow!("failed to generate account key")))?;
+    Ok(pkcs8.as_ref().to_vec())
+}
+
+/// An ACME account, registered with a key generated in the worker.
+pub(super) struct Account {
+    http: reqwest::Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    kid: String,
+    nonce: Mutex<Option<String>>,
+}
+
+impl Account {
+    /// Registers the account of the key with the CA behind the directory URL, which finds the
+    /// existing account if the key was registered before.
+    pub async fn register(
+        http: reqwest::Client,
+        directory_url: &str,
+        contact: &[String],
+        pkcs8: &[u8],
+    ) -> Result<Self> {
+        let response = http
+            .get(directory_url)
+            .send()
+            .await
+            .context("failed to fetch the ACME directory")?
+            .error_for_status()?;
+        let directory: Directory = json_body(response).await?;
+        let rng = SystemRandom::new();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8, &rng)
+            .or(Err(anyhow!("failed to load account key")))?;
+        let mut account = Self {
+            http,
+            directory,
+            key,
+            kid: String::new(),
+            nonce: Mutex::new(None),
+        };
+        let new_account = NewAccount {
+            terms_of_service_agreed: true,
+            contact,
+        };
+        let url = account.directory.new_account.clone();
+        let response = account.post(&url, Some(&new_account)).await?;
+        account.kid = response
+            .headers()
+            .get("Location")
+            .and_then(|location| location.to_str().ok())
+            .context("no account URL in the response")?
+            .to_string();
+        Ok(account)
+    }
+
+    fn jwk(&self) -> Jwk {
+        // The uncompressed point, `04 || x || y`.
+        let public_key = self.key.public_key().as_ref();
+        Jwk {
+            crv: "P-256",
+            kty: "EC",
+            x: b64(&public_key[1..33]),
+            y: b64(&public_key[33..]),
+        }
+    }
+
+    /// The key authorization of a challenge token, RFC 8555 section 8.1.
+    pub fn key_authorization(&self, token: &str) -> Result<String> {
+        // The members of the JWK are serialized in lexicographic order, as RFC 7638 requires.
+        let jwk = serde_json::to_vec(&self.jwk())?;
+        let thumbprint = digest::digest(&digest::SHA256, &jwk);
+        Ok(format!("{token}.{}", b64(thumbprint)))
+    }
+
+    async fn nonce(&self) -> Result<String> {
+        if let Some(nonce) = self.nonce.lock().unwrap().take() {
+            return Ok(nonce);
+        }
+        let response = self
+            .http
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .context("failed to fetch a nonce")?;
+        replay_nonce(response.headers().get(REPLAY_NONCE)).context("no nonce in the response")
+    }
+
+    fn sign(&self, url: &str, nonce: String, payload: &str) -> Result<Jws> {
+        let protected = Protected {
+            alg: "ES256",
+            jwk: self.kid.is_empty().then(|| self.jwk()),
+            kid: (!self.kid.is_empty()).then_some(self.kid.as_str()),
+            nonce,
+            url,
+        };
+        let protected = b64(serde_json::to_vec(&protected)?);
+        let payload = b64(payload);
+        let signature = self
+            .key
+            .sign(
+                &SystemRandom::new(),
+                format!("{protected}.{payload}").as_bytes(),
+            )
+            .or(Err(anyhow!("failed to sign the request")))?;
+        Ok(Jws {
+            protected,
+            payload,
+            signature: b64(signature),
+        })
+    }
+
+    /// Sends a signed request, or a POST-as-GET one without payload.
+    async fn post(&self, url: &str, payload: Option<&impl Serialize>) -> Result<Response> {
+        let payload = match payload {
+            Some(payload) => serde_json::to_string(payload)?,
+            None => String::new(),
+        };
+        let mut retried = false;
+        loop {
+            let jws = self.sign(url, self.nonce().await?, &payload)?;
+            let response = self
+                .http
+                .post(url)
+                .header("Content-Type", "application/jose+json")
+                .body(serde_json::to_vec(&jws)?)
+                .send()
+                .await
+                .with_context(|| format!("failed to post to {url}"))?;
+            if let Some(nonce) = replay_nonce(response.headers().get(REPLAY_NONCE)) {
+                *self.nonce.lock().unwrap() = Some(nonce);
+            }
+            if response.status().is_success() {
+                return Ok(response);
+            }
+            let status = response.status();
+            let problem = response.text().await.unwrap_or_default();
+            // Nonces may expire, retry once with a fresh one.
+            if status == StatusCode::BAD_REQUEST && problem.contains(BAD_NONCE) && !retried {
+                retried = true;
+                continue;
+            }
+            bail!("ACME request to {url} failed with {status}: {problem}");
+        }
+    }
+
+    pub async fn new_order(&self, names: &[String]) -> Result<(String, Order)> {
+        let new_order = NewOrder {
+            identifiers: names
+                .iter()
+                .map(|name| Identifier {
+                    kind: "dns".into(),
+                    value: name.clone(),
+                })
+                .collect(),
+        };
+        let response = self
+            .post(&self.directory.new_order, Some(&new_order))
+            .await?;
+        let url = response
+            .headers()
+            .get("Location")
+            .and_then(|location| location.to_str().ok())
+            .context("no order URL in the response")?
+            .to_string();
+        Ok((url, json_body(response).await?))
+    }
+
+    pub async fn authorization(&self, url: &str) -> Result<Authorization> {
+        json_body(self.post(url, None::<&Empty>).await?).await
+    }
+
+    /// Tells the CA that the challenge is ready to be validated.
+    pub async fn respond(&self, challenge: &Challenge) -> Result<()> {
+        self.post(&challenge.url, Some(&Empty {})).await?;
+        Ok(())
+    }
+
+    pub async fn order(&self, url: &str) -> Result<Order> {
+        json_body(self.post(url, None::<&Empty>).await?).await
+    }
+
+    /// Polls the authorization until it is no longer pending.
+    pub async fn wait_authorization(&self, url: &str) -> Result<Authorization> {
+        for _ in 0..MAX_POLLS {
+            let authorization = self.authorization(url).await?;
+            if authorization.status != "pending" {
+                return Ok(authorization);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        bail!("timed out waiting for the authorization")
+    }
+
+    /// Polls the order until it is no longer pending or processing.
+    pub async fn wait_order(&self, url: &str) -> Result<Order> {
+        for _ in 0..MAX_POLLS {
+            let order = self.order(url).await?;
+            if !matches!(order.status.as_str(), "pending" | "processing") {
+                return Ok(order);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        bail!("timed out waiting for the order")
+    }
+
+    pub async fn finalize(&self, order: &Order, csr: &[u8]) -> Result<()> {
+        let finalize = Finalize { csr: b64(csr) };
+        self.post(&order.finalize, Some(&finalize)).await?;
+        Ok(())
+    }
+
+    /// Downloads the PEM encoded certificate chain of a valid order.
+    pub async fn certificate(&self, order: &Order) -> Result<String> {
+        let url = order.certificate.as_deref().context("no certificate URL")?;
+        Ok(self.post(url, None::<&Empty>).await?.text().await?)
+    }
+}
+
+fn replay_nonce(value: Option<&HeaderValue>) -> Option<String> {
+    value?.to_str().ok().map(Into::into)
+}
+
+async fn json_body<T: DeserializeOwned>(response: Response) -> Result<T> {
+    let body = response
+        .bytes()
+        .await
+        .context("failed to read the response")?;
+    serde_json::from_slice(&body).context("invalid response from the ACME server")
+}
@@ -0,0 +1,667 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use ring_crypto::{
+    digest,
+    signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1, ECDSA_P256_SHA256_FIXED},
+};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    client::WebPkiServerVerifier,
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+use rustls_pki_types::{CertificateDer, ServerName, UnixTime};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::TlsConnector;
+
+use super::{x509::KeyPairDoc, AcmeConfig, AcmeHost};
+use crate::SniTlsListener;
+
+const DOMAIN: &str = "example.com";
+const OWNER: &str = "app";
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+#[test]
+fn self_signed_cert_is_valid() {
+    SniTlsListener::install_ring_provider();
+    let key = KeyPairDoc::generate().unwrap();
+    let cert = key.self_signed(&[DOMAIN.into()], None).unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(cert.clone().into()).unwrap();
+    let verifier = WebPkiServerVerifier::builder(roots.into()).build().unwrap();
+    let server_name = ServerName::try_from(DOMAIN).unwrap();
+    verifier
+        .verify_server_cert(&cert.into(), &[], &server_name, &[], UnixTime::now())
+        .unwrap();
+}
+
+#[test]
+fn challenge_cert_commits_to_key_authorization() {
+    let key_authorization = "token.thumbprint";
+    let digest =
+        ring_crypto::digest::digest(&ring_crypto::digest::SHA256, key_authorization.as_bytes());
+    let key = KeyPairDoc::generate().unwrap();
+    let cert = key
+        .self_signed(&[DOMAIN.into()], Some(key_authorization))
+        .unwrap();
+    let contains = |needle: &[u8]| cert.windows(needle.len()).any(|w| w == needle);
+    // The critical acmeIdentifier extension, holding the digest as an OCTET STRING.
+    assert!(contains(&[
+        0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x1f, 0x01, 0x01, 0xff
+    ]));
+    assert!(contains(
+        &[&[0x04, 0x22, 0x04, 0x20][..], digest.as_ref()].concat()
+    ));
+    assert!(contains(DOMAIN.as_bytes()));
+}
+
+/// Allows [`DOMAIN`] to [`OWNER`] only, and keeps the saved data in memory.
+#[derive(Default)]
+struct TestHost {
+    saved: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl AcmeHost for TestHost {
+    fn is_allowed(&self, owner: &str, name: &str) -> bool {
+        owner == OWNER && name == DOMAIN
+    }
+
+    fn load(&self, key: &str) -> Option<Vec<u8>> {
+        self.saved.lock().unwrap().get(key).cloned()
+    }
+
+    fn save(&self, key: &str, data: &[u8]) {
+        self.saved.lock().unwrap().insert(key.into(), data.into());
+    }
+}
+
+/// Accepts any server certificate, to look at the ones the listener serves.
+///
+/// Only P-256 keys are supported. The handshake signature is checked with the key of the
+/// certificate, as the critical acmeIdentifier extension makes webpki reject challenge ones.
+#[derive(Debug)]
+struct AcceptAnyCert;
+
+impl AcceptAnyCert {
+    fn verify_signature(
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let invalid = || rustls::Error::General("invalid handshake signature".into());
+        if dss.scheme != SignatureScheme::ECDSA_NISTP256_SHA256 {
+            return Err(invalid());
+        }
+        let public_key = cert_public_key(cert).ok_or_else(invalid)?;
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, public_key)
+            .verify(message, dss.signature())
+            .or(Err(invalid()))?;
+        Ok(HandshakeSignatureValid::assertion())
+    }
+}
+
+impl ServerCertVerifier for AcceptAnyCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Self::verify_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        Self::verify_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![SignatureScheme::ECDSA_NISTP256_SHA256]
+    }
+}
+
+/// Connects to the listener and returns the certificate it serves for the server name.
+async fn served_cert(addr: SocketAddr, server_name: &str, alpn: Option<&[u8]>) -> Option<Vec<u8>> {
+    let mut config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCert))
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.into_iter().map(<[u8]>::to_vec).collect();
+    let tcp = TcpStream::connect(addr).await.ok()?;
+    let server_name = ServerName::try_from(server_name.to_string()).ok()?;
+    let tls = TlsConnector::from(Arc::new(config))
+        .connect(server_name, tcp)
+        .await
+        .ok()?;
+    let (_, connection) = tls.get_ref();
+    if connection.alpn_protocol() != alpn {
+        return None;
+    }
+    Some(connection.peer_certificates()?.first()?.to_vec())
+}
+
+/// Splits the next DER element off `data`, returning the whole element and its value.
+fn next_der<'a>(data: &mut &'a [u8]) -> Option<(&'a [u8], &'a [u8])> {
+    let first = *data.get(1)? as usize;
+    let (header, len) = if first < 0x80 {
+        (2, first)
+    } else {
+        let n = first & 0x7f;
+        let len = data.get(2..2 + n)?;
+        (2 + n, len.iter().fold(0, |acc, b| (acc << 8) | *b as usize))
+    };
+    let element = data.get(..header + len)?;
+    *data = &data[header + len..];
+    Some((element, &element[header..]))
+}
+
+/// The public key of a certificate, `04 || x || y`.
+fn cert_public_key(cert: &[u8]) -> Option<&[u8]> {
+    let (_, mut cert) = next_der(&mut &cert[..])?;
+    let (_, mut tbs) = next_der(&mut cert)?;
+    // Version, serial number, signature algorithm, issuer, validity and subject.
+    for _ in 0..6 {
+        next_der(&mut tbs)?;
+    }
+    let (_, mut key_info) = next_der(&mut tbs)?;
+    let _algorithm = next_der(&mut key_info)?;
+    let (_, public_key) = next_der(&mut key_info)?;
+    public_key.get(1..)
+}
+
+/// Checks the signature and the names of a CSR, returning its subject public key info.
+fn check_csr(csr: &[u8], domain: &str) -> Option<Vec<u8>> {
+    let (_, mut csr) = next_der(&mut &csr[..])?;
+    let (info, mut info_fields) = next_der(&mut csr)?;
+    let _algorithm = next_der(&mut csr)?;
+    let (_, signature) = next_der(&mut csr)?;
+    let _version = next_der(&mut info_fields)?;
+    let _subject = next_der(&mut info_fields)?;
+    let (key_info, mut key_fields) = next_der(&mut info_fields)?;
+    let _key_algorithm = next_der(&mut key_fields)?;
+    let (_, public_key) = next_der(&mut key_fields)?;
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, &public_key[1..])
+        .verify(info, &signature[1..])
+        .ok()?;
+    let contains_domain = info_fields
+        .windows(domain.len())
+        .any(|window| window == domain.as_bytes());
+    contains_domain.then(|| key_info.to_vec())
+}
+
+struct Reply {
+    status: u16,
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn json(status: u16, body: Value) -> Self {
+        Self {
+            status,
+            location: None,
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    fn created(location: String, body: Value) -> Self {
+        Self {
+            location: Some(location),
+            ..Self::json(201, body)
+        }
+    }
+
+    fn problem(kind: &str) -> Self {
+        Self::json(
+            400,
+            json!({ "type": format!("urn:ietf:params:acme:error:{kind}") }),
+        )
+    }
+}
+
+struct MockOrder {
+    domain: String,
+    /// "pending" until the challenge is validated, "ready" until finalized, then "valid".
+    status: &'static str,
+    token: String,
+    certificate: Option<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct MockState {
+    next_nonce: u64,
+    nonces: HashSet<String>,
+    /// Requests to reject with badNonce, as if their nonce had expired.
+    expired_nonces: usize,
+    /// The JWK coordinates of the registered account key.
+    account: Option<(String, String)>,
+    orders: Vec<MockOrder>,
+    /// What the CA rejected, reported by the test rather than retried by the issuer.
+    problems: Vec<String>,
+}
+
+/// A stand-in for an ACME CA like Pebble, validating TLS-ALPN-01 challenges by connecting to the
+/// listener at `sni_addr`.
+struct MockCa {
+    base: String,
+    sni_addr: SocketAddr,
+    key: KeyPairDoc,
+    cert: Vec<u8>,
+    state: Mutex<MockState>,
+}
+
+impl MockCa {
+    async fn serve(sni_addr: SocketAddr) -> Arc<Self> {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let key = KeyPairDoc::generate().unwrap();
+        let cert = key.self_signed(&[], None).unwrap();
+        let ca = Arc::new(Self {
+            base: format!("http://{}", tcp.local_addr().unwrap()),
+            sni_addr,
+            key,
+            cert,
+            state: Mutex::new(MockState {
+                expired_nonces: 1,
+                ..Default::default()
+            }),
+        });
+        let server = ca.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = tcp.accept().await {
+                tokio::spawn(server.clone().handle(stream));
+            }
+        });
+        ca
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base)
+    }
+
+    fn issued(&self) -> Vec<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state
+            .orders
+            .iter()
+            .filter_map(|order| order.certificate.clone())
+            .collect()
+    }
+
+    async fn handle(self: Arc<Self>, mut stream: TcpStream) {
+        let Some((method, path, body)) = read_request(&mut stream).await else {
+            return;
+        };
+        let reply = match (method.as_str(), path.as_str()) {
+            ("GET", "/directory") => Reply::json(
+                200,
+                json!({
+                    "newNonce": self.url("/nonce"),
+                    "newAccount": self.url("/account"),
+                    "newOrder": self.url("/order"),
+                }),
+            ),
+            ("HEAD", "/nonce") => Reply::json(200, Value::Null),
+            ("POST", _) => self.post(&path, &body).await,
+            _ => Reply::json(404, Value::Null),
+        };
+        let nonce = {
+            let mut state = self.state.lock().unwrap();
+            state.next_nonce += 1;
+            let nonce = format!("nonce-{}", state.next_nonce);
+            state.nonces.insert(nonce.clone());
+            nonce
+        };
+        let body = if method == "HEAD" { vec![] } else { reply.body };
+        let mut head = format!(
+            "HTTP/1.1 {} X\r\nreplay-nonce: {nonce}\r\ncontent-length: {}\r\nconnection: close\r\n",
+            reply.status,
+            body.len()
+        );
+        if let Some(location) = reply.location {
+            head.push_str(&format!("location: {location}\r\n"));
+        }
+        head.push_str("\r\n");
+        let _ = stream.write_all(head.as_bytes()).await;
+        let _ = stream.write_all(&body).await;
+    }
+
+    /// Checks the JWS of a request like a CA would, returning its payload.
+    fn verify_jws(&self, url: &str, body: &[u8]) -> Result<Value, &'static str> {
+        let decode = |value: &Value| {
+            URL_SAFE_NO_PAD
+                .decode(value.as_str().unwrap_or_default())
+                .or(Err("malformed"))
+        };
+        let jws: Value = serde_json::from_slice(body).or(Err("malformed"))?;
+        let protected: Value =
+            serde_json::from_slice(&decode(&jws["protected"])?).or(Err("malformed"))?;
+        let mut state = self.state.lock().unwrap();
+        if !state
+            .nonces
+            .remove(protected["nonce"].as_str().unwrap_or_default())
+        {
+            return Err("badNonce");
+        }
+        if state.expired_nonces > 0 {
+            state.expired_nonces -= 1;
+            return Err("badNonce");
+        }
+        if protected["alg"] != "ES256" || protected["url"] != url {
+            return Err("malformed");
+        }
+        let (x, y) = if url == self.url("/account") {
+            let jwk = &protected["jwk"];
+            let coordinate = |name| jwk[name].as_str().unwrap_or_default().to_string();
+            (coordinate("x"), coordinate("y"))
+        } else if protected["kid"] == self.url("/account/1") {
+            state.account.clone().ok_or("accountDoesNotExist")?
+        } else {
+            return Err("unauthorized");
+        };
+        let public_key = [&[0x04][..], &decode(&json!(x))?, &decode(&json!(y))?].concat();
+        let signed = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap_or_default(),
+            jws["payload"].as_str().unwrap_or_default()
+        );
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, public_key)
+            .verify(signed.as_bytes(), &decode(&jws["signature"])?)
+            .or(Err("unauthorized"))?;
+        if state.account.is_none() {
+            state.account = Some((x, y));
+        }
+        let payload = decode(&jws["payload"])?;
+        if payload.is_empty() {
+            return Ok(Value::Null);
+        }
+        serde_json::from_slice(&payload).or(Err("malformed"))
+    }
+
+    fn order_json(&self, id: usize, order: &MockOrder) -> Value {
+        let mut value = json!({
+            "status": order.status,
+            "authorizations": [self.url(&format!("/authz/{id}"))],
+            "finalize": self.url(&format!("/finalize/{id}")),
+        });
+        if order.certificate.is_some() {
+            value["certificate"] = json!(self.url(&format!("/cert/{id}")));
+        }
+        value
+    }
+
+    async fn post(&self, path: &str, body: &[u8]) -> Reply {
+        let reply = match self.verify_jws(&self.url(path), body) {
+            Ok(payload) => self.route(path, payload).await,
+            Err(kind) => Err(kind),
+        };
+        reply.unwrap_or_else(|kind| {
+            if kind != "badNonce" {
+                let problem = format!("{kind} on {path}");
+                self.state.lock().unwrap().problems.push(problem);
+            }
+            Reply::problem(kind)
+        })
+    }
+
+    async fn route(&self, path: &str, payload: Value) -> Result<Reply, &'static str> {
+        if path == "/account" {
+            let account = json!({ "status": "valid", "contact": payload["contact"] });
+            return Ok(Reply::created(self.url("/account/1"), account));
+        }
+        if path == "/order" {
+            let identifiers = payload["identifiers"].as_array().ok_or("malformed")?;
+            let [identifier] = &identifiers[..] else {
+                return Err("rejectedIdentifier");
+            };
+            if identifier["type"] != "dns" {
+                return Err("rejectedIdentifier");
+            }
+            let mut state = self.state.lock().unwrap();
+            let id = state.orders.len();
+            let order = MockOrder {
+                domain: identifier["value"].as_str().unwrap_or_default().into(),
+                status: "pending",
+                token: format!("token-{id}"),
+                certificate: None,
+            };
+            let value = self.order_json(id, &order);
+            state.orders.push(order);
+            return Ok(Reply::created(self.url(&format!("/order/{id}")), value));
+        }
+        let (kind, id) = path[1..].split_once('/').ok_or("malformed")?;
+        let id: usize = id.parse().or(Err("malformed"))?;
+        let (domain, status, token) = {
+            let state = self.state.lock().unwrap();
+            let order = state.orders.get(id).ok_or("malformed")?;
+            (order.domain.clone(), order.status, order.token.clone())
+        };
+        match kind {
+            "order" => {
+                let state = self.state.lock().unwrap();
+                Ok(Reply::json(200, self.order_json(id, &state.orders[id])))
+            }
+            "authz" => Ok(Reply::json(
+                200,
+                json!({
+                    "status": if status == "pending" { "pending" } else { "valid" },
+                    "identifier": { "type": "dns", "value": domain },
+                    "challenges": [
+                        { "type": "http-01", "url": self.url("/unused"), "token": token },
+                        { "type": "tls-alpn-01", "url": self.url(&format!("/challenge/{id}")), "token": token },
+                    ],
+                }),
+            )),
+            "challenge" => {
+                self.validate(&domain, &token).await?;
+                self.state.lock().unwrap().orders[id].status = "ready";
+                Ok(Reply::json(
+                    200,
+                    json!({ "type": "tls-alpn-01", "status": "valid" }),
+                ))
+            }
+            "finalize" => {
+                if status != "ready" {
+                    return Err("orderNotReady");
+                }
+                let csr = URL_SAFE_NO_PAD
+                    .decode(payload["csr"].as_str().unwrap_or_default())
+                    .or(Err("badCSR"))?;
+                let key_info = check_csr(&csr, &domain).ok_or("badCSR")?;
+                let cert = self
+                    .key
+                    .certificate("Mock CA", key_info, &[domain], None)
+                    .or(Err("serverInternal"))?;
+                let mut state = self.state.lock().unwrap();
+                let order = &mut state.orders[id];
+                order.status = "valid";
+                order.certificate = Some(cert);
+                Ok(Reply::json(200, self.order_json(id, order)))
+            }
+            "cert" => {
+                let state = self.state.lock().unwrap();
+                let cert = state.orders[id].certificate.as_ref().ok_or("malformed")?;
+                let pem: String = [cert, &self.cert]
+                    .iter()
+                    .map(|der| {
+                        let base64 = base64::engine::general_purpose::STANDARD.encode(der);
+                        format!(
+                            "-----BEGIN CERTIFICATE-----\n{base64}\n-----END CERTIFICATE-----\n"
+                        )
+                    })
+                    .collect();
+                Ok(Reply {
+                    status: 200,
+                    location: None,
+                    body: pem.into_bytes(),
+                })
+            }
+            _ => Err("malformed"),
+        }
+    }
+
+    /// Validates a TLS-ALPN-01 challenge, RFC 8737 section 3.
+    async fn validate(&self, domain: &str, token: &str) -> Result<(), &'static str> {
+        let (x, y) = self
+            .state
+            .lock()
+            .unwrap()
+            .account
+            .clone()
+            .ok_or("unauthorized")?;
+        // RFC 7638 thumbprint, with the members in lexicographic order.
+        let jwk = format!(r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#);
+        let thumbprint = digest::digest(&digest::SHA256, jwk.as_bytes());
+        let key_authorization = format!("{token}.{}", URL_SAFE_NO_PAD.encode(thumbprint));
+        let cert = served_cert(self.sni_addr, domain, Some(ACME_TLS_ALPN))
+            .await
+            .ok_or("connection")?;
+        let digest = digest::digest(&digest::SHA256, key_authorization.as_bytes());
+        let contains = |needle: &[u8]| cert.windows(needle.len()).any(|w| w == needle);
+        if !contains(&[&[0x04, 0x22, 0x04, 0x20][..], digest.as_ref()].concat())
+            || !contains(domain.as_bytes())
+        {
+            return Err("incorrectResponse");
+        }
+        Ok(())
+    }
+
+    /// Waits for the listener to serve the `n`th certificate the CA issued, or a later one.
+    async fn wait_served(&self, n: usize) -> Vec<u8> {
+        for _ in 0..200 {
+            let problems = self.state.lock().unwrap().problems.clone();
+            assert!(
+                problems.is_empty(),
+                "the CA rejected requests: {problems:?}"
+            );
+            let issued = self.issued();
+            if issued.len() >= n {
+                let served = served_cert(self.sni_addr, DOMAIN, None).await;
+                if served.is_some_and(|served| issued[n - 1..].contains(&served)) {
+                    return issued[n - 1].clone();
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("timed out waiting for certificate {n}");
+    }
+}
+
+/// Reads an HTTP request, returning its method, path and body.
+async fn read_request(stream: &mut TcpStream) -> Option<(String, String, Vec<u8>)> {
+    let mut data = vec![];
+    let mut buf = [0; 4096];
+    let mut content = None;
+    loop {
+        if content.is_none() {
+            if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&data[..end]).into_owned();
+                let len: usize = head
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                    .and_then(|(_, value)| value.trim().parse().ok())
+                    .unwrap_or(0);
+                content = Some((head, end + 4, len));
+            }
+        }
+        if let Some((head, start, len)) = &content {
+            if data.len() >= start + len {
+                let mut request_line = head.split(' ');
+                let method = request_line.next()?.to_string();
+                let path = request_line.next()?.to_string();
+                return Some((method, path, data[*start..start + len].to_vec()));
+            }
+        }
+        let n = stream.read(&mut buf).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        data.extend_from_slice(&buf[..n]);
+    }
+}
+
+#[tokio::test]
+async fn certificates_are_issued_and_rotated() {
+    SniTlsListener::install_ring_provider();
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let listener = SniTlsListener::bind("127.0.0.1", port, false)
+        .await
+        .unwrap();
+    let sni_addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let ca = MockCa::serve(sni_addr).await;
+    let config = AcmeConfig {
+        directory_url: ca.url("/directory"),
+        contact: vec!["mailto:admin@example.com".into()],
+        ca_cert: None,
+        renew_interval_secs: 2,
+    };
+    let host = Arc::new(TestHost::default());
+    listener.enable_acme(config.clone(), host.clone()).unwrap();
+    assert!(listener.acme_key("other", DOMAIN).is_err());
+    assert!(listener.acme_key(OWNER, "other.example.com").is_err());
+    let placeholder = listener.acme_key(OWNER, DOMAIN).unwrap();
+    let subscription = listener.subscribe(DOMAIN, placeholder.clone()).unwrap();
+
+    // The issued certificate replaces the placeholder, and then the renewed one replaces it.
+    let first = ca.wait_served(1).await;
+    assert_ne!(first, placeholder.cert[0].to_vec());
+    let second = ca.wait_served(2).await;
+    assert_ne!(first, second);
+
+    // Updating the subscription with the key it was made with keeps the current certificate.
+    listener.update_key(DOMAIN, placeholder.clone()).unwrap();
+    let served = served_cert(sni_addr, DOMAIN, None).await.unwrap();
+    assert!(ca.issued()[1..].contains(&served));
+
+    // The challenge certificate is only served while the CA validates it.
+    assert_eq!(
+        served_cert(sni_addr, DOMAIN, Some(ACME_TLS_ALPN)).await,
+        None
+    );
+
+    // The keys are dropped along with the subscription.
+    assert_eq!(listener.n_managed_keys(), 1);
+    drop(subscription);
+    assert_eq!(listener.n_managed_keys(), 0);
+
+    // A saved certificate not due for renewal is served again without ordering a new one.
+    let restarted = SniTlsListener::bind("127.0.0.1", 0, false).await.unwrap();
+    let config = AcmeConfig {
+        renew_interval_secs: 3600,
+        ..config
+    };
+    restarted.enable_acme(config, host).unwrap();
+    let issued = ca.issued();
+    let key = restarted.acme_key(OWNER, DOMAIN).unwrap();
+    assert_eq!(key.cert[0].to_vec(), *issued.last().unwrap());
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(ca.issued().len(), issued.len());
+}
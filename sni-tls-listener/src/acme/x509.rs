@@ -0,0 +1,242 @@
+//! Just enough DER encoding to build the P-256 certificates and CSRs needed by the ACME client.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use ring_crypto::{
+    digest,
+    rand::{SecureRandom, SystemRandom},
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+};
+use rustls::{crypto::CryptoProvider, sign::CertifiedKey};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+
+const BOOLEAN: u8 = 0x01;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const UTF8_STRING: u8 = 0x0c;
+const UTC_TIME: u8 = 0x17;
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const CONTEXT_0: u8 = 0xa0;
+const CONTEXT_3: u8 = 0xa3;
+const DNS_NAME: u8 = 0x82;
+
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const OID_EXTENSION_REQUEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e];
+/// id-pe-acmeIdentifier, RFC 8737.
+const OID_ACME_IDENTIFIER: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x1f];
+
+/// The longest value X.520 allows for a common name.
+const MAX_COMMON_NAME_LEN: usize = 64;
+/// The common name of the self-signed certificates.
+const SELF_SIGNED_NAME: &str = "sni-tls-listener";
+
+fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(value);
+    out
+}
+
+fn seq(items: &[Vec<u8>]) -> Vec<u8> {
+    tlv(SEQUENCE, &items.concat())
+}
+
+fn bit_string(value: &[u8]) -> Vec<u8> {
+    tlv(BIT_STRING, &[&[0], value].concat())
+}
+
+fn name(common_name: Option<&str>) -> Vec<u8> {
+    match common_name {
+        Some(cn) => seq(&[tlv(
+            SET,
+            &seq(&[tlv(OID, OID_COMMON_NAME), tlv(UTF8_STRING, cn.as_bytes())]),
+        )]),
+        None => seq(&[]),
+    }
+}
+
+fn extension(oid: &[u8], critical: bool, value: &[u8]) -> Vec<u8> {
+    let mut items = vec![tlv(OID, oid)];
+    if critical {
+        items.push(tlv(BOOLEAN, &[0xff]));
+    }
+    items.push(tlv(OCTET_STRING, value));
+    seq(&items)
+}
+
+fn subject_alt_name(names: &[String]) -> Vec<u8> {
+    let names: Vec<_> = names
+        .iter()
+        .map(|name| tlv(DNS_NAME, name.as_bytes()))
+        .collect();
+    extension(OID_SUBJECT_ALT_NAME, false, &seq(&names))
+}
+
+fn signature_algorithm() -> Vec<u8> {
+    seq(&[tlv(OID, OID_ECDSA_WITH_SHA256)])
+}
+
+/// A P-256 key generated in the worker, which never leaves it.
+pub(super) struct KeyPairDoc {
+    pkcs8: Vec<u8>,
+    pair: EcdsaKeyPair,
+}
+
+impl KeyPairDoc {
+    pub fn generate() -> Result<Self> {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+            .or(Err(anyhow!("failed to generate key")))?;
+        let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+            .or(Err(anyhow!("failed to load generated key")))?;
+        Ok(Self {
+            pkcs8: pkcs8.as_ref().to_vec(),
+            pair,
+        })
+    }
+
+    /// Loads a key saved with [`Self::pkcs8`].
+    pub fn from_pkcs8(pkcs8: Vec<u8>) -> Result<Self> {
+        let pair = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_ASN1_SIGNING,
+            &pkcs8,
+            &SystemRandom::new(),
+        )
+        .or(Err(anyhow!("failed to load key")))?;
+        Ok(Self { pkcs8, pair })
+    }
+
+    /// The PKCS #8 document of the key.
+    pub fn pkcs8(&self) -> &[u8] {
+        &self.pkcs8
+    }
+
+    fn public_key_info(&self) -> Vec<u8> {
+        seq(&[
+            seq(&[tlv(OID, OID_EC_PUBLIC_KEY), tlv(OID, OID_PRIME256V1)]),
+            bit_string(self.pair.public_key().as_ref()),
+        ])
+    }
+
+    /// Signs `tbs` and wraps it with the signature algorithm and the signature.
+    fn signed(&self, tbs: Vec<u8>) -> Result<Vec<u8>> {
+        let signature = self
+            .pair
+            .sign(&SystemRandom::new(), &tbs)
+            .or(Err(anyhow!("failed to sign")))?;
+        Ok(seq(&[
+            tbs,
+            signature_algorithm(),
+            bit_string(signature.as_ref()),
+        ]))
+    }
+
+    /// Builds a PKCS #10 certificate signing request for the names.
+    pub fn csr(&self, names: &[String]) -> Result<Vec<u8>> {
+        let common_name = names
+            .first()
+            .map(String::as_str)
+            .filter(|name| name.len() <= MAX_COMMON_NAME_LEN);
+        let extension_request = seq(&[
+            tlv(OID, OID_EXTENSION_REQUEST),
+            tlv(SET, &seq(&[subject_alt_name(names)])),
+        ]);
+        let info = seq(&[
+            tlv(INTEGER, &[0]),
+            name(common_name),
+            self.public_key_info(),
+            tlv(CONTEXT_0, &extension_request),
+        ]);
+        self.signed(info)
+    }
+
+    /// Builds a self-signed certificate for the names.
+    ///
+    /// With `key_authorization`, the certificate carries the acmeIdentifier extension and answers
+    /// a TLS-ALPN-01 challenge.
+    pub fn self_signed(
+        &self,
+        names: &[String],
+        key_authorization: Option<&str>,
+    ) -> Result<Vec<u8>> {
+        self.certificate(
+            SELF_SIGNED_NAME,
+            self.public_key_info(),
+            names,
+            key_authorization,
+        )
+    }
+
+    /// Builds a certificate of the DER encoded subject public key info for the names, issued by
+    /// this key under the `issuer` name.
+    pub fn certificate(
+        &self,
+        issuer: &str,
+        subject_key_info: Vec<u8>,
+        names: &[String],
+        key_authorization: Option<&str>,
+    ) -> Result<Vec<u8>> {
+        let mut serial = [0u8; 16];
+        SystemRandom::new()
+            .fill(&mut serial)
+            .or(Err(anyhow!("failed to generate serial number")))?;
+        // Positive and without redundant leading zeros.
+        serial[0] = (serial[0] & 0x7f) | 0x40;
+        let mut extensions = vec![subject_alt_name(names)];
+        if let Some(key_authorization) = key_authorization {
+            let digest = digest::digest(&digest::SHA256, key_authorization.as_bytes());
+            extensions.push(extension(
+                OID_ACME_IDENTIFIER,
+                true,
+                &tlv(OCTET_STRING, digest.as_ref()),
+            ));
+        }
+        let tbs = seq(&[
+            tlv(CONTEXT_0, &tlv(INTEGER, &[2])),
+            tlv(INTEGER, &serial),
+            signature_algorithm(),
+            name(Some(issuer)),
+            seq(&[
+                tlv(UTC_TIME, b"000101000000Z"),
+                tlv(UTC_TIME, b"491231235959Z"),
+            ]),
+            name(Some(SELF_SIGNED_NAME)),
+            subject_key_info,
+            tlv(CONTEXT_3, &seq(&extensions)),
+        ]);
+        self.signed(tbs)
+    }
+
+    /// Pairs the key with its certificate chain for serving.
+    pub fn certified(&self, chain: Vec<CertificateDer<'static>>) -> Result<Arc<CertifiedKey>> {
+        let key = CryptoProvider::get_default()
+            .context("no crypto provider")?
+            .key_provider
+            .load_private_key(PrivateKeyDer::Pkcs8(self.pkcs8.clone().into()))
+            .context("failed to load private key")?;
+        Ok(Arc::new(CertifiedKey::new(chain, key)))
+    }
+}
+
+/// A key with a self-signed certificate, served for the names until the CA issues one.
+pub(crate) fn placeholder_key(names: &[String]) -> Result<Arc<CertifiedKey>> {
+    let key = KeyPairDoc::generate()?;
+    let cert = key.self_signed(names, None)?;
+    key.certified(vec![cert.into()])
+}
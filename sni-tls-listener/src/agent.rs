@@ -1,4 +1,4 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use core::fmt;
 use std::{
     collections::{BTreeMap, VecDeque},
@@ -16,7 +16,7 @@ use tracing::info;
 
 use crate::{
     traits::{Generate, Subscribe},
-    SniTlsListener,
+    RoutingListener, SniTlsListener,
 };
 
 #[cfg(test)]
@@ -32,12 +32,84 @@ type ConnectionOf<T> = <<<T as Config>::Listener as Subscribe>::Sub as Generate>
 type KeyOf<T> = <<T as Config>::Listener as Subscribe>::Key;
 type SubscriptionOf<T> = <<T as Config>::Listener as Subscribe>::Sub;
 
+/// Limits on the connections of each domain.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// The connections accepted per second, unlimited if zero.
+    pub accept_rate: u32,
+    /// How many connections can be accepted at once beyond the rate.
+    pub accept_burst: u32,
+    /// How many connections can wait for an instance to take them.
+    pub max_pending: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            accept_rate: 0,
+            accept_burst: 0,
+            max_pending: 1024,
+        }
+    }
+}
+
+/// Counters of the connections of a domain, since it was first subscribed through the agent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Connections handed to an instance or queued for one.
+    pub accepted: u64,
+    /// Connections refused by the limits or failed to be handed over.
+    pub dropped: u64,
+    /// Queued connections no instance took in time.
+    pub timed_out: u64,
+    /// Connections for the domain whose TLS handshake failed in the listener.
+    pub handshake_failed: u64,
+    /// Connections currently waiting for an instance.
+    pub pending: u64,
+}
+
+/// A token bucket of the accept rate.
+struct RateLimiter {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        Self {
+            tokens: f64::INFINITY,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn try_acquire(&mut self, rate: u32, burst: u32) -> bool {
+        if rate == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * rate as f64;
+        self.tokens = (self.tokens + refill).min(burst.max(1) as f64);
+        self.last_refill = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
 pub struct DefaultConfig;
 
 impl Config for DefaultConfig {
     type Listener = SniTlsListener;
 }
 
+pub struct RoutedConfig;
+
+impl Config for RoutedConfig {
+    type Listener = RoutingListener;
+}
+
 pub struct Subscription<T: Config> {
     id: u64,
     domain: String,
@@ -107,6 +179,7 @@ fn sub_channel<T: Config>(domain: String, agent: WeakAgent<T>) -> (SubTx<T>, Sub
 struct SubState<T: Config> {
     queue: VecDeque<(Instant, ConnectionOf<T>)>,
     subscriptions: BTreeMap<u64, SubTx<T>>,
+    limiter: RateLimiter,
     _cancel_tx: oneshot::Sender<()>,
 }
 
@@ -115,13 +188,17 @@ impl<T: Config> SubState<T> {
         Self {
             queue: Default::default(),
             subscriptions: Default::default(),
+            limiter: RateLimiter::new(),
             _cancel_tx: cancel_tx,
         }
     }
 
-    fn clear_timeout_connections(&mut self, threshold: Duration) {
+    /// Drops the queued connections older than the threshold, returning how many.
+    fn clear_timeout_connections(&mut self, threshold: Duration) -> usize {
+        let len = self.queue.len();
         self.queue
             .retain(|(instant, _)| instant.elapsed() < threshold);
+        len - self.queue.len()
     }
 }
 
@@ -172,6 +249,12 @@ struct AgentState<T: Config> {
     create_instance: Box<dyn Fn() + Send + Sync + 'static>,
     reuse_subscriber: bool,
     connect_timeout: Duration,
+    limits: Limits,
+    /// The counters of each subscribed domain, removed with its last subscription.
+    stats: BTreeMap<String, ConnectionStats>,
+    /// Who the ACME certificates are requested for through the agent.
+    #[cfg(feature = "acme")]
+    acme_owner: String,
 }
 
 impl<T: Config> Agent<T> {
@@ -190,6 +273,10 @@ impl<T: Config> Agent<T> {
                     create_instance: Box::new(create_instance) as _,
                     reuse_subscriber,
                     connect_timeout,
+                    limits: Limits::default(),
+                    stats: Default::default(),
+                    #[cfg(feature = "acme")]
+                    acme_owner: String::new(),
                 })
             }),
         }
@@ -203,6 +290,28 @@ impl<T: Config> Agent<T> {
         self.lock().remove_sub(domain, id)
     }
 
+    pub fn set_limits(&self, limits: Limits) {
+        self.lock().limits = limits;
+    }
+
+    /// The connection counters of each domain subscribed through the agent.
+    pub fn stats(&self) -> BTreeMap<String, ConnectionStats> {
+        let state = self.lock();
+        state
+            .stats
+            .iter()
+            .map(|(domain, stats)| {
+                let mut stats = stats.clone();
+                stats.handshake_failed = state.listener.handshake_failures(domain);
+                stats.pending = state
+                    .subscribers
+                    .get(domain)
+                    .map_or(0, |sub| sub.queue.len() as u64);
+                (domain.clone(), stats)
+            })
+            .collect()
+    }
+
     #[cfg(test)]
     fn queued_connections(&self, domain: &str) -> usize {
         self.lock()
@@ -299,7 +408,10 @@ where
                         if let Some(agent) = weak_self.upgrade() {
                             let mut state = agent.lock();
                             if let Some(sub) = state.subscribers.get_mut(&owned_domain) {
-                                sub.clear_timeout_connections(timeout);
+                                let timed_out = sub.clear_timeout_connections(timeout);
+                                if let Some(stats) = state.stats.get_mut(&owned_domain) {
+                                    stats.timed_out += timed_out as u64;
+                                }
                             }
                         }
                     }
@@ -312,6 +424,7 @@ where
                     }
                 });
                 self.subscribers.insert(domain.to_string(), sub_state);
+                self.stats.entry(domain.to_string()).or_default();
                 rx
             }
         };
@@ -320,19 +433,34 @@ where
 
     fn dispatch_connection(&mut self, domain: &str, connection: ConnectionOf<T>) -> Result<()> {
         let sub = self.subscribers.get_mut(domain).context("no subscribers")?;
-
+        let stats = self.stats.entry(domain.to_string()).or_default();
+        let limits = &self.limits;
+
+        if !sub
+            .limiter
+            .try_acquire(limits.accept_rate, limits.accept_burst)
+        {
+            stats.dropped += 1;
+            bail!("accept rate limit exceeded");
+        }
         let subscriber = match self.reuse_subscriber {
             true => sub.subscriptions.values().next().cloned(),
             false => None,
         };
         if let Some(subscriber) = subscriber {
-            subscriber
-                .send(connection)
-                .context("failed to dispatch the connection")?;
+            if let Err(err) = subscriber.send(connection) {
+                stats.dropped += 1;
+                return Err(err.context("failed to dispatch the connection"));
+            }
         } else {
+            if sub.queue.len() >= limits.max_pending {
+                stats.dropped += 1;
+                bail!("too many pending connections");
+            }
             sub.queue.push_back((Instant::now(), connection));
             (self.create_instance)();
         }
+        stats.accepted += 1;
         Ok(())
     }
 }
@@ -340,6 +468,7 @@ where
 impl<T: Config> AgentState<T> {
     fn unsubscribe(&mut self, domain: &str) -> Result<()> {
         let _sub = self.subscribers.remove(domain).context("no subscribers")?;
+        self.stats.remove(domain);
         Ok(())
     }
 
@@ -363,3 +492,28 @@ impl SniTlsListener {
         Agent::new(self.clone(), create_instance, reuse, timeout)
     }
 }
+
+impl RoutingListener {
+    pub fn agent(
+        &self,
+        create_instance: impl Fn() + Send + Sync + 'static,
+        reuse: bool,
+        timeout: Duration,
+    ) -> Agent<RoutedConfig> {
+        Agent::new(self.clone(), create_instance, reuse, timeout)
+    }
+}
+
+#[cfg(feature = "acme")]
+impl Agent<DefaultConfig> {
+    /// Sets the owner to request the ACME certificates for, see [`SniTlsListener::acme_key`].
+    pub fn set_acme_owner(&self, owner: &str) {
+        self.lock().acme_owner = owner.to_string();
+    }
+
+    /// See [`SniTlsListener::acme_key`].
+    pub fn acme_key(&self, domain: &str) -> Result<Arc<rustls::sign::CertifiedKey>> {
+        let state = self.lock();
+        state.listener.acme_key(&state.acme_owner, domain)
+    }
+}
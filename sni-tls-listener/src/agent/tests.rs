@@ -1,4 +1,4 @@
-use core::task::Poll;
+use core::{future::Future, task::Poll};
 use std::pin::Pin;
 
 use mpsc::error::TryRecvError;
@@ -247,3 +247,62 @@ async fn connect_timeout_works() {
     // Should timed out
     assert_eq!(agent.queued_connections(EXAMPLE_DOMAIN), 0);
 }
+
+#[tokio::test]
+async fn limits_and_stats_work() {
+    let listener = TestListener {
+        subscriptions: Default::default(),
+    };
+
+    let (inst_tx, _inst_rx) = mpsc::channel(32);
+    let reuse = false;
+    let agent = Agent::<TestConfig>::new(
+        listener.clone(),
+        move || inst_tx.try_send(()).unwrap(),
+        reuse,
+        Duration::from_millis(1000),
+    );
+    agent.set_limits(Limits {
+        accept_rate: 0,
+        accept_burst: 0,
+        max_pending: 2,
+    });
+
+    let sub0 = agent.subscribe(EXAMPLE_DOMAIN, "key0".to_string()).unwrap();
+    for i in 0..3 {
+        listener.send_connection(EXAMPLE_DOMAIN, format!("conn{i}"));
+    }
+    sleep_ms(10).await;
+
+    // The third connection should be dropped
+    let stats = agent.stats().remove(EXAMPLE_DOMAIN).unwrap();
+    assert_eq!(
+        stats,
+        ConnectionStats {
+            accepted: 2,
+            dropped: 1,
+            pending: 2,
+            ..Default::default()
+        }
+    );
+
+    agent.set_limits(Limits {
+        accept_rate: 1,
+        accept_burst: 1,
+        max_pending: 16,
+    });
+    let mut sub1 = agent.subscribe(EXAMPLE_DOMAIN, "key0".to_string()).unwrap();
+    assert_eq!(should_ready(sub1.next()).await.unwrap().unwrap(), "conn0");
+    for i in 3..6 {
+        listener.send_connection(EXAMPLE_DOMAIN, format!("conn{i}"));
+    }
+    sleep_ms(10).await;
+
+    // Only one connection fits in the burst
+    let stats = agent.stats().remove(EXAMPLE_DOMAIN).unwrap();
+    assert_eq!((stats.accepted, stats.dropped), (3, 3));
+
+    // The counters go away with the last subscription
+    drop((sub0, sub1));
+    assert!(agent.stats().is_empty());
+}
@@ -1,10 +1,25 @@
+#[cfg(feature = "acme")]
+pub use acme::{AcmeConfig, AcmeHost};
+pub use agent::{ConnectionStats, Limits};
 pub use listener::{wrap_certified_key, SniTlsListener};
+pub use names::normalize_names;
+pub use prefixed::PrefixedStream;
+pub use proxy_protocol::ProxyProtocol;
+pub use routing::{Route, RoutingListener};
 
 pub type Agent = agent::Agent<agent::DefaultConfig>;
 pub type Subscription = agent::Subscription<agent::DefaultConfig>;
+pub type RoutedAgent = agent::Agent<agent::RoutedConfig>;
+pub type RoutedSubscription = agent::Subscription<agent::RoutedConfig>;
 
 pub use traits::{Generate, Subscribe};
 
+#[cfg(feature = "acme")]
+mod acme;
 mod agent;
 mod listener;
+mod names;
+mod prefixed;
+mod proxy_protocol;
+mod routing;
 mod traits;
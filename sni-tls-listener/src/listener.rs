@@ -1,5 +1,6 @@
 use std::{
     collections::HashMap,
+    io,
     net::SocketAddr,
     sync::{Arc, Mutex, Weak},
 };
@@ -8,31 +9,45 @@ use anyhow::{Context, Result};
 use rustls::{
     client::{danger::ServerCertVerifier, WebPkiServerVerifier},
     crypto::CryptoProvider,
-    server::{ClientHello, ResolvesServerCert},
+    server::{Acceptor, ClientHello, ResolvesServerCert, ServerConfig},
     sign::CertifiedKey,
 };
 use rustls_pemfile::Item;
 use rustls_pki_types::{PrivateKeyDer, ServerName, UnixTime};
 use tokio::{
-    net::{TcpListener, TcpStream},
+    net::TcpListener,
     sync::{
         mpsc::{error::TrySendError, Receiver, Sender},
         oneshot,
     },
 };
-use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tokio_rustls::{server::TlsStream, LazyConfigAcceptor};
 use tracing::{debug, info, trace, warn};
 
-use crate::traits::{Generate, Subscribe};
+#[cfg(feature = "acme")]
+use crate::acme::{self, AcmeConfig, AcmeHost, AcmeIssuer};
+use crate::{
+    names::{candidates, parse_names, sample_name},
+    prefixed::PrefixedStream,
+    proxy_protocol::ProxyProtocol,
+    routing::{self, peek_server_name, Route, RoutingListener},
+    traits::{Generate, Subscribe},
+};
+
+/// The ALPN protocol of TLS-ALPN-01 validation connections, RFC 8737.
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+/// How many previous ACME managed keys of the names are still accepted to subscribe with.
+#[cfg(feature = "acme")]
+const MAX_MANAGED_KEYS: usize = 3;
 
 pub struct Connection {
-    pub stream: TlsStream<TcpStream>,
+    pub stream: TlsStream<PrefixedStream>,
     pub remote_addr: SocketAddr,
 }
 
 pub struct Subscription {
     rx: Receiver<Connection>,
-    sni: String,
+    names: Vec<String>,
     weak_listener: WeakSniTlsListener,
 }
 
@@ -46,7 +61,14 @@ impl Drop for Subscription {
     fn drop(&mut self) {
         if let Some(listener) = self.weak_listener.state.upgrade() {
             let mut guard = listener.lock().unwrap();
-            guard.subscribers.remove(&self.sni);
+            for name in &self.names {
+                guard.subscribers.remove(name);
+                guard.handshake_failures.remove(name);
+            }
+            #[cfg(feature = "acme")]
+            guard
+                .managed_keys
+                .retain(|_, managed| managed.names != self.names);
         }
     }
 }
@@ -56,10 +78,86 @@ struct SubscribeInfo {
     tx: Sender<Connection>,
 }
 
+/// The keys the ACME issuer generated for the names of an owner.
+#[cfg(feature = "acme")]
+struct ManagedKeys {
+    names: Vec<String>,
+    /// The current key last.
+    keys: Vec<Arc<CertifiedKey>>,
+    /// Stops the renewal when dropped.
+    _cancel_tx: oneshot::Sender<()>,
+}
+
 struct SniTlsListenerState {
     _term_tx: oneshot::Sender<()>,
+    /// The subscribers by name, a subscription for several names appears under each of them.
     subscribers: HashMap<String, SubscribeInfo>,
     verify_certifacate: bool,
+    /// TLS-ALPN-01 challenge certificates, only served to ACME validation connections.
+    challenges: HashMap<String, Arc<CertifiedKey>>,
+    /// The subscribers of the connections to pass through without terminating TLS.
+    passthrough: RoutingListener,
+    proxy_protocol: ProxyProtocol,
+    /// Failed TLS handshakes by the subscribed name they matched, removed with the subscription.
+    handshake_failures: HashMap<String, u64>,
+    #[cfg(feature = "acme")]
+    acme: Option<Arc<AcmeIssuer>>,
+    /// The keys the ACME issuer generated by owner and names.
+    #[cfg(feature = "acme")]
+    managed_keys: HashMap<(String, String), ManagedKeys>,
+}
+
+impl SniTlsListenerState {
+    /// Checks the key to serve the names with.
+    ///
+    /// Keys managed by the ACME issuer are trusted as is and replaced with the current one.
+    fn check_key(&self, names: &[String], key: Arc<CertifiedKey>) -> Result<Arc<CertifiedKey>> {
+        #[cfg(feature = "acme")]
+        for managed in self.managed_keys.values() {
+            if managed.names == names && managed.keys.iter().any(|k| Arc::ptr_eq(k, &key)) {
+                return Ok(managed.keys.last().cloned().unwrap_or(key));
+            }
+        }
+        if self.verify_certifacate {
+            for name in names {
+                verify_certifacate(&key, &sample_name(name))?;
+            }
+        }
+        Ok(key)
+    }
+
+    /// Finds the subscriber of the exact server name, or else of the wildcard matching it.
+    fn lookup(&self, server_name: &str) -> Option<&SubscribeInfo> {
+        candidates(server_name)
+            .iter()
+            .find_map(|name| self.subscribers.get(name))
+    }
+
+    fn record_handshake_failure(&mut self, server_name: &str) {
+        let matched = candidates(server_name)
+            .into_iter()
+            .find(|name| self.subscribers.contains_key(name));
+        if let Some(name) = matched {
+            *self.handshake_failures.entry(name).or_default() += 1;
+        }
+    }
+
+    /// Whether the closest match of the server name is subscribed for passthrough rather than to
+    /// be terminated here. The terminated subscription wins on the same name.
+    fn is_passthrough(&self, server_name: &str) -> bool {
+        candidates(server_name)
+            .iter()
+            .find_map(|name| {
+                if self.subscribers.contains_key(name) {
+                    Some(false)
+                } else if self.passthrough.is_subscribed(name) {
+                    Some(true)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(false)
+    }
 }
 
 impl std::fmt::Debug for SniTlsListenerState {
@@ -96,6 +194,14 @@ impl SniTlsListener {
                 _term_tx: term_tx,
                 subscribers: HashMap::new(),
                 verify_certifacate,
+                challenges: HashMap::new(),
+                passthrough: RoutingListener::tls_passthrough(),
+                proxy_protocol: ProxyProtocol::default(),
+                handshake_failures: HashMap::new(),
+                #[cfg(feature = "acme")]
+                acme: None,
+                #[cfg(feature = "acme")]
+                managed_keys: HashMap::new(),
             })),
         };
 
@@ -117,38 +223,184 @@ impl SniTlsListener {
         Ok(this)
     }
 
-    pub fn update_key(&self, server_name: &str, key: Arc<CertifiedKey>) -> Result<()> {
+    /// Replaces the key of a subscription, see [`Self::subscribe`] for `server_names`.
+    pub fn update_key(&self, server_names: &str, key: Arc<CertifiedKey>) -> Result<()> {
+        let names = parse_names(server_names)?;
         let mut guard = self.state.lock().unwrap();
-        if guard.verify_certifacate {
-            verify_certifacate(&key, server_name)?;
+        let key = guard.check_key(&names, key)?;
+        if !names
+            .iter()
+            .all(|name| guard.subscribers.contains_key(name))
+        {
+            anyhow::bail!("server_name not subscribed");
+        }
+        for name in &names {
+            if let Some(subscriber) = guard.subscribers.get_mut(name) {
+                subscriber.key = key.clone();
+            }
         }
-        let subscriber = guard
-            .subscribers
-            .get_mut(server_name)
-            .context("server_name not subscribed")?;
-        subscriber.key = key;
         Ok(())
     }
 
-    pub fn subscribe(&self, server_name: &str, key: Arc<CertifiedKey>) -> Result<Subscription> {
+    /// Subscribes to the connections for the given server names.
+    ///
+    /// `server_names` is a comma separated list of DNS names or wildcards like
+    /// `*.app.example.com`, all served with the same key.
+    pub fn subscribe(&self, server_names: &str, key: Arc<CertifiedKey>) -> Result<Subscription> {
+        let names = parse_names(server_names)?;
         let (tx, rx) = tokio::sync::mpsc::channel(32);
         let mut guard = self.state.lock().unwrap();
-        if guard.verify_certifacate {
-            verify_certifacate(&key, server_name)?;
+        let key = guard.check_key(&names, key)?;
+        if let Some(name) = names
+            .iter()
+            .find(|name| guard.subscribers.contains_key(*name))
+        {
+            anyhow::bail!("server_name {name} already subscribed");
         }
-        if guard.subscribers.contains_key(server_name) {
-            anyhow::bail!("server_name already subscribed");
+        debug!(target: "wapo::tls", server_names, "subscribing tls connection");
+        for name in &names {
+            let info = SubscribeInfo {
+                key: key.clone(),
+                tx: tx.clone(),
+            };
+            guard.subscribers.insert(name.clone(), info);
         }
-        debug!(target: "wapo::tls", server_name, "subscribing tls connection");
-        guard
-            .subscribers
-            .insert(server_name.to_string(), SubscribeInfo { key, tx });
         Ok(Subscription {
             rx,
-            sni: server_name.to_string(),
+            names,
             weak_listener: self.downgrade(),
         })
     }
+
+    /// The listener of the TLS connections to hand over to the subscriber untouched, for apps
+    /// doing TLS by themselves. They arrive on the port of this listener and are selected by SNI.
+    pub fn passthrough(&self) -> RoutingListener {
+        self.state.lock().unwrap().passthrough.clone()
+    }
+
+    /// Reads the client address from the PROXY protocol header sent by the trusted upstreams.
+    pub fn set_proxy_protocol(&self, proxy_protocol: ProxyProtocol) {
+        self.state.lock().unwrap().proxy_protocol = proxy_protocol;
+    }
+
+    /// Enables [`Self::acme_key`], with certificates issued by the configured ACME CA for the
+    /// names the host allows.
+    #[cfg(feature = "acme")]
+    pub fn enable_acme(&self, config: AcmeConfig, host: Arc<dyn AcmeHost>) -> Result<()> {
+        let issuer = AcmeIssuer::new(config, host)?;
+        self.state.lock().unwrap().acme = Some(Arc::new(issuer));
+        Ok(())
+    }
+
+    /// The key for the owner to subscribe the names with to be served with a certificate from the
+    /// ACME CA, if the host allows the owner the names.
+    ///
+    /// The key comes with the saved certificate of the names if it is still valid, or else with
+    /// a self-signed one until the first certificate is issued. The key of the subscriber is then
+    /// rotated each time the certificate is issued or renewed, until the names are unsubscribed.
+    /// Wildcards are not supported, as TLS-ALPN-01 can't validate them.
+    #[cfg(feature = "acme")]
+    pub fn acme_key(&self, owner: &str, server_names: &str) -> Result<Arc<CertifiedKey>> {
+        let names = parse_names(server_names)?;
+        if names.iter().any(|name| name.starts_with('*')) {
+            anyhow::bail!("wildcard names are not supported by ACME: {server_names}");
+        }
+        let id = (owner.to_string(), names.join(","));
+        let mut guard = self.state.lock().unwrap();
+        let issuer = guard.acme.clone().context("ACME is not enabled")?;
+        if !issuer.is_allowed(owner, &names) {
+            anyhow::bail!("names not allowed for ACME: {server_names}");
+        }
+        if let Some(key) = guard
+            .managed_keys
+            .get(&id)
+            .and_then(|managed| managed.keys.last())
+        {
+            return Ok(key.clone());
+        }
+        let (key, renew_in) = match issuer.saved_cert(&names) {
+            Ok(Some(saved)) => saved,
+            Ok(None) => (acme::placeholder_key(&names)?, Default::default()),
+            Err(err) => {
+                warn!(target: "wapo::tls", server_names, "failed to load the saved certificate: {err:?}");
+                (acme::placeholder_key(&names)?, Default::default())
+            }
+        };
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        let managed = ManagedKeys {
+            names: names.clone(),
+            keys: vec![key.clone()],
+            _cancel_tx: cancel_tx,
+        };
+        guard.managed_keys.insert(id, managed);
+        drop(guard);
+        tokio::spawn(acme::maintain(
+            issuer,
+            owner.to_string(),
+            names,
+            self.downgrade(),
+            renew_in,
+            cancel_rx,
+        ));
+        Ok(key)
+    }
+
+    /// Makes the key the current one of the names of the owner, rotating the key of the
+    /// subscriber.
+    #[cfg(feature = "acme")]
+    pub(crate) fn install_managed_key(
+        &self,
+        owner: &str,
+        names: &[String],
+        key: Arc<CertifiedKey>,
+    ) {
+        let mut guard = self.state.lock().unwrap();
+        let id = (owner.to_string(), names.join(","));
+        let Some(managed) = guard.managed_keys.get_mut(&id) else {
+            return;
+        };
+        managed.keys.push(key.clone());
+        if managed.keys.len() > MAX_MANAGED_KEYS {
+            managed.keys.remove(0);
+        }
+        for name in names {
+            if let Some(subscriber) = guard.subscribers.get_mut(name) {
+                subscriber.key = key.clone();
+            }
+        }
+    }
+
+    /// Forgets the keys of the names of the owner if they are no longer subscribed, returning
+    /// whether it did.
+    #[cfg(feature = "acme")]
+    pub(crate) fn release_managed_key(&self, owner: &str, names: &[String]) -> bool {
+        let mut guard = self.state.lock().unwrap();
+        if names
+            .iter()
+            .any(|name| guard.subscribers.contains_key(name))
+        {
+            return false;
+        }
+        guard
+            .managed_keys
+            .remove(&(owner.to_string(), names.join(",")));
+        true
+    }
+
+    #[cfg(all(test, feature = "acme"))]
+    pub(crate) fn n_managed_keys(&self) -> usize {
+        self.state.lock().unwrap().managed_keys.len()
+    }
+
+    /// Sets or clears the TLS-ALPN-01 challenge certificate of the domain.
+    #[cfg(feature = "acme")]
+    pub(crate) fn set_challenge(&self, domain: &str, key: Option<Arc<CertifiedKey>>) {
+        let mut guard = self.state.lock().unwrap();
+        match key {
+            Some(key) => guard.challenges.insert(domain.to_string(), key),
+            None => guard.challenges.remove(domain),
+        };
+    }
 }
 
 impl Subscribe for SniTlsListener {
@@ -160,6 +412,16 @@ impl Subscribe for SniTlsListener {
     fn update_key(&self, domain: &str, key: Self::Key) -> Result<()> {
         self.update_key(domain, key)
     }
+    fn handshake_failures(&self, domain: &str) -> u64 {
+        let Ok(names) = parse_names(domain) else {
+            return 0;
+        };
+        let guard = self.state.lock().unwrap();
+        names
+            .iter()
+            .filter_map(|name| guard.handshake_failures.get(name))
+            .sum()
+    }
 }
 
 impl Generate for Subscription {
@@ -186,16 +448,16 @@ async fn listening_service(
     tcp_listener: TcpListener,
     sni_listner: WeakSniTlsListener,
 ) -> Result<()> {
-    let config = rustls::ServerConfig::builder()
+    let config = ServerConfig::builder()
         .with_no_client_auth()
         .with_cert_resolver(Arc::new(sni_listner.clone()));
-    let tls_acceptor = TlsAcceptor::from(Arc::new(config));
+    let config = Arc::new(config);
     let permits = Arc::new(tokio::sync::Semaphore::new(1024));
     loop {
         trace!(target: "wapo::tls", "waiting for incoming tcp connection");
         let (tcp_stream, peer_addr) = tcp_listener.accept().await?;
         trace!(target: "wapo::tls", ?peer_addr, "incoming tcp connection");
-        let tls_acceptor = tls_acceptor.clone();
+        let config = config.clone();
         let sni_listener = sni_listner.clone();
         let Some(state) = sni_listener.state.upgrade() else {
             anyhow::bail!("sni_listener dropped, shutting down tcp_listener");
@@ -206,11 +468,58 @@ async fn listening_service(
                 warn!(target: "wapo::tls", "semaphore full, dropping connection");
                 return;
             };
+            let mut stream = PrefixedStream::from(tcp_stream);
             let timeout = tokio::time::Duration::from_secs(10);
-            let tls_stream = match tokio::time::timeout(timeout, tls_acceptor.accept(tcp_stream))
+            let (passthrough, proxy_protocol) = {
+                let guard = state.lock().unwrap();
+                (guard.passthrough.clone(), guard.proxy_protocol.clone())
+            };
+            let proxied =
+                tokio::time::timeout(timeout, proxy_protocol.remote_addr(&mut stream, peer_addr))
+                    .await;
+            let peer_addr = match proxied {
+                Ok(Ok(addr)) => addr,
+                Ok(Err(err)) => {
+                    debug!(target: "wapo::tls", ?peer_addr, "failed to read proxy header: {err}");
+                    return;
+                }
+                Err(_) => {
+                    debug!(target: "wapo::tls", ?peer_addr, "timeout reading proxy header");
+                    return;
+                }
+            };
+            if !passthrough.is_empty() {
+                let peeked = tokio::time::timeout(
+                    timeout,
+                    peek_server_name(&mut stream, Route::TlsPassthrough),
+                )
+                .await;
+                match peeked {
+                    Ok(Ok(server_name)) if state.lock().unwrap().is_passthrough(&server_name) => {
+                        debug!(target: "wapo::tls", server_name, ?peer_addr, "passing tls connection through");
+                        passthrough.dispatch(routing::Connection {
+                            stream,
+                            remote_addr: peer_addr,
+                            server_name,
+                        });
+                        return;
+                    }
+                    // Anything else is left to the handshake below to accept or reject.
+                    Ok(_) => {}
+                    Err(_) => {
+                        debug!(target: "wapo::tls", ?peer_addr, "timeout peeking tls connection");
+                        return;
+                    }
+                }
+            }
+            let tls_stream = match tokio::time::timeout(timeout, accept(stream, config, &state))
                 .await
             {
-                Ok(Ok(stream)) => stream,
+                Ok(Ok(Some(stream))) => stream,
+                Ok(Ok(None)) => {
+                    debug!(target: "wapo::tls", ?peer_addr, "answered ACME challenge");
+                    return;
+                }
                 Ok(Err(err)) => {
                     debug!(target: "wapo::tls", ?peer_addr, "failed to accept tls connection: {err}");
                     return;
@@ -223,7 +532,7 @@ async fn listening_service(
             let server_name = tls_stream.get_ref().1.server_name().unwrap_or_default();
             debug!(target: "wapo::tls", server_name, ?peer_addr, "incoming tls connection");
             let mut guard = state.lock().unwrap();
-            if let Some(subscriber) = guard.subscribers.get(server_name) {
+            if let Some(subscriber) = guard.lookup(server_name) {
                 debug!(target: "wapo::tls", server_name, "sending tls stream to the subscriber");
                 if let Err(err) = subscriber.tx.try_send(Connection {
                     stream: tls_stream,
@@ -233,12 +542,9 @@ async fn listening_service(
                         TrySendError::Full(_) => {
                             warn!("subscriber buffer full, dropping connection");
                         }
-                        TrySendError::Closed(connection) => {
+                        TrySendError::Closed(_) => {
                             warn!("subscriber dropped, shutting down tcp_listener");
-                            let stream = connection.stream;
-                            guard
-                                .subscribers
-                                .remove(stream.get_ref().1.server_name().unwrap_or_default());
+                            guard.subscribers.retain(|_, info| !info.tx.is_closed());
                         }
                     }
                 }
@@ -249,6 +555,49 @@ async fn listening_service(
     }
 }
 
+/// Completes the TLS handshake of an incoming connection.
+///
+/// TLS-ALPN-01 validation connections are answered with the challenge certificate of the
+/// domain and not returned.
+async fn accept(
+    stream: PrefixedStream,
+    config: Arc<ServerConfig>,
+    state: &Mutex<SniTlsListenerState>,
+) -> io::Result<Option<TlsStream<PrefixedStream>>> {
+    let start = LazyConfigAcceptor::new(Acceptor::default(), stream).await?;
+    let client_hello = start.client_hello();
+    let is_acme_challenge = client_hello
+        .alpn()
+        .is_some_and(|mut protocols| protocols.any(|protocol| protocol == ACME_TLS_ALPN));
+    if !is_acme_challenge {
+        let server_name = client_hello.server_name().map(str::to_owned);
+        let result = start.into_stream(config).await;
+        if let (Err(_), Some(server_name)) = (&result, server_name) {
+            state.lock().unwrap().record_handshake_failure(&server_name);
+        }
+        return result.map(Some);
+    }
+    let challenge = client_hello
+        .server_name()
+        .and_then(|server_name| state.lock().unwrap().challenges.get(server_name).cloned())
+        .ok_or_else(|| io::Error::other("no pending ACME challenge"))?;
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(ChallengeCert(challenge)));
+    config.alpn_protocols = vec![ACME_TLS_ALPN.to_vec()];
+    start.into_stream(Arc::new(config)).await?;
+    Ok(None)
+}
+
+#[derive(Debug)]
+struct ChallengeCert(Arc<CertifiedKey>);
+
+impl ResolvesServerCert for ChallengeCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+}
+
 impl ResolvesServerCert for WeakSniTlsListener {
     fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
         let key = self
@@ -256,8 +605,7 @@ impl ResolvesServerCert for WeakSniTlsListener {
             .upgrade()?
             .lock()
             .unwrap()
-            .subscribers
-            .get(client_hello.server_name()?)?
+            .lookup(client_hello.server_name()?)?
             .key
             .clone();
         Some(key)
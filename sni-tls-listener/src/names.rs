@@ -0,0 +1,93 @@
+//! The server names a subscription is made for.
+//!
+//! A subscription covers one or more comma separated names, each being either a DNS name or a
+//! wildcard like `*.app.example.com`. As in X.509 certificates, a wildcard matches a single label,
+//! so `*.app.example.com` matches `a.app.example.com` but not `b.a.app.example.com`. A connection
+//! goes to the subscription of its exact server name if any, or else to the one of the wildcard
+//! of its parent domain.
+
+use anyhow::{bail, Result};
+use rustls_pki_types::ServerName;
+
+/// Normalizes a comma separated list of server names.
+///
+/// The names are lowercased, sorted and deduplicated, so that the same set of names always
+/// gives the same subscription.
+pub fn normalize_names(names: &str) -> Result<String> {
+    Ok(parse_names(names)?.join(","))
+}
+
+pub(crate) fn parse_names(names: &str) -> Result<Vec<String>> {
+    let mut names: Vec<String> = names
+        .split(',')
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+    names.sort();
+    names.dedup();
+    for name in &names {
+        validate(name)?;
+    }
+    Ok(names)
+}
+
+fn validate(name: &str) -> Result<()> {
+    let (host, is_wildcard) = match name.strip_prefix("*.") {
+        Some(suffix) => (suffix, true),
+        None => (name, false),
+    };
+    let valid = matches!(ServerName::try_from(host), Ok(ServerName::DnsName(_)))
+        && !host.contains('*')
+        // Wildcards for a whole top-level domain are not allowed.
+        && (!is_wildcard || host.contains('.'));
+    if !valid {
+        bail!("invalid server name: {name}");
+    }
+    Ok(())
+}
+
+/// The names that would match the server name, from the most to the least specific.
+pub(crate) fn candidates(server_name: &str) -> Vec<String> {
+    let server_name = server_name.to_ascii_lowercase();
+    let wildcard = server_name
+        .find('.')
+        .map(|i| format!("*{}", &server_name[i..]));
+    let mut candidates = vec![server_name];
+    candidates.extend(wildcard);
+    candidates
+}
+
+/// A name that the wildcard matches, to check certificates against.
+pub(crate) fn sample_name(name: &str) -> String {
+    match name.strip_prefix("*.") {
+        Some(suffix) => format!("wildcard.{suffix}"),
+        None => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_works() {
+        assert_eq!(
+            normalize_names(" B.example.com,*.app.example.com,b.example.com").unwrap(),
+            "*.app.example.com,b.example.com"
+        );
+        assert!(normalize_names("").is_err());
+        assert!(normalize_names("example.com,").is_err());
+        assert!(normalize_names("*.com").is_err());
+        assert!(normalize_names("a.*.example.com").is_err());
+        assert!(normalize_names("127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn wildcards_match_one_label() {
+        assert_eq!(
+            candidates("A.customer.app.example.com"),
+            ["a.customer.app.example.com", "*.customer.app.example.com"]
+        );
+        assert!(!candidates("a.customer.app.example.com").contains(&"*.app.example.com".into()));
+        assert_eq!(candidates("localhost"), ["localhost"]);
+    }
+}
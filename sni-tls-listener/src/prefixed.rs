@@ -0,0 +1,105 @@
+//! A TCP stream with its first bytes read ahead, to tell where the connection goes from them.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+/// A TCP stream yielding the bytes read ahead from it before the rest.
+pub struct PrefixedStream {
+    inner: TcpStream,
+    /// Bytes read ahead from the stream.
+    buffered: Vec<u8>,
+    /// How many of the buffered bytes were read through the stream.
+    consumed: usize,
+}
+
+impl From<TcpStream> for PrefixedStream {
+    fn from(inner: TcpStream) -> Self {
+        Self {
+            inner,
+            buffered: vec![],
+            consumed: 0,
+        }
+    }
+}
+
+impl PrefixedStream {
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.inner
+    }
+
+    /// The bytes read ahead and not read through the stream yet.
+    pub(crate) fn buffered(&self) -> &[u8] {
+        &self.buffered[self.consumed..]
+    }
+
+    /// Reads more bytes ahead, keeping at most `max_len` buffered, returning how many were read.
+    pub(crate) async fn read_ahead(&mut self, max_len: usize) -> io::Result<usize> {
+        self.buffered.drain(..self.consumed);
+        self.consumed = 0;
+        let limit = max_len.saturating_sub(self.buffered.len()) as u64;
+        (&mut self.inner)
+            .take(limit)
+            .read_buf(&mut self.buffered)
+            .await
+    }
+}
+
+impl AsyncRead for PrefixedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let buffered = &this.buffered[this.consumed..];
+        if buffered.is_empty() {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        let len = buffered.len().min(buf.remaining());
+        buf.put_slice(&buffered[..len]);
+        this.consumed += len;
+        if this.consumed == this.buffered.len() {
+            this.buffered = vec![];
+            this.consumed = 0;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for PrefixedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,169 @@
+//! The PROXY protocol v1 and v2, by which a load balancer in front of the listeners passes on the
+//! address of the client it accepted a connection from.
+//!
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use ipnet::IpNet;
+use tokio::io::AsyncReadExt;
+
+use crate::{prefixed::PrefixedStream, routing::peek};
+
+#[cfg(test)]
+mod tests;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest v1 header allowed, CRLF included.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_FIXED_LEN: usize = 16;
+
+/// Which upstreams to read the PROXY protocol header from.
+///
+/// Connections from a trusted upstream must start with the header, the others are taken as is.
+/// Nothing is trusted by default.
+#[derive(Clone, Debug, Default)]
+pub struct ProxyProtocol {
+    trusted: Arc<Vec<IpNet>>,
+}
+
+impl ProxyProtocol {
+    pub fn new(trusted: impl IntoIterator<Item = IpNet>) -> Self {
+        Self {
+            trusted: Arc::new(trusted.into_iter().collect()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.trusted.is_empty()
+    }
+
+    fn is_trusted(&self, addr: IpAddr) -> bool {
+        let addr = addr.to_canonical();
+        self.trusted.iter().any(|net| net.contains(&addr))
+    }
+
+    /// The address of the client of the connection.
+    ///
+    /// For a trusted upstream, the header is consumed from the stream and the source address in
+    /// it is returned, or the one of the upstream if it tells none, e.g. for health checks.
+    pub async fn remote_addr(
+        &self,
+        stream: &mut PrefixedStream,
+        peer_addr: SocketAddr,
+    ) -> io::Result<SocketAddr> {
+        if !self.is_trusted(peer_addr.ip()) {
+            return Ok(peer_addr);
+        }
+        let header_len = peek(stream, V1_MAX_LEN, header_len).await?;
+        let mut header = vec![0u8; header_len];
+        stream.read_exact(&mut header).await?;
+        Ok(parse_header(&header)?.unwrap_or(peer_addr))
+    }
+}
+
+/// The length of the header the data starts with, `None` if more bytes are needed.
+fn header_len(data: &[u8]) -> io::Result<Option<usize>> {
+    if data.starts_with(V2_SIGNATURE) {
+        let Some(len) = data.get(14..V2_FIXED_LEN) else {
+            return Ok(None);
+        };
+        return Ok(Some(
+            V2_FIXED_LEN + u16::from_be_bytes([len[0], len[1]]) as usize,
+        ));
+    }
+    if data.starts_with(V1_PREFIX) {
+        return match data.windows(2).position(|w| w == b"\r\n") {
+            Some(pos) => Ok(Some(pos + 2)),
+            None if data.len() >= V1_MAX_LEN => Err(invalid("v1 header too long")),
+            None => Ok(None),
+        };
+    }
+    let len = data.len();
+    if V2_SIGNATURE.starts_with(&data[..len.min(V2_SIGNATURE.len())])
+        || V1_PREFIX.starts_with(&data[..len.min(V1_PREFIX.len())])
+    {
+        return Ok(None);
+    }
+    Err(invalid("missing header"))
+}
+
+/// The source address in a complete header, `None` if it tells none.
+fn parse_header(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if header.starts_with(V2_SIGNATURE) {
+        parse_v2(header)
+    } else {
+        parse_v1(header)
+    }
+}
+
+fn parse_v1(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(header)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or_else(|| invalid("invalid v1 header"))?;
+    let mut fields = line.split(' ').skip(1);
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("invalid v1 protocol")),
+    }
+    let (Some(src), Some(_dst), Some(src_port), Some(_dst_port), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return Err(invalid("invalid v1 header"));
+    };
+    let ip: IpAddr = src.parse().map_err(|_| invalid("invalid v1 address"))?;
+    let port: u16 = src_port.parse().map_err(|_| invalid("invalid v1 port"))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+fn parse_v2(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let version_command = header[12];
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported v2 version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL, e.g. health checks of the upstream itself.
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unsupported v2 command")),
+    }
+    let addresses = &header[V2_FIXED_LEN..];
+    let addr = match header[13] {
+        // TCP over IPv4.
+        0x11 => {
+            let a = addresses
+                .get(..12)
+                .ok_or_else(|| invalid("short v2 addresses"))?;
+            let ip = Ipv4Addr::new(a[0], a[1], a[2], a[3]);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([a[8], a[9]]))
+        }
+        // TCP over IPv6.
+        0x21 => {
+            let a = addresses
+                .get(..36)
+                .ok_or_else(|| invalid("short v2 addresses"))?;
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&a[..16]);
+            let ip = Ipv6Addr::from(ip);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([a[32], a[33]]))
+        }
+        // UNSPEC, UDP and unix sockets tell no usable address.
+        _ => return Ok(None),
+    };
+    Ok(Some(addr))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("PROXY protocol: {msg}"))
+}
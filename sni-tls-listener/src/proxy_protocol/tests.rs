@@ -0,0 +1,81 @@
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+
+use super::*;
+
+fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.push(0x20 | command);
+    header.push(family);
+    header.extend((addresses.len() as u16).to_be_bytes());
+    header.extend(addresses);
+    header
+}
+
+#[test]
+fn parse_v1_works() {
+    let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /";
+    assert_eq!(header_len(header).unwrap(), Some(45));
+    assert_eq!(
+        parse_header(&header[..45]).unwrap(),
+        Some("192.0.2.1:56324".parse().unwrap())
+    );
+    assert_eq!(
+        parse_header(b"PROXY TCP6 2001:db8::1 2001:db8::2 1234 443\r\n").unwrap(),
+        Some("[2001:db8::1]:1234".parse().unwrap())
+    );
+    assert_eq!(parse_header(b"PROXY UNKNOWN\r\n").unwrap(), None);
+    assert!(parse_header(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n").is_err());
+    assert_eq!(header_len(b"PROXY TCP4 192.0").unwrap(), None);
+    assert_eq!(header_len(b"PRO").unwrap(), None);
+    assert!(header_len(b"GET / HTTP/1.1\r\n").is_err());
+}
+
+#[test]
+fn parse_v2_works() {
+    let addresses = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+    let header = v2_header(1, 0x11, &addresses);
+    assert_eq!(header_len(&header[..15]).unwrap(), None);
+    assert_eq!(header_len(&header).unwrap(), Some(28));
+    assert_eq!(
+        parse_header(&header).unwrap(),
+        Some("192.0.2.1:56324".parse().unwrap())
+    );
+    assert_eq!(parse_header(&v2_header(0, 0x00, &[])).unwrap(), None);
+    assert!(parse_header(&v2_header(1, 0x11, &addresses[..8])).is_err());
+}
+
+#[tokio::test]
+async fn remote_addr_works() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = tokio::spawn(async move {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        // Split to exercise waiting for the rest of the header.
+        stream.write_all(b"PROXY TCP4 192.0.2.1 ").await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        stream
+            .write_all(b"198.51.100.1 56324 443\r\nhello")
+            .await
+            .unwrap();
+    });
+    let (stream, peer_addr) = listener.accept().await.unwrap();
+    let mut stream = PrefixedStream::from(stream);
+
+    let untrusted = ProxyProtocol::new(["10.0.0.0/8".parse().unwrap()]);
+    assert_eq!(
+        untrusted.remote_addr(&mut stream, peer_addr).await.unwrap(),
+        peer_addr
+    );
+    let trusted = ProxyProtocol::new(["127.0.0.0/8".parse().unwrap()]);
+    assert_eq!(
+        trusted.remote_addr(&mut stream, peer_addr).await.unwrap(),
+        "192.0.2.1:56324".parse().unwrap()
+    );
+    let mut rest = String::new();
+    stream.read_to_string(&mut rest).await.unwrap();
+    assert_eq!(rest, "hello");
+    client.await.unwrap();
+}
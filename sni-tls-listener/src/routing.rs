@@ -0,0 +1,392 @@
+//! Routing of connections that are not terminated by the listener.
+//!
+//! The first bytes of a connection are read ahead to tell the server name it is made for: the
+//! `Host` header of a plain HTTP/1.1 request, or the SNI of a TLS ClientHello. The connection is
+//! then handed over untouched to the subscriber, with the bytes read ahead still to be read, so
+//! the app speaks HTTP, or does the TLS handshake, by itself.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use rustls::server::Acceptor;
+use tokio::{
+    net::TcpListener,
+    sync::{
+        mpsc::{error::TrySendError, Receiver, Sender},
+        oneshot,
+    },
+};
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    names::{candidates, parse_names},
+    prefixed::PrefixedStream,
+    proxy_protocol::ProxyProtocol,
+    traits::{Generate, Subscribe},
+};
+
+#[cfg(test)]
+mod tests;
+
+/// How many bytes to read ahead at most to find the server name.
+const MAX_PEEK_LEN: usize = 16 * 1024;
+const PEEK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How the server name of a connection is told.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Route {
+    /// By the `Host` header of a plain HTTP/1.1 request.
+    Http,
+    /// By the SNI of a TLS ClientHello, with the TLS handshake left to the subscriber.
+    TlsPassthrough,
+}
+
+pub struct Connection {
+    pub stream: PrefixedStream,
+    pub remote_addr: SocketAddr,
+    /// The server name the connection was made for.
+    pub server_name: String,
+}
+
+pub struct Subscription {
+    rx: Receiver<Connection>,
+    names: Vec<String>,
+    weak_listener: WeakRoutingListener,
+}
+
+impl Subscription {
+    pub async fn next(&mut self) -> Option<Connection> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(listener) = self.weak_listener.state.upgrade() {
+            let mut guard = listener.lock().unwrap();
+            for name in &self.names {
+                guard.subscribers.remove(name);
+            }
+        }
+    }
+}
+
+struct RoutingListenerState {
+    _term_tx: Option<oneshot::Sender<()>>,
+    route: Route,
+    proxy_protocol: ProxyProtocol,
+    /// The subscribers by name, a subscription for several names appears under each of them.
+    subscribers: HashMap<String, Sender<Connection>>,
+}
+
+/// A listener dispatching connections by server name without terminating them.
+///
+/// Names are subscribed as with [`SniTlsListener::subscribe`](crate::SniTlsListener::subscribe),
+/// wildcards included, but without a key.
+#[derive(Clone)]
+pub struct RoutingListener {
+    state: Arc<Mutex<RoutingListenerState>>,
+}
+
+impl std::fmt::Debug for RoutingListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RoutingListener").finish()
+    }
+}
+
+impl RoutingListener {
+    fn new(route: Route, term_tx: Option<oneshot::Sender<()>>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(RoutingListenerState {
+                _term_tx: term_tx,
+                route,
+                proxy_protocol: ProxyProtocol::default(),
+                subscribers: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Listens on plain HTTP connections, routed by their `Host` header.
+    pub async fn bind_http(host: &str, port: u16) -> Result<Self> {
+        info!(target: "wapo::tls", "binding http RoutingListener on {host}:{port}");
+        let tcp_listener = TcpListener::bind((host, port))
+            .await
+            .context("failed to bind on tcp port")?;
+        let (term_tx, term_rx) = oneshot::channel();
+        let this = Self::new(Route::Http, Some(term_tx));
+        let service_task = listening_service(tcp_listener, this.downgrade());
+        tokio::spawn(async move {
+            tokio::select! {
+                res = service_task => {
+                    if let Err(err) = res {
+                        warn!("error in routing listening_service: {:?}", err);
+                    }
+                }
+                _ = term_rx => {}
+            }
+            info!(target: "wapo::tls", "RoutingListener terminated");
+        });
+        Ok(this)
+    }
+
+    /// A listener fed with the TLS connections to pass through by another one.
+    pub(crate) fn tls_passthrough() -> Self {
+        Self::new(Route::TlsPassthrough, None)
+    }
+
+    pub fn route(&self) -> Route {
+        self.state.lock().unwrap().route
+    }
+
+    /// Reads the client address from the PROXY protocol header sent by the trusted upstreams.
+    ///
+    /// Not applied to the passthrough listener of a [`SniTlsListener`](crate::SniTlsListener),
+    /// which gets the connections with the client address already read.
+    pub fn set_proxy_protocol(&self, proxy_protocol: ProxyProtocol) {
+        self.state.lock().unwrap().proxy_protocol = proxy_protocol;
+    }
+
+    pub fn downgrade(&self) -> WeakRoutingListener {
+        WeakRoutingListener {
+            state: Arc::downgrade(&self.state),
+        }
+    }
+
+    /// Subscribes to the connections for the given comma separated server names.
+    pub fn subscribe(&self, server_names: &str) -> Result<Subscription> {
+        let names = parse_names(server_names)?;
+        let (tx, rx) = tokio::sync::mpsc::channel(32);
+        let mut guard = self.state.lock().unwrap();
+        if let Some(name) = names
+            .iter()
+            .find(|name| guard.subscribers.contains_key(*name))
+        {
+            anyhow::bail!("server_name {name} already subscribed");
+        }
+        debug!(target: "wapo::tls", server_names, route = ?guard.route, "subscribing routed connection");
+        for name in &names {
+            guard.subscribers.insert(name.clone(), tx.clone());
+        }
+        Ok(Subscription {
+            rx,
+            names,
+            weak_listener: self.downgrade(),
+        })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().subscribers.is_empty()
+    }
+
+    /// Whether the exact name, which may be a wildcard, is subscribed.
+    pub(crate) fn is_subscribed(&self, name: &str) -> bool {
+        self.state.lock().unwrap().subscribers.contains_key(name)
+    }
+
+    /// Hands the connection over to the subscriber of its server name.
+    pub(crate) fn dispatch(&self, connection: Connection) {
+        let mut guard = self.state.lock().unwrap();
+        let server_name = connection.server_name.as_str();
+        let Some(tx) = candidates(server_name)
+            .iter()
+            .find_map(|name| guard.subscribers.get(name))
+        else {
+            debug!(target: "wapo::tls", "no subscriber for {server_name}");
+            return;
+        };
+        debug!(target: "wapo::tls", server_name, "sending routed stream to the subscriber");
+        match tx.try_send(connection) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!("subscriber buffer full, dropping connection");
+            }
+            Err(TrySendError::Closed(_)) => {
+                warn!("subscriber dropped, dropping connection");
+                guard.subscribers.retain(|_, tx| !tx.is_closed());
+            }
+        }
+    }
+}
+
+impl Subscribe for RoutingListener {
+    type Sub = Subscription;
+    type Key = ();
+    fn subscribe(&self, domain: &str, _key: Self::Key) -> Result<Self::Sub> {
+        self.subscribe(domain)
+    }
+    fn update_key(&self, _domain: &str, _key: Self::Key) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Generate for Subscription {
+    type Item = Connection;
+    async fn next(&mut self) -> Option<Self::Item> {
+        self.next().await
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WeakRoutingListener {
+    state: Weak<Mutex<RoutingListenerState>>,
+}
+
+impl WeakRoutingListener {
+    pub fn upgrade(&self) -> Option<RoutingListener> {
+        Some(RoutingListener {
+            state: self.state.upgrade()?,
+        })
+    }
+}
+
+async fn listening_service(tcp_listener: TcpListener, listener: WeakRoutingListener) -> Result<()> {
+    let permits = Arc::new(tokio::sync::Semaphore::new(1024));
+    loop {
+        let (tcp_stream, peer_addr) = tcp_listener.accept().await?;
+        trace!(target: "wapo::tls", ?peer_addr, "incoming routed tcp connection");
+        let Some(listener) = listener.upgrade() else {
+            anyhow::bail!("routing listener dropped, shutting down tcp_listener");
+        };
+        let permits = permits.clone();
+        tokio::spawn(async move {
+            let Ok(_sem) = permits.try_acquire() else {
+                warn!(target: "wapo::tls", "semaphore full, dropping connection");
+                return;
+            };
+            let mut stream = PrefixedStream::from(tcp_stream);
+            let (route, proxy_protocol) = {
+                let guard = listener.state.lock().unwrap();
+                (guard.route, guard.proxy_protocol.clone())
+            };
+            let proxied = tokio::time::timeout(
+                PEEK_TIMEOUT,
+                proxy_protocol.remote_addr(&mut stream, peer_addr),
+            )
+            .await;
+            let peer_addr = match proxied {
+                Ok(Ok(addr)) => addr,
+                Ok(Err(err)) => {
+                    debug!(target: "wapo::tls", ?peer_addr, "failed to read proxy header: {err}");
+                    return;
+                }
+                Err(_) => {
+                    debug!(target: "wapo::tls", ?peer_addr, "timeout reading proxy header");
+                    return;
+                }
+            };
+            let peeked =
+                tokio::time::timeout(PEEK_TIMEOUT, peek_server_name(&mut stream, route)).await;
+            let server_name = match peeked {
+                Ok(Ok(server_name)) => server_name,
+                Ok(Err(err)) => {
+                    debug!(target: "wapo::tls", ?peer_addr, "failed to route connection: {err}");
+                    return;
+                }
+                Err(_) => {
+                    debug!(target: "wapo::tls", ?peer_addr, "timeout routing connection");
+                    return;
+                }
+            };
+            listener.dispatch(Connection {
+                stream,
+                remote_addr: peer_addr,
+                server_name,
+            });
+        });
+    }
+}
+
+/// Reads ahead the first bytes of the connection until the server name can be told.
+pub(crate) async fn peek_server_name(
+    stream: &mut PrefixedStream,
+    route: Route,
+) -> io::Result<String> {
+    peek(stream, MAX_PEEK_LEN, |data| match route {
+        Route::Http => http_host(data),
+        Route::TlsPassthrough => client_hello_server_name(data),
+    })
+    .await
+}
+
+/// Reads ahead up to `max_len` first bytes of the connection until `parse` tells something from
+/// them, returning `None` while it needs more bytes. The bytes are left to be read.
+pub(crate) async fn peek<T>(
+    stream: &mut PrefixedStream,
+    max_len: usize,
+    mut parse: impl FnMut(&[u8]) -> io::Result<Option<T>>,
+) -> io::Result<T> {
+    loop {
+        let buffered = stream.buffered();
+        if !buffered.is_empty() {
+            if let Some(parsed) = parse(buffered)? {
+                return Ok(parsed);
+            }
+            if buffered.len() >= max_len {
+                return Err(io::Error::other("nothing to tell from the first bytes"));
+            }
+        }
+        if stream.read_ahead(max_len).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+/// The host in the `Host` header of an HTTP/1.x request head, `None` if more bytes are needed.
+fn http_host(data: &[u8]) -> io::Result<Option<String>> {
+    let mut lines = data.split(|b| *b == b'\n');
+    // The last piece is not terminated yet.
+    let _ = lines.next_back();
+    for (i, line) in lines.enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if i == 0 {
+            if !line.ends_with(b" HTTP/1.1") && !line.ends_with(b" HTTP/1.0") {
+                return Err(io::Error::other("not an HTTP/1.x request"));
+            }
+            continue;
+        }
+        if line.is_empty() {
+            return Err(io::Error::other("no Host header"));
+        }
+        let Some((name, value)) = line.split_first_chunk::<5>() else {
+            continue;
+        };
+        if !name.eq_ignore_ascii_case(b"host:") {
+            continue;
+        }
+        let value = std::str::from_utf8(value)
+            .map_err(|_| io::Error::other("invalid Host header"))?
+            .trim();
+        // Strip the port, IPv6 literals are kept as is and never match a subscription.
+        let host = match value.rsplit_once(':') {
+            Some((host, _)) if !value.starts_with('[') => host,
+            _ => value,
+        };
+        return Ok(Some(host.trim_end_matches('.').to_ascii_lowercase()));
+    }
+    Ok(None)
+}
+
+/// The SNI of a TLS ClientHello, `None` if more bytes are needed.
+fn client_hello_server_name(mut data: &[u8]) -> io::Result<Option<String>> {
+    let mut acceptor = Acceptor::default();
+    while !data.is_empty() {
+        if acceptor.read_tls(&mut data)? == 0 {
+            break;
+        }
+    }
+    match acceptor.accept() {
+        Ok(None) => Ok(None),
+        Ok(Some(accepted)) => accepted
+            .client_hello()
+            .server_name()
+            .map(|name| Some(name.to_ascii_lowercase()))
+            .ok_or_else(|| io::Error::other("no SNI in the ClientHello")),
+        Err((err, _)) => Err(io::Error::other(err)),
+    }
+}
@@ -0,0 +1,47 @@
+use super::*;
+
+#[test]
+fn http_host_works() {
+    let host = |data: &str| http_host(data.as_bytes()).ok().flatten();
+    assert_eq!(
+        host("GET / HTTP/1.1\r\nAccept: */*\r\nHost: App.Example.com:8080\r\n\r\n"),
+        Some("app.example.com".into())
+    );
+    assert_eq!(
+        host("GET / HTTP/1.1\nhost:example.com\n"),
+        Some("example.com".into())
+    );
+    assert_eq!(
+        host("GET / HTTP/1.1\r\nHost: example.com.:80\r\n"),
+        Some("example.com".into())
+    );
+    assert_eq!(host("GET / HTTP/1.1\r\nHost: exam"), None);
+    assert_eq!(host("GET / HTT"), None);
+    assert!(http_host(b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\n").is_err());
+    assert!(http_host(b"\x16\x03\x01\x02\x00\x01\n").is_err());
+}
+
+#[cfg(feature = "ring")]
+#[test]
+fn client_hello_server_name_works() {
+    use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore};
+
+    crate::SniTlsListener::install_ring_provider();
+    let config = ClientConfig::builder()
+        .with_root_certificates(RootCertStore::empty())
+        .with_no_client_auth();
+    let server_name = ServerName::try_from("App.Example.com").unwrap();
+    let mut client = ClientConnection::new(Arc::new(config), server_name).unwrap();
+    let mut hello = vec![];
+    client.write_tls(&mut hello).unwrap();
+
+    assert_eq!(
+        client_hello_server_name(&hello).unwrap(),
+        Some("app.example.com".into())
+    );
+    assert_eq!(
+        client_hello_server_name(&hello[..hello.len() / 2]).unwrap(),
+        None
+    );
+    assert!(client_hello_server_name(b"GET / HTTP/1.1\r\n").is_err());
+}
@@ -6,6 +6,10 @@ pub trait Subscribe {
     type Key;
     fn subscribe(&self, domain: &str, key: Self::Key) -> Result<Self::Sub>;
     fn update_key(&self, domain: &str, key: Self::Key) -> Result<()>;
+    /// How many connections for the domain failed their TLS handshake in the listener.
+    fn handshake_failures(&self, _domain: &str) -> u64 {
+        0
+    }
 }
 pub trait Generate {
     type Item;
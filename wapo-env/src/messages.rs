@@ -13,6 +13,17 @@ pub struct QueryRequest {
     pub reply_tx: i32,
 }
 
+/// A message published to a topic.
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct TopicMessage {
+    /// The topic the message was published to.
+    pub topic: String,
+    /// The address of the app that published the message, or None if published via RPC.
+    pub publisher: Option<AccountId>,
+    /// The message payload.
+    pub payload: Vec<u8>,
+}
+
 #[derive(Encode, Decode, Debug)]
 pub struct HttpHead {
     pub method: String,
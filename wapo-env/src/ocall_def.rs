@@ -33,6 +33,12 @@ pub trait OcallFuncs {
     #[ocall(id = 106)]
     fn poll_res(waker_id: i32, resource_id: i32) -> Result<i32>;
 
+    /// Move the read position of a seekable resource, such as an opened blob.
+    ///
+    /// Returns the new position from the start of the resource.
+    #[ocall(id = 107, encode_output)]
+    fn seek(resource_id: i32, whence: SeekWhence, offset: i64) -> Result<u64>;
+
     /// Mark a task as ready for next polling
     #[ocall(id = 109)]
     fn mark_task_ready(task_id: i32) -> Result<()>;
@@ -96,6 +102,18 @@ pub trait OcallFuncs {
     #[ocall(id = 215, encode_input)]
     fn tls_listen_sni(sni: Cow<str>, config: TlsServerConfig) -> Result<i32>;
 
+    /// Get the SNI server name a TLS connection accepted from `tls_listen_sni` was made for.
+    #[ocall(id = 216, encode_output)]
+    fn tls_server_name(resource_id: i32) -> Result<String>;
+
+    /// Listen to plain HTTP connections dispatched by their `Host` header.
+    #[ocall(id = 217, encode_input)]
+    fn http_listen_host(host: Cow<str>) -> Result<i32>;
+
+    /// Listen to TLS connections dispatched by SNI server name, leaving the handshake to the app.
+    #[ocall(id = 218, encode_input)]
+    fn tls_listen_sni_passthrough(sni: Cow<str>) -> Result<i32>;
+
     /// Print log message.
     #[ocall(id = 220)]
     fn log(level: log::Level, message: &str) -> Result<()>;
@@ -113,9 +131,22 @@ pub trait OcallFuncs {
     fn emit_program_output(output: &[u8]) -> Result<()>;
 
     /// Reverse lookup hash object.
+    ///
+    /// The hash can be in the form of `<algo>:<hex>`, with algo being one of `sha256`, `sha512`
+    /// and `blake2_256`, or an IPFS CID.
     #[ocall(id = 244, encode_output)]
     fn blob_get(hash: &str) -> Result<Vec<u8>>;
 
+    /// Open a hash object for streaming reads.
+    ///
+    /// The returned resource supports `poll_read` and `seek`. Takes the same IDs as `blob_get`.
+    #[ocall(id = 245)]
+    fn blob_open(hash: &str) -> Result<i32>;
+
+    /// Get the size of a hash object in bytes.
+    #[ocall(id = 246, encode_output)]
+    fn blob_size(hash: &str) -> Result<u64>;
+
     /// Request the worker to sign data of max 64 bytes.
     #[ocall(id = 250, encode_output)]
     fn sign(data: &[u8]) -> Result<Vec<u8>>;
@@ -157,6 +188,39 @@ pub trait OcallFuncs {
     /// Unlock a app scoped resource by id.
     #[ocall(id = 263)]
     fn app_unlock(path: &str) -> Result<()>;
+
+    /// Send a query to another app deployed on the same worker.
+    ///
+    /// The address of the calling app is passed to the callee as the query origin, and the gas
+    /// consumed to process the query is counted against the callee. Poll the returned resource
+    /// to get the SCALE encoded `Result<Vec<u8>, String>` reply.
+    #[ocall(id = 264, encode_input)]
+    fn app_query(address: [u8; 32], path: String, payload: Vec<u8>) -> Result<i32>;
+
+    /// Subscribe to a topic.
+    ///
+    /// Messages published to the topic are delivered to the `TopicMessage` input channel, which
+    /// must be created before subscribing.
+    #[ocall(id = 265)]
+    fn subscribe(topic: &str) -> Result<()>;
+
+    /// Unsubscribe from a topic.
+    #[ocall(id = 266)]
+    fn unsubscribe(topic: &str) -> Result<()>;
+
+    /// Publish a message to all running instances subscribed to the topic.
+    ///
+    /// Subscribers whose queue is full drop the message.
+    #[ocall(id = 267)]
+    fn publish(topic: &str, data: &[u8]) -> Result<()>;
+
+    /// Get the current time cross-checked against multiple trusted time sources.
+    ///
+    /// Returns the unix timestamp in milliseconds and its uncertainty bound in milliseconds.
+    /// Fails with `ConditionError` if the worker can not provide a trusted time, e.g. the time
+    /// sources disagree.
+    #[ocall(id = 268, encode_output)]
+    fn trusted_now() -> Result<(u64, u64)>;
 }
 
 #[repr(u8)]
@@ -166,6 +230,8 @@ pub enum InputChannel {
     Query = 3,
     /// Input channel for incoming HTTP requests.
     HttpRequest = 4,
+    /// Input channel for messages published to subscribed topics.
+    TopicMessage = 5,
 }
 
 impl I32Convertible for InputChannel {
@@ -176,6 +242,33 @@ impl I32Convertible for InputChannel {
         match i {
             3 => Ok(InputChannel::Query),
             4 => Ok(InputChannel::HttpRequest),
+            5 => Ok(InputChannel::TopicMessage),
+            _ => Err(OcallError::InvalidParameter),
+        }
+    }
+}
+
+/// The reference point of a `seek` offset.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeekWhence {
+    /// From the start of the resource.
+    Start = 0,
+    /// From the end of the resource.
+    End = 1,
+    /// From the current position.
+    Current = 2,
+}
+
+impl I32Convertible for SeekWhence {
+    fn to_i32(&self) -> i32 {
+        *self as i32
+    }
+    fn from_i32(i: i32) -> Result<Self> {
+        match i {
+            0 => Ok(SeekWhence::Start),
+            1 => Ok(SeekWhence::End),
+            2 => Ok(SeekWhence::Current),
             _ => Err(OcallError::InvalidParameter),
         }
     }
@@ -9,6 +9,11 @@ pub enum TlsServerConfig {
         /// The private key of the certificate, in PEM format.
         key: String,
     },
+    /// A certificate issued by the ACME CA configured in the worker, for a key generated in the
+    /// worker. The certificate is renewed and rotated automatically.
+    ///
+    /// Only supported by `tls_listen_sni`.
+    Acme,
 }
 
 /// TLS client configuration.
@@ -8,17 +8,23 @@ mod runtime;
 
 pub use error::ArcError;
 pub mod service;
+pub use runtime::blob_fs::{BlobMount, MountFormat};
 pub use runtime::blobs;
 pub use runtime::metrics::{Meter, Metrics};
+pub use runtime::scratch_fs::ScratchConfig;
 pub use runtime::vm_context::{vm_count, ShortId};
 
 pub type VmId = [u8; 32];
 pub use run::{
-    InstanceConfig, InstanceConfigBuilder, RuntimeCalls, WasmEngine, WasmModule, WasmRun,
+    AppQueryFuture, InstanceConfig, InstanceConfigBuilder, RuntimeCalls, WasmEngine, WasmModule,
+    WasmRun,
 };
 pub use wasmtime;
 
-pub use module_loader::{ModuleLoader, ModuleLoaderInfo};
+pub use module_loader::{DiskCacheConfig, ModuleLoader, ModuleLoaderInfo};
 pub use service::{IncomingHttpRequest, VmStatus, VmStatusReceiver};
-pub use sni_tls_listener::SniTlsListener;
-pub use wapo_env::{MetricsToken, OcallError};
+pub use sni_tls_listener::{
+    AcmeConfig, AcmeHost, Agent as SniAgent, ConnectionStats, Limits as ConnectionLimits,
+    ProxyProtocol, RoutedAgent, RoutingListener, SniTlsListener,
+};
+pub use wapo_env::{messages::TopicMessage, MetricsToken, OcallError};
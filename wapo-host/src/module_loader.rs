@@ -1,14 +1,17 @@
 use std::{
     collections::{BTreeMap, BTreeSet},
     num::NonZeroUsize,
+    path::PathBuf,
     sync::{Arc, Mutex},
-    time::Instant,
+    time::{Instant, SystemTime},
 };
 
-use anyhow::{anyhow, Context, Result};
+use aes_gcm::{aead::AeadMutInPlace, AeadCore, Aes256Gcm, KeyInit};
+use anyhow::{anyhow, bail, Context, Result};
 use lru::LruCache;
+use sha2::{Digest, Sha256};
 use tokio::sync::broadcast::{channel, Sender};
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
 use crate::{blobs::BlobLoader, ArcError, ShortId, WasmEngine, WasmModule};
 
@@ -22,10 +25,130 @@ pub struct ModuleLoaderInfo {
     pub compiling_tasks: usize,
 }
 
+/// Where and how to persist the compiled modules across restarts.
+pub struct DiskCacheConfig {
+    /// The directory to store the compiled modules.
+    pub dir: PathBuf,
+    /// The key to encrypt and authenticate the cached modules.
+    pub key: [u8; 32],
+    /// The maximum total size of the cached modules in bytes. The least recently used ones are
+    /// removed beyond it.
+    pub max_size: u64,
+}
+
+/// Compiled modules persisted on disk, encrypted with AES-256-GCM.
+///
+/// A cached module is native code loaded without validation, so the authentication tag is what
+/// protects us from tampered files. Each file is bound to the code hash and the engine
+/// fingerprint, thus a wasmtime upgrade or a config change makes the old entries unreachable.
+struct DiskCache {
+    dir: PathBuf,
+    key: [u8; 32],
+    fingerprint: [u8; 32],
+    max_size: u64,
+}
+
+impl DiskCache {
+    const NONCE_LEN: usize = 12;
+
+    fn cache_id(&self, code_hash: &str) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.fingerprint);
+        hasher.update(code_hash.as_bytes());
+        hasher.finalize().into()
+    }
+
+    fn path(&self, cache_id: &[u8; 32]) -> PathBuf {
+        self.dir.join(hex::encode(cache_id))
+    }
+
+    fn load(&self, engine: &WasmEngine, code_hash: &str) -> Result<Option<WasmModule>> {
+        let cache_id = self.cache_id(code_hash);
+        let path = self.path(&cache_id);
+        let mut data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context("failed to read cached module"),
+        };
+        if data.len() < Self::NONCE_LEN {
+            bail!("cached module truncated");
+        }
+        let nonce = data.split_off(data.len() - Self::NONCE_LEN);
+        let mut cipher = Aes256Gcm::new_from_slice(&self.key).expect("invalid key");
+        cipher
+            .decrypt_in_place(nonce.as_slice().into(), &cache_id, &mut data)
+            .or(Err(anyhow!("cached module corrupted")))?;
+        // Safety: the data is authenticated so it must be written by `store` with the same
+        // engine fingerprint.
+        let module =
+            unsafe { engine.deserialize(&data) }.context("failed to deserialize cached module")?;
+        // The modification time tells the least recently used entries to evict.
+        if let Err(err) = std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now()))
+        {
+            debug!(target: "wapo", "failed to touch cached module: {err}");
+        }
+        Ok(Some(module))
+    }
+
+    fn store(&self, code_hash: &str, module: &WasmModule) -> Result<()> {
+        let cache_id = self.cache_id(code_hash);
+        let mut data = module.serialize().context("failed to serialize module")?;
+        let mut cipher = Aes256Gcm::new_from_slice(&self.key).expect("invalid key");
+        let nonce = Aes256Gcm::generate_nonce(&mut rand::thread_rng());
+        cipher
+            .encrypt_in_place(&nonce, &cache_id, &mut data)
+            .or(Err(anyhow!("failed to encrypt module")))?;
+        data.extend_from_slice(&nonce);
+        let path = self.path(&cache_id);
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, data).context("failed to write cached module")?;
+        std::fs::rename(&tmp_path, &path).context("failed to rename cached module")?;
+        self.evict().context("failed to evict cached modules")
+    }
+
+    /// Removes the least recently used modules until the total size fits in `max_size`.
+    fn evict(&self) -> Result<()> {
+        let mut entries = vec![];
+        let mut total = 0;
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            total += metadata.len();
+            entries.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+        entries.sort();
+        for (_, len, path) in entries {
+            if total <= self.max_size {
+                break;
+            }
+            debug!(target: "wapo", path = %path.display(), "evicting cached module");
+            std::fs::remove_file(&path)?;
+            total -= len;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, code_hash: &str) {
+        let path = self.path(&self.cache_id(code_hash));
+        if let Err(err) = std::fs::remove_file(path) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!(target: "wapo", "failed to remove cached module: {err}");
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct ModuleLoader {
     engine: WasmEngine,
     blob_loader: BlobLoader,
+    disk_cache: Option<Arc<DiskCache>>,
     max_compilation_tasks: usize,
     queue_cap: usize,
     state: Arc<Mutex<ModuleLoaderState>>,
@@ -46,6 +169,7 @@ impl ModuleLoader {
         Self {
             engine,
             blob_loader,
+            disk_cache: None,
             max_compilation_tasks: 2,
             queue_cap: 128,
             state: Arc::new(Mutex::new(ModuleLoaderState {
@@ -57,6 +181,35 @@ impl ModuleLoader {
         }
     }
 
+    /// Persist the compiled modules to the given directory.
+    pub fn with_disk_cache(mut self, config: DiskCacheConfig) -> Result<Self> {
+        std::fs::create_dir_all(&config.dir).context("failed to create module cache dir")?;
+        self.disk_cache = Some(Arc::new(DiskCache {
+            dir: config.dir,
+            key: config.key,
+            fingerprint: self.engine.fingerprint(),
+            max_size: config.max_size,
+        }));
+        Ok(self)
+    }
+
+    /// Drops the compiled module of the code from the memory and disk caches.
+    pub fn evict(&self, code_hash: &str) {
+        self.state
+            .lock()
+            .expect("BUG: ModuleLoaderState lock poisoned")
+            .cache
+            .pop(code_hash);
+        if let Some(disk_cache) = &self.disk_cache {
+            disk_cache.remove(code_hash);
+        }
+    }
+
+    /// The blob store the code is loaded from, shared with the instances.
+    pub fn blob_loader(&self) -> &BlobLoader {
+        &self.blob_loader
+    }
+
     pub fn info(&self) -> ModuleLoaderInfo {
         let state = self
             .state
@@ -118,6 +271,28 @@ impl ModuleLoader {
     }
 
     fn compile(&self, code_hash: &str) -> Result<WasmModule> {
+        let Some(disk_cache) = &self.disk_cache else {
+            return self.compile_code(code_hash);
+        };
+        match disk_cache.load(&self.engine, code_hash) {
+            Ok(Some(module)) => {
+                info!(target: "wapo", "module loaded from disk cache");
+                return Ok(module);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                warn!(target: "wapo", "discarding cached module: {err:?}");
+                disk_cache.remove(code_hash);
+            }
+        }
+        let module = self.compile_code(code_hash)?;
+        if let Err(err) = disk_cache.store(code_hash, &module) {
+            warn!(target: "wapo", "failed to cache module on disk: {err:?}");
+        }
+        Ok(module)
+    }
+
+    fn compile_code(&self, code_hash: &str) -> Result<WasmModule> {
         info!(target: "wapo", "loading module code...");
         let wasm_code = self
             .blob_loader
@@ -171,3 +346,94 @@ impl ModuleLoader {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// An empty module.
+    const WASM: &[u8] = b"\0asm\x01\0\0\0";
+
+    fn engine() -> WasmEngine {
+        WasmEngine::new(wasmtime::Config::new(), 0, 1024 * 1024, 0).unwrap()
+    }
+
+    fn disk_cache(engine: &WasmEngine, max_size: u64) -> DiskCache {
+        let dir = std::env::temp_dir().join(format!("wapo-module-cache-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        DiskCache {
+            dir,
+            key: [7; 32],
+            fingerprint: engine.fingerprint(),
+            max_size,
+        }
+    }
+
+    #[test]
+    fn cached_modules_round_trip() {
+        let engine = engine();
+        let cache = disk_cache(&engine, u64::MAX);
+        let module = engine.compile(WASM).unwrap();
+        assert!(cache.load(&engine, "sha256:01").unwrap().is_none());
+        cache.store("sha256:01", &module).unwrap();
+        assert!(cache.load(&engine, "sha256:01").unwrap().is_some());
+        assert!(cache.load(&engine, "sha256:02").unwrap().is_none());
+        cache.remove("sha256:01");
+        assert!(cache.load(&engine, "sha256:01").unwrap().is_none());
+        std::fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn tampered_modules_are_rejected() {
+        let engine = engine();
+        let cache = disk_cache(&engine, u64::MAX);
+        let module = engine.compile(WASM).unwrap();
+        cache.store("sha256:01", &module).unwrap();
+        let path = cache.path(&cache.cache_id("sha256:01"));
+        let mut data = std::fs::read(&path).unwrap();
+        data[0] ^= 1;
+        std::fs::write(&path, data).unwrap();
+        assert!(cache.load(&engine, "sha256:01").is_err());
+        std::fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn other_fingerprints_miss() {
+        let engine = engine();
+        let cache = disk_cache(&engine, u64::MAX);
+        let module = engine.compile(WASM).unwrap();
+        cache.store("sha256:01", &module).unwrap();
+        let other = DiskCache {
+            dir: cache.dir.clone(),
+            key: cache.key,
+            fingerprint: [0; 32],
+            max_size: u64::MAX,
+        };
+        assert!(other.load(&engine, "sha256:01").unwrap().is_none());
+        std::fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_modules_are_evicted() {
+        let engine = engine();
+        let mut cache = disk_cache(&engine, u64::MAX);
+        let module = engine.compile(WASM).unwrap();
+        cache.store("sha256:01", &module).unwrap();
+        let path1 = cache.path(&cache.cache_id("sha256:01"));
+        let entry_size = std::fs::metadata(&path1).unwrap().len();
+        std::fs::File::options()
+            .write(true)
+            .open(&path1)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(3600))
+            .unwrap();
+
+        cache.max_size = entry_size;
+        cache.store("sha256:02", &module).unwrap();
+        assert!(!path1.exists());
+        assert!(cache.load(&engine, "sha256:02").unwrap().is_some());
+        std::fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
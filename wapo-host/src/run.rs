@@ -1,9 +1,10 @@
 use anyhow::{bail, Context as _, Result};
 use phala_scheduler::TaskScheduler;
-use sni_tls_listener::Agent;
+use sha2::{Digest, Sha256};
+use sni_tls_listener::{Agent, ProxyProtocol, RoutedAgent};
 use std::future::Future;
+use std::hash::{Hash, Hasher};
 use std::ops::{Deref, DerefMut, RangeInclusive};
-use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
@@ -19,12 +20,12 @@ use wasmtime::{
 use crate::linear_memory::MemoryPool;
 use crate::runtime::vm_context::WapoVmConfig;
 use crate::runtime::{
-    async_context,
+    async_context, blob_fs, scratch_fs,
     vm_context::{self as wapo_ctx, WapoCtx},
 };
-use crate::{Meter, VmId};
+use crate::{blobs::BlobLoader, BlobMount, Meter, ScratchConfig, VmId};
 
-pub use crate::runtime::vm_context::RuntimeCalls;
+pub use crate::runtime::vm_context::{AppQueryFuture, RuntimeCalls};
 
 type RuntimeError = anyhow::Error;
 
@@ -78,9 +79,44 @@ impl WasmEngine {
             module: Module::new(&self.inner, wasm_code)?,
         })
     }
+
+    /// Loads a module previously produced by [`WasmModule::serialize`].
+    ///
+    /// # Safety
+    ///
+    /// The bytes are loaded as native code without validation. The caller must ensure they are
+    /// produced by `WasmModule::serialize` and have not been tampered with.
+    pub unsafe fn deserialize(&self, bytes: &[u8]) -> Result<WasmModule> {
+        Ok(WasmModule {
+            engine: self.clone(),
+            module: Module::deserialize(&self.inner, bytes)?,
+        })
+    }
+
+    /// A fingerprint of the settings that affect the compiled code, including the wasmtime
+    /// version. Serialized modules are only compatible with engines with the same fingerprint.
+    pub fn fingerprint(&self) -> [u8; 32] {
+        struct Sha256Hasher(Sha256);
+        impl Hasher for Sha256Hasher {
+            fn finish(&self) -> u64 {
+                unreachable!("only the digest is used")
+            }
+            fn write(&mut self, bytes: &[u8]) {
+                self.0.update(bytes);
+            }
+        }
+        let mut hasher = Sha256Hasher(Sha256::new());
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+        self.inner.precompile_compatibility_hash().hash(&mut hasher);
+        hasher.0.finalize().into()
+    }
 }
 
 impl WasmModule {
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        self.module.serialize()
+    }
+
     pub fn run<OCalls>(&self, config: InstanceConfig<OCalls>) -> Result<WasmRun>
     where
         OCalls: RuntimeCalls + 'static,
@@ -92,12 +128,18 @@ impl WasmModule {
             weight,
             runtime_calls,
             args,
-            envs,
+            mut envs,
+            secret_envs,
             epoch_deadline,
-            blobs_dir,
+            blob_loader,
             meter,
             tcp_listen_port_range,
             sni_tls_listener,
+            http_listener,
+            tls_passthrough_listener,
+            proxy_protocol,
+            mounts,
+            scratch,
         } = config;
         let engine = self.engine.inner.clone();
         let mut linker = Linker::<VmCtx>::new(&engine);
@@ -105,8 +147,20 @@ impl WasmModule {
         let vm_config = WapoVmConfig::builder()
             .tcp_listen_port_range(tcp_listen_port_range)
             .sni_tls_listener(sni_tls_listener)
+            .http_listener(http_listener)
+            .tls_passthrough_listener(tls_passthrough_listener)
+            .proxy_protocol(proxy_protocol)
             .build();
-        let mut wapo_ctx = WapoCtx::new(id, runtime_calls, blobs_dir, meter, vm_config);
+        for (name, encrypted) in &secret_envs {
+            let value = runtime_calls
+                .decrypt_env(encrypted)
+                .with_context(|| format!("failed to decrypt env {name}"))?;
+            envs.push((name.clone(), value));
+        }
+        let scratch_secret = scratch
+            .as_ref()
+            .map(|_| runtime_calls.derive_secret(scratch_fs::SCRATCH_SECRET_PATH));
+        let mut wapo_ctx = WapoCtx::new(id, runtime_calls, blob_loader, meter, vm_config);
         wapo_ctx.set_weight(weight);
         wapo_ctx::add_ocalls_to_linker(&mut linker, |c| &mut c.wapo_ctx)?;
 
@@ -116,6 +170,20 @@ impl WasmModule {
             .envs(&envs)
             .context("failed to set envs")?
             .build();
+        if !mounts.is_empty() {
+            let root = blob_fs::build(wapo_ctx.blob_loader(), &mounts, wapo_ctx.meter())
+                .context("failed to mount blobs")?;
+            wasi_ctx
+                .push_preopened_dir(root, "/")
+                .map_err(|err| anyhow::anyhow!("failed to preopen blob mounts: {err:?}"))?;
+        }
+        if let (Some(scratch), Some(secret)) = (&scratch, &scratch_secret) {
+            let dir = scratch_fs::open(scratch, secret, wapo_ctx.meter())
+                .context("failed to open the scratch directory")?;
+            wasi_ctx
+                .push_preopened_dir(dir, &scratch.guest_path)
+                .map_err(|err| anyhow::anyhow!("failed to preopen scratch directory: {err:?}"))?;
+        }
         wasi_common::sync::add_to_linker(&mut linker, |c| &mut c.wasi_ctx)?;
 
         let memory_size = (max_memory_pages as usize)
@@ -188,12 +256,30 @@ pub struct InstanceConfig<OCalls> {
     epoch_deadline: u64,
     runtime_calls: OCalls,
     envs: Vec<(String, String)>,
+    /// Environment variables whose values are decrypted with `RuntimeCalls::decrypt_env`.
+    #[builder(default)]
+    secret_envs: Vec<(String, Vec<u8>)>,
     args: Vec<String>,
-    blobs_dir: PathBuf,
+    blob_loader: BlobLoader,
     #[builder(default)]
     meter: Option<Arc<Meter>>,
     tcp_listen_port_range: RangeInclusive<u16>,
     sni_tls_listener: Option<Agent>,
+    /// Plain HTTP connections routed by `Host`.
+    #[builder(default)]
+    http_listener: Option<RoutedAgent>,
+    /// TLS connections routed by SNI without being terminated.
+    #[builder(default)]
+    tls_passthrough_listener: Option<RoutedAgent>,
+    /// The trusted upstreams to read the client address of the guest TCP listeners from.
+    #[builder(default)]
+    proxy_protocol: ProxyProtocol,
+    /// Blobs exposed to the guest as a read-only filesystem.
+    #[builder(default)]
+    mounts: Vec<BlobMount>,
+    /// A writable directory for the guest, encrypted at rest.
+    #[builder(default)]
+    scratch: Option<ScratchConfig>,
 }
 
 pub struct WasmRun {
@@ -0,0 +1,849 @@
+//! A read-only virtual filesystem built from blobs, preopened as `/` in the guest's WASI context.
+//!
+//! A mount either exposes a blob as a single file or exposes the contents of a tar or zip blob
+//! as a directory tree. File contents are read from the blob files on demand, except for
+//! compressed zip entries which are inflated into memory when opened. Everything, including the
+//! archive headers, is read through [`VerifiedBlob`], so changes made by the host are detected.
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::io::{IoSliceMut, Read, SeekFrom};
+use std::path::{Component, Path};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::{anyhow, bail, Context, Result};
+use wasi_common::dir::{OpenResult, ReaddirCursor, ReaddirEntity, WasiDir};
+use wasi_common::file::{FdFlags, FileType, Filestat, OFlags, WasiFile};
+use wasi_common::{Error, ErrorExt};
+
+use super::blobs::{BlobLoader, VerifiedBlob};
+use super::metrics::Meter;
+
+/// Compressed zip entries larger than this can not be opened. Store large files uncompressed.
+const MAX_INFLATED_SIZE: u64 = 64 * 1024 * 1024;
+/// Limit on the zip entries kept inflated at the same time by the mounts of an instance.
+const MAX_INFLATED_TOTAL: u64 = 256 * 1024 * 1024;
+
+/// How a blob is exposed to the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountFormat {
+    /// The blob itself as a single file.
+    File,
+    /// The contents of an uncompressed tar archive as a directory.
+    Tar,
+    /// The contents of a zip archive as a directory.
+    Zip,
+}
+
+impl FromStr for MountFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "" | "file" => Ok(Self::File),
+            "tar" => Ok(Self::Tar),
+            "zip" => Ok(Self::Zip),
+            _ => bail!("unsupported mount format: {s}"),
+        }
+    }
+}
+
+/// A blob mounted at an absolute path in the guest's filesystem.
+#[derive(Debug, Clone)]
+pub struct BlobMount {
+    pub path: String,
+    pub blob: String,
+    pub format: MountFormat,
+}
+
+enum Content {
+    /// A range of a blob file.
+    Range {
+        file: Arc<VerifiedBlob>,
+        offset: u64,
+        size: u64,
+    },
+    /// A compressed entry of a zip archive.
+    Zipped {
+        archive: Arc<VerifiedBlob>,
+        index: usize,
+        size: u64,
+        /// The inflated entry, shared by the files open on it.
+        inflated: Mutex<Weak<Inflated>>,
+    },
+}
+
+impl Content {
+    fn size(&self) -> u64 {
+        match self {
+            Content::Range { size, .. } | Content::Zipped { size, .. } => *size,
+        }
+    }
+}
+
+enum Node {
+    Dir {
+        inode: u64,
+        entries: BTreeMap<String, Node>,
+    },
+    File {
+        inode: u64,
+        content: Content,
+    },
+}
+
+impl Node {
+    fn inode(&self) -> u64 {
+        match self {
+            Node::Dir { inode, .. } | Node::File { inode, .. } => *inode,
+        }
+    }
+
+    fn filetype(&self) -> FileType {
+        match self {
+            Node::Dir { .. } => FileType::Directory,
+            Node::File { .. } => FileType::RegularFile,
+        }
+    }
+
+    fn filestat(&self) -> Filestat {
+        let size = match self {
+            Node::Dir { .. } => 0,
+            Node::File { content, .. } => content.size(),
+        };
+        Filestat {
+            device_id: 0,
+            inode: self.inode(),
+            filetype: self.filetype(),
+            nlink: 1,
+            size,
+            atim: None,
+            mtim: None,
+            ctim: None,
+        }
+    }
+}
+
+/// Splits a path into its normal components, rejecting the ones escaping the root.
+fn components(path: &str) -> Result<Vec<String>> {
+    let mut parts = vec![];
+    for component in Path::new(path).components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(part) => parts.push(
+                part.to_str()
+                    .ok_or_else(|| anyhow!("invalid path: {path}"))?
+                    .to_string(),
+            ),
+            Component::ParentDir | Component::Prefix(_) => bail!("invalid path: {path}"),
+        }
+    }
+    Ok(parts)
+}
+
+struct TreeBuilder {
+    root: Node,
+    next_inode: u64,
+}
+
+impl TreeBuilder {
+    fn new() -> Self {
+        Self {
+            root: Node::Dir {
+                inode: 1,
+                entries: Default::default(),
+            },
+            next_inode: 2,
+        }
+    }
+
+    fn alloc_inode(&mut self) -> u64 {
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        inode
+    }
+
+    /// Returns the directory at the path, creating the missing ones.
+    fn dir(&mut self, parts: &[String]) -> Result<&mut BTreeMap<String, Node>> {
+        let mut node = &mut self.root;
+        for part in parts {
+            let Node::Dir { entries, .. } = node else {
+                bail!("not a directory: {}", parts.join("/"));
+            };
+            let inode = self.next_inode;
+            node = entries.entry(part.clone()).or_insert_with(|| {
+                self.next_inode += 1;
+                Node::Dir {
+                    inode,
+                    entries: Default::default(),
+                }
+            });
+        }
+        match node {
+            Node::Dir { entries, .. } => Ok(entries),
+            Node::File { .. } => bail!("not a directory: {}", parts.join("/")),
+        }
+    }
+
+    fn add_file(&mut self, parts: &[String], content: Content) -> Result<()> {
+        let Some((name, parent)) = parts.split_last() else {
+            bail!("can not mount a file at the root");
+        };
+        let inode = self.alloc_inode();
+        let entries = self.dir(parent)?;
+        if entries.contains_key(name) {
+            bail!("duplicate path: {}", parts.join("/"));
+        }
+        entries.insert(name.clone(), Node::File { inode, content });
+        Ok(())
+    }
+
+    fn mount(&mut self, blob_loader: &BlobLoader, mount: &BlobMount, meter: &Meter) -> Result<()> {
+        let file = blob_loader
+            .open(&mount.blob)?
+            .ok_or_else(|| anyhow!("blob not found: {}", mount.blob))?;
+        meter.record_gas(file.verified_on_open() / 128);
+        let base = components(&mount.path)?;
+        match mount.format {
+            MountFormat::File => {
+                let size = file.len();
+                self.add_file(
+                    &base,
+                    Content::Range {
+                        file,
+                        offset: 0,
+                        size,
+                    },
+                )?;
+            }
+            MountFormat::Tar => {
+                self.dir(&base)?;
+                let mut archive = tar::Archive::new(file.reader());
+                for entry in archive.entries_with_seek().context("invalid tar archive")? {
+                    let entry = entry.context("invalid tar archive")?;
+                    let kind = entry.header().entry_type();
+                    let path = base
+                        .iter()
+                        .cloned()
+                        .chain(components(&entry.path()?.to_string_lossy())?)
+                        .collect::<Vec<_>>();
+                    if kind.is_dir() {
+                        self.dir(&path)?;
+                    } else if kind.is_file() {
+                        let content = Content::Range {
+                            file: file.clone(),
+                            offset: entry.raw_file_position(),
+                            size: entry.size(),
+                        };
+                        self.add_file(&path, content)?;
+                    }
+                    // Links and special files are not supported.
+                }
+            }
+            MountFormat::Zip => {
+                self.dir(&base)?;
+                let mut archive =
+                    zip::ZipArchive::new(file.reader()).context("invalid zip archive")?;
+                for index in 0..archive.len() {
+                    let entry = archive.by_index_raw(index)?;
+                    let Some(name) = entry.enclosed_name() else {
+                        bail!("invalid path in zip archive: {}", entry.name());
+                    };
+                    let path = base
+                        .iter()
+                        .cloned()
+                        .chain(components(&name.to_string_lossy())?)
+                        .collect::<Vec<_>>();
+                    if entry.is_dir() {
+                        self.dir(&path)?;
+                        continue;
+                    }
+                    let content = match entry.compression() {
+                        zip::CompressionMethod::Stored => Content::Range {
+                            file: file.clone(),
+                            offset: entry.data_start(),
+                            size: entry.size(),
+                        },
+                        _ => Content::Zipped {
+                            archive: file.clone(),
+                            index,
+                            size: entry.size(),
+                            inflated: Default::default(),
+                        },
+                    };
+                    self.add_file(&path, content)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds the virtual filesystem of the given mounts.
+pub(crate) fn build(
+    blob_loader: &BlobLoader,
+    mounts: &[BlobMount],
+    meter: Arc<Meter>,
+) -> Result<Box<dyn WasiDir>> {
+    let mut builder = TreeBuilder::new();
+    for mount in mounts {
+        builder
+            .mount(blob_loader, mount, &meter)
+            .with_context(|| format!("failed to mount {} at {}", mount.blob, mount.path))?;
+    }
+    Ok(Box::new(VirtualDir {
+        root: Arc::new(builder.root),
+        path: vec![],
+        meter,
+        inflated: Default::default(),
+    }))
+}
+
+struct VirtualDir {
+    root: Arc<Node>,
+    /// Path of this directory from the root.
+    path: Vec<String>,
+    meter: Arc<Meter>,
+    /// Bytes of the zip entries currently inflated.
+    inflated: Arc<AtomicU64>,
+}
+
+impl VirtualDir {
+    /// Resolves a path relative to this directory, returning the node and its full path.
+    fn lookup(&self, path: &str) -> Result<(&Node, Vec<String>), Error> {
+        let mut full = self.path.clone();
+        let base_len = full.len();
+        for component in Path::new(path).components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if full.len() == base_len {
+                        return Err(Error::perm());
+                    }
+                    full.pop();
+                }
+                Component::Normal(part) => full.push(
+                    part.to_str()
+                        .ok_or_else(Error::illegal_byte_sequence)?
+                        .into(),
+                ),
+                Component::RootDir | Component::Prefix(_) => return Err(Error::perm()),
+            }
+        }
+        let mut node = &*self.root;
+        for part in &full {
+            let Node::Dir { entries, .. } = node else {
+                return Err(Error::not_dir());
+            };
+            node = entries.get(part).ok_or_else(Error::not_found)?;
+        }
+        Ok((node, full))
+    }
+
+    /// Returns the node at a path from the root that is known to exist.
+    fn node_at(&self, parts: &[String]) -> &Node {
+        let mut node = &*self.root;
+        for part in parts {
+            let Node::Dir { entries, .. } = node else {
+                unreachable!("BUG: parent is not a directory");
+            };
+            node = &entries[part];
+        }
+        node
+    }
+}
+
+#[wiggle::async_trait]
+impl WasiDir for VirtualDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn open_file(
+        &self,
+        _symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<OpenResult, Error> {
+        if write
+            || oflags.intersects(OFlags::CREATE | OFlags::EXCLUSIVE | OFlags::TRUNCATE)
+            || fdflags.contains(FdFlags::APPEND)
+        {
+            return Err(Error::perm());
+        }
+        let (node, full) = self.lookup(path)?;
+        match node {
+            Node::Dir { .. } => Ok(OpenResult::Dir(Box::new(VirtualDir {
+                root: self.root.clone(),
+                path: full,
+                meter: self.meter.clone(),
+                inflated: self.inflated.clone(),
+            }))),
+            Node::File { .. } if oflags.contains(OFlags::DIRECTORY) => Err(Error::not_dir()),
+            Node::File { inode, content } => {
+                if !read {
+                    return Err(Error::perm());
+                }
+                let (data, size) = match content {
+                    Content::Range { file, offset, size } => {
+                        let data = Data::Range {
+                            file: file.clone(),
+                            offset: *offset,
+                        };
+                        (data, *size)
+                    }
+                    Content::Zipped {
+                        archive,
+                        index,
+                        size,
+                        inflated,
+                    } => {
+                        let data = self
+                            .inflate(archive, *index, *size, inflated)
+                            .map_err(|_| Error::io())?;
+                        let size = data.data.len() as u64;
+                        (Data::Memory(data), size)
+                    }
+                };
+                Ok(OpenResult::File(Box::new(VirtualFile {
+                    inode: *inode,
+                    size,
+                    data,
+                    position: Mutex::new(0),
+                    meter: self.meter.clone(),
+                })))
+            }
+        }
+    }
+
+    async fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        let Node::Dir { inode, entries } = self.node_at(&self.path) else {
+            return Err(Error::not_dir());
+        };
+        let parent_inode = match self.path.split_last() {
+            Some((_, parent)) => self.node_at(parent).inode(),
+            None => *inode,
+        };
+        let dots = [
+            (".".to_string(), *inode, FileType::Directory),
+            ("..".to_string(), parent_inode, FileType::Directory),
+        ];
+        let children = entries
+            .iter()
+            .map(|(name, node)| (name.clone(), node.inode(), node.filetype()));
+        let items: Vec<_> = dots
+            .into_iter()
+            .chain(children)
+            .enumerate()
+            .skip(u64::from(cursor) as usize)
+            .map(|(i, (name, inode, filetype))| {
+                Ok(ReaddirEntity {
+                    next: ReaddirCursor::from(i as u64 + 1),
+                    inode,
+                    name,
+                    filetype,
+                })
+            })
+            .collect();
+        Ok(Box::new(items.into_iter()))
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        Ok(self.node_at(&self.path).filestat())
+    }
+
+    async fn get_path_filestat(
+        &self,
+        path: &str,
+        _follow_symlinks: bool,
+    ) -> Result<Filestat, Error> {
+        Ok(self.lookup(path)?.0.filestat())
+    }
+}
+
+impl VirtualDir {
+    /// Returns the inflated zip entry, sharing it with the files already open on it.
+    ///
+    /// The bytes inflated are charged as gas and counted against `MAX_INFLATED_TOTAL` until the
+    /// last file open on the entry is closed.
+    fn inflate(
+        &self,
+        archive: &Arc<VerifiedBlob>,
+        index: usize,
+        size: u64,
+        cache: &Mutex<Weak<Inflated>>,
+    ) -> Result<Arc<Inflated>> {
+        let mut cache = cache.lock().unwrap();
+        if let Some(inflated) = cache.upgrade() {
+            return Ok(inflated);
+        }
+        if size > MAX_INFLATED_SIZE {
+            bail!("compressed entry too large, max={MAX_INFLATED_SIZE}");
+        }
+        let reserved = InflatedBytes::reserve(&self.inflated, size)?;
+        let mut archive = zip::ZipArchive::new(archive.reader())?;
+        let entry = archive.by_index(index)?;
+        let mut data = Vec::with_capacity(size as usize);
+        entry.take(size).read_to_end(&mut data)?;
+        self.meter.record_gas(size / 128);
+        let inflated = Arc::new(Inflated {
+            data,
+            _reserved: reserved,
+        });
+        *cache = Arc::downgrade(&inflated);
+        Ok(inflated)
+    }
+}
+
+/// The content of a compressed zip entry.
+struct Inflated {
+    data: Vec<u8>,
+    _reserved: InflatedBytes,
+}
+
+/// Bytes counted against the inflated total of the mounts, released on drop.
+struct InflatedBytes {
+    total: Arc<AtomicU64>,
+    size: u64,
+}
+
+impl InflatedBytes {
+    fn reserve(total: &Arc<AtomicU64>, size: u64) -> Result<Self> {
+        let reserved = Self {
+            total: total.clone(),
+            size,
+        };
+        if total.fetch_add(size, Ordering::SeqCst) + size > MAX_INFLATED_TOTAL {
+            bail!("too many compressed entries open, max={MAX_INFLATED_TOTAL}");
+        }
+        Ok(reserved)
+    }
+}
+
+impl Drop for InflatedBytes {
+    fn drop(&mut self) {
+        self.total.fetch_sub(self.size, Ordering::SeqCst);
+    }
+}
+
+enum Data {
+    Range {
+        file: Arc<VerifiedBlob>,
+        offset: u64,
+    },
+    Memory(Arc<Inflated>),
+}
+
+struct VirtualFile {
+    inode: u64,
+    size: u64,
+    data: Data,
+    position: Mutex<u64>,
+    meter: Arc<Meter>,
+}
+
+impl VirtualFile {
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> std::io::Result<usize> {
+        let available = self.size.saturating_sub(pos);
+        let len = (buf.len() as u64).min(available) as usize;
+        let buf = &mut buf[..len];
+        let read = match &self.data {
+            Data::Range { file, offset } => file.read_at(buf, offset + pos)?,
+            Data::Memory(inflated) => {
+                if len > 0 {
+                    buf.copy_from_slice(&inflated.data[pos as usize..pos as usize + len]);
+                }
+                len
+            }
+        };
+        self.meter.record_storage_read(read as u64);
+        Ok(read)
+    }
+
+    fn read_vectored_at(&self, bufs: &mut [IoSliceMut<'_>], mut pos: u64) -> Result<u64, Error> {
+        let mut total = 0;
+        for buf in bufs.iter_mut() {
+            let want = buf.len();
+            let read = self.read_at(buf, pos)?;
+            total += read as u64;
+            pos += read as u64;
+            if read < want {
+                break;
+            }
+        }
+        Ok(total)
+    }
+}
+
+#[wiggle::async_trait]
+impl WasiFile for VirtualFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn get_filetype(&self) -> Result<FileType, Error> {
+        Ok(FileType::RegularFile)
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        Ok(Filestat {
+            device_id: 0,
+            inode: self.inode,
+            filetype: FileType::RegularFile,
+            nlink: 1,
+            size: self.size,
+            atim: None,
+            mtim: None,
+            ctim: None,
+        })
+    }
+
+    async fn read_vectored<'a>(&self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        let mut position = self.position.lock().unwrap();
+        let read = self.read_vectored_at(bufs, *position)?;
+        *position += read;
+        Ok(read)
+    }
+
+    async fn read_vectored_at<'a>(
+        &self,
+        bufs: &mut [IoSliceMut<'a>],
+        offset: u64,
+    ) -> Result<u64, Error> {
+        VirtualFile::read_vectored_at(self, bufs, offset)
+    }
+
+    async fn seek(&self, pos: SeekFrom) -> Result<u64, Error> {
+        let mut position = self.position.lock().unwrap();
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => position.checked_add_signed(offset),
+        };
+        *position = new_position.ok_or_else(Error::invalid_argument)?;
+        Ok(*position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+
+    use crate::runtime::blobs::test_loader;
+
+    fn tar_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn zip_of(files: &[(&str, &[u8], zip::CompressionMethod)]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        for (path, data, method) in files {
+            let options = zip::write::FileOptions::default().compression_method(*method);
+            writer.start_file(*path, options).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    async fn put(loader: &BlobLoader, data: &[u8]) -> String {
+        loader.put("sha256:", &mut &data[..]).await.unwrap()
+    }
+
+    fn mount(path: &str, blob: &str, format: MountFormat) -> BlobMount {
+        BlobMount {
+            path: path.into(),
+            blob: blob.into(),
+            format,
+        }
+    }
+
+    async fn read_file(dir: &dyn WasiDir, path: &str) -> Result<Vec<u8>, Error> {
+        let opened = dir
+            .open_file(false, path, OFlags::empty(), true, false, FdFlags::empty())
+            .await?;
+        let OpenResult::File(file) = opened else {
+            panic!("not a file: {path}");
+        };
+        let mut content = vec![];
+        let mut buf = [0; 7];
+        loop {
+            let n = file.read_vectored(&mut [IoSliceMut::new(&mut buf)]).await?;
+            if n == 0 {
+                return Ok(content);
+            }
+            content.extend_from_slice(&buf[..n as usize]);
+        }
+    }
+
+    #[tokio::test]
+    async fn mounts_expose_the_blob_contents() {
+        let (loader, dir) = test_loader();
+        let tar = put(
+            &loader,
+            &tar_of(&[("a/hello.txt", b"hello from tar"), ("b.txt", b"tar b")]),
+        )
+        .await;
+        let zip = put(
+            &loader,
+            &zip_of(&[
+                (
+                    "stored.txt",
+                    b"stored in zip",
+                    zip::CompressionMethod::Stored,
+                ),
+                (
+                    "x/deflated.txt",
+                    b"deflated in zip",
+                    zip::CompressionMethod::Deflated,
+                ),
+            ]),
+        )
+        .await;
+        let raw = put(&loader, b"mounted as a single file").await;
+        let root = build(
+            &loader,
+            &[
+                mount("/data", &tar, MountFormat::Tar),
+                mount("/pkg", &zip, MountFormat::Zip),
+                mount("/etc/raw.bin", &raw, MountFormat::File),
+            ],
+            Default::default(),
+        )
+        .unwrap();
+        let root = &*root;
+        assert_eq!(
+            read_file(root, "data/a/hello.txt").await.unwrap(),
+            b"hello from tar"
+        );
+        assert_eq!(read_file(root, "data/b.txt").await.unwrap(), b"tar b");
+        assert_eq!(
+            read_file(root, "pkg/stored.txt").await.unwrap(),
+            b"stored in zip"
+        );
+        assert_eq!(
+            read_file(root, "pkg/x/deflated.txt").await.unwrap(),
+            b"deflated in zip"
+        );
+        assert_eq!(
+            read_file(root, "etc/raw.bin").await.unwrap(),
+            b"mounted as a single file"
+        );
+        assert!(read_file(root, "data/../../etc/raw.bin").await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn inflated_entries_are_shared_between_opens() {
+        let (loader, dir) = test_loader();
+        let data = vec![b'x'; 4096];
+        let zip = put(
+            &loader,
+            &zip_of(&[("file.txt", &data, zip::CompressionMethod::Deflated)]),
+        )
+        .await;
+        let root = build(
+            &loader,
+            &[mount("/zip", &zip, MountFormat::Zip)],
+            Default::default(),
+        )
+        .unwrap();
+        let inflated = root
+            .as_any()
+            .downcast_ref::<VirtualDir>()
+            .unwrap()
+            .inflated
+            .clone();
+        let open = || async {
+            root.open_file(
+                false,
+                "zip/file.txt",
+                OFlags::empty(),
+                true,
+                false,
+                FdFlags::empty(),
+            )
+            .await
+            .unwrap()
+        };
+        let first = open().await;
+        let second = open().await;
+        assert_eq!(inflated.load(Ordering::SeqCst), data.len() as u64);
+        drop(first);
+        assert_eq!(inflated.load(Ordering::SeqCst), data.len() as u64);
+        drop(second);
+        assert_eq!(inflated.load(Ordering::SeqCst), 0);
+        assert_eq!(read_file(&*root, "zip/file.txt").await.unwrap(), data);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tampered_archive_headers_are_rejected() {
+        let (loader, dir) = test_loader();
+        let tar = put(&loader, &tar_of(&[("evil.txt", b"tampered header")])).await;
+        // Rename the entry to escape the mount.
+        let path = loader.path_of(&tar).unwrap();
+        let file = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+        file.write_at(b"../e", 0).unwrap();
+        let mounts = [mount("/data", &tar, MountFormat::Tar)];
+        assert!(build(&loader, &mounts, Default::default()).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn contents_changed_after_mounting_are_not_read() {
+        let (loader, dir) = test_loader();
+        // Incompressible and spanning several chunks, so that the middle chunk of the archives
+        // is not read while mounting.
+        let mut seed = 0x2545f491_u32;
+        let data: Vec<u8> = (0..1024 * 1024)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                seed as u8
+            })
+            .collect();
+        let tar = put(&loader, &tar_of(&[("file.txt", &data)])).await;
+        let zip = put(
+            &loader,
+            &zip_of(&[("file.txt", &data, zip::CompressionMethod::Deflated)]),
+        )
+        .await;
+        let root = build(
+            &loader,
+            &[
+                mount("/tar", &tar, MountFormat::Tar),
+                mount("/zip", &zip, MountFormat::Zip),
+            ],
+            Default::default(),
+        )
+        .unwrap();
+        for id in [&tar, &zip] {
+            let path = loader.path_of(id).unwrap();
+            let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+            let middle = file.metadata().unwrap().len() / 2;
+            file.write_at(b"tampered", middle).unwrap();
+        }
+        assert!(read_file(&*root, "tar/file.txt").await.is_err());
+        assert!(read_file(&*root, "zip/file.txt").await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
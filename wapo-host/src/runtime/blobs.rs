@@ -1,20 +1,87 @@
 use std::{
+    collections::HashMap,
     fmt::Display,
-    io::{BufReader, Read as _},
+    io::{BufReader, Read, Seek, SeekFrom},
     num::NonZeroUsize,
+    os::unix::fs::FileExt,
     path::{Path, PathBuf},
     str::FromStr,
     sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::{bail, Context, Error, Result};
+use blake2::{digest::consts::U32, Blake2b};
+use cid::Cid;
 use scale::{Decode, Encode};
 use sha2::Digest;
-use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use super::unixfs::{UnixFsBuilder, CODEC_DAG_PB, CODEC_RAW, MH_SHA2_256};
+
+const MAX_UPLOADS: usize = 16;
+const INDEX_DIR: &str = ".index";
+/// The index entries of each object, so that they can be removed along with the object.
+const ALIASES_DIR: &str = ".aliases";
+const MH_SHA2_512: u64 = 0x13;
+const MH_BLAKE2B_256: u64 = 0xb220;
+/// Granularity of the verification of [`VerifiedBlob`] reads.
+const VERIFY_CHUNK_SIZE: u64 = 256 * 1024;
+/// How many blobs the chunk hashes are kept for. Blobs beyond it are verified again when opened.
+const CHUNK_HASHES_CACHE_SIZE: usize = 1024;
+
+type Blake2b256 = Blake2b<U32>;
 
 struct BlobLoaderState {
     store_dir: PathBuf,
     cache: Mutex<lru::LruCache<String, Vec<u8>>>,
+    /// The chunk hashes of the objects, by file name, recorded when they are stored or verified.
+    chunk_hashes: Mutex<lru::LruCache<String, Arc<ChunkHashes>>>,
+    /// Chunked uploads in progress. `None` means a chunk is being appended to the upload.
+    uploads: Mutex<HashMap<String, Option<Upload>>>,
+}
+
+struct Upload {
+    blob_id: BlobId,
+    size: u64,
+    offset: u64,
+    hasher: ContentHasher,
+    last_active: Instant,
+}
+
+/// The progress of a chunked upload.
+#[derive(Debug, Clone)]
+pub struct UploadStatus {
+    pub id: String,
+    /// The number of bytes received so far, which is the offset of the next chunk.
+    pub offset: u64,
+    /// The total size declared when the upload began.
+    pub size: u64,
+}
+
+/// Puts the upload back when an append or commit finishes or is cancelled.
+struct UploadGuard<'a> {
+    loader: &'a BlobLoader,
+    id: &'a str,
+    upload: Option<Upload>,
+}
+
+impl Drop for UploadGuard<'_> {
+    fn drop(&mut self) {
+        let mut uploads = self.loader.state.uploads.lock().unwrap();
+        if let Some(slot) = uploads.get_mut(self.id) {
+            *slot = self.upload.take();
+        }
+    }
+}
+
+/// An object file in the blobs directory.
+#[derive(Debug, Clone)]
+pub struct BlobFile {
+    /// The hex encoded hash of the object, which is also the file name.
+    pub key: String,
+    pub size: u64,
+    pub modified: SystemTime,
 }
 
 #[derive(Clone)]
@@ -34,36 +101,201 @@ impl BlobLoader {
             state: Arc::new(BlobLoaderState {
                 store_dir: store_dir.as_ref().to_path_buf(),
                 cache: Mutex::new(lru::LruCache::new(NonZeroUsize::new(16).unwrap())),
+                chunk_hashes: Mutex::new(lru::LruCache::new(
+                    NonZeroUsize::new(CHUNK_HASHES_CACHE_SIZE).unwrap(),
+                )),
+                uploads: Default::default(),
             }),
         }
     }
 
-    pub fn get(&self, hash: &str) -> Result<Option<Vec<u8>>> {
-        get_object(self.state.store_dir.as_path(), hash)
+    /// Reads and verifies the object. The ID can be any form accepted by [`BlobId`].
+    pub fn get(&self, id: &str) -> Result<Option<Vec<u8>>> {
+        get_object(self.state.store_dir.as_path(), id)
+    }
+
+    /// Opens the object for random access reads, each of which is verified.
+    ///
+    /// Reads are checked against the chunk hashes recorded when the object was stored, since the
+    /// file can be changed by the host at any time. Objects stored before the worker started, or
+    /// whose hashes were evicted, are read through to be verified against the ID first, with the
+    /// runtime told that the thread blocks. See [`VerifiedBlob::verified_on_open`].
+    pub fn open(&self, id: &str) -> Result<Option<Arc<VerifiedBlob>>> {
+        let id = BlobId::from_str(id)?;
+        let Some(path) = resolve_path(&self.state.store_dir, &id) else {
+            return Ok(None);
+        };
+        let key = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context("invalid object path")?
+            .to_string();
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context("failed to open object file"),
+        };
+        let cached = self.state.chunk_hashes.lock().unwrap().get(&key).cloned();
+        let (chunk_hashes, verified_on_open) = match cached {
+            Some(chunk_hashes) => (chunk_hashes, 0),
+            None => {
+                let chunk_hashes = Arc::new(blocking(|| verify_chunks(&file, &id))?);
+                self.record_chunk_hashes(key, chunk_hashes.clone());
+                let size = chunk_hashes.size;
+                (chunk_hashes, size)
+            }
+        };
+        Ok(Some(Arc::new(VerifiedBlob {
+            file,
+            chunk_hashes,
+            verified_on_open,
+            chunks: Mutex::new(lru::LruCache::new(NonZeroUsize::new(4).unwrap())),
+        })))
     }
 
-    pub async fn put<'a, R>(&self, hash: &str, data: &'a mut R) -> Result<String>
+    fn record_chunk_hashes(&self, key: String, chunk_hashes: Arc<ChunkHashes>) {
+        self.state
+            .chunk_hashes
+            .lock()
+            .unwrap()
+            .put(key, chunk_hashes);
+    }
+
+    /// Verifies the file and moves it into the store, recording its chunk hashes.
+    fn store(&self, file: &Path, id: &BlobId, digests: Digests) -> Result<String> {
+        let (id, key) = store_file(&self.state.store_dir, file, id, &digests)?;
+        self.record_chunk_hashes(key, Arc::new(digests.chunk_hashes));
+        Ok(id)
+    }
+
+    pub async fn put<R>(&self, id: &str, data: &mut R) -> Result<String>
     where
         R: AsyncRead + Unpin + ?Sized,
     {
-        put_object(self.state.store_dir.as_path(), hash, data).await
+        let id = BlobId::from_str(id)?;
+        let tmp_filepath = self.temp_path()?;
+        let mut tmpfile = tokio::fs::File::create(&tmp_filepath)
+            .await
+            .context("failed to create temporary object file")?;
+        let _guard = scopeguard::guard((), |_| {
+            let _ = std::fs::remove_file(&tmp_filepath);
+        });
+        tokio::io::copy(data, &mut tmpfile)
+            .await
+            .context("failed to write object file")?;
+        tmpfile
+            .sync_all()
+            .await
+            .context("failed to sync object file")?;
+        drop(tmpfile);
+
+        // Make sure the hash of the file is correct
+        let digests = digest_file(&tmp_filepath).await?;
+        self.store(&tmp_filepath, &id, digests)
     }
 
-    pub fn remove(&self, hash: &str) -> Result<()> {
-        let hash = HashValue::from_str(hash).map_err(Error::msg)?;
-        std::fs::remove_file(self.path(&hash.hash))?;
-        Ok(())
+    /// Moves a local file into the store after verifying it against the ID. The file should be
+    /// on the same filesystem as the store, e.g. created at [`BlobLoader::temp_path`].
+    pub async fn put_file(&self, id: &str, path: &Path) -> Result<String> {
+        let id = BlobId::from_str(id)?;
+        let digests = digest_file(path).await?;
+        self.store(path, &id, digests)
+    }
+
+    /// Returns a new path in the temporary directory of the store.
+    pub fn temp_path(&self) -> Result<PathBuf> {
+        let tmpdir = self.state.store_dir.join(".tmp");
+        std::fs::create_dir_all(&tmpdir).context("failed to create blobs directory")?;
+        Ok(tmpdir.join(uuid::Uuid::new_v4().to_string()))
+    }
+
+    pub fn remove(&self, id: &str) -> Result<()> {
+        let key = self.resolve_key(id).context("object not found")?;
+        self.remove_key(&key)
     }
 
     pub fn path(&self, hash: &[u8]) -> PathBuf {
         self.state.store_dir.join(hex::encode(hash))
     }
 
-    pub fn exists(&self, hash: &str) -> bool {
-        let Ok(hash) = HashValue::from_str(hash) else {
-            return false;
-        };
-        self.path(&hash.hash).exists()
+    /// Returns the path of the object with the given ID, or with the given file name.
+    pub fn path_of(&self, id: &str) -> Option<PathBuf> {
+        if hex::decode(id).is_ok() {
+            let path = self.state.store_dir.join(id);
+            return path.is_file().then_some(path);
+        }
+        resolve_path(&self.state.store_dir, &BlobId::from_str(id).ok()?)
+    }
+
+    pub fn exists(&self, id: &str) -> bool {
+        self.resolve_key(id).is_some()
+    }
+
+    /// Returns the file name of the object with the given ID if it is in the store.
+    pub fn resolve_key(&self, id: &str) -> Option<String> {
+        let id = BlobId::from_str(id).ok()?;
+        let path = resolve_path(&self.state.store_dir, &id)?;
+        Some(path.file_name()?.to_str()?.to_string())
+    }
+
+    /// Lists the objects in the store. Raw files and temporary files are not included.
+    pub fn list(&self) -> Result<Vec<BlobFile>> {
+        let mut files = vec![];
+        let entries =
+            std::fs::read_dir(&self.state.store_dir).context("failed to read blobs directory")?;
+        for entry in entries {
+            let entry = entry.context("failed to read blobs directory")?;
+            let Ok(key) = entry.file_name().into_string() else {
+                continue;
+            };
+            if hex::decode(&key).is_err() {
+                continue;
+            }
+            let metadata = entry.metadata().context("failed to read object metadata")?;
+            if !metadata.is_file() {
+                continue;
+            }
+            files.push(BlobFile {
+                key,
+                size: metadata.len(),
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            });
+        }
+        Ok(files)
+    }
+
+    /// Removes the object with the given file name as returned by [`BlobLoader::list`].
+    pub fn remove_key(&self, key: &str) -> Result<()> {
+        if hex::decode(key).is_err() {
+            bail!("invalid object key");
+        }
+        std::fs::remove_file(self.state.store_dir.join(key))?;
+        self.state.chunk_hashes.lock().unwrap().pop(key);
+        prune_index(&self.state.store_dir, key)
+    }
+
+    /// Returns the total size of the files in the blobs directory, including raw files and
+    /// unfinished uploads.
+    pub fn disk_usage(&self) -> Result<u64> {
+        fn dir_size(dir: &Path) -> Result<u64> {
+            let mut total = 0;
+            let entries = match std::fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+                Err(err) => return Err(err).context("failed to read blobs directory"),
+            };
+            for entry in entries {
+                let metadata = entry?.metadata()?;
+                if metadata.is_file() {
+                    total += metadata.len();
+                }
+            }
+            Ok(total)
+        }
+        let store_dir = &self.state.store_dir;
+        Ok(dir_size(store_dir)?
+            + dir_size(&store_dir.join(".tmp"))?
+            + dir_size(&self.uploads_dir())?)
     }
 }
 
@@ -102,10 +334,381 @@ impl BlobLoader {
     }
 }
 
+// Chunked uploads
+//
+// An upload is declared with the expected hash and size, then the data is appended chunk by
+// chunk at increasing offsets. The hash is computed while appending, so the commit only needs to
+// compare it. If a chunk fails in the middle, the client can query the offset and resume from
+// there. Uploads that are idle for too long are removed by `cleanup_uploads`.
+impl BlobLoader {
+    fn uploads_dir(&self) -> PathBuf {
+        self.state.store_dir.join(".uploads")
+    }
+
+    pub fn begin_upload(&self, id: &str, size: u64) -> Result<UploadStatus> {
+        let blob_id = BlobId::from_str(id)?;
+        let mut uploads = self.state.uploads.lock().unwrap();
+        if uploads.len() >= MAX_UPLOADS {
+            bail!("too many uploads in progress");
+        }
+        let uploads_dir = self.uploads_dir();
+        std::fs::create_dir_all(&uploads_dir).context("failed to create uploads directory")?;
+        let id = uuid::Uuid::new_v4().to_string();
+        std::fs::File::create(uploads_dir.join(&id)).context("failed to create upload file")?;
+        let upload = Upload {
+            blob_id,
+            hasher: ContentHasher::new(),
+            size,
+            offset: 0,
+            last_active: Instant::now(),
+        };
+        uploads.insert(id.clone(), Some(upload));
+        Ok(UploadStatus {
+            id,
+            offset: 0,
+            size,
+        })
+    }
+
+    fn take_upload<'a>(&'a self, id: &'a str) -> Result<UploadGuard<'a>> {
+        let mut uploads = self.state.uploads.lock().unwrap();
+        let Some(slot) = uploads.get_mut(id) else {
+            bail!("upload not found");
+        };
+        let upload = slot.take().context("upload is busy")?;
+        Ok(UploadGuard {
+            loader: self,
+            id,
+            upload: Some(upload),
+        })
+    }
+
+    pub fn upload_status(&self, id: &str) -> Result<UploadStatus> {
+        let uploads = self.state.uploads.lock().unwrap();
+        let Some(slot) = uploads.get(id) else {
+            bail!("upload not found");
+        };
+        let upload = slot.as_ref().context("upload is busy")?;
+        Ok(UploadStatus {
+            id: id.to_string(),
+            offset: upload.offset,
+            size: upload.size,
+        })
+    }
+
+    /// Appends a chunk to the upload. The offset must equal the number of bytes received so far.
+    pub async fn append_upload<R>(
+        &self,
+        id: &str,
+        offset: u64,
+        data: &mut R,
+    ) -> Result<UploadStatus>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        let mut guard = self.take_upload(id)?;
+        let upload = guard.upload.as_mut().expect("BUG: upload taken");
+        if offset != upload.offset {
+            bail!("offset mismatch, expected {}", upload.offset);
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(self.uploads_dir().join(id))
+            .await
+            .context("failed to open upload file")?;
+        // Discard the tail of a previously interrupted chunk, if any.
+        file.set_len(upload.offset).await?;
+        file.seek(SeekFrom::Start(upload.offset)).await?;
+        let mut hasher = upload.hasher.clone();
+        let mut written = 0_u64;
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let n = data.read(&mut buf).await.context("failed to read chunk")?;
+            if n == 0 {
+                break;
+            }
+            written += n as u64;
+            if upload.offset + written > upload.size {
+                bail!("upload exceeds the declared size {}", upload.size);
+            }
+            hasher.update(&buf[..n]);
+            file.write_all(&buf[..n])
+                .await
+                .context("failed to write upload file")?;
+        }
+        file.sync_data()
+            .await
+            .context("failed to sync upload file")?;
+        upload.hasher = hasher;
+        upload.offset += written;
+        upload.last_active = Instant::now();
+        Ok(UploadStatus {
+            id: id.to_string(),
+            offset: upload.offset,
+            size: upload.size,
+        })
+    }
+
+    /// Verifies the hash of a completed upload and moves it into the store.
+    pub fn commit_upload(&self, id: &str) -> Result<String> {
+        let mut guard = self.take_upload(id)?;
+        let upload = guard.upload.as_mut().expect("BUG: upload taken");
+        if upload.offset != upload.size {
+            bail!("upload incomplete, {}/{} bytes", upload.offset, upload.size);
+        }
+        let digests = upload.hasher.clone().finalize();
+        let blob_id = self.store(&self.uploads_dir().join(id), &upload.blob_id, digests)?;
+        guard.upload = None;
+        self.state.uploads.lock().unwrap().remove(id);
+        Ok(blob_id)
+    }
+
+    pub fn abort_upload(&self, id: &str) -> Result<()> {
+        if self.state.uploads.lock().unwrap().remove(id).is_none() {
+            bail!("upload not found");
+        }
+        std::fs::remove_file(self.uploads_dir().join(id)).context("failed to remove upload")?;
+        Ok(())
+    }
+
+    /// Removes the uploads that have been idle for longer than `timeout`, including the leftovers
+    /// of a previous run. Returns the number of uploads removed.
+    pub fn cleanup_uploads(&self, timeout: Duration) -> Result<usize> {
+        let mut uploads = self.state.uploads.lock().unwrap();
+        uploads.retain(|_, slot| match slot {
+            Some(upload) => upload.last_active.elapsed() < timeout,
+            None => true,
+        });
+        let entries = match std::fs::read_dir(self.uploads_dir()) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err).context("failed to read uploads directory"),
+        };
+        let mut removed = 0;
+        for entry in entries {
+            let entry = entry?;
+            let id = entry.file_name().to_string_lossy().into_owned();
+            if uploads.contains_key(&id) {
+                continue;
+            }
+            let modified = entry.metadata()?.modified()?;
+            if modified.elapsed().unwrap_or_default() < timeout {
+                // Might be a file just created by `begin_upload`.
+                continue;
+            }
+            std::fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+}
+
+/// The hashes of the chunks of a blob verified against its ID.
+struct ChunkHashes {
+    size: u64,
+    hashes: Vec<[u8; 32]>,
+}
+
+/// Hashes the content in chunks of [`VERIFY_CHUNK_SIZE`], whatever the sizes of the updates.
+#[derive(Clone, Default)]
+struct ChunkHasher {
+    current: sha2::Sha256,
+    filled: u64,
+    size: u64,
+    hashes: Vec<[u8; 32]>,
+}
+
+impl ChunkHasher {
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let n = data.len().min((VERIFY_CHUNK_SIZE - self.filled) as usize);
+            self.current.update(&data[..n]);
+            self.filled += n as u64;
+            self.size += n as u64;
+            data = &data[n..];
+            if self.filled == VERIFY_CHUNK_SIZE {
+                self.hashes
+                    .push(std::mem::take(&mut self.current).finalize().into());
+                self.filled = 0;
+            }
+        }
+    }
+
+    fn finalize(mut self) -> ChunkHashes {
+        if self.filled > 0 {
+            self.hashes.push(self.current.finalize().into());
+        }
+        ChunkHashes {
+            size: self.size,
+            hashes: self.hashes,
+        }
+    }
+}
+
+/// Runs blocking work, letting the runtime move its other tasks off the thread if it is a worker
+/// of a multi-threaded runtime.
+fn blocking<R>(f: impl FnOnce() -> R) -> R {
+    use tokio::runtime::{Handle, RuntimeFlavor};
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+/// Reads the file through, checking it against the ID.
+fn verify_chunks(mut file: &std::fs::File, id: &BlobId) -> Result<ChunkHashes> {
+    let mut hasher = match id {
+        BlobId::Hash(hash) => Some(Hasher::new(hash.algo)),
+        BlobId::Cid(_) => None,
+    };
+    let mut content_hasher = hasher.is_none().then(ContentHasher::new);
+    let mut chunk_hasher = ChunkHasher::default();
+    let mut buf = vec![0; 64 * 1024];
+    file.rewind()?;
+    loop {
+        let n = file.read(&mut buf).context("failed to read object file")?;
+        if n == 0 {
+            break;
+        }
+        if let Some(hasher) = &mut hasher {
+            hasher.update(&buf[..n]);
+        }
+        if let Some(hasher) = &mut content_hasher {
+            hasher.update(&buf[..n]);
+        }
+        chunk_hasher.update(&buf[..n]);
+    }
+    let valid = match (hasher, content_hasher, id) {
+        (Some(hasher), _, BlobId::Hash(hash)) => hasher.finalize() == hash.hash,
+        (_, Some(hasher), _) => hasher.finalize().check(id).is_ok(),
+        _ => false,
+    };
+    if !valid {
+        bail!("blob {id} is corrupted");
+    }
+    Ok(chunk_hasher.finalize())
+}
+
+/// A blob file whose reads are verified chunk by chunk. See [`BlobLoader::open`].
+pub struct VerifiedBlob {
+    file: std::fs::File,
+    chunk_hashes: Arc<ChunkHashes>,
+    verified_on_open: u64,
+    /// Recently read chunks, since small sequential reads would otherwise verify a chunk each.
+    chunks: Mutex<lru::LruCache<u64, Arc<Vec<u8>>>>,
+}
+
+impl VerifiedBlob {
+    /// The size of the blob as verified on open.
+    pub fn len(&self) -> u64 {
+        self.chunk_hashes.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunk_hashes.size == 0
+    }
+
+    /// The number of bytes read to verify the blob when it was opened, 0 if its chunk hashes
+    /// were already known.
+    pub fn verified_on_open(&self) -> u64 {
+        self.verified_on_open
+    }
+
+    fn chunk(&self, index: u64) -> std::io::Result<Arc<Vec<u8>>> {
+        if let Some(chunk) = self.chunks.lock().unwrap().get(&index) {
+            return Ok(chunk.clone());
+        }
+        let corrupted = || std::io::Error::new(std::io::ErrorKind::InvalidData, "blob corrupted");
+        let expected = self
+            .chunk_hashes
+            .hashes
+            .get(index as usize)
+            .ok_or_else(corrupted)?;
+        let offset = index * VERIFY_CHUNK_SIZE;
+        let mut chunk = vec![0; VERIFY_CHUNK_SIZE as usize];
+        let mut len = 0;
+        while len < chunk.len() {
+            match self.file.read_at(&mut chunk[len..], offset + len as u64)? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        chunk.truncate(len);
+        if sha2::Sha256::digest(&chunk).as_slice() != expected {
+            return Err(corrupted());
+        }
+        let chunk = Arc::new(chunk);
+        self.chunks.lock().unwrap().put(index, chunk.clone());
+        Ok(chunk)
+    }
+
+    /// Reads at the offset like [`FileExt::read_at`]. Fails if the content doesn't match.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let index = offset / VERIFY_CHUNK_SIZE;
+        if buf.is_empty() || offset >= self.len() {
+            return Ok(0);
+        }
+        let chunk = self.chunk(index)?;
+        let start = ((offset % VERIFY_CHUNK_SIZE) as usize).min(chunk.len());
+        let len = buf.len().min(chunk.len() - start);
+        buf[..len].copy_from_slice(&chunk[start..start + len]);
+        Ok(len)
+    }
+
+    /// Returns a reader starting at the beginning of the blob.
+    pub fn reader(self: &Arc<Self>) -> BlobReader {
+        BlobReader {
+            blob: self.clone(),
+            position: 0,
+        }
+    }
+}
+
+/// A cursor over a [`VerifiedBlob`].
+pub struct BlobReader {
+    blob: Arc<VerifiedBlob>,
+    position: u64,
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.blob.read_at(buf, self.position)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for BlobReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.blob.len().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid seek position")
+        })?;
+        Ok(self.position)
+    }
+}
+
 #[derive(Debug, Encode, Decode, Clone, Copy)]
 pub enum HashAlgo {
     Sha256,
     Sha512,
+    Blake2b256,
+}
+
+impl HashAlgo {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+            Self::Blake2b256 => "blake2_256",
+        }
+    }
 }
 
 pub struct HashValue {
@@ -120,6 +723,7 @@ impl FromStr for HashAlgo {
         match s {
             "sha256" => Ok(Self::Sha256),
             "sha512" => Ok(Self::Sha512),
+            "blake2_256" => Ok(Self::Blake2b256),
             _ => Err("Invalid hash algorithm"),
         }
     }
@@ -140,58 +744,227 @@ impl FromStr for HashValue {
 
 impl Display for HashValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}:{}",
-            match self.algo {
-                HashAlgo::Sha256 => "sha256",
-                HashAlgo::Sha512 => "sha512",
-            },
-            hex::encode(&self.hash)
-        )
+        write!(f, "{}:{}", self.algo.name(), hex::encode(&self.hash))
+    }
+}
+
+/// The identifier of a blob.
+///
+/// Either a hash in the form of `<algo>:<hex>`, where algo is one of `sha256`, `sha512` and
+/// `blake2_256`, or an IPFS CID. A CID is verified against the DAG that `ipfs add` builds with
+/// the default settings, or against the whole content for the raw codec.
+pub enum BlobId {
+    Hash(HashValue),
+    Cid(Cid),
+}
+
+impl FromStr for BlobId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.contains(':') {
+            return Ok(Self::Hash(HashValue::from_str(s).map_err(Error::msg)?));
+        }
+        let cid = Cid::try_from(s).context("invalid blob id")?;
+        Ok(Self::Cid(cid))
+    }
+}
+
+impl Display for BlobId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Hash(hash) => hash.fmt(f),
+            Self::Cid(cid) => cid.fmt(f),
+        }
+    }
+}
+
+impl BlobId {
+    /// The file name in the index directory. CIDv0 and the equivalent CIDv1 share the same key.
+    fn index_key(&self) -> String {
+        match self {
+            Self::Hash(hash) => format!("{}-{}", hash.algo.name(), hex::encode(&hash.hash)),
+            Self::Cid(cid) => format!("cid-{}", Cid::new_v1(cid.codec(), *cid.hash())),
+        }
+    }
+
+    fn verify(&self, data: &[u8]) -> bool {
+        match self {
+            Self::Hash(hash) => Hasher::hash(data, hash.algo) == hash.hash,
+            Self::Cid(_) => {
+                let mut hasher = ContentHasher::new();
+                hasher.update(data);
+                hasher.finalize().check(self).is_ok()
+            }
+        }
+    }
+}
+
+/// Computes all the supported IDs of a blob in one pass.
+#[derive(Clone)]
+struct ContentHasher {
+    sha256: sha2::Sha256,
+    sha512: sha2::Sha512,
+    blake2_256: Blake2b256,
+    unixfs_v0: UnixFsBuilder,
+    unixfs_v1: UnixFsBuilder,
+    chunks: ChunkHasher,
+}
+
+struct Digests {
+    sha256: Vec<u8>,
+    sha512: Vec<u8>,
+    blake2_256: Vec<u8>,
+    cid_v0: Cid,
+    cid_v1: Cid,
+    chunk_hashes: ChunkHashes,
+}
+
+impl ContentHasher {
+    fn new() -> Self {
+        Self {
+            sha256: sha2::Sha256::new(),
+            sha512: sha2::Sha512::new(),
+            blake2_256: Blake2b256::new(),
+            unixfs_v0: UnixFsBuilder::new(false),
+            unixfs_v1: UnixFsBuilder::new(true),
+            chunks: ChunkHasher::default(),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.sha256.update(data);
+        self.sha512.update(data);
+        self.blake2_256.update(data);
+        self.unixfs_v0.update(data);
+        self.unixfs_v1.update(data);
+        self.chunks.update(data);
+    }
+
+    fn finalize(self) -> Digests {
+        Digests {
+            sha256: self.sha256.finalize().to_vec(),
+            sha512: self.sha512.finalize().to_vec(),
+            blake2_256: self.blake2_256.finalize().to_vec(),
+            cid_v0: self.unixfs_v0.finalize(),
+            cid_v1: self.unixfs_v1.finalize(),
+            chunk_hashes: self.chunks.finalize(),
+        }
+    }
+}
+
+impl Digests {
+    fn digest(&self, algo: HashAlgo) -> &[u8] {
+        match algo {
+            HashAlgo::Sha256 => &self.sha256,
+            HashAlgo::Sha512 => &self.sha512,
+            HashAlgo::Blake2b256 => &self.blake2_256,
+        }
+    }
+
+    fn ids(&self) -> Vec<BlobId> {
+        let hash = |algo| {
+            BlobId::Hash(HashValue {
+                algo,
+                hash: self.digest(algo).to_vec(),
+            })
+        };
+        vec![
+            hash(HashAlgo::Sha256),
+            hash(HashAlgo::Sha512),
+            hash(HashAlgo::Blake2b256),
+            BlobId::Cid(self.cid_v0),
+            BlobId::Cid(self.cid_v1),
+        ]
+    }
+
+    /// Checks the content against the ID. Returns the ID with the hash filled in if the given
+    /// hash is empty.
+    fn check(&self, id: &BlobId) -> Result<BlobId> {
+        match id {
+            BlobId::Hash(hash) => {
+                let actual = self.digest(hash.algo);
+                if !hash.hash.is_empty() && actual != hash.hash {
+                    bail!(
+                        "blob hash mismatch, actual: {}, expected: {}",
+                        hex_fmt::HexFmt(actual),
+                        hex_fmt::HexFmt(&hash.hash)
+                    );
+                }
+                Ok(BlobId::Hash(HashValue {
+                    algo: hash.algo,
+                    hash: actual.to_vec(),
+                }))
+            }
+            BlobId::Cid(cid) => {
+                let mh = cid.hash();
+                let matched = match cid.codec() {
+                    CODEC_DAG_PB => [self.cid_v0, self.cid_v1].iter().any(|c| c.hash() == mh),
+                    CODEC_RAW => match mh.code() {
+                        MH_SHA2_256 => mh.digest() == self.sha256,
+                        MH_SHA2_512 => mh.digest() == self.sha512,
+                        MH_BLAKE2B_256 => mh.digest() == self.blake2_256,
+                        _ => false,
+                    },
+                    _ => false,
+                };
+                if !matched {
+                    bail!(
+                        "blob cid mismatch, expected: {cid}, actual: {}",
+                        self.cid_v0
+                    );
+                }
+                Ok(BlobId::Cid(*cid))
+            }
+        }
     }
 }
 
 enum Hasher {
     Sha256(sha2::Sha256),
     Sha512(sha2::Sha512),
+    Blake2b256(Blake2b256),
 }
 
 impl Hasher {
+    fn new(hash_algo: HashAlgo) -> Self {
+        match hash_algo {
+            HashAlgo::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgo::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+            HashAlgo::Blake2b256 => Hasher::Blake2b256(Blake2b256::new()),
+        }
+    }
+
     fn update(&mut self, data: &[u8]) {
         match self {
             Self::Sha256(h) => h.update(data),
             Self::Sha512(h) => h.update(data),
+            Self::Blake2b256(h) => h.update(data),
         }
     }
     fn finalize(self) -> Vec<u8> {
         match self {
             Self::Sha256(h) => h.finalize().as_slice().to_vec(),
             Self::Sha512(h) => h.finalize().as_slice().to_vec(),
+            Self::Blake2b256(h) => h.finalize().as_slice().to_vec(),
         }
     }
 
     fn hash(data: &[u8], hash_algo: HashAlgo) -> Vec<u8> {
-        let mut hasher = match hash_algo {
-            HashAlgo::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
-            HashAlgo::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
-        };
+        let mut hasher = Hasher::new(hash_algo);
         hasher.update(data);
         hasher.finalize()
     }
 }
 
-async fn hash_file(path: impl AsRef<Path>, hash_algo: HashAlgo) -> Result<Vec<u8>> {
+async fn digest_file(path: impl AsRef<Path>) -> Result<Digests> {
     let path = path.as_ref();
     let file = tokio::fs::File::open(path)
         .await
         .context("failed to open object file")?;
-    let mut hasher = match hash_algo {
-        HashAlgo::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
-        HashAlgo::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
-    };
+    let mut hasher = ContentHasher::new();
     let mut file = tokio::io::BufReader::new(file);
-    let mut buf = [0; 4096];
+    let mut buf = vec![0; 64 * 1024];
     loop {
         let n = file
             .read(&mut buf)
@@ -205,66 +978,342 @@ async fn hash_file(path: impl AsRef<Path>, hash_algo: HashAlgo) -> Result<Vec<u8
     Ok(hasher.finalize())
 }
 
-pub async fn put_object<'a, R>(
-    path: impl AsRef<Path>,
-    hash: &str,
-    data: &'a mut R,
-) -> Result<String>
-where
-    R: AsyncRead + Unpin + ?Sized,
-{
-    let hash = HashValue::from_str(hash).map_err(Error::msg)?;
-    let path = path.as_ref();
-    let tmpdir = path.join(".tmp");
-    std::fs::create_dir_all(&tmpdir).context("failed to create blobs directory")?;
+/// Verifies the file and moves it into the store, indexed by all of its IDs.
+///
+/// Objects are stored under the hex of the requested hash, or of the sha256 if requested by CID.
+/// If the same content is already stored under another ID, the existing file is reused.
+///
+/// Objects stored before the index existed are only found by the hash they were stored under,
+/// until they are put again, which indexes the existing file.
+///
+/// Returns the ID and the file name of the object.
+fn store_file(
+    store_dir: &Path,
+    file: &Path,
+    id: &BlobId,
+    digests: &Digests,
+) -> Result<(String, String)> {
+    let id = digests.check(id)?;
+    let existing = digests
+        .ids()
+        .iter()
+        .find_map(|id| resolve_path(store_dir, id))
+        .and_then(|path| Some(path.file_name()?.to_str()?.to_string()));
+    let key = match existing {
+        Some(key) => {
+            std::fs::remove_file(file).context("failed to remove duplicated object file")?;
+            key
+        }
+        None => {
+            let key = match &id {
+                BlobId::Hash(hash) => hex::encode(&hash.hash),
+                BlobId::Cid(_) => hex::encode(&digests.sha256),
+            };
+            std::fs::rename(file, store_dir.join(&key))
+                .context("failed to move object file to blobs directory")?;
+            key
+        }
+    };
+    let index_dir = store_dir.join(INDEX_DIR);
+    std::fs::create_dir_all(&index_dir).context("failed to create index directory")?;
+    let aliases: Vec<_> = digests.ids().iter().map(BlobId::index_key).collect();
+    for alias in &aliases {
+        std::fs::write(index_dir.join(alias), &key).context("failed to write index")?;
+    }
+    let aliases_dir = store_dir.join(ALIASES_DIR);
+    std::fs::create_dir_all(&aliases_dir).context("failed to create aliases directory")?;
+    std::fs::write(aliases_dir.join(&key), aliases.join("\n"))
+        .context("failed to write aliases")?;
+    Ok((id.to_string(), key))
+}
 
-    let tmp_filepath = tmpdir.join(uuid::Uuid::new_v4().to_string());
-    let mut tmpfile = tokio::fs::File::create(&tmp_filepath)
-        .await
-        .context("failed to create temporary object file")?;
-    let _guard = scopeguard::guard((), |_| {
-        let _ = std::fs::remove_file(&tmp_filepath);
-    });
-    tokio::io::copy(data, &mut tmpfile)
-        .await
-        .context("failed to write object file")?;
-    tmpfile
-        .sync_all()
-        .await
-        .context("failed to sync object file")?;
-    drop(tmpfile);
-
-    // Make sure the hash of the file is correct
-    let actual_hash = hash_file(&tmp_filepath, hash.algo).await?;
-    if !hash.hash.is_empty() && actual_hash != hash.hash {
-        bail!(
-            "blob hash mismatch, actual: {}, expected: {}",
-            hex_fmt::HexFmt(&actual_hash),
-            hex_fmt::HexFmt(&hash.hash)
-        );
+fn resolve_path(store_dir: &Path, id: &BlobId) -> Option<PathBuf> {
+    if let BlobId::Hash(hash) = id {
+        let path = store_dir.join(hex::encode(&hash.hash));
+        if path.is_file() {
+            return Some(path);
+        }
+    }
+    let key = std::fs::read_to_string(store_dir.join(INDEX_DIR).join(id.index_key())).ok()?;
+    hex::decode(&key).ok()?;
+    let path = store_dir.join(key);
+    path.is_file().then_some(path)
+}
+
+/// Removes the index entries pointing to the object.
+fn prune_index(store_dir: &Path, key: &str) -> Result<()> {
+    let index_dir = store_dir.join(INDEX_DIR);
+    let aliases_path = store_dir.join(ALIASES_DIR).join(key);
+    let aliases = match std::fs::read_to_string(&aliases_path) {
+        Ok(aliases) => aliases,
+        // Stored under its hash only, without index entries.
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context("failed to read aliases"),
+    };
+    for alias in aliases.lines() {
+        let path = index_dir.join(alias);
+        if std::fs::read_to_string(&path).is_ok_and(|target| target == key) {
+            std::fs::remove_file(path)?;
+        }
     }
-    let key = hex::encode(&actual_hash);
-    std::fs::rename(&tmp_filepath, path.join(key))
-        .context("failed to move object file to blobs directory")?;
-    let mut hash = hash;
-    hash.hash = actual_hash;
-    Ok(hash.to_string())
+    std::fs::remove_file(aliases_path).context("failed to remove aliases")
 }
 
-pub fn get_object(blobs_dir: impl AsRef<Path>, hash: &str) -> Result<Option<Vec<u8>>> {
-    let hash = HashValue::from_str(hash).map_err(Error::msg)?;
-    let result = std::fs::read(blobs_dir.as_ref().join(hex::encode(&hash.hash)));
-    let data = match result {
+pub fn get_object(blobs_dir: impl AsRef<Path>, id: &str) -> Result<Option<Vec<u8>>> {
+    let id = BlobId::from_str(id)?;
+    let Some(path) = resolve_path(blobs_dir.as_ref(), &id) else {
+        return Ok(None);
+    };
+    let data = match std::fs::read(path) {
         Ok(data) => data,
         Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
         Err(err) => return Err(err).context("failed to read object file"),
     };
-    let actual_hash = Hasher::hash(&data, hash.algo);
-    if actual_hash != hash.hash {
-        bail!(
-            "blob hash mismatch, actual hash is {}",
-            hex_fmt::HexFmt(actual_hash)
-        );
+    if !id.verify(&data) {
+        bail!("blob {id} is corrupted");
     }
     Ok(Some(data))
 }
+
+/// A loader over a fresh temporary directory, returned along with it for cleanup.
+#[cfg(test)]
+pub(crate) fn test_loader() -> (BlobLoader, PathBuf) {
+    let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    std::fs::create_dir_all(&dir).unwrap();
+    (BlobLoader::new(&dir), dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"hello world\n";
+    const SHA256: &str = "sha256:a948904f2f0f479b8f8197694b30184b0d2ed1c1cd2a1ec0fb85d299a192a447";
+
+    fn index_entries(dir: &Path) -> usize {
+        std::fs::read_dir(dir.join(INDEX_DIR)).unwrap().count()
+    }
+
+    #[tokio::test]
+    async fn removing_an_object_prunes_its_index() {
+        let (loader, dir) = test_loader();
+        loader.put(SHA256, &mut &DATA[..]).await.unwrap();
+        loader.put("sha256:", &mut &b"other"[..]).await.unwrap();
+        assert_eq!(index_entries(&dir), 10);
+        let cid = "QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o";
+        assert_eq!(loader.get(cid).unwrap().unwrap(), DATA);
+
+        loader.remove(cid).unwrap();
+        assert!(!loader.exists(SHA256));
+        assert_eq!(index_entries(&dir), 5);
+        assert!(!dir.join(ALIASES_DIR).join(&SHA256[7..]).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn opened_blobs_are_verified_on_read() {
+        let (loader, dir) = test_loader();
+        let data: Vec<u8> = (0..VERIFY_CHUNK_SIZE * 2 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let id = loader.put("sha256:", &mut &data[..]).await.unwrap();
+        let blob = loader.open(&id).unwrap().unwrap();
+        assert_eq!(blob.len(), data.len() as u64);
+        let mut buf = vec![0; 300];
+        let offset = VERIFY_CHUNK_SIZE - 100;
+        // Reads stop at the chunk boundaries.
+        assert_eq!(blob.read_at(&mut buf, offset).unwrap(), 100);
+        assert_eq!(&buf[..100], &data[offset as usize..][..100]);
+        let mut reader = blob.reader();
+        reader.seek(SeekFrom::End(-50)).unwrap();
+        let mut tail = vec![];
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, &data[data.len() - 50..]);
+
+        // Changed by the host after the blob was opened.
+        let path = loader.path_of(&id).unwrap();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_at(b"x", VERIFY_CHUNK_SIZE + 10).unwrap();
+        assert!(blob.read_at(&mut buf, VERIFY_CHUNK_SIZE).is_err());
+        // The cached chunks were verified before.
+        assert!(blob.read_at(&mut buf, offset).is_ok());
+        // So do the later opens, which reuse the hashes recorded when the blob was stored.
+        let mut content = vec![];
+        let mut reader = loader.open(&id).unwrap().unwrap().reader();
+        assert!(reader.read_to_end(&mut content).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrupted_blobs_can_not_be_opened() {
+        let (loader, dir) = test_loader();
+        loader.put(SHA256, &mut &DATA[..]).await.unwrap();
+        std::fs::write(loader.path_of(SHA256).unwrap(), b"hello world!").unwrap();
+        // Opened against the hashes recorded when stored, the reads fail.
+        let mut content = vec![];
+        let mut reader = loader.open(SHA256).unwrap().unwrap().reader();
+        assert!(reader.read_to_end(&mut content).is_err());
+        // After a restart, the blob is verified when opened.
+        assert!(BlobLoader::new(&dir).open(SHA256).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn chunk_hashes_are_recorded_when_stored() {
+        let (loader, dir) = test_loader();
+        let data: Vec<u8> = (0..VERIFY_CHUNK_SIZE * 2 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let upload = loader.begin_upload("sha256:", data.len() as u64).unwrap();
+        // Appended in pieces that don't line up with the chunks.
+        for piece in data.chunks(100_000) {
+            let offset = loader.upload_status(&upload.id).unwrap().offset;
+            loader
+                .append_upload(&upload.id, offset, &mut &piece[..])
+                .await
+                .unwrap();
+        }
+        let id = loader.commit_upload(&upload.id).unwrap();
+        let blob = loader.open(&id).unwrap().unwrap();
+        assert_eq!(blob.verified_on_open(), 0);
+        assert_eq!(blob.len(), data.len() as u64);
+
+        // A restarted worker verifies the blob once, to the same hashes.
+        let restarted = BlobLoader::new(&dir);
+        let verified = restarted.open(&id).unwrap().unwrap();
+        assert_eq!(verified.verified_on_open(), data.len() as u64);
+        assert_eq!(verified.chunk_hashes.hashes, blob.chunk_hashes.hashes);
+        assert_eq!(verified.chunk_hashes.hashes.len(), 3);
+        assert_eq!(restarted.open(&id).unwrap().unwrap().verified_on_open(), 0);
+
+        // The hashes are forgotten with the blob.
+        let key = loader.resolve_key(&id).unwrap();
+        loader.remove(&id).unwrap();
+        assert!(loader
+            .state
+            .chunk_hashes
+            .lock()
+            .unwrap()
+            .get(&key)
+            .is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn upload_in_chunks() {
+        let (loader, dir) = test_loader();
+        let upload = loader.begin_upload(SHA256, DATA.len() as u64).unwrap();
+        let status = loader
+            .append_upload(&upload.id, 0, &mut &DATA[..5])
+            .await
+            .unwrap();
+        assert_eq!(status.offset, 5);
+        let status = loader
+            .append_upload(&upload.id, 5, &mut &DATA[5..])
+            .await
+            .unwrap();
+        assert_eq!(status.offset, DATA.len() as u64);
+        assert_eq!(loader.commit_upload(&upload.id).unwrap(), SHA256);
+        assert_eq!(loader.get(SHA256).unwrap().unwrap(), DATA);
+        assert!(loader.upload_status(&upload.id).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn upload_resumes_at_the_offset() {
+        let (loader, dir) = test_loader();
+        let upload = loader.begin_upload(SHA256, DATA.len() as u64).unwrap();
+        loader
+            .append_upload(&upload.id, 0, &mut &DATA[..5])
+            .await
+            .unwrap();
+        // A chunk at a wrong offset is rejected without changing the progress.
+        assert!(loader
+            .append_upload(&upload.id, 3, &mut &DATA[3..])
+            .await
+            .is_err());
+        // So is a chunk failing in the middle, which leaves a partial tail in the file.
+        let too_long = [&DATA[5..], b"trailing"].concat();
+        assert!(loader
+            .append_upload(&upload.id, 5, &mut &too_long[..])
+            .await
+            .is_err());
+        assert_eq!(loader.upload_status(&upload.id).unwrap().offset, 5);
+        loader
+            .append_upload(&upload.id, 5, &mut &DATA[5..])
+            .await
+            .unwrap();
+        loader.commit_upload(&upload.id).unwrap();
+        assert_eq!(loader.get(SHA256).unwrap().unwrap(), DATA);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn upload_with_wrong_hash_is_not_committed() {
+        let (loader, dir) = test_loader();
+        let upload = loader.begin_upload(SHA256, 5).unwrap();
+        loader
+            .append_upload(&upload.id, 0, &mut &DATA[..5])
+            .await
+            .unwrap();
+        assert!(loader.commit_upload(&upload.id).is_err());
+        assert!(!loader.exists(SHA256));
+        // The upload is kept so that it can be aborted.
+        loader.abort_upload(&upload.id).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn incomplete_upload_is_not_committed() {
+        let (loader, dir) = test_loader();
+        let upload = loader.begin_upload(SHA256, DATA.len() as u64).unwrap();
+        loader
+            .append_upload(&upload.id, 0, &mut &DATA[..5])
+            .await
+            .unwrap();
+        assert!(loader.commit_upload(&upload.id).is_err());
+        assert_eq!(loader.upload_status(&upload.id).unwrap().offset, 5);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn aborted_upload_is_removed() {
+        let (loader, dir) = test_loader();
+        let upload = loader.begin_upload(SHA256, DATA.len() as u64).unwrap();
+        loader.abort_upload(&upload.id).unwrap();
+        assert!(!loader.uploads_dir().join(&upload.id).exists());
+        assert!(loader
+            .append_upload(&upload.id, 0, &mut &DATA[..])
+            .await
+            .is_err());
+        assert!(loader.abort_upload(&upload.id).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn idle_uploads_are_cleaned_up() {
+        let (loader, dir) = test_loader();
+        let active = loader.begin_upload(SHA256, DATA.len() as u64).unwrap();
+        let idle = loader.begin_upload(SHA256, DATA.len() as u64).unwrap();
+        // Left over by a previous run.
+        std::fs::write(loader.uploads_dir().join("stale"), DATA).unwrap();
+        assert_eq!(
+            loader.cleanup_uploads(Duration::from_secs(3600)).unwrap(),
+            0
+        );
+
+        std::thread::sleep(Duration::from_millis(200));
+        loader
+            .append_upload(&active.id, 0, &mut &DATA[..1])
+            .await
+            .unwrap();
+        assert_eq!(
+            loader.cleanup_uploads(Duration::from_millis(100)).unwrap(),
+            2
+        );
+        assert!(loader.upload_status(&active.id).is_ok());
+        assert!(loader.upload_status(&idle.id).is_err());
+        assert!(!loader.uploads_dir().join("stale").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -2,6 +2,9 @@ pub(crate) mod async_context;
 pub(crate) mod metrics;
 pub(crate) mod vm_context;
 
+pub mod blob_fs;
 pub mod blobs;
 mod resource;
+pub mod scratch_fs;
 mod tls;
+mod unixfs;
@@ -1,20 +1,26 @@
-use sni_tls_listener::Subscription as SniSubscription;
+use scale::Encode;
+use sni_tls_listener::{
+    PrefixedStream, ProxyProtocol, RoutedSubscription, Subscription as SniSubscription,
+};
 use std::future::Future;
-use std::io::ErrorKind;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::task::Poll::*;
+use std::task::{Context, Poll, Poll::*};
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::oneshot::Sender;
 use tokio::time::Sleep;
 use tokio_rustls::rustls::ServerConfig;
-use tracing::error;
-use wapo_env::{OcallError, Result};
+use tracing::{debug, error, warn};
+use wapo_env::{OcallError, Result, SeekWhence};
 use Resource::*;
 
 use super::async_context::{get_task_cx, poll_in_task_cx, GuestWaker};
+use super::blobs::BlobReader;
 use super::metrics::Meter;
 use super::tls::TlsStream;
 
@@ -24,21 +30,100 @@ pub struct PollContext {
 }
 
 pub struct TcpListenerResource {
-    pub listener: TcpListener,
+    pub listener: TcpIncoming,
     pub tls_config: Option<Arc<ServerConfig>>,
 }
 
+/// Where a guest TCP listener takes its connections from.
+pub enum TcpIncoming {
+    Listener(TcpListener),
+    /// Connections with the PROXY protocol header of the trusted upstreams already read, by a
+    /// task accepting from the listener.
+    Proxied(Receiver<(PrefixedStream, SocketAddr)>),
+}
+
+impl TcpIncoming {
+    pub fn new(listener: TcpListener, proxy_protocol: ProxyProtocol) -> Self {
+        if !proxy_protocol.is_enabled() {
+            return Self::Listener(listener);
+        }
+        let (tx, rx) = tokio::sync::mpsc::channel(32);
+        tokio::spawn(accept_proxied(listener, proxy_protocol, tx));
+        Self::Proxied(rx)
+    }
+
+    pub fn poll_accept(
+        &mut self,
+        cx: &mut Context,
+    ) -> Poll<std::io::Result<(PrefixedStream, SocketAddr)>> {
+        match self {
+            Self::Listener(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, addr)| (stream.into(), addr)),
+            Self::Proxied(rx) => rx
+                .poll_recv(cx)
+                .map(|accepted| accepted.ok_or_else(|| ErrorKind::BrokenPipe.into())),
+        }
+    }
+}
+
+async fn accept_proxied(
+    listener: TcpListener,
+    proxy_protocol: ProxyProtocol,
+    tx: tokio::sync::mpsc::Sender<(PrefixedStream, SocketAddr)>,
+) {
+    const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+    loop {
+        let accepted = tokio::select! {
+            _ = tx.closed() => break,
+            accepted = listener.accept() => accepted,
+        };
+        let (stream, peer_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!(target: "wapo", "failed to accept tcp connection: {err}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let tx = tx.clone();
+        let proxy_protocol = proxy_protocol.clone();
+        tokio::spawn(async move {
+            let mut stream = PrefixedStream::from(stream);
+            let proxied = tokio::time::timeout(
+                HEADER_TIMEOUT,
+                proxy_protocol.remote_addr(&mut stream, peer_addr),
+            )
+            .await;
+            match proxied {
+                Ok(Ok(remote_addr)) => {
+                    _ = tx.send((stream, remote_addr)).await;
+                }
+                Ok(Err(err)) => {
+                    debug!(target: "wapo", ?peer_addr, "failed to read proxy header: {err}");
+                }
+                Err(_) => {
+                    debug!(target: "wapo", ?peer_addr, "timeout reading proxy header");
+                }
+            }
+        });
+    }
+}
+
 pub enum Resource {
     Sleep(Pin<Box<Sleep>>),
     ChannelRx(Receiver<Vec<u8>>),
     OneshotTx(Option<Sender<Result<Vec<u8>, String>>>),
     TcpListener(Box<TcpListenerResource>),
-    TcpStream(Box<TcpStream>),
+    TcpStream(Box<PrefixedStream>),
     TlsStream(Box<TlsStream>),
     TcpConnect(Pin<Box<dyn Future<Output = std::io::Result<TcpStream>> + Send>>),
     TlsConnect(Pin<Box<dyn Future<Output = std::io::Result<TlsStream>> + Send>>),
     DuplexStream(DuplexStream),
     SniSubscription(Box<SniSubscription>),
+    RoutedSubscription(Box<RoutedSubscription>),
+    AppQuery(Pin<Box<dyn Future<Output = Result<Vec<u8>, String>> + Send>>),
+    Blob(Box<BlobReader>),
 }
 
 impl Resource {
@@ -55,6 +140,10 @@ impl Resource {
                     Pending => Err(OcallError::Pending),
                 }
             }
+            AppQuery(fut) => match poll_in_task_cx(waker, fut.as_mut()) {
+                Ready(reply) => Ok(reply.encode()),
+                Pending => Err(OcallError::Pending),
+            },
             _ => Err(OcallError::UnsupportedOperation),
         }
     }
@@ -68,7 +157,7 @@ impl Resource {
                     Pending => Err(OcallError::Pending),
                     Ready(Ok(stream)) => {
                         ctx.meter.record_tcp_connect_done();
-                        Ok(Resource::TcpStream(Box::new(stream)))
+                        Ok(Resource::TcpStream(Box::new(stream.into())))
                     }
                     Ready(Err(err)) => {
                         error!("tcp connect error: {}", err);
@@ -117,27 +206,30 @@ impl Resource {
                 Ready(_) => Ok(0),
                 Pending => Err(OcallError::Pending),
             },
-            TcpStream(stream) => loop {
-                match stream.try_read(buf) {
-                    Ok(sz) => {
-                        ctx.meter.record_net_ingress(sz as _);
-                        break Ok(sz as _);
-                    }
-                    Err(err) => {
-                        if err.kind() == ErrorKind::WouldBlock {
-                            match get_task_cx(ctx.waker.clone(), |cx| stream.poll_read_ready(cx)) {
-                                Pending => break Err(OcallError::Pending),
-                                Ready(Err(_err)) => break Err(OcallError::IoError),
-                                Ready(Ok(())) => continue,
-                            }
-                        } else {
-                            break Err(OcallError::IoError);
-                        }
-                    }
-                }
-            },
+            TcpStream(stream) => stream_poll_read(stream, ctx, buf),
             TlsStream(stream) => stream_poll_read(stream, ctx, buf),
             DuplexStream(stream) => stream_poll_read(stream, ctx, buf),
+            // Blobs are local files, so read them synchronously like `blob_get` does.
+            Blob(file) => {
+                let read_sz = file.read(buf).or(Err(OcallError::IoError))?;
+                ctx.meter.record_storage_read(read_sz as _);
+                ctx.meter.record_gas(read_sz as u64 / 128);
+                Ok(read_sz as _)
+            }
+            _ => Err(OcallError::UnsupportedOperation),
+        }
+    }
+
+    pub(crate) fn seek(&mut self, whence: SeekWhence, offset: i64) -> Result<u64> {
+        let pos = match whence {
+            SeekWhence::Start => {
+                SeekFrom::Start(offset.try_into().or(Err(OcallError::InvalidParameter))?)
+            }
+            SeekWhence::End => SeekFrom::End(offset),
+            SeekWhence::Current => SeekFrom::Current(offset),
+        };
+        match self {
+            Blob(file) => file.seek(pos).or(Err(OcallError::InvalidParameter)),
             _ => Err(OcallError::UnsupportedOperation),
         }
     }
@@ -159,25 +251,7 @@ impl Resource {
             }
         }
         match self {
-            TcpStream(stream) => loop {
-                match stream.try_write(buf) {
-                    Ok(sz) => {
-                        ctx.meter.record_net_egress(sz as _);
-                        break Ok(sz as _);
-                    }
-                    Err(err) => {
-                        if err.kind() == ErrorKind::WouldBlock {
-                            match get_task_cx(ctx.waker.clone(), |cx| stream.poll_write_ready(cx)) {
-                                Pending => break Err(OcallError::Pending),
-                                Ready(Err(_err)) => break Err(OcallError::IoError),
-                                Ready(Ok(())) => continue,
-                            }
-                        } else {
-                            break Err(OcallError::IoError);
-                        }
-                    }
-                }
-            },
+            TcpStream(stream) => stream_poll_write(stream, ctx, buf),
             TlsStream(stream) => stream_poll_write(stream, ctx, buf),
             DuplexStream(stream) => stream_poll_write(stream, ctx, buf),
             _ => Err(OcallError::UnsupportedOperation),
@@ -0,0 +1,1105 @@
+//! A writable scratch directory, preopened in the guest's WASI context.
+//!
+//! Files are stored on the host encrypted with a key derived from the app's secret, so neither
+//! the contents nor the names are readable outside of the worker. Each file starts with a header
+//! sealing a random file id to the file name, followed by blocks of up to `BLOCK_SIZE` bytes that
+//! are sealed independently with AES-GCM, bound to the file id, the block index and whether it is
+//! the last block. An empty file has a single empty block, so cutting or extending a file on the
+//! host is detected, as is moving blocks between files or files between names. The host can
+//! still put back an earlier version of a block or of a whole file. Names are encrypted
+//! deterministically so that lookups don't need to scan the directory.
+//!
+//! The total size of the files is limited by a quota. Writes beyond the quota fail with ENOSPC.
+//!
+//! Instances opening the same persistent directory at the same time share its state, so that the
+//! quota and the known file sizes hold across all of them.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{IoSlice, IoSliceMut, SeekFrom};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+use aes_gcm::{aead::AeadInPlace, AeadCore, Aes256Gcm, KeyInit};
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use tracing::warn;
+use wasi_common::dir::{OpenResult, ReaddirCursor, ReaddirEntity, WasiDir};
+use wasi_common::file::{FdFlags, FileType, Filestat, OFlags, WasiFile};
+use wasi_common::{Error, ErrorExt};
+
+use super::metrics::Meter;
+
+/// The derive_secret path of the key that encrypts the scratch directory.
+pub(crate) const SCRATCH_SECRET_PATH: &[u8] = b"wapo/scratch";
+
+const BLOCK_SIZE: u64 = 4096;
+const FILE_ID_LEN: u64 = 16;
+const NONCE_LEN: u64 = 12;
+const TAG_LEN: u64 = 16;
+const HEADER_LEN: u64 = NONCE_LEN + FILE_ID_LEN + TAG_LEN;
+const SEALED_BLOCK_SIZE: u64 = NONCE_LEN + BLOCK_SIZE + TAG_LEN;
+const MAX_NAME_LEN: usize = 96;
+const ENOSPC: i32 = 28;
+const ZEROS: [u8; BLOCK_SIZE as usize] = [0; BLOCK_SIZE as usize];
+
+/// The persistent scratch directories open by running instances, by host path.
+static OPEN_DIRS: Lazy<Mutex<HashMap<PathBuf, Weak<ScratchFs>>>> = Lazy::new(Default::default);
+
+/// Configuration of the scratch directory of an instance.
+#[derive(Debug, Clone)]
+pub struct ScratchConfig {
+    /// The host directory to store the files in.
+    pub dir: PathBuf,
+    /// The absolute path the directory is preopened at in the guest.
+    pub guest_path: String,
+    /// Maximum total size of the files in bytes.
+    pub quota: u64,
+    /// Start with an empty directory and remove it when the instance exits.
+    pub ephemeral: bool,
+}
+
+/// Opens the scratch directory, returning its root to be preopened at `config.guest_path`.
+pub(crate) fn open(
+    config: &ScratchConfig,
+    secret: &[u8; 64],
+    meter: Arc<Meter>,
+) -> Result<Box<dyn WasiDir>> {
+    let fs = if config.ephemeral {
+        let root = config.dir.join(uuid::Uuid::new_v4().to_string());
+        Arc::new(ScratchFs::open(root, config, secret)?)
+    } else {
+        let mut open_dirs = OPEN_DIRS.lock().unwrap();
+        open_dirs.retain(|_, fs| fs.strong_count() > 0);
+        match open_dirs.get(&config.dir).and_then(Weak::upgrade) {
+            Some(fs) => fs,
+            None => {
+                let fs = Arc::new(ScratchFs::open(config.dir.clone(), config, secret)?);
+                open_dirs.insert(config.dir.clone(), Arc::downgrade(&fs));
+                fs
+            }
+        }
+    };
+    Ok(Box::new(ScratchDir {
+        fs,
+        path: vec![],
+        meter,
+    }))
+}
+
+fn disk_usage(dir: &Path) -> std::io::Result<u64> {
+    let mut total = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            total += disk_usage(&entry.path())?;
+        } else {
+            total += logical_size(metadata.len());
+        }
+    }
+    Ok(total)
+}
+
+/// The plaintext size of a file given its size on disk.
+fn logical_size(disk_len: u64) -> u64 {
+    let body = disk_len.saturating_sub(HEADER_LEN);
+    let full_blocks = body / SEALED_BLOCK_SIZE;
+    let rest = body % SEALED_BLOCK_SIZE;
+    full_blocks * BLOCK_SIZE + rest.saturating_sub(NONCE_LEN + TAG_LEN)
+}
+
+/// The size on disk of a file given its plaintext size.
+fn disk_len(size: u64) -> u64 {
+    let blocks = (last_block(size) + 1) * (NONCE_LEN + TAG_LEN);
+    HEADER_LEN + size + blocks
+}
+
+/// The index of the last block of a file of the given size.
+fn last_block(size: u64) -> u64 {
+    size.saturating_sub(1) / BLOCK_SIZE
+}
+
+struct ScratchFs {
+    root: PathBuf,
+    ephemeral: bool,
+    cipher: Aes256Gcm,
+    name_cipher: Aes256Gcm,
+    name_key: [u8; 32],
+    quota: u64,
+    used: Mutex<u64>,
+    /// The sizes of the files opened so far by file id, which are trusted over the file lengths
+    /// on the host.
+    sizes: Mutex<HashMap<[u8; 16], u64>>,
+}
+
+impl Drop for ScratchFs {
+    fn drop(&mut self) {
+        if self.ephemeral {
+            if let Err(err) = std::fs::remove_dir_all(&self.root) {
+                warn!(target: "wapo", "failed to remove the scratch directory: {err}");
+            }
+        }
+    }
+}
+
+impl ScratchFs {
+    fn open(root: PathBuf, config: &ScratchConfig, secret: &[u8; 64]) -> Result<Self> {
+        std::fs::create_dir_all(&root).context("failed to create the scratch directory")?;
+        let used = disk_usage(&root).context("failed to compute the scratch directory usage")?;
+        Ok(Self {
+            root,
+            ephemeral: config.ephemeral,
+            cipher: Aes256Gcm::new_from_slice(&secret[..32]).expect("invalid key"),
+            name_cipher: Aes256Gcm::new_from_slice(&secret[32..]).expect("invalid key"),
+            name_key: secret[32..].try_into().expect("invalid key"),
+            quota: config.quota,
+            used: Mutex::new(used),
+            sizes: Default::default(),
+        })
+    }
+
+    fn reserve(&self, bytes: u64) -> Result<(), Error> {
+        let mut used = self.used.lock().unwrap();
+        match used.checked_add(bytes) {
+            Some(total) if total <= self.quota => {
+                *used = total;
+                Ok(())
+            }
+            _ => Err(std::io::Error::from_raw_os_error(ENOSPC).into()),
+        }
+    }
+
+    fn release(&self, bytes: u64) {
+        let mut used = self.used.lock().unwrap();
+        *used = used.saturating_sub(bytes);
+    }
+
+    fn encrypt_name(&self, name: &str) -> Result<String, Error> {
+        if name.len() > MAX_NAME_LEN {
+            return Err(Error::name_too_long());
+        }
+        // The nonce is derived from the name so that the same name always maps to the same
+        // file on the host.
+        let digest = Sha256::new()
+            .chain_update(self.name_key)
+            .chain_update(name)
+            .finalize();
+        let nonce = &digest[..NONCE_LEN as usize];
+        let mut buffer = name.as_bytes().to_vec();
+        self.name_cipher
+            .encrypt_in_place(nonce.into(), b"", &mut buffer)
+            .or(Err(Error::io()))?;
+        Ok(format!("{}{}", hex::encode(nonce), hex::encode(buffer)))
+    }
+
+    fn decrypt_name(&self, encoded: &str) -> Option<String> {
+        let mut buffer = hex::decode(encoded).ok()?;
+        if buffer.len() < (NONCE_LEN + TAG_LEN) as usize {
+            return None;
+        }
+        let mut data = buffer.split_off(NONCE_LEN as usize);
+        self.name_cipher
+            .decrypt_in_place(buffer.as_slice().into(), b"", &mut data)
+            .ok()?;
+        String::from_utf8(data).ok()
+    }
+
+    fn host_path(&self, parts: &[String]) -> Result<PathBuf, Error> {
+        let mut path = self.root.clone();
+        for part in parts {
+            path.push(self.encrypt_name(part)?);
+        }
+        Ok(path)
+    }
+
+    fn seal(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = Aes256Gcm::generate_nonce(&mut rand::thread_rng());
+        let mut buffer = data.to_vec();
+        self.cipher
+            .encrypt_in_place(&nonce, aad, &mut buffer)
+            .or(Err(Error::io()))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&buffer);
+        Ok(sealed)
+    }
+
+    fn unseal(&self, aad: &[u8], mut sealed: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if sealed.len() < (NONCE_LEN + TAG_LEN) as usize {
+            return Err(Error::io());
+        }
+        let mut data = sealed.split_off(NONCE_LEN as usize);
+        self.cipher
+            .decrypt_in_place(sealed.as_slice().into(), aad, &mut data)
+            .or(Err(Error::io()))?;
+        Ok(data)
+    }
+
+    /// Writes the header binding the file id to the file name.
+    fn write_header(&self, file: &File, file_id: &[u8; 16], name: &str) -> Result<(), Error> {
+        let header = self.seal(&header_aad(name), file_id)?;
+        file.write_all_at(&header, 0)?;
+        Ok(())
+    }
+
+    fn read_header(&self, file: &File, name: &str) -> Result<[u8; 16], Error> {
+        let mut header = vec![0u8; HEADER_LEN as usize];
+        file.read_exact_at(&mut header, 0).or(Err(Error::io()))?;
+        let file_id = self.unseal(&header_aad(name), header)?;
+        file_id.try_into().or(Err(Error::io()))
+    }
+
+    /// Moves the header of a file to its new name.
+    fn rename_header(&self, path: &Path, from: &str, to: &str) -> Result<(), Error> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let file_id = self.read_header(&file, from)?;
+        self.write_header(&file, &file_id, to)
+    }
+}
+
+fn header_aad(name: &str) -> Vec<u8> {
+    [b"header:", name.as_bytes()].concat()
+}
+
+fn block_aad(file_id: &[u8; 16], index: u64, last: bool) -> [u8; 25] {
+    let mut aad = [0u8; 25];
+    aad[..16].copy_from_slice(file_id);
+    aad[16..24].copy_from_slice(&index.to_le_bytes());
+    aad[24] = last as u8;
+    aad
+}
+
+fn filestat(metadata: &Metadata) -> Filestat {
+    let (filetype, size) = if metadata.is_dir() {
+        (FileType::Directory, 0)
+    } else {
+        (FileType::RegularFile, logical_size(metadata.len()))
+    };
+    Filestat {
+        device_id: 0,
+        inode: metadata.ino(),
+        filetype,
+        nlink: metadata.nlink(),
+        size,
+        atim: metadata.accessed().ok(),
+        mtim: metadata.modified().ok(),
+        ctim: None,
+    }
+}
+
+struct ScratchDir {
+    fs: Arc<ScratchFs>,
+    path: Vec<String>,
+    meter: Arc<Meter>,
+}
+
+impl ScratchDir {
+    /// Resolves a path relative to this directory into its components from the root.
+    fn resolve(&self, path: &str) -> Result<Vec<String>, Error> {
+        let mut full = self.path.clone();
+        let base_len = full.len();
+        for component in Path::new(path).components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if full.len() == base_len {
+                        return Err(Error::perm());
+                    }
+                    full.pop();
+                }
+                Component::Normal(part) => full.push(
+                    part.to_str()
+                        .ok_or_else(Error::illegal_byte_sequence)?
+                        .into(),
+                ),
+                Component::RootDir | Component::Prefix(_) => return Err(Error::perm()),
+            }
+        }
+        Ok(full)
+    }
+
+    fn host_path_of(&self, path: &str) -> Result<PathBuf, Error> {
+        self.fs.host_path(&self.resolve(path)?)
+    }
+}
+
+#[wiggle::async_trait]
+impl WasiDir for ScratchDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn open_file(
+        &self,
+        _symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<OpenResult, Error> {
+        let parts = self.resolve(path)?;
+        let host_path = self.fs.host_path(&parts)?;
+        let exclusive = oflags.contains(OFlags::CREATE | OFlags::EXCLUSIVE);
+        let existing = match std::fs::metadata(&host_path) {
+            Ok(metadata) => Some(metadata),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        match &existing {
+            Some(_) if exclusive => return Err(Error::exist()),
+            Some(metadata) if metadata.is_dir() => {
+                if write || oflags.contains(OFlags::TRUNCATE) {
+                    return Err(Error::perm());
+                }
+                return Ok(OpenResult::Dir(Box::new(ScratchDir {
+                    fs: self.fs.clone(),
+                    path: parts,
+                    meter: self.meter.clone(),
+                })));
+            }
+            Some(_) if oflags.contains(OFlags::DIRECTORY) => return Err(Error::not_dir()),
+            None if !oflags.contains(OFlags::CREATE) || oflags.contains(OFlags::DIRECTORY) => {
+                return Err(Error::not_found());
+            }
+            _ => {}
+        }
+        let name = parts.last().ok_or_else(Error::perm)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(existing.is_none())
+            .open(&host_path)?;
+        let file = match &existing {
+            None => {
+                let file_id: [u8; 16] = rand::random();
+                self.fs.write_header(&file, &file_id, name)?;
+                let file = ScratchFile::new(self, file, file_id, read, write, fdflags);
+                file.write_block(0, &[], true)?;
+                file.set_size(0);
+                file
+            }
+            Some(metadata) => {
+                let file_id = self.fs.read_header(&file, name)?;
+                let file = ScratchFile::new(self, file, file_id, read, write, fdflags);
+                if file.known_size().is_none() {
+                    // Checks that the last block is where the length on the host says.
+                    let size = logical_size(metadata.len());
+                    file.read_block(last_block(size), size)?;
+                    file.set_size(size);
+                }
+                if write && oflags.contains(OFlags::TRUNCATE) {
+                    file.truncate(0)?;
+                }
+                file
+            }
+        };
+        Ok(OpenResult::File(Box::new(file)))
+    }
+
+    async fn create_dir(&self, path: &str) -> Result<(), Error> {
+        std::fs::create_dir(self.host_path_of(path)?)?;
+        Ok(())
+    }
+
+    async fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        let host_path = self.fs.host_path(&self.path)?;
+        let inode = std::fs::metadata(&host_path)?.ino();
+        let parent_inode = match self.path.split_last() {
+            Some((_, parent)) => std::fs::metadata(self.fs.host_path(parent)?)?.ino(),
+            None => inode,
+        };
+        let mut children = vec![];
+        for entry in std::fs::read_dir(&host_path)? {
+            let entry = entry?;
+            let Some(name) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| self.fs.decrypt_name(name))
+            else {
+                continue;
+            };
+            let metadata = entry.metadata()?;
+            let filetype = if metadata.is_dir() {
+                FileType::Directory
+            } else {
+                FileType::RegularFile
+            };
+            children.push((name, metadata.ino(), filetype));
+        }
+        children.sort_by(|a, b| a.0.cmp(&b.0));
+        let dots = [
+            (".".to_string(), inode, FileType::Directory),
+            ("..".to_string(), parent_inode, FileType::Directory),
+        ];
+        let items: Vec<_> = dots
+            .into_iter()
+            .chain(children)
+            .enumerate()
+            .skip(u64::from(cursor) as usize)
+            .map(|(i, (name, inode, filetype))| {
+                Ok(ReaddirEntity {
+                    next: ReaddirCursor::from(i as u64 + 1),
+                    inode,
+                    name,
+                    filetype,
+                })
+            })
+            .collect();
+        Ok(Box::new(items.into_iter()))
+    }
+
+    async fn remove_dir(&self, path: &str) -> Result<(), Error> {
+        std::fs::remove_dir(self.host_path_of(path)?)?;
+        Ok(())
+    }
+
+    async fn unlink_file(&self, path: &str) -> Result<(), Error> {
+        let host_path = self.host_path_of(path)?;
+        let metadata = std::fs::metadata(&host_path)?;
+        if metadata.is_dir() {
+            return Err(Error::perm());
+        }
+        std::fs::remove_file(&host_path)?;
+        self.fs.release(logical_size(metadata.len()));
+        Ok(())
+    }
+
+    async fn rename(
+        &self,
+        path: &str,
+        dest_dir: &dyn WasiDir,
+        dest_path: &str,
+    ) -> Result<(), Error> {
+        let dest_dir = dest_dir
+            .as_any()
+            .downcast_ref::<ScratchDir>()
+            .ok_or_else(Error::not_supported)?;
+        if !Arc::ptr_eq(&self.fs, &dest_dir.fs) {
+            return Err(Error::not_supported());
+        }
+        let from_parts = self.resolve(path)?;
+        let to_parts = dest_dir.resolve(dest_path)?;
+        let (Some(from_name), Some(to_name)) = (from_parts.last(), to_parts.last()) else {
+            return Err(Error::perm());
+        };
+        let from = self.fs.host_path(&from_parts)?;
+        let to = self.fs.host_path(&to_parts)?;
+        let replaced = std::fs::metadata(&to)
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| logical_size(metadata.len()));
+        std::fs::rename(&from, &to)?;
+        if let Some(size) = replaced {
+            self.fs.release(size);
+        }
+        // Blocks are bound to the file id, so only the header changes along with the name.
+        if std::fs::metadata(&to)?.is_file() {
+            self.fs.rename_header(&to, from_name, to_name)?;
+        }
+        Ok(())
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        let metadata = std::fs::metadata(self.fs.host_path(&self.path)?)?;
+        Ok(filestat(&metadata))
+    }
+
+    async fn get_path_filestat(
+        &self,
+        path: &str,
+        _follow_symlinks: bool,
+    ) -> Result<Filestat, Error> {
+        let metadata = std::fs::metadata(self.host_path_of(path)?)?;
+        Ok(filestat(&metadata))
+    }
+}
+
+struct ScratchFile {
+    fs: Arc<ScratchFs>,
+    meter: Arc<Meter>,
+    file: File,
+    file_id: [u8; 16],
+    read: bool,
+    write: bool,
+    fdflags: FdFlags,
+    position: Mutex<u64>,
+}
+
+impl ScratchFile {
+    fn new(
+        dir: &ScratchDir,
+        file: File,
+        file_id: [u8; 16],
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Self {
+        Self {
+            fs: dir.fs.clone(),
+            meter: dir.meter.clone(),
+            file,
+            file_id,
+            read,
+            write,
+            fdflags,
+            position: Mutex::new(0),
+        }
+    }
+
+    fn known_size(&self) -> Option<u64> {
+        self.fs.sizes.lock().unwrap().get(&self.file_id).copied()
+    }
+
+    fn set_size(&self, size: u64) {
+        self.fs.sizes.lock().unwrap().insert(self.file_id, size);
+    }
+
+    fn size(&self) -> Result<u64, Error> {
+        self.known_size().ok_or_else(Error::io)
+    }
+
+    fn block_offset(index: u64) -> u64 {
+        HEADER_LEN + index * SEALED_BLOCK_SIZE
+    }
+
+    /// Reads and decrypts a block of a file of the given size. Blocks after the last one are
+    /// empty.
+    fn read_block(&self, index: u64, size: u64) -> Result<Vec<u8>, Error> {
+        let last = last_block(size);
+        if index > last {
+            return Ok(vec![]);
+        }
+        let len = (size - index * BLOCK_SIZE).min(BLOCK_SIZE);
+        let mut sealed = vec![0u8; (NONCE_LEN + len + TAG_LEN) as usize];
+        self.file
+            .read_exact_at(&mut sealed, Self::block_offset(index))
+            .or(Err(Error::io()))?;
+        self.fs
+            .unseal(&block_aad(&self.file_id, index, index == last), sealed)
+    }
+
+    fn write_block(&self, index: u64, data: &[u8], last: bool) -> Result<(), Error> {
+        let sealed = self.fs.seal(&block_aad(&self.file_id, index, last), data)?;
+        self.file.write_all_at(&sealed, Self::block_offset(index))?;
+        Ok(())
+    }
+
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> Result<usize, Error> {
+        let size = self.size()?;
+        let mut read = 0;
+        while read < buf.len() {
+            let offset = pos + read as u64;
+            if offset >= size {
+                break;
+            }
+            let block = self.read_block(offset / BLOCK_SIZE, size)?;
+            let in_block = (offset % BLOCK_SIZE) as usize;
+            let len = (block.len() - in_block).min(buf.len() - read);
+            buf[read..read + len].copy_from_slice(&block[in_block..in_block + len]);
+            read += len;
+        }
+        self.meter.record_storage_read(read as u64);
+        Ok(read)
+    }
+
+    /// Writes data at a position not beyond the end of a file of the given size, returning the
+    /// new size.
+    fn write_blocks(&self, data: &[u8], pos: u64, size: u64) -> Result<u64, Error> {
+        if data.is_empty() {
+            return Ok(size);
+        }
+        let end = pos + data.len() as u64;
+        let new_size = size.max(end);
+        let old_last = last_block(size);
+        let new_last = last_block(new_size);
+        if old_last < new_last && old_last < pos / BLOCK_SIZE {
+            // No longer the last block, and not rewritten below.
+            let block = self.read_block(old_last, size)?;
+            self.write_block(old_last, &block, false)?;
+        }
+        let mut written = 0;
+        while written < data.len() {
+            let offset = pos + written as u64;
+            let index = offset / BLOCK_SIZE;
+            let in_block = (offset % BLOCK_SIZE) as usize;
+            let len = (BLOCK_SIZE as usize - in_block).min(data.len() - written);
+            let mut block = self.read_block(index, size)?;
+            if block.len() < in_block + len {
+                block.resize(in_block + len, 0);
+            }
+            block[in_block..in_block + len].copy_from_slice(&data[written..written + len]);
+            self.write_block(index, &block, index == new_last)?;
+            written += len;
+        }
+        self.set_size(new_size);
+        Ok(new_size)
+    }
+
+    /// Extends a file of the given size with zeros up to `end`.
+    fn fill_zeros(&self, mut size: u64, end: u64) -> Result<u64, Error> {
+        while size < end {
+            let len = (BLOCK_SIZE - size % BLOCK_SIZE).min(end - size);
+            size = self.write_blocks(&ZEROS[..len as usize], size, size)?;
+        }
+        Ok(size)
+    }
+
+    fn write_at(&self, data: &[u8], pos: u64) -> Result<u64, Error> {
+        if !self.write {
+            return Err(Error::badf());
+        }
+        let size = self.size()?;
+        let end = pos
+            .checked_add(data.len() as u64)
+            .ok_or_else(Error::overflow)?;
+        let grow = end.saturating_sub(size);
+        self.fs.reserve(grow)?;
+        let result = self
+            .fill_zeros(size, pos)
+            .and_then(|size| self.write_blocks(data, pos, size));
+        if result.is_err() {
+            let actual = self.size().unwrap_or(size);
+            self.fs
+                .release(grow - actual.saturating_sub(size).min(grow));
+        }
+        result?;
+        self.meter.record_storage_written(data.len() as u64);
+        Ok(data.len() as u64)
+    }
+
+    fn truncate(&self, new_size: u64) -> Result<(), Error> {
+        if !self.write {
+            return Err(Error::badf());
+        }
+        let size = self.size()?;
+        if new_size > size {
+            self.fs.reserve(new_size - size)?;
+            if let Err(err) = self.fill_zeros(size, new_size) {
+                let actual = self.size().unwrap_or(size);
+                self.fs.release(new_size - actual.clamp(size, new_size));
+                return Err(err);
+            }
+        } else if new_size < size {
+            // The block the file now ends in becomes the last one.
+            let index = last_block(new_size);
+            let mut block = self.read_block(index, size)?;
+            block.truncate((new_size - index * BLOCK_SIZE) as usize);
+            self.write_block(index, &block, true)?;
+            self.file.set_len(disk_len(new_size))?;
+            self.set_size(new_size);
+            self.fs.release(size - new_size);
+        }
+        Ok(())
+    }
+
+    fn read_vectored_at(&self, bufs: &mut [IoSliceMut<'_>], mut pos: u64) -> Result<u64, Error> {
+        if !self.read {
+            return Err(Error::badf());
+        }
+        let mut total = 0;
+        for buf in bufs.iter_mut() {
+            let want = buf.len();
+            let read = self.read_at(buf, pos)?;
+            total += read as u64;
+            pos += read as u64;
+            if read < want {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    fn write_vectored_at(&self, bufs: &[IoSlice<'_>], mut pos: u64) -> Result<u64, Error> {
+        let mut total = 0;
+        for buf in bufs {
+            let written = self.write_at(buf, pos)?;
+            total += written;
+            pos += written;
+        }
+        Ok(total)
+    }
+}
+
+#[wiggle::async_trait]
+impl WasiFile for ScratchFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn get_filetype(&self) -> Result<FileType, Error> {
+        Ok(FileType::RegularFile)
+    }
+
+    async fn get_fdflags(&self) -> Result<FdFlags, Error> {
+        Ok(self.fdflags)
+    }
+
+    async fn set_fdflags(&mut self, fdflags: FdFlags) -> Result<(), Error> {
+        self.fdflags = fdflags;
+        Ok(())
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        Ok(Filestat {
+            size: self.size()?,
+            ..filestat(&self.file.metadata()?)
+        })
+    }
+
+    async fn set_filestat_size(&self, size: u64) -> Result<(), Error> {
+        self.truncate(size)
+    }
+
+    async fn datasync(&self) -> Result<(), Error> {
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    async fn sync(&self) -> Result<(), Error> {
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    async fn read_vectored<'a>(&self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        let mut position = self.position.lock().unwrap();
+        let read = self.read_vectored_at(bufs, *position)?;
+        *position += read;
+        Ok(read)
+    }
+
+    async fn read_vectored_at<'a>(
+        &self,
+        bufs: &mut [IoSliceMut<'a>],
+        offset: u64,
+    ) -> Result<u64, Error> {
+        ScratchFile::read_vectored_at(self, bufs, offset)
+    }
+
+    async fn write_vectored<'a>(&self, bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
+        let mut position = self.position.lock().unwrap();
+        if self.fdflags.contains(FdFlags::APPEND) {
+            *position = self.size()?;
+        }
+        let written = self.write_vectored_at(bufs, *position)?;
+        *position += written;
+        Ok(written)
+    }
+
+    async fn write_vectored_at<'a>(&self, bufs: &[IoSlice<'a>], offset: u64) -> Result<u64, Error> {
+        ScratchFile::write_vectored_at(self, bufs, offset)
+    }
+
+    async fn seek(&self, pos: SeekFrom) -> Result<u64, Error> {
+        let mut position = self.position.lock().unwrap();
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size()?.checked_add_signed(offset),
+            SeekFrom::Current(offset) => position.checked_add_signed(offset),
+        };
+        *position = new_position.ok_or_else(Error::invalid_argument)?;
+        Ok(*position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasi_common::snapshots::preview_1::types::Errno;
+
+    struct TestDir {
+        root: Box<dyn WasiDir>,
+        host_dir: PathBuf,
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.host_dir);
+        }
+    }
+
+    impl TestDir {
+        fn new(quota: u64) -> Self {
+            let host_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+            let config = ScratchConfig {
+                dir: host_dir.clone(),
+                guest_path: "/scratch".into(),
+                quota,
+                ephemeral: false,
+            };
+            let root = open(&config, &[7; 64], Default::default()).unwrap();
+            Self { root, host_dir }
+        }
+
+        fn fs(&self) -> &ScratchFs {
+            &self.root.as_any().downcast_ref::<ScratchDir>().unwrap().fs
+        }
+
+        fn host_path(&self, path: &str) -> PathBuf {
+            let parts: Vec<_> = path.split('/').map(String::from).collect();
+            self.fs().host_path(&parts).unwrap()
+        }
+
+        async fn open(&self, path: &str, oflags: OFlags) -> Result<Box<dyn WasiFile>, Error> {
+            let opened = self
+                .root
+                .open_file(false, path, oflags, true, true, FdFlags::empty())
+                .await?;
+            match opened {
+                OpenResult::File(file) => Ok(file),
+                OpenResult::Dir(_) => panic!("not a file: {path}"),
+            }
+        }
+
+        async fn create(&self, path: &str) -> Box<dyn WasiFile> {
+            self.open(path, OFlags::CREATE).await.unwrap()
+        }
+    }
+
+    async fn write(file: &dyn WasiFile, data: &[u8]) -> Result<u64, Error> {
+        file.write_vectored(&[IoSlice::new(data)]).await
+    }
+
+    async fn read_at(file: &dyn WasiFile, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+        let mut buf = vec![0; len];
+        let n = file
+            .read_vectored_at(&mut [IoSliceMut::new(&mut buf)], offset)
+            .await?;
+        buf.truncate(n as usize);
+        Ok(buf)
+    }
+
+    async fn read_all(file: &dyn WasiFile) -> Result<Vec<u8>, Error> {
+        read_at(file, 0, 1024 * 1024).await
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn reads_and_writes_across_blocks() {
+        let dir = TestDir::new(1024 * 1024);
+        let data = pattern(BLOCK_SIZE as usize * 2 + 100);
+        let file = dir.create("file").await;
+        write(&*file, &data).await.unwrap();
+        assert_eq!(file.get_filestat().await.unwrap().size, data.len() as u64);
+        assert_eq!(
+            std::fs::metadata(dir.host_path("file")).unwrap().len(),
+            disk_len(data.len() as u64)
+        );
+        let at = BLOCK_SIZE - 10;
+        assert_eq!(
+            read_at(&*file, at, 20).await.unwrap(),
+            &data[at as usize..][..20]
+        );
+
+        // Overwrite across a block boundary, then write past the end leaving a hole.
+        file.seek(SeekFrom::Start(at)).await.unwrap();
+        write(&*file, &[0xff; 20]).await.unwrap();
+        let end = data.len() as u64 + BLOCK_SIZE;
+        file.seek(SeekFrom::Start(end)).await.unwrap();
+        write(&*file, b"tail").await.unwrap();
+        drop(file);
+
+        let mut expected = data.clone();
+        expected[at as usize..][..20].fill(0xff);
+        expected.resize(end as usize, 0);
+        expected.extend_from_slice(b"tail");
+        let file = dir.open("file", OFlags::empty()).await.unwrap();
+        assert_eq!(read_all(&*file).await.unwrap(), expected);
+        assert_eq!(file.seek(SeekFrom::End(-4)).await.unwrap(), end);
+        assert_eq!(read_at(&*file, end, 100).await.unwrap(), b"tail");
+    }
+
+    #[tokio::test]
+    async fn truncates_and_extends() {
+        let dir = TestDir::new(1024 * 1024);
+        let data = pattern(BLOCK_SIZE as usize * 3);
+        let file = dir.create("file").await;
+        write(&*file, &data).await.unwrap();
+        for size in [BLOCK_SIZE * 2 + 5, BLOCK_SIZE, 3, 0] {
+            file.set_filestat_size(size).await.unwrap();
+            assert_eq!(read_all(&*file).await.unwrap(), &data[..size as usize]);
+            let reopened = dir.open("file", OFlags::empty()).await.unwrap();
+            assert_eq!(read_all(&*reopened).await.unwrap(), &data[..size as usize]);
+        }
+        file.set_filestat_size(BLOCK_SIZE + 1).await.unwrap();
+        assert_eq!(
+            read_all(&*file).await.unwrap(),
+            vec![0; BLOCK_SIZE as usize + 1]
+        );
+
+        let truncated = dir.open("file", OFlags::TRUNCATE).await.unwrap();
+        assert_eq!(truncated.get_filestat().await.unwrap().size, 0);
+        assert_eq!(*dir.fs().used.lock().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn writes_beyond_the_quota_fail() {
+        let dir = TestDir::new(BLOCK_SIZE * 2);
+        let file = dir.create("a").await;
+        write(&*file, &pattern(BLOCK_SIZE as usize * 2))
+            .await
+            .unwrap();
+        let other = dir.create("b").await;
+        let err = write(&*other, b"x").await.unwrap_err();
+        assert_eq!(err.downcast().unwrap(), Errno::Nospc);
+        assert!(other.set_filestat_size(1).await.is_err());
+
+        dir.root.unlink_file("a").await.unwrap();
+        write(&*other, b"x").await.unwrap();
+        assert_eq!(*dir.fs().used.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn instances_share_the_directory() {
+        let dir = TestDir::new(BLOCK_SIZE);
+        let config = ScratchConfig {
+            dir: dir.host_dir.clone(),
+            guest_path: "/scratch".into(),
+            quota: BLOCK_SIZE,
+            ephemeral: false,
+        };
+        let other = open(&config, &[7; 64], Default::default()).unwrap();
+        let other_fs = &other.as_any().downcast_ref::<ScratchDir>().unwrap().fs;
+        assert!(std::ptr::eq(dir.fs(), &**other_fs));
+
+        write(&*dir.create("a").await, &pattern(BLOCK_SIZE as usize))
+            .await
+            .unwrap();
+        let opened = other
+            .open_file(false, "b", OFlags::CREATE, true, true, FdFlags::empty())
+            .await
+            .unwrap();
+        let OpenResult::File(file) = opened else {
+            panic!("not a file");
+        };
+        let err = write(&*file, b"x").await.unwrap_err();
+        assert_eq!(err.downcast().unwrap(), Errno::Nospc);
+    }
+
+    #[tokio::test]
+    async fn names_are_encrypted() {
+        let dir = TestDir::new(1024 * 1024);
+        let fs = dir.fs();
+        let encrypted = fs.encrypt_name("hello.txt").unwrap();
+        assert!(!encrypted.contains("hello"));
+        assert_eq!(fs.encrypt_name("hello.txt").unwrap(), encrypted);
+        assert_eq!(fs.decrypt_name(&encrypted).unwrap(), "hello.txt");
+        assert!(fs.decrypt_name(&encrypted[2..]).is_none());
+        assert!(fs.encrypt_name(&"x".repeat(MAX_NAME_LEN + 1)).is_err());
+
+        dir.root.create_dir("sub").await.unwrap();
+        dir.create("sub/hello.txt").await;
+        let host_names: Vec<_> = std::fs::read_dir(dir.host_path("sub"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        assert_eq!(host_names, [encrypted]);
+        let opened = dir
+            .root
+            .open_file(
+                false,
+                "sub",
+                OFlags::DIRECTORY,
+                true,
+                false,
+                FdFlags::empty(),
+            )
+            .await
+            .unwrap();
+        let OpenResult::Dir(sub) = opened else {
+            panic!("not a directory");
+        };
+        let names: Vec<_> = sub
+            .readdir(ReaddirCursor::from(0))
+            .await
+            .unwrap()
+            .map(|entry| entry.unwrap().name)
+            .collect();
+        assert_eq!(names, [".", "..", "hello.txt"]);
+    }
+
+    #[tokio::test]
+    async fn renamed_files_keep_their_contents() {
+        let dir = TestDir::new(1024 * 1024);
+        let file = dir.create("a").await;
+        write(&*file, b"moved").await.unwrap();
+        dir.root.create_dir("sub").await.unwrap();
+        dir.root.rename("a", &*dir.root, "sub/b").await.unwrap();
+        let file = dir.open("sub/b", OFlags::empty()).await.unwrap();
+        assert_eq!(read_all(&*file).await.unwrap(), b"moved");
+    }
+
+    /// Opens the file with a new [`ScratchFs`], which hasn't seen its size yet.
+    async fn reopen(dir: &TestDir, path: &str) -> Result<Vec<u8>, Error> {
+        let config = ScratchConfig {
+            dir: dir.host_dir.clone(),
+            guest_path: "/scratch".into(),
+            quota: 1024 * 1024,
+            ephemeral: false,
+        };
+        let root = ScratchDir {
+            fs: Arc::new(ScratchFs::open(dir.host_dir.clone(), &config, &[7; 64]).unwrap()),
+            path: vec![],
+            meter: Default::default(),
+        };
+        let file = root
+            .open_file(false, path, OFlags::empty(), true, false, FdFlags::empty())
+            .await?;
+        let OpenResult::File(file) = file else {
+            panic!("not a file: {path}");
+        };
+        read_all(&*file).await
+    }
+
+    #[tokio::test]
+    async fn changes_on_the_host_are_detected() {
+        let dir = TestDir::new(1024 * 1024);
+        let data = pattern(BLOCK_SIZE as usize * 2);
+        for name in ["a", "b", "c"] {
+            write(&*dir.create(name).await, &data).await.unwrap();
+        }
+        let (a, b, c) = (dir.host_path("a"), dir.host_path("b"), dir.host_path("c"));
+        assert_eq!(reopen(&dir, "a").await.unwrap(), data);
+
+        // Cut at a block boundary.
+        let file = OpenOptions::new().write(true).open(&a).unwrap();
+        file.set_len(disk_len(BLOCK_SIZE)).unwrap();
+        assert!(reopen(&dir, "a").await.is_err());
+        // The worker trusts its own idea of the size over the host's.
+        let opened = dir.open("a", OFlags::empty()).await.unwrap();
+        assert!(read_all(&*opened).await.is_err());
+
+        // Extended with a block of another file.
+        let mut extended = std::fs::read(&b).unwrap();
+        let block = std::fs::read(&c).unwrap()[sealed_block(1)].to_vec();
+        extended.extend_from_slice(&block);
+        std::fs::write(&b, &extended).unwrap();
+        assert!(reopen(&dir, "b").await.is_err());
+
+        // Swapped names.
+        std::fs::rename(&c, &a).unwrap();
+        assert!(reopen(&dir, "a").await.is_err());
+
+        // An empty file cut to the header.
+        dir.create("empty").await;
+        let empty = dir.host_path("empty");
+        assert_eq!(reopen(&dir, "empty").await.unwrap(), b"");
+        let file = OpenOptions::new().write(true).open(&empty).unwrap();
+        file.set_len(HEADER_LEN).unwrap();
+        assert!(reopen(&dir, "empty").await.is_err());
+    }
+
+    fn sealed_block(index: u64) -> std::ops::Range<usize> {
+        let start = ScratchFile::block_offset(index) as usize;
+        start..start + SEALED_BLOCK_SIZE as usize
+    }
+}
@@ -7,6 +7,7 @@ use std::task::{Context, Poll};
 use futures::ready;
 use once_cell::sync::Lazy;
 use rustls_pemfile::Item;
+use sni_tls_listener::PrefixedStream;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
 use tokio_rustls::{
@@ -23,8 +24,8 @@ use wapo_env::tls::TlsServerConfig;
 use wapo_env::OcallError;
 
 pub enum TlsStream {
-    ServerHandshaking(Accept<TcpStream>),
-    ServerStreaming(ServerTlsStream<TcpStream>),
+    ServerHandshaking(Accept<PrefixedStream>),
+    ServerStreaming(ServerTlsStream<PrefixedStream>),
     ClientHandshaking(Connect<TcpStream>),
     ClientStreaming(ClientTlsStream<TcpStream>),
     Closed,
@@ -36,8 +37,8 @@ impl From<ClientTlsStream<TcpStream>> for TlsStream {
     }
 }
 
-impl From<ServerTlsStream<TcpStream>> for TlsStream {
-    fn from(stream: ServerTlsStream<TcpStream>) -> Self {
+impl From<ServerTlsStream<PrefixedStream>> for TlsStream {
+    fn from(stream: ServerTlsStream<PrefixedStream>) -> Self {
         TlsStream::ServerStreaming(stream)
     }
 }
@@ -55,7 +56,7 @@ fn default_client_config() -> Arc<ClientConfig> {
 }
 
 impl TlsStream {
-    pub(crate) fn accept(stream: TcpStream, config: Arc<ServerConfig>) -> TlsStream {
+    pub(crate) fn accept(stream: PrefixedStream, config: Arc<ServerConfig>) -> TlsStream {
         let accept = TlsAcceptor::from(config).accept(stream);
         TlsStream::ServerHandshaking(accept)
     }
@@ -65,6 +66,14 @@ impl TlsStream {
         let connector = TlsConnector::from(client_config);
         TlsStream::ClientHandshaking(connector.connect(domain, stream))
     }
+
+    /// The SNI server name the client requested, once the server handshake is done.
+    pub(crate) fn server_name(&self) -> Option<&str> {
+        match self {
+            TlsStream::ServerStreaming(stream) => stream.get_ref().1.server_name(),
+            _ => None,
+        }
+    }
 }
 
 impl AsyncRead for TlsStream {
@@ -210,6 +219,7 @@ impl AsyncWrite for TlsStream {
 pub(crate) fn load_tls_config(config: TlsServerConfig) -> Result<ServerConfig, OcallError> {
     let (cert_pem, key_pem) = match &config {
         TlsServerConfig::V0 { cert, key } => (cert, key),
+        TlsServerConfig::Acme => return Err(OcallError::InvalidParameter),
     };
 
     let certs = load_certs(cert_pem)?;
@@ -1,18 +1,22 @@
 use std::{
     borrow::Cow,
     collections::VecDeque,
-    fmt, io,
+    fmt,
+    future::Future,
+    io,
     net::SocketAddr,
     ops::RangeInclusive,
-    path::PathBuf,
+    pin::Pin,
     sync::{Arc, Mutex},
     task::Poll::{Pending, Ready},
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use aes_gcm::{aead::AeadMutInPlace, AeadCore as _, Aes256Gcm, KeyInit as _};
 use anyhow::Context;
-use sni_tls_listener::{wrap_certified_key, Agent, Generate};
+use sni_tls_listener::{
+    normalize_names, wrap_certified_key, Agent, Generate, ProxyProtocol, RoutedAgent,
+};
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::oneshot::Sender as OneshotSender,
@@ -33,7 +37,7 @@ use wasmtime::Caller;
 use super::{
     async_context::{get_task_cx, poll_in_task_cx, set_task_env, GuestWaker},
     metrics::Meter,
-    resource::{PollContext, Resource, ResourceTable, TcpListenerResource},
+    resource::{PollContext, Resource, ResourceTable, TcpIncoming, TcpListenerResource},
     tls::{load_tls_config, TlsStream},
 };
 use crate::{blobs::BlobLoader, IncomingHttpRequest, Metrics, VmId};
@@ -103,8 +107,34 @@ pub trait RuntimeCalls: Send + 'static {
     fn query_listened(&self);
     fn try_lock(&self, path: &str) -> bool;
     fn unlock(&self, path: &str) -> bool;
+    fn app_query(
+        &self,
+        _address: [u8; 32],
+        _path: String,
+        _payload: Vec<u8>,
+    ) -> Option<AppQueryFuture> {
+        None
+    }
+    fn subscribe(&self, _topic: &str, _tx: Sender<Vec<u8>>) -> bool {
+        false
+    }
+    fn unsubscribe(&self, _topic: &str, _tx: &Sender<Vec<u8>>) -> bool {
+        false
+    }
+    fn publish(&self, _topic: &str, _payload: &[u8]) -> bool {
+        false
+    }
+    fn trusted_now(&self) -> Option<(SystemTime, Duration)> {
+        None
+    }
+    /// Decrypts the value of a secret environment variable when building the WASI context.
+    fn decrypt_env(&self, _encrypted: &[u8]) -> Option<String> {
+        None
+    }
 }
 
+pub type AppQueryFuture = Pin<Box<dyn Future<Output = Result<Vec<u8>, String>> + Send>>;
+
 impl RuntimeCalls for () {
     fn worker_pubkey(&self) -> [u8; 32] {
         [0; 32]
@@ -140,6 +170,12 @@ impl RuntimeCalls for () {
 pub struct WapoVmConfig {
     pub tcp_listen_port_range: RangeInclusive<u16>,
     pub sni_tls_listener: Option<Agent>,
+    #[builder(default)]
+    pub http_listener: Option<RoutedAgent>,
+    #[builder(default)]
+    pub tls_passthrough_listener: Option<RoutedAgent>,
+    #[builder(default)]
+    pub proxy_protocol: ProxyProtocol,
 }
 
 pub(crate) struct WapoCtx {
@@ -149,6 +185,7 @@ pub(crate) struct WapoCtx {
     ocall_trace_enabled: bool,
     query_tx: Option<Sender<Vec<u8>>>,
     http_connect_tx: Option<Sender<Vec<u8>>>,
+    topic_tx: Option<Sender<Vec<u8>>>,
     awake_tasks: Arc<TaskSet>,
     weight: u32,
     runtime_calls: Box<dyn RuntimeCalls>,
@@ -162,7 +199,7 @@ impl WapoCtx {
     pub(crate) fn new<OCalls>(
         id: VmId,
         runtime_calls: OCalls,
-        blobs_dir: PathBuf,
+        blob_loader: BlobLoader,
         meter: Option<Arc<Meter>>,
         config: WapoVmConfig,
     ) -> Self
@@ -176,12 +213,13 @@ impl WapoCtx {
             ocall_trace_enabled: false,
             query_tx: None,
             http_connect_tx: None,
+            topic_tx: None,
             awake_tasks: Arc::new(TaskSet::with_task0()),
             weight: 1,
             runtime_calls: Box::new(runtime_calls),
             _counter: Default::default(),
             meter: meter.unwrap_or_default(),
-            blob_loader: BlobLoader::new(blobs_dir),
+            blob_loader,
             config,
         }
     }
@@ -211,6 +249,27 @@ impl WapoCtx {
     pub fn meter(&self) -> Arc<Meter> {
         self.meter.clone()
     }
+
+    pub(crate) fn blob_loader(&self) -> &BlobLoader {
+        &self.blob_loader
+    }
+
+    /// Subscribes to the connections routed to the names without being terminated.
+    fn listen_routed(&mut self, names: &str, listener: Option<RoutedAgent>) -> Result<i32> {
+        self.meter.record_gas(1000);
+        let listener = listener.ok_or(OcallError::Forbiden)?;
+        let names = normalize_names(names).map_err(|e| {
+            warn!(target: "wapo::tls", "invalid server names: {e}");
+            OcallError::InvalidParameter
+        })?;
+        let subscription = listener.subscribe(&names, ()).map_err(|e| {
+            warn!(target: "wapo::tls", "failed to subscribe routed connection: {e}");
+            OcallError::InvalidParameter
+        })?;
+        self.meter.record_gas(10000);
+        self.resources
+            .push(Resource::RoutedSubscription(Box::new(subscription)))
+    }
 }
 
 impl env::OcallEnv for WapoCtx {
@@ -262,6 +321,11 @@ impl env::OcallFuncs for WapoCtx {
         self.resources.push(res)
     }
 
+    fn seek(&mut self, resource_id: i32, whence: env::SeekWhence, offset: i64) -> Result<u64> {
+        self.meter.record_gas(200);
+        self.resources.get_mut(resource_id)?.seek(whence, offset)
+    }
+
     fn mark_task_ready(&mut self, task_id: i32) -> Result<()> {
         self.meter.record_gas(200);
         self.awake_tasks.push_task(task_id);
@@ -310,6 +374,7 @@ impl env::OcallFuncs for WapoCtx {
             .or(Err(OcallError::IoError))?;
         let listener = TcpListener::from_std(std_listener).or(Err(OcallError::IoError))?;
         let tls_config = tls_config.map(load_tls_config).transpose()?.map(Arc::new);
+        let listener = TcpIncoming::new(listener, self.config.proxy_protocol.clone());
         self.resources
             .push(Resource::TcpListener(Box::new(TcpListenerResource {
                 listener,
@@ -358,6 +423,17 @@ impl env::OcallFuncs for WapoCtx {
                     let res = Resource::TlsStream(Box::new(TlsStream::ServerStreaming(stream)));
                     (res, addr)
                 }
+                Resource::RoutedSubscription(ref mut res) => {
+                    let fut = res.next();
+                    futures::pin_mut!(fut);
+                    let (stream, addr) = match poll_in_task_cx(waker, fut) {
+                        Ready(Some(data)) => (data.stream, data.remote_addr),
+                        Ready(None) => return Err(OcallError::EndOfFile),
+                        Pending => return Err(OcallError::Pending),
+                    };
+                    ctx.meter.record_net_ingress(128);
+                    (Resource::TcpStream(Box::new(stream)), addr)
+                }
                 _ => return Err(OcallError::UnsupportedOperation),
             }
         };
@@ -409,33 +485,60 @@ impl env::OcallFuncs for WapoCtx {
 
     fn tls_listen_sni(&mut self, sni: Cow<str>, config: TlsServerConfig) -> Result<i32> {
         self.meter.record_gas(1000);
-        let (cert, key) = match config {
-            TlsServerConfig::V0 { cert, key } => (cert, key),
-        };
         let listener = self
             .config
             .sni_tls_listener
             .as_ref()
             .ok_or(OcallError::Forbiden)?;
         let listener = listener.clone();
+        let sni = normalize_names(&sni).map_err(|e| {
+            warn!(target: "wapo::tls", "invalid SNI server names: {e}");
+            OcallError::InvalidParameter
+        })?;
         let subscription = {
-            let certified_key =
-                wrap_certified_key(cert.as_bytes(), key.as_bytes()).map_err(|e| {
-                    warn!(target: "wapo::tls", "failed to wrap certified key: {e}");
-                    OcallError::InvalidParameter
-                })?;
-            listener
-                .subscribe(sni.as_ref(), certified_key)
-                .map_err(|e| {
-                    warn!(target: "wapo::tls", "failed to subscribe TLS connection: {e}");
+            let certified_key = match config {
+                TlsServerConfig::V0 { cert, key } => {
+                    wrap_certified_key(cert.as_bytes(), key.as_bytes()).map_err(|e| {
+                        warn!(target: "wapo::tls", "failed to wrap certified key: {e}");
+                        OcallError::InvalidParameter
+                    })?
+                }
+                TlsServerConfig::Acme => listener.acme_key(&sni).map_err(|e| {
+                    warn!(target: "wapo::tls", "failed to get ACME key: {e}");
                     OcallError::InvalidParameter
-                })?
+                })?,
+            };
+            listener.subscribe(&sni, certified_key).map_err(|e| {
+                warn!(target: "wapo::tls", "failed to subscribe TLS connection: {e}");
+                OcallError::InvalidParameter
+            })?
         };
         self.meter.record_gas(10000);
         self.resources
             .push(Resource::SniSubscription(Box::new(subscription)))
     }
 
+    fn http_listen_host(&mut self, host: Cow<str>) -> Result<i32> {
+        let listener = self.config.http_listener.clone();
+        self.listen_routed(&host, listener)
+    }
+
+    fn tls_listen_sni_passthrough(&mut self, sni: Cow<str>) -> Result<i32> {
+        let listener = self.config.tls_passthrough_listener.clone();
+        self.listen_routed(&sni, listener)
+    }
+
+    fn tls_server_name(&mut self, resource_id: i32) -> Result<String> {
+        self.meter.record_gas(100);
+        match self.resources.get_mut(resource_id)? {
+            Resource::TlsStream(stream) => stream
+                .server_name()
+                .map(Into::into)
+                .ok_or(OcallError::NotFound),
+            _ => Err(OcallError::UnsupportedOperation),
+        }
+    }
+
     fn log(&mut self, level: log::Level, message: &str) -> Result<()> {
         self.meter.record_gas(message.as_bytes().len() as u64);
         self.runtime_calls.log(level, message);
@@ -512,6 +615,7 @@ impl env::OcallFuncs for WapoCtx {
                 ret
             }
             HttpRequest => create_channel!(self.http_connect_tx),
+            TopicMessage => create_channel!(self.topic_tx),
         }
     }
 
@@ -538,6 +642,28 @@ impl env::OcallFuncs for WapoCtx {
         Ok(obj)
     }
 
+    fn blob_open(&mut self, hash: &str) -> Result<i32> {
+        self.meter.record_gas(200);
+        let file = self
+            .blob_loader
+            .open(hash)
+            .or(Err(OcallError::IoError))?
+            .ok_or(OcallError::NotFound)?;
+        self.meter.record_gas(file.verified_on_open() / 128);
+        self.resources.push(Resource::Blob(Box::new(file.reader())))
+    }
+
+    fn blob_size(&mut self, hash: &str) -> Result<u64> {
+        self.meter.record_gas(100);
+        let file = self
+            .blob_loader
+            .open(hash)
+            .or(Err(OcallError::IoError))?
+            .ok_or(OcallError::NotFound)?;
+        self.meter.record_gas(file.verified_on_open() / 128);
+        Ok(file.len())
+    }
+
     fn sign(&mut self, data: &[u8]) -> Result<Vec<u8>> {
         if data.len() > 1024 * 1024 * 8 {
             self.meter.record_gas(100);
@@ -623,8 +749,71 @@ impl env::OcallFuncs for WapoCtx {
             Err(OcallError::ConditionError)
         }
     }
+
+    fn app_query(&mut self, address: [u8; 32], path: String, payload: Vec<u8>) -> Result<i32> {
+        self.meter
+            .record_gas(1000 + (path.len() + payload.len()) as u64 / 128);
+        if address == self.id {
+            return Err(OcallError::InvalidParameter);
+        }
+        let fut = self
+            .runtime_calls
+            .app_query(address, path, payload)
+            .ok_or(OcallError::UnsupportedOperation)?;
+        self.resources.push(Resource::AppQuery(fut))
+    }
+
+    fn subscribe(&mut self, topic: &str) -> Result<()> {
+        self.meter.record_gas(1000);
+        if topic.is_empty() || topic.len() > MAX_TOPIC_LEN {
+            return Err(OcallError::InvalidParameter);
+        }
+        let tx = self.topic_tx.clone().ok_or(OcallError::ConditionError)?;
+        if self.runtime_calls.subscribe(topic, tx) {
+            Ok(())
+        } else {
+            Err(OcallError::ResourceLimited)
+        }
+    }
+
+    fn unsubscribe(&mut self, topic: &str) -> Result<()> {
+        self.meter.record_gas(500);
+        let tx = self.topic_tx.as_ref().ok_or(OcallError::ConditionError)?;
+        if self.runtime_calls.unsubscribe(topic, tx) {
+            Ok(())
+        } else {
+            Err(OcallError::NotFound)
+        }
+    }
+
+    fn publish(&mut self, topic: &str, data: &[u8]) -> Result<()> {
+        self.meter.record_gas(1000 + data.len() as u64 / 128);
+        if topic.is_empty() || topic.len() > MAX_TOPIC_LEN || data.len() > MAX_TOPIC_MESSAGE_SIZE {
+            return Err(OcallError::InvalidParameter);
+        }
+        if self.runtime_calls.publish(topic, data) {
+            Ok(())
+        } else {
+            Err(OcallError::UnsupportedOperation)
+        }
+    }
+
+    fn trusted_now(&mut self) -> Result<(u64, u64)> {
+        self.meter.record_gas(100);
+        let (now, uncertainty) = self
+            .runtime_calls
+            .trusted_now()
+            .ok_or(OcallError::ConditionError)?;
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .or(Err(OcallError::ConditionError))?;
+        Ok((now.as_millis() as u64, uncertainty.as_millis() as u64))
+    }
 }
 
+const MAX_TOPIC_LEN: usize = 128;
+const MAX_TOPIC_MESSAGE_SIZE: usize = 1024 * 64;
+
 const MAX_BOOT_DATA_SIZE: usize = 1024 * 64;
 fn boot_filename(vm_id: VmId) -> String {
     format!("{}-bootdata", hex_fmt::HexFmt(vm_id))
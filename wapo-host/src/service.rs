@@ -1,10 +1,9 @@
 use anyhow::{Context, Result};
 use scopeguard::ScopeGuard;
 use serde::{Deserialize, Serialize};
-use sni_tls_listener::Agent;
+use sni_tls_listener::{Agent, ProxyProtocol, RoutedAgent};
 use std::future::{pending, Future};
 use std::ops::{Deref, RangeInclusive};
-use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::io::DuplexStream;
@@ -22,9 +21,9 @@ use crate::runtime::vm_context::RuntimeCalls;
 use crate::Meter;
 use crate::{
     blobs::BlobLoader,
-    module_loader::ModuleLoader,
+    module_loader::{DiskCacheConfig, ModuleLoader},
     run::{InstanceConfig, WasmEngine},
-    ShortId, VmId,
+    BlobMount, ScratchConfig, ShortId, VmId,
 };
 
 use tokio::sync::watch;
@@ -191,10 +190,11 @@ pub struct ServiceHandle {
 pub fn service(
     worker_threads: usize,
     module_cache_size: usize,
-    blobs_dir: &PathBuf,
+    blob_loader: BlobLoader,
     mem_limit: usize,
     mem_pool_size: usize,
     use_winch: bool,
+    module_disk_cache: Option<DiskCacheConfig>,
 ) -> Result<(ServiceRun, ServiceHandle)> {
     let worker_threads = worker_threads.max(1);
     let runtime = tokio::runtime::Builder::new_multi_thread()
@@ -209,7 +209,6 @@ pub fn service(
     let runtime_handle = runtime.handle().clone();
     let (report_tx, report_rx) = channel(100);
     let run = ServiceRun { runtime, report_rx };
-    let blob_loader = BlobLoader::new(blobs_dir);
     let config = Config::new()
         .strategy(if use_winch {
             Strategy::Winch
@@ -219,7 +218,10 @@ pub fn service(
         .to_owned();
     let engine = WasmEngine::new(config, 10, mem_limit, mem_pool_size)
         .context("failed to create Wasm engine")?;
-    let module_loader = ModuleLoader::new(engine, blob_loader, module_cache_size);
+    let mut module_loader = ModuleLoader::new(engine, blob_loader, module_cache_size);
+    if let Some(config) = module_disk_cache {
+        module_loader = module_loader.with_disk_cache(config)?;
+    }
     let spawner = ServiceHandle {
         runtime_handle,
         report_tx,
@@ -258,15 +260,30 @@ pub struct InstanceStartConfig<OCalls> {
     max_memory_pages: u32,
     id: VmId,
     weight: u32,
-    blobs_dir: PathBuf,
+    blob_loader: BlobLoader,
     auto_restart: bool,
     runtime_calls: OCalls,
     args: Vec<String>,
     envs: Vec<(String, String)>,
+    #[builder(default)]
+    secret_envs: Vec<(String, Vec<u8>)>,
     tcp_listen_port_range: RangeInclusive<u16>,
     sni_tls_listener: Option<Agent>,
+    /// Plain HTTP connections routed by `Host`.
+    #[builder(default)]
+    http_listener: Option<RoutedAgent>,
+    /// TLS connections routed by SNI without being terminated.
+    #[builder(default)]
+    tls_passthrough_listener: Option<RoutedAgent>,
+    /// The trusted upstreams to read the client address of the guest TCP listeners from.
+    #[builder(default)]
+    proxy_protocol: ProxyProtocol,
     #[builder(default)]
     time_limit: Option<Duration>,
+    #[builder(default)]
+    mounts: Vec<BlobMount>,
+    #[builder(default)]
+    scratch: Option<ScratchConfig>,
 }
 
 impl ServiceHandle {
@@ -283,14 +300,20 @@ impl ServiceHandle {
             max_memory_pages,
             id,
             weight,
-            blobs_dir,
+            blob_loader,
             auto_restart,
             runtime_calls,
             args,
             envs,
+            secret_envs,
             tcp_listen_port_range,
             sni_tls_listener,
+            http_listener,
+            tls_passthrough_listener,
+            proxy_protocol,
             time_limit,
+            mounts,
+            scratch,
         } = config;
         let (cmd_tx, mut cmd_rx) = channel(128);
         let (ctl_cmd_tx, mut ctl_cmd_rx) = unbounded_channel();
@@ -339,13 +362,19 @@ impl ServiceHandle {
                 .id(id)
                 .max_memory_pages(max_memory_pages)
                 .weight(weight)
-                .blobs_dir(blobs_dir)
+                .blob_loader(blob_loader)
                 .meter(Some(meter_cloned))
                 .runtime_calls(runtime_calls)
                 .args(args)
                 .envs(envs)
+                .secret_envs(secret_envs)
                 .tcp_listen_port_range(tcp_listen_port_range)
                 .sni_tls_listener(sni_tls_listener)
+                .http_listener(http_listener)
+                .tls_passthrough_listener(tls_passthrough_listener)
+                .proxy_protocol(proxy_protocol)
+                .mounts(mounts)
+                .scratch(scratch)
                 .build();
             let mut wasm_run = match module.run(config.clone()).context("failed to create instance") {
                 Ok(i) => i,
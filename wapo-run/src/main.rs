@@ -5,7 +5,10 @@ use clap::{Parser, ValueEnum};
 use pink_types::js::JsValue;
 use scale::Decode;
 use tracing::{error, info};
-use wapo_host::{wasmtime::Config, InstanceConfig, Meter, SniTlsListener, WasmEngine};
+use wapo_host::{
+    blobs::BlobLoader, wasmtime::Config, InstanceConfig, Meter, RoutingListener, SniTlsListener,
+    WasmEngine,
+};
 
 /// The compiler backend to use
 #[derive(ValueEnum, Clone, Debug)]
@@ -40,6 +43,9 @@ pub struct Args {
     /// The port to listen sni based tls
     #[arg(long, short = 'T')]
     tls_port: Option<u16>,
+    /// The port to listen plain http connections routed by Host header
+    #[arg(long)]
+    http_port: Option<u16>,
     /// The time of a single epoch tick
     #[arg(long, default_value_t = 10)]
     tick_time_ms: u64,
@@ -109,17 +115,33 @@ pub async fn run(mut args: Args) -> Result<(Vec<u8>, Arc<Meter>)> {
         }),
         None => None,
     };
+    let passthrough_agent = sni_tls_listener.as_ref().map(|listener| {
+        listener
+            .passthrough()
+            .agent(|| (), true, Duration::from_secs(1))
+    });
     let agent =
         sni_tls_listener.map(|listener| listener.agent(|| (), true, Duration::from_secs(1)));
+    let http_agent = match args.http_port {
+        Some(port) => Some(
+            RoutingListener::bind_http("0.0.0.0", port)
+                .await
+                .context("failed to bind http listener")?
+                .agent(|| (), true, Duration::from_secs(1)),
+        ),
+        None => None,
+    };
     let config = InstanceConfig::builder()
         .epoch_deadline(args.epoch_deadline)
         .max_memory_pages(args.max_memory_pages)
         .args(vm_args)
         .envs(vm_envs)
-        .blobs_dir("./data/storage_files/blobs".into())
+        .blob_loader(BlobLoader::new("./data/storage_files/blobs"))
         .runtime_calls(())
         .tcp_listen_port_range(0..=65535)
         .sni_tls_listener(agent)
+        .http_listener(http_agent)
+        .tls_passthrough_listener(passthrough_agent)
         .build();
     let mut wasm_run = module.run(config).context("failed to start the instance")?;
     if let Some(kill_timeout) = args.kill_timeout {
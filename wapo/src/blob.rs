@@ -0,0 +1,146 @@
+//! Access to the hash objects in the worker's blob store.
+//!
+//! Objects are identified by `<algo>:<hex>` hashes or IPFS CIDs. Use [`get`] for small objects
+//! and [`Blob`] to stream objects that don't fit in the instance memory.
+
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use crate::env::{self, tasks, OcallError, SeekWhence};
+use crate::{ocall, ResourceId};
+
+/// Reads the whole object into memory.
+pub fn get(id: &str) -> Result<Vec<u8>, OcallError> {
+    ocall::blob_get(id)
+}
+
+/// Returns the size of the object in bytes.
+pub fn size(id: &str) -> Result<u64, OcallError> {
+    ocall::blob_size(id)
+}
+
+/// An opened object, readable with `AsyncRead` and seekable with `AsyncSeek`.
+#[derive(Debug)]
+pub struct Blob {
+    res_id: ResourceId,
+    size: u64,
+    seek_result: Option<Result<u64, OcallError>>,
+}
+
+impl Blob {
+    /// Open the object with the given ID.
+    pub fn open(id: &str) -> Result<Self, OcallError> {
+        let size = ocall::blob_size(id)?;
+        let res_id = ResourceId(ocall::blob_open(id)?);
+        Ok(Self {
+            res_id,
+            size,
+            seek_result: None,
+        })
+    }
+
+    /// The size of the object in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Move the read position, returning the new position from the start of the object.
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, OcallError> {
+        let (whence, offset) = match pos {
+            SeekFrom::Start(offset) => (
+                SeekWhence::Start,
+                offset.try_into().or(Err(OcallError::InvalidParameter))?,
+            ),
+            SeekFrom::End(offset) => (SeekWhence::End, offset),
+            SeekFrom::Current(offset) => (SeekWhence::Current, offset),
+        };
+        ocall::seek(self.res_id.0, whence, offset)
+    }
+
+    fn poll_read_buf(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        let waker_id = tasks::intern_waker(cx.waker().clone());
+        into_poll(ocall::poll_read(waker_id, self.res_id.0, buf).map(|len| len as usize))
+    }
+}
+
+#[cfg(feature = "tokio")]
+mod impl_tokio {
+    use super::*;
+    use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+    impl AsyncRead for Blob {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let size = buf.remaining();
+            let len = match self.poll_read_buf(cx, buf.initialize_unfilled_to(size)) {
+                Poll::Ready(Ok(len)) => len,
+                other => return other.map_ok(|_| ()),
+            };
+            if len > size {
+                return Poll::Ready(Err(std::io::Error::from_raw_os_error(
+                    OcallError::InvalidEncoding as i32,
+                )));
+            }
+            buf.advance(len);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncSeek for Blob {
+        fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+            let result = self.seek(position);
+            self.seek_result = Some(result);
+            Ok(())
+        }
+
+        fn poll_complete(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<u64>> {
+            // Seeking completes synchronously, so the result is already there unless no seek was
+            // started, in which case the current position is returned.
+            let result = match self.seek_result.take() {
+                Some(result) => result,
+                None => self.seek(SeekFrom::Current(0)),
+            };
+            into_poll(result)
+        }
+    }
+}
+
+mod impl_futures_io {
+    use super::*;
+    use futures::io::{AsyncRead, AsyncSeek};
+
+    impl AsyncRead for Blob {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.poll_read_buf(cx, buf)
+        }
+    }
+
+    impl AsyncSeek for Blob {
+        fn poll_seek(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            pos: SeekFrom,
+        ) -> Poll<std::io::Result<u64>> {
+            into_poll(self.seek(pos))
+        }
+    }
+}
+
+fn into_poll<T>(res: Result<T, env::OcallError>) -> Poll<std::io::Result<T>> {
+    match res {
+        Ok(v) => Poll::Ready(Ok(v)),
+        Err(env::OcallError::Pending) => Poll::Pending,
+        Err(err) => Poll::Ready(Err(std::io::Error::from_raw_os_error(err as i32))),
+    }
+}
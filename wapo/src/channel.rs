@@ -18,7 +18,7 @@ use std::{
 
 use lazy_static::lazy_static;
 
-/// A query from an external RPC request.
+/// A query from an external RPC request or from another app on the same worker.
 pub struct Query {
     /// The account sending the query.
     pub origin: Option<AccountId>,
@@ -179,3 +179,79 @@ impl Future for Next<'_, HttpRequest> {
 pub fn incoming_http_requests() -> &'static Receiver<HttpRequest> {
     singleton_channel!(HttpRequest)
 }
+
+pub use wapo_env::messages::TopicMessage;
+
+impl Future for Next<'_, TopicMessage> {
+    type Output = Option<TopicMessage>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let waker_id = crate::env::tasks::intern_waker(cx.waker().clone());
+        match ocall::poll(waker_id, self.ch.res_id.0) {
+            Ok(msg) => Poll::Ready(Some(
+                TopicMessage::decode(&mut &msg[..]).expect("failed to decode TopicMessage"),
+            )),
+            Err(OcallError::EndOfFile) => Poll::Ready(None), // The tx dropped
+            Err(OcallError::Pending) => Poll::Pending,
+            Err(err) => panic!("unexpected error: {err:?}"),
+        }
+    }
+}
+
+/// Messages published to the subscribed topics.
+pub fn incoming_topic_messages() -> &'static Receiver<TopicMessage> {
+    singleton_channel!(TopicMessage)
+}
+
+/// Subscribe to a topic. Messages published to it are received from `incoming_topic_messages()`.
+pub fn subscribe(topic: &str) -> Result<(), OcallError> {
+    // Make sure the input channel exists before subscribing.
+    let _ = incoming_topic_messages();
+    ocall::subscribe(topic)
+}
+
+/// Unsubscribe from a topic.
+pub fn unsubscribe(topic: &str) -> Result<(), OcallError> {
+    ocall::unsubscribe(topic)
+}
+
+/// Publish a message to all running instances subscribed to the topic.
+pub fn publish(topic: &str, data: &[u8]) -> Result<(), OcallError> {
+    ocall::publish(topic, data)
+}
+
+/// The future of a query sent to another app by `query_app`.
+pub struct AppQuery {
+    res_id: ResourceId,
+}
+
+impl Future for AppQuery {
+    type Output = Result<Vec<u8>, String>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let waker_id = crate::env::tasks::intern_waker(cx.waker().clone());
+        match ocall::poll(waker_id, self.res_id.0) {
+            Ok(msg) => Poll::Ready(
+                Result::<Vec<u8>, String>::decode(&mut &msg[..])
+                    .unwrap_or_else(|_| Err("failed to decode the reply".into())),
+            ),
+            Err(OcallError::Pending) => Poll::Pending,
+            Err(err) => Poll::Ready(Err(err.to_string())),
+        }
+    }
+}
+
+/// Send a query to another app deployed on the same worker.
+///
+/// The callee receives it from `incoming_queries()` with the address of the calling app as the
+/// origin.
+pub fn query_app(
+    address: [u8; 32],
+    path: impl Into<String>,
+    payload: Vec<u8>,
+) -> Result<AppQuery, OcallError> {
+    let res_id = ocall::app_query(address, path.into(), payload)?;
+    Ok(AppQuery {
+        res_id: ResourceId(res_id),
+    })
+}
@@ -11,6 +11,7 @@ pub use env::ocall_funcs_guest as ocall;
 pub use env::{spawn, spawn_named};
 pub use env::tasks as task;
 
+pub mod blob;
 pub mod channel;
 pub mod hyper_rt;
 pub mod logger;
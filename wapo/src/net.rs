@@ -12,7 +12,7 @@ use crate::{ocall, ResourceId};
 
 mod sni_listener;
 
-pub use sni_listener::{SniTlsAcceptor, SniTlsListener};
+pub use sni_listener::{RoutedAcceptor, RoutedListener, SniTlsAcceptor, SniTlsListener};
 
 /// A TCP socket server, listening for connections.
 pub struct TcpListener {
@@ -120,6 +120,11 @@ impl TcpStream {
         TcpConnector { res }
     }
 
+    /// The SNI server name of a connection accepted from a [`SniTlsListener`].
+    pub fn tls_server_name(&self) -> Result<String> {
+        ocall::tls_server_name(self.res_id.0)
+    }
+
     #[allow(dead_code)]
     fn connect_with_components(
         scheme: Option<&str>,
@@ -16,6 +16,11 @@ pub struct SniTlsListener {
 
 impl SniTlsListener {
     /// Bind to a given SNI and listen for incoming connections.
+    ///
+    /// `sni` is a comma separated list of server names, each of which can be a wildcard like
+    /// `*.app.example.com`. A connection is dispatched to the listener of its exact server name,
+    /// or else to the one of the longest matching wildcard. Use [`TcpStream::tls_server_name`]
+    /// to find out which name an accepted connection was made for.
     pub fn bind(sni: &str, config: TlsServerConfig) -> Result<Self> {
         let raw_res = ocall::tls_listen_sni(sni.into(), config)?;
         let res_id = ResourceId(raw_res);
@@ -40,3 +45,53 @@ impl<'a> Future for SniTlsAcceptor<'a> {
         super::poll_tcp_accept(&self.listener.res_id, cx)
     }
 }
+
+/// A listener of connections routed by server name but not terminated by the worker.
+///
+/// The accepted stream starts with the bytes the server name was told from, the app is left to
+/// speak HTTP or to do the TLS handshake by itself.
+pub struct RoutedListener {
+    res_id: ResourceId,
+}
+
+impl RoutedListener {
+    /// Listen to plain HTTP connections on the HTTP port of the worker, routed by `Host` header.
+    ///
+    /// `host` takes the same comma separated names and wildcards as [`SniTlsListener::bind`].
+    pub fn bind_http(host: &str) -> Result<Self> {
+        let raw_res = ocall::http_listen_host(host.into())?;
+        Ok(Self {
+            res_id: ResourceId(raw_res),
+        })
+    }
+
+    /// Listen to TLS connections on the SNI port of the worker, routed by SNI and passed through
+    /// without terminating TLS.
+    ///
+    /// When a name is also bound with [`SniTlsListener::bind`], the connection is terminated by
+    /// the worker.
+    pub fn bind_tls_passthrough(sni: &str) -> Result<Self> {
+        let raw_res = ocall::tls_listen_sni_passthrough(sni.into())?;
+        Ok(Self {
+            res_id: ResourceId(raw_res),
+        })
+    }
+
+    /// Accept a new incoming connection.
+    pub fn accept(&mut self) -> RoutedAcceptor<'_> {
+        RoutedAcceptor { listener: self }
+    }
+}
+
+/// A future that resolves to a new incoming routed connection.
+pub struct RoutedAcceptor<'a> {
+    listener: &'a RoutedListener,
+}
+
+impl<'a> Future for RoutedAcceptor<'a> {
+    type Output = Result<(TcpStream, SocketAddr)>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        super::poll_tcp_accept(&self.listener.res_id, cx)
+    }
+}
@@ -6,7 +6,7 @@ use core::pin::Pin;
 use derive_more::{Display, Error};
 use std::future::Future;
 use std::task::{Context, Poll};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use crate::ResourceId;
 
@@ -111,3 +111,15 @@ impl Future for Breath {
 pub fn breath() -> Breath {
     Breath::new(true)
 }
+
+/// Returns the current time cross-checked by the worker against multiple trusted time sources,
+/// together with its uncertainty bound.
+///
+/// Unlike `SystemTime::now()`, the result does not rely on the host's clock.
+pub fn trusted_now() -> Result<(SystemTime, Duration), env::OcallError> {
+    let (now_ms, uncertainty_ms) = ocall::trusted_now()?;
+    Ok((
+        UNIX_EPOCH + Duration::from_millis(now_ms),
+        Duration::from_millis(uncertainty_ms),
+    ))
+}
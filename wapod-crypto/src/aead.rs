@@ -23,11 +23,16 @@ fn load_key(raw: &[u8]) -> Result<AeadKey> {
 
 // Encrypts the data in-place and appends a 128bit auth tag
 pub fn encrypt(iv: &IV, secret: &[u8], in_out: &mut Vec<u8>) -> Result<()> {
+    encrypt_with_aad(iv, secret, &[], in_out)
+}
+
+// Encrypts the data in-place and appends a 128bit auth tag covering the data and the aad
+pub fn encrypt_with_aad(iv: &IV, secret: &[u8], aad: &[u8], in_out: &mut Vec<u8>) -> Result<()> {
     let nonce = ring::aead::Nonce::assume_unique_for_key(*iv);
     let key = load_key(secret)?;
 
     key.0
-        .seal_in_place_append_tag(nonce, ring::aead::Aad::empty(), in_out)
+        .seal_in_place_append_tag(nonce, ring::aead::Aad::from(aad), in_out)
         .map_err(|_| Error::CryptoError)?;
     Ok(())
 }
@@ -37,12 +42,22 @@ pub fn decrypt<'in_out>(
     iv: IV,
     secret: &[u8],
     in_out: &'in_out mut [u8],
+) -> Result<&'in_out mut [u8]> {
+    decrypt_with_aad(iv, secret, &[], in_out)
+}
+
+// Decrypts the cipher sealed with the given aad in-place and returns the message as a slice.
+pub fn decrypt_with_aad<'in_out>(
+    iv: IV,
+    secret: &[u8],
+    aad: &[u8],
+    in_out: &'in_out mut [u8],
 ) -> Result<&'in_out mut [u8]> {
     let key = load_key(secret)?;
     let nonce = ring::aead::Nonce::assume_unique_for_key(iv);
 
     key.0
-        .open_in_place(nonce, ring::aead::Aad::empty(), in_out)
+        .open_in_place(nonce, ring::aead::Aad::from(aad), in_out)
         .map_err(|_| Error::CryptoError)
 }
 
@@ -64,4 +79,17 @@ mod test {
 
         assert_eq!(decrypted_messgae, message);
     }
+
+    #[test]
+    fn aad_must_match() {
+        let iv = generate_iv();
+        let secret = [233_u8; 32];
+        let mut encrypted_message = [233_u8; 64].to_vec();
+
+        encrypt_with_aad(&iv, &secret, b"aad", &mut encrypted_message).unwrap();
+        assert!(decrypt(iv, &secret, &mut encrypted_message.clone()[..]).is_err());
+        assert!(decrypt_with_aad(iv, &secret, b"aae", &mut encrypted_message.clone()[..]).is_err());
+        let decrypted = decrypt_with_aad(iv, &secret, b"aad", &mut encrypted_message[..]).unwrap();
+        assert_eq!(decrypted, [233_u8; 64]);
+    }
 }
@@ -0,0 +1,570 @@
+//! Verification of SGX DCAP quotes.
+//!
+//! [`verify`] checks a quote produced by a worker against the collateral fetched from a PCCS
+//! server, with the Intel SGX root CA certificate as the trust anchor:
+//!
+//! - the PCK certificate chain in the quote, including revocation with the CRLs,
+//! - the quoting enclave report, signed by the PCK, and its binding to the attestation key,
+//! - the app enclave report, signed by the attestation key,
+//! - the TCB info and QE identity, signed by the TCB signing key.
+//!
+//! The Intel SGX root CA certificate is not bundled. Pin the DER certificate downloaded from
+//! Intel rather than taking the root of a collateral issuer chain.
+
+use core::time::Duration;
+
+use pki_types::{CertificateDer, TrustAnchor, UnixTime};
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use webpki::{
+    BorrowedCertRevocationList, CertRevocationList, EndEntityCert, KeyUsage, RevocationCheckDepth,
+    RevocationOptionsBuilder, UnknownStatusPolicy,
+};
+
+use crate::ContentType;
+
+pub use quote::EnclaveReport;
+
+mod der;
+mod quote;
+mod tcb;
+
+type Result<T, E = Error> = core::result::Result<T, E>;
+
+#[derive(Debug, Clone, thiserror::Error, PartialEq, Eq)]
+pub enum Error {
+    #[error("Invalid quote: {0}")]
+    InvalidQuote(&'static str),
+    #[error("Unsupported quote type")]
+    UnsupportedQuote,
+    #[error("Invalid certificate: {0}")]
+    InvalidCertificate(&'static str),
+    #[error("Invalid root CA certificate")]
+    InvalidRootCa,
+    #[error("Certificate chain verification failed: {0}")]
+    CertChain(String),
+    #[error("Invalid signature of {0}")]
+    InvalidSignature(&'static str),
+    #[error("Attestation key does not match the QE report")]
+    AttestationKeyMismatch,
+    #[error("Invalid collateral: {0}")]
+    InvalidCollateral(&'static str),
+    #[error("Collateral expired or not yet valid")]
+    CollateralExpired,
+    #[error("No matching TCB level")]
+    TcbLevelNotFound,
+    #[error("Untrusted quoting enclave")]
+    UntrustedQuotingEnclave,
+    #[error("TCB revoked")]
+    Revoked,
+    #[error("Report data mismatch")]
+    ReportDataMismatch,
+}
+
+/// The collateral to verify quotes with, as returned by the PCCS API.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct QuoteCollateral {
+    /// The DER encoded CRL of the Intel SGX root CA.
+    pub root_ca_crl: Vec<u8>,
+    /// The DER encoded CRL of the CA that issued the PCK certificate.
+    pub pck_crl: Vec<u8>,
+    /// The PEM encoded certificate chain of the TCB info signer.
+    pub tcb_info_issuer_chain: String,
+    /// The `tcbInfo` JSON object, exactly as it was signed.
+    pub tcb_info: String,
+    /// The raw `r || s` ECDSA signature of `tcb_info`.
+    pub tcb_info_signature: Vec<u8>,
+    /// The PEM encoded certificate chain of the QE identity signer.
+    pub qe_identity_issuer_chain: String,
+    /// The `enclaveIdentity` JSON object, exactly as it was signed.
+    pub qe_identity: String,
+    /// The raw `r || s` ECDSA signature of `qe_identity`.
+    pub qe_identity_signature: Vec<u8>,
+}
+
+/// A quote that passed verification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedReport {
+    /// The TCB status of the platform, e.g. "UpToDate", "SWHardeningNeeded" or "OutOfDate".
+    ///
+    /// Quotes of revoked platforms fail verification, other statuses are left to the caller.
+    pub tcb_status: String,
+    /// The Intel security advisories that apply to the platform.
+    pub advisory_ids: Vec<String>,
+    /// The FMSPC of the platform.
+    pub fmspc: [u8; 6],
+    /// The report of the attested enclave.
+    pub report: EnclaveReport,
+}
+
+impl VerifiedReport {
+    pub fn mr_enclave(&self) -> [u8; 32] {
+        self.report.mr_enclave
+    }
+
+    pub fn mr_signer(&self) -> [u8; 32] {
+        self.report.mr_signer
+    }
+
+    pub fn report_data(&self) -> [u8; 64] {
+        self.report.report_data
+    }
+
+    /// Checks that the report data commits to the given content, as produced by the worker.
+    pub fn check_content(&self, content_type: ContentType, content: &[u8]) -> Result<()> {
+        if self.report.report_data != report_data(content_type, content) {
+            return Err(Error::ReportDataMismatch);
+        }
+        Ok(())
+    }
+}
+
+/// The report data a worker puts in the quote of the given content.
+///
+/// Worker attestations commit to the blake2 hash of the content, while other content types
+/// commit to the keccak hash of [`ContentType::wrap_message`] of the content.
+pub fn report_data(content_type: ContentType, content: &[u8]) -> [u8; 64] {
+    let hash = match content_type {
+        ContentType::WorkerAttestation => sp_core::blake2_256(content),
+        _ => sp_core::keccak_256(&content_type.wrap_message(content)),
+    };
+    let mut report_data = [0u8; 64];
+    report_data[..32].copy_from_slice(&hash);
+    report_data
+}
+
+/// Verifies a quote against the collateral at the given unix timestamp in seconds.
+///
+/// `root_ca` is the DER encoded Intel SGX root CA certificate.
+pub fn verify(
+    raw_quote: &[u8],
+    collateral: &QuoteCollateral,
+    root_ca: &[u8],
+    now: u64,
+) -> Result<VerifiedReport> {
+    let quote = quote::Quote::parse(raw_quote)?;
+    let root_ca = CertificateDer::from(root_ca);
+    let anchors = [webpki::anchor_from_trusted_cert(&root_ca).or(Err(Error::InvalidRootCa))?];
+    let root_ca_crl = parse_crl(&collateral.root_ca_crl)?;
+    let pck_crl = parse_crl(&collateral.pck_crl)?;
+    let time = UnixTime::since_unix_epoch(Duration::from_secs(now));
+
+    // The PCK certificate identifies the platform and signs the QE report.
+    if quote.cert_data_type != quote::CERT_DATA_PCK_CHAIN {
+        return Err(Error::UnsupportedQuote);
+    }
+    let pck_chain = pem_certs(quote.cert_data)?;
+    verify_chain(&pck_chain, &anchors, &[&root_ca_crl, &pck_crl], time)?;
+    let pck = der::parse_cert(&pck_chain[0])?;
+    let sgx_extensions = pck
+        .sgx_extensions
+        .ok_or(Error::InvalidCertificate("not a PCK certificate"))?;
+    let platform = der::parse_sgx_extensions(sgx_extensions)?;
+    verify_signature(
+        pck.public_key,
+        quote.qe_report_raw,
+        quote.qe_report_signature,
+        "QE report",
+    )?;
+
+    // The QE report commits to the attestation key, which signs the app enclave report.
+    let key_hash = ring::digest::digest(
+        &ring::digest::SHA256,
+        &[quote.attestation_key, quote.qe_auth_data].concat(),
+    );
+    if quote.qe_report.report_data[..32] != *key_hash.as_ref()
+        || quote.qe_report.report_data[32..] != [0; 32]
+    {
+        return Err(Error::AttestationKeyMismatch);
+    }
+    let attestation_key = [&[0x04], quote.attestation_key].concat();
+    verify_signature(
+        &attestation_key,
+        quote.signed_data,
+        quote.report_signature,
+        "enclave report",
+    )?;
+
+    let tcb_info: tcb::TcbInfo = verify_signed_json(
+        &collateral.tcb_info_issuer_chain,
+        &collateral.tcb_info,
+        &collateral.tcb_info_signature,
+        &anchors,
+        &root_ca_crl,
+        time,
+    )?;
+    let qe_identity: tcb::QeIdentity = verify_signed_json(
+        &collateral.qe_identity_issuer_chain,
+        &collateral.qe_identity,
+        &collateral.qe_identity_signature,
+        &anchors,
+        &root_ca_crl,
+        time,
+    )?;
+    let platform_status = tcb_info.platform_status(&platform, now)?;
+    let qe_status = qe_identity.qe_status(&quote.qe_report, now)?;
+    if platform_status.tcb_status == "Revoked" || qe_status.tcb_status == "Revoked" {
+        return Err(Error::Revoked);
+    }
+    // An outdated quoting enclave downgrades an up to date platform.
+    let tcb_status = if platform_status.tcb_status == "UpToDate" {
+        qe_status.tcb_status.clone()
+    } else {
+        platform_status.tcb_status.clone()
+    };
+    let mut advisory_ids = platform_status.advisory_ids.clone();
+    for id in &qe_status.advisory_ids {
+        if !advisory_ids.contains(id) {
+            advisory_ids.push(id.clone());
+        }
+    }
+    Ok(VerifiedReport {
+        tcb_status,
+        advisory_ids,
+        fmspc: platform.fmspc,
+        report: quote.report,
+    })
+}
+
+fn parse_crl(der: &[u8]) -> Result<CertRevocationList<'_>> {
+    BorrowedCertRevocationList::from_der(der)
+        .map(Into::into)
+        .or(Err(Error::InvalidCollateral("invalid CRL")))
+}
+
+/// Decodes the certificates of a PEM chain, from the leaf to the root.
+fn pem_certs(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+    use base64::Engine as _;
+
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    let invalid = Error::InvalidCertificate("invalid PEM certificate chain");
+    let pem = core::str::from_utf8(pem)
+        .or(Err(invalid.clone()))?
+        .trim_end_matches('\0');
+    let mut certs = vec![];
+    let mut rest = pem;
+    while let Some(start) = rest.find(BEGIN) {
+        let body = &rest[start + BEGIN.len()..];
+        let end = body.find(END).ok_or(invalid.clone())?;
+        let base64: String = body[..end].split_whitespace().collect();
+        let der = base64::engine::general_purpose::STANDARD
+            .decode(base64)
+            .or(Err(invalid.clone()))?;
+        certs.push(CertificateDer::from(der));
+        rest = &body[end + END.len()..];
+    }
+    if certs.is_empty() {
+        return Err(invalid);
+    }
+    Ok(certs)
+}
+
+fn verify_chain(
+    chain: &[CertificateDer<'_>],
+    anchors: &[TrustAnchor<'_>],
+    crls: &[&CertRevocationList<'_>],
+    time: UnixTime,
+) -> Result<()> {
+    let leaf = EndEntityCert::try_from(&chain[0])
+        .or(Err(Error::InvalidCertificate("invalid leaf certificate")))?;
+    let revocation = RevocationOptionsBuilder::new(crls)
+        .or(Err(Error::InvalidCollateral("no CRL")))?
+        .with_depth(RevocationCheckDepth::Chain)
+        .with_status_policy(UnknownStatusPolicy::Deny)
+        .build();
+    // Intel certificates carry no extended key usage, which `server_auth` accepts.
+    leaf.verify_for_usage(
+        &[webpki::ring::ECDSA_P256_SHA256],
+        anchors,
+        &chain[1..],
+        time,
+        KeyUsage::server_auth(),
+        Some(revocation),
+        None,
+    )
+    .map_err(|err| Error::CertChain(format!("{err:?}")))?;
+    Ok(())
+}
+
+fn verify_signature(
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+    what: &'static str,
+) -> Result<()> {
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, public_key)
+        .verify(message, signature)
+        .or(Err(Error::InvalidSignature(what)))
+}
+
+fn verify_signed_json<T: DeserializeOwned>(
+    issuer_chain: &str,
+    body: &str,
+    signature: &[u8],
+    anchors: &[TrustAnchor<'_>],
+    root_ca_crl: &CertRevocationList<'_>,
+    time: UnixTime,
+) -> Result<T> {
+    let chain = pem_certs(issuer_chain.as_bytes())?;
+    verify_chain(&chain, anchors, &[root_ca_crl], time)?;
+    let signer = der::parse_cert(&chain[0])?;
+    verify_signature(signer.public_key, body.as_bytes(), signature, "collateral")?;
+    serde_json::from_str(body).or(Err(Error::InvalidCollateral("malformed JSON")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_report_content() {
+        let report = VerifiedReport {
+            tcb_status: "UpToDate".into(),
+            advisory_ids: vec![],
+            fmspc: [0; 6],
+            report: EnclaveReport {
+                cpu_svn: [0; 16],
+                misc_select: 0,
+                attributes: [0; 16],
+                mr_enclave: [0; 32],
+                mr_signer: [0; 32],
+                isv_prod_id: 0,
+                isv_svn: 0,
+                report_data: report_data(ContentType::AppData, b"hello"),
+            },
+        };
+        assert!(report.check_content(ContentType::AppData, b"hello").is_ok());
+        assert_eq!(
+            report.check_content(ContentType::AppData, b"world"),
+            Err(Error::ReportDataMismatch)
+        );
+        assert_eq!(
+            report.check_content(ContentType::WorkerAttestation, b"hello"),
+            Err(Error::ReportDataMismatch)
+        );
+    }
+
+    #[test]
+    fn reject_empty_pem_chain() {
+        assert!(pem_certs(b"no certificates here").is_err());
+    }
+
+    // A test PKI shaped like the Intel one, see `dcap/testdata/gen.sh`. Quotes and collateral
+    // are signed with its keys by the tests.
+    const ROOT_CA: &[u8] = include_bytes!("dcap/testdata/root.der");
+    const ROOT_CA_CRL: &[u8] = include_bytes!("dcap/testdata/root_crl.der");
+    const PCK_CRL: &[u8] = include_bytes!("dcap/testdata/pck_crl.der");
+    const PCK_CRL_REVOKED: &[u8] = include_bytes!("dcap/testdata/pck_crl_revoked.der");
+    const PCK_CA: &str = include_str!("dcap/testdata/pck_ca.pem");
+    const PCK: &str = include_str!("dcap/testdata/pck.pem");
+    const PCK_KEY: &[u8] = include_bytes!("dcap/testdata/pck.pk8");
+    const TCB_SIGNING: &str = include_str!("dcap/testdata/tcb_signing.pem");
+    const TCB_SIGNING_KEY: &[u8] = include_bytes!("dcap/testdata/tcb_signing.pk8");
+
+    /// 2040-01-01, within the validity of the test PKI and of the collateral.
+    const NOW: u64 = 2_208_988_800;
+    const MR_ENCLAVE: [u8; 32] = [0xe1; 32];
+    const MR_SIGNER: [u8; 32] = [0x51; 32];
+    const QE_MR_SIGNER: [u8; 32] = [0x8c; 32];
+
+    // Offsets of the fields in an enclave report.
+    const ATTRIBUTES: usize = 48;
+    const MR_ENCLAVE_AT: usize = 64;
+    const MR_SIGNER_AT: usize = 128;
+    const ISV_PROD_ID: usize = 256;
+    const ISV_SVN: usize = 258;
+    const REPORT_DATA: usize = 320;
+    /// Offset of the QE report in a quote.
+    const QE_REPORT_AT: usize = quote::HEADER_LEN + quote::REPORT_LEN + 4 + 128;
+
+    fn key_pair(pkcs8: &[u8]) -> ring::signature::EcdsaKeyPair {
+        use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+        let rng = ring::rand::SystemRandom::new();
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8, &rng).unwrap()
+    }
+
+    fn sign(key: &ring::signature::EcdsaKeyPair, message: &[u8]) -> Vec<u8> {
+        let rng = ring::rand::SystemRandom::new();
+        key.sign(&rng, message).unwrap().as_ref().to_vec()
+    }
+
+    fn build_quote(report_data: [u8; 64]) -> Vec<u8> {
+        use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+
+        let rng = ring::rand::SystemRandom::new();
+        let attestation_pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let attestation_key = key_pair(attestation_pkcs8.as_ref());
+        let attestation_public = &attestation_key.public_key().as_ref()[1..];
+        let qe_auth_data = [0xa5; 32];
+
+        let mut quote = vec![];
+        quote.extend(3u16.to_le_bytes());
+        quote.extend(2u16.to_le_bytes());
+        quote.extend(0u32.to_le_bytes());
+        quote.extend([0; 4]);
+        quote.extend(quote::INTEL_QE_VENDOR_ID);
+        quote.extend([0; 20]);
+        let mut report = [0u8; quote::REPORT_LEN];
+        report[MR_ENCLAVE_AT..MR_ENCLAVE_AT + 32].copy_from_slice(&MR_ENCLAVE);
+        report[MR_SIGNER_AT..MR_SIGNER_AT + 32].copy_from_slice(&MR_SIGNER);
+        report[REPORT_DATA..].copy_from_slice(&report_data);
+        quote.extend(report);
+        let report_signature = sign(&attestation_key, &quote);
+
+        let mut qe_report = [0u8; quote::REPORT_LEN];
+        qe_report[ATTRIBUTES] = 0x11;
+        qe_report[MR_SIGNER_AT..MR_SIGNER_AT + 32].copy_from_slice(&QE_MR_SIGNER);
+        qe_report[ISV_PROD_ID..ISV_PROD_ID + 2].copy_from_slice(&1u16.to_le_bytes());
+        qe_report[ISV_SVN..ISV_SVN + 2].copy_from_slice(&8u16.to_le_bytes());
+        let key_hash = ring::digest::digest(
+            &ring::digest::SHA256,
+            &[attestation_public, &qe_auth_data].concat(),
+        );
+        qe_report[REPORT_DATA..REPORT_DATA + 32].copy_from_slice(key_hash.as_ref());
+        let qe_report_signature = sign(&key_pair(PCK_KEY), &qe_report);
+
+        let cert_data = format!("{PCK}{PCK_CA}");
+        let mut signature = vec![];
+        signature.extend(report_signature);
+        signature.extend(attestation_public);
+        signature.extend(qe_report);
+        signature.extend(qe_report_signature);
+        signature.extend((qe_auth_data.len() as u16).to_le_bytes());
+        signature.extend(qe_auth_data);
+        signature.extend(quote::CERT_DATA_PCK_CHAIN.to_le_bytes());
+        signature.extend((cert_data.len() as u32).to_le_bytes());
+        signature.extend(cert_data.as_bytes());
+        quote.extend((signature.len() as u32).to_le_bytes());
+        quote.extend(signature);
+        quote
+    }
+
+    fn tcb_info(status: &str) -> String {
+        let level = |svn: u8, status: &str| {
+            format!(
+                r#"{{"tcb":{{"sgxtcbcomponents":[{}],"pcesvn":10}},"tcbDate":"2039-11-13T00:00:00Z","tcbStatus":"{status}","advisoryIDs":["INTEL-SA-00000"]}}"#,
+                vec![format!(r#"{{"svn":{svn}}}"#); 16].join(",")
+            )
+        };
+        format!(
+            r#"{{"id":"SGX","version":3,"issueDate":"2039-12-15T00:00:00Z","nextUpdate":"2040-01-14T00:00:00Z","fmspc":"00906ed50000","pceId":"0000","tcbType":0,"tcbEvaluationDataNumber":17,"tcbLevels":[{},{}]}}"#,
+            level(3, "UpToDate"),
+            level(2, status),
+        )
+    }
+
+    fn qe_identity() -> String {
+        format!(
+            r#"{{"id":"QE","version":2,"issueDate":"2039-12-15T00:00:00Z","nextUpdate":"2040-01-14T00:00:00Z","tcbEvaluationDataNumber":17,"miscselect":"00000000","miscselectMask":"FFFFFFFF","attributes":"11000000000000000000000000000000","attributesMask":"FBFFFFFFFFFFFFFF0000000000000000","mrsigner":"{}","isvprodid":1,"tcbLevels":[{{"tcb":{{"isvsvn":8}},"tcbDate":"2039-11-13T00:00:00Z","tcbStatus":"UpToDate"}}]}}"#,
+            hex::encode_upper(QE_MR_SIGNER)
+        )
+    }
+
+    fn collateral(tcb_info: String) -> QuoteCollateral {
+        let signer = key_pair(TCB_SIGNING_KEY);
+        let qe_identity = qe_identity();
+        QuoteCollateral {
+            root_ca_crl: ROOT_CA_CRL.to_vec(),
+            pck_crl: PCK_CRL.to_vec(),
+            tcb_info_issuer_chain: TCB_SIGNING.into(),
+            tcb_info_signature: sign(&signer, tcb_info.as_bytes()),
+            tcb_info,
+            qe_identity_issuer_chain: TCB_SIGNING.into(),
+            qe_identity_signature: sign(&signer, qe_identity.as_bytes()),
+            qe_identity,
+        }
+    }
+
+    #[test]
+    fn verify_quote() {
+        let quote = build_quote(report_data(ContentType::AppData, b"hello"));
+        let collateral = collateral(tcb_info("SWHardeningNeeded"));
+        let report = verify(&quote, &collateral, ROOT_CA, NOW).unwrap();
+        assert_eq!(report.mr_enclave(), MR_ENCLAVE);
+        assert_eq!(report.mr_signer(), MR_SIGNER);
+        assert!(report.check_content(ContentType::AppData, b"hello").is_ok());
+        assert_eq!(report.tcb_status, "SWHardeningNeeded");
+        assert_eq!(report.advisory_ids, ["INTEL-SA-00000"]);
+        assert_eq!(report.fmspc, [0x00, 0x90, 0x6e, 0xd5, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn reject_tampered_qe_report() {
+        let mut quote = build_quote([0; 64]);
+        quote[QE_REPORT_AT + ISV_SVN] += 1;
+        let collateral = collateral(tcb_info("UpToDate"));
+        assert_eq!(
+            verify(&quote, &collateral, ROOT_CA, NOW),
+            Err(Error::InvalidSignature("QE report"))
+        );
+    }
+
+    #[test]
+    fn reject_tampered_enclave_report() {
+        let mut quote = build_quote([0; 64]);
+        quote[quote::HEADER_LEN + MR_ENCLAVE_AT] ^= 1;
+        let collateral = collateral(tcb_info("UpToDate"));
+        assert_eq!(
+            verify(&quote, &collateral, ROOT_CA, NOW),
+            Err(Error::InvalidSignature("enclave report"))
+        );
+    }
+
+    #[test]
+    fn reject_tampered_collateral() {
+        let quote = build_quote([0; 64]);
+        let mut collateral = collateral(tcb_info("OutOfDate"));
+        collateral.tcb_info = tcb_info("UpToDate");
+        assert_eq!(
+            verify(&quote, &collateral, ROOT_CA, NOW),
+            Err(Error::InvalidSignature("collateral"))
+        );
+    }
+
+    #[test]
+    fn reject_expired_collateral() {
+        let quote = build_quote([0; 64]);
+        let collateral = collateral(tcb_info("UpToDate"));
+        let day = 24 * 3600;
+        for now in [NOW - 30 * day, NOW + 30 * day] {
+            assert_eq!(
+                verify(&quote, &collateral, ROOT_CA, now),
+                Err(Error::CollateralExpired)
+            );
+        }
+    }
+
+    #[test]
+    fn reject_revoked_tcb() {
+        let quote = build_quote([0; 64]);
+        let collateral = collateral(tcb_info("Revoked"));
+        assert_eq!(
+            verify(&quote, &collateral, ROOT_CA, NOW),
+            Err(Error::Revoked)
+        );
+    }
+
+    #[test]
+    fn reject_revoked_pck_certificate() {
+        let quote = build_quote([0; 64]);
+        let mut collateral = collateral(tcb_info("UpToDate"));
+        collateral.pck_crl = PCK_CRL_REVOKED.to_vec();
+        assert!(matches!(
+            verify(&quote, &collateral, ROOT_CA, NOW),
+            Err(Error::CertChain(_))
+        ));
+    }
+
+    #[test]
+    fn reject_untrusted_root() {
+        let quote = build_quote([0; 64]);
+        let collateral = collateral(tcb_info("UpToDate"));
+        let other_root = pem_certs(TCB_SIGNING.as_bytes()).unwrap().remove(0);
+        assert!(matches!(
+            verify(&quote, &collateral, &other_root, NOW),
+            Err(Error::CertChain(_))
+        ));
+    }
+}
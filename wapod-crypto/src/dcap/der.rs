@@ -0,0 +1,245 @@
+//! Just enough DER parsing to read the public key and the SGX extension of a PCK certificate.
+
+use super::Error;
+
+type Result<T> = core::result::Result<T, Error>;
+
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const BOOLEAN: u8 = 0x01;
+const EXPLICIT_VERSION: u8 = 0xa0;
+const EXPLICIT_EXTENSIONS: u8 = 0xa3;
+
+/// OID 1.2.840.113741.1.13.1, the SGX extensions of PCK certificates.
+const SGX_EXTENSIONS_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf8, 0x4d, 0x01, 0x0d, 0x01];
+
+fn invalid() -> Error {
+    Error::InvalidCertificate("malformed DER")
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Reads the next element, returning its tag and value.
+    fn read(&mut self) -> Result<(u8, &'a [u8])> {
+        let [tag, len, rest @ ..] = self.0 else {
+            return Err(invalid());
+        };
+        let (len, rest) = if len & 0x80 == 0 {
+            (*len as usize, rest)
+        } else {
+            let n = (len & 0x7f) as usize;
+            if n == 0 || n > 4 || rest.len() < n {
+                return Err(invalid());
+            }
+            let len = rest[..n]
+                .iter()
+                .fold(0_usize, |acc, b| (acc << 8) | *b as usize);
+            (len, &rest[n..])
+        };
+        if rest.len() < len {
+            return Err(invalid());
+        }
+        let (value, rest) = rest.split_at(len);
+        self.0 = rest;
+        Ok((*tag, value))
+    }
+
+    fn expect(&mut self, tag: u8) -> Result<&'a [u8]> {
+        match self.read()? {
+            (t, value) if t == tag => Ok(value),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+fn integer(value: &[u8]) -> Result<u16> {
+    let value = match value {
+        [0, rest @ ..] => rest,
+        _ => value,
+    };
+    if value.len() > 2 {
+        return Err(invalid());
+    }
+    Ok(value.iter().fold(0, |acc, b| (acc << 8) | *b as u16))
+}
+
+/// The fields of a certificate the verifier needs beyond what webpki checks.
+pub(super) struct CertFields<'a> {
+    /// The uncompressed EC public key, `04 || x || y`.
+    pub public_key: &'a [u8],
+    /// The value of the SGX extensions, if present.
+    pub sgx_extensions: Option<&'a [u8]>,
+}
+
+pub(super) fn parse_cert(der: &[u8]) -> Result<CertFields<'_>> {
+    let cert = Reader(der).expect(SEQUENCE)?;
+    let mut tbs = Reader(Reader(cert).expect(SEQUENCE)?);
+    let (tag, _) = tbs.read()?;
+    if tag == EXPLICIT_VERSION {
+        tbs.expect(INTEGER)?;
+    }
+    // Signature algorithm, issuer, validity and subject.
+    for _ in 0..4 {
+        tbs.expect(SEQUENCE)?;
+    }
+    let mut spki = Reader(tbs.expect(SEQUENCE)?);
+    spki.expect(SEQUENCE)?;
+    let public_key = match spki.expect(BIT_STRING)? {
+        [0, key @ ..] => key,
+        _ => return Err(invalid()),
+    };
+    let mut sgx_extensions = None;
+    while !tbs.is_empty() {
+        let (tag, value) = tbs.read()?;
+        if tag != EXPLICIT_EXTENSIONS {
+            continue;
+        }
+        let mut extensions = Reader(Reader(value).expect(SEQUENCE)?);
+        while !extensions.is_empty() {
+            let mut extension = Reader(extensions.expect(SEQUENCE)?);
+            let oid = extension.expect(OID)?;
+            let (mut tag, mut value) = extension.read()?;
+            if tag == BOOLEAN {
+                (tag, value) = extension.read()?;
+            }
+            if tag != OCTET_STRING {
+                return Err(invalid());
+            }
+            if oid == SGX_EXTENSIONS_OID {
+                sgx_extensions = Some(value);
+            }
+        }
+    }
+    Ok(CertFields {
+        public_key,
+        sgx_extensions,
+    })
+}
+
+/// The platform identity and TCB recorded in a PCK certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct SgxExtensions {
+    pub cpu_svn_components: [u8; 16],
+    pub pce_svn: u16,
+    pub pce_id: [u8; 2],
+    pub fmspc: [u8; 6],
+}
+
+pub(super) fn parse_sgx_extensions(value: &[u8]) -> Result<SgxExtensions> {
+    let mut ext = SgxExtensions {
+        cpu_svn_components: [0; 16],
+        pce_svn: 0,
+        pce_id: [0; 2],
+        fmspc: [0; 6],
+    };
+    let (mut has_tcb, mut has_pce_id, mut has_fmspc) = (false, false, false);
+    let mut items = Reader(Reader(value).expect(SEQUENCE)?);
+    while !items.is_empty() {
+        let mut item = Reader(items.expect(SEQUENCE)?);
+        let oid = item.expect(OID)?;
+        let Some(arc) = oid.strip_prefix(SGX_EXTENSIONS_OID) else {
+            continue;
+        };
+        match arc {
+            [0x02] => {
+                let mut components = Reader(item.expect(SEQUENCE)?);
+                while !components.is_empty() {
+                    let mut component = Reader(components.expect(SEQUENCE)?);
+                    let oid = component.expect(OID)?;
+                    match oid.strip_prefix(SGX_EXTENSIONS_OID) {
+                        Some([0x02, n @ 1..=16]) => {
+                            let svn = integer(component.expect(INTEGER)?)?;
+                            ext.cpu_svn_components[*n as usize - 1] =
+                                svn.try_into().or(Err(invalid()))?;
+                        }
+                        Some([0x02, 17]) => ext.pce_svn = integer(component.expect(INTEGER)?)?,
+                        _ => {}
+                    }
+                }
+                has_tcb = true;
+            }
+            [0x03] => {
+                ext.pce_id = item.expect(OCTET_STRING)?.try_into().or(Err(invalid()))?;
+                has_pce_id = true;
+            }
+            [0x04] => {
+                ext.fmspc = item.expect(OCTET_STRING)?.try_into().or(Err(invalid()))?;
+                has_fmspc = true;
+            }
+            _ => {}
+        }
+    }
+    if !(has_tcb && has_pce_id && has_fmspc) {
+        return Err(Error::InvalidCertificate("incomplete SGX extensions"));
+    }
+    Ok(ext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_long_form_length() {
+        let mut data = vec![OCTET_STRING, 0x81, 200];
+        data.extend([7; 200]);
+        data.extend([INTEGER, 1, 5]);
+        let mut reader = Reader(&data);
+        assert_eq!(reader.expect(OCTET_STRING).unwrap(), &[7; 200][..]);
+        assert_eq!(integer(reader.expect(INTEGER).unwrap()).unwrap(), 5);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn reject_truncated_element() {
+        let data = [SEQUENCE, 4, 0, 0];
+        assert!(Reader(&data).read().is_err());
+    }
+
+    #[test]
+    fn parse_sgx_extension_values() {
+        fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+            let len = value.len() as u16;
+            let mut out = match len {
+                0..=127 => vec![tag, len as u8],
+                _ => vec![tag, 0x82, (len >> 8) as u8, len as u8],
+            };
+            out.extend_from_slice(value);
+            out
+        }
+        fn oid(arcs: &[u8]) -> Vec<u8> {
+            tlv(OID, &[SGX_EXTENSIONS_OID, arcs].concat())
+        }
+        let mut components = vec![];
+        for n in 1..=16u8 {
+            components.extend(tlv(SEQUENCE, &[oid(&[2, n]), tlv(INTEGER, &[n])].concat()));
+        }
+        components.extend(tlv(
+            SEQUENCE,
+            &[oid(&[2, 17]), tlv(INTEGER, &[0, 0x80])].concat(),
+        ));
+        let items = [
+            tlv(SEQUENCE, &[oid(&[1]), tlv(OCTET_STRING, &[9; 16])].concat()),
+            tlv(SEQUENCE, &[oid(&[2]), tlv(SEQUENCE, &components)].concat()),
+            tlv(SEQUENCE, &[oid(&[3]), tlv(OCTET_STRING, &[0, 0])].concat()),
+            tlv(
+                SEQUENCE,
+                &[oid(&[4]), tlv(OCTET_STRING, &[1, 2, 3, 4, 5, 6])].concat(),
+            ),
+        ]
+        .concat();
+        let ext = parse_sgx_extensions(&tlv(SEQUENCE, &items)).unwrap();
+        assert_eq!(ext.cpu_svn_components[0], 1);
+        assert_eq!(ext.cpu_svn_components[15], 16);
+        assert_eq!(ext.pce_svn, 0x80);
+        assert_eq!(ext.fmspc, [1, 2, 3, 4, 5, 6]);
+    }
+}
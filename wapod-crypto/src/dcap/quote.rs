@@ -0,0 +1,207 @@
+//! Parsing of version 3 SGX quotes signed with ECDSA P-256.
+
+use super::Error;
+
+type Result<T> = core::result::Result<T, Error>;
+
+pub(super) const HEADER_LEN: usize = 48;
+pub(super) const REPORT_LEN: usize = 384;
+
+const QUOTE_VERSION: u16 = 3;
+const ATTESTATION_KEY_TYPE_ECDSA_P256: u16 = 2;
+const TEE_TYPE_SGX: u32 = 0;
+pub(super) const INTEL_QE_VENDOR_ID: [u8; 16] = [
+    0x93, 0x9a, 0x72, 0x33, 0xf7, 0x9c, 0x4c, 0xa9, 0x94, 0x0a, 0x0d, 0xb3, 0x95, 0x7f, 0x06, 0x07,
+];
+/// Certification data holding the PEM encoded PCK certificate chain.
+pub(super) const CERT_DATA_PCK_CHAIN: u16 = 5;
+
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(Error::InvalidQuote("unexpected end of quote"));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().expect("length checked"))
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+}
+
+/// The report of an enclave, either the attested app enclave or the quoting enclave.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnclaveReport {
+    pub cpu_svn: [u8; 16],
+    pub misc_select: u32,
+    pub attributes: [u8; 16],
+    pub mr_enclave: [u8; 32],
+    pub mr_signer: [u8; 32],
+    pub isv_prod_id: u16,
+    pub isv_svn: u16,
+    pub report_data: [u8; 64],
+}
+
+impl EnclaveReport {
+    fn parse(raw: &[u8]) -> Result<Self> {
+        let mut cursor = Cursor(raw);
+        let cpu_svn = cursor.array()?;
+        let misc_select = cursor.u32()?;
+        cursor.take(28)?;
+        let attributes = cursor.array()?;
+        let mr_enclave = cursor.array()?;
+        cursor.take(32)?;
+        let mr_signer = cursor.array()?;
+        cursor.take(96)?;
+        let isv_prod_id = cursor.u16()?;
+        let isv_svn = cursor.u16()?;
+        cursor.take(60)?;
+        let report_data = cursor.array()?;
+        Ok(Self {
+            cpu_svn,
+            misc_select,
+            attributes,
+            mr_enclave,
+            mr_signer,
+            isv_prod_id,
+            isv_svn,
+            report_data,
+        })
+    }
+
+    /// Whether the enclave runs in debug mode, in which its memory is not protected.
+    pub fn is_debug(&self) -> bool {
+        self.attributes[0] & 0x02 != 0
+    }
+}
+
+pub(super) struct Quote<'a> {
+    /// The header and the app enclave report, signed by the attestation key.
+    pub signed_data: &'a [u8],
+    pub report: EnclaveReport,
+    pub report_signature: &'a [u8],
+    pub attestation_key: &'a [u8],
+    /// The raw quoting enclave report, signed by the PCK.
+    pub qe_report_raw: &'a [u8],
+    pub qe_report: EnclaveReport,
+    pub qe_report_signature: &'a [u8],
+    pub qe_auth_data: &'a [u8],
+    pub cert_data_type: u16,
+    pub cert_data: &'a [u8],
+}
+
+impl<'a> Quote<'a> {
+    pub fn parse(raw: &'a [u8]) -> Result<Self> {
+        let mut cursor = Cursor(raw);
+        let version = cursor.u16()?;
+        let attestation_key_type = cursor.u16()?;
+        let tee_type = cursor.u32()?;
+        if version != QUOTE_VERSION
+            || attestation_key_type != ATTESTATION_KEY_TYPE_ECDSA_P256
+            || tee_type != TEE_TYPE_SGX
+        {
+            return Err(Error::UnsupportedQuote);
+        }
+        // QE SVN and PCE SVN, which are checked through the QE report and the PCK certificate.
+        cursor.take(4)?;
+        let qe_vendor_id: [u8; 16] = cursor.array()?;
+        if qe_vendor_id != INTEL_QE_VENDOR_ID {
+            return Err(Error::UnsupportedQuote);
+        }
+        cursor.take(20)?;
+        let report = EnclaveReport::parse(cursor.take(REPORT_LEN)?)?;
+        let signed_data = &raw[..HEADER_LEN + REPORT_LEN];
+
+        let signature_len = cursor.u32()? as usize;
+        let mut cursor = Cursor(cursor.take(signature_len)?);
+        let report_signature = cursor.take(64)?;
+        let attestation_key = cursor.take(64)?;
+        let qe_report_raw = cursor.take(REPORT_LEN)?;
+        let qe_report = EnclaveReport::parse(qe_report_raw)?;
+        let qe_report_signature = cursor.take(64)?;
+        let qe_auth_data_len = cursor.u16()? as usize;
+        let qe_auth_data = cursor.take(qe_auth_data_len)?;
+        let cert_data_type = cursor.u16()?;
+        let cert_data_len = cursor.u32()? as usize;
+        let cert_data = cursor.take(cert_data_len)?;
+        Ok(Self {
+            signed_data,
+            report,
+            report_signature,
+            attestation_key,
+            qe_report_raw,
+            qe_report,
+            qe_report_signature,
+            qe_auth_data,
+            cert_data_type,
+            cert_data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_quote(cert_data: &[u8]) -> Vec<u8> {
+        let mut quote = vec![];
+        quote.extend(QUOTE_VERSION.to_le_bytes());
+        quote.extend(ATTESTATION_KEY_TYPE_ECDSA_P256.to_le_bytes());
+        quote.extend(TEE_TYPE_SGX.to_le_bytes());
+        quote.extend([0; 4]);
+        quote.extend(INTEL_QE_VENDOR_ID);
+        quote.extend([0; 20]);
+        let mut report = [0u8; REPORT_LEN];
+        report[64..96].copy_from_slice(&[1; 32]);
+        report[128..160].copy_from_slice(&[2; 32]);
+        report[256..258].copy_from_slice(&7u16.to_le_bytes());
+        report[320..384].copy_from_slice(&[3; 64]);
+        quote.extend(report);
+        let mut signature = vec![];
+        signature.extend([4; 64]);
+        signature.extend([5; 64]);
+        signature.extend([0; REPORT_LEN]);
+        signature.extend([6; 64]);
+        signature.extend(2u16.to_le_bytes());
+        signature.extend([8; 2]);
+        signature.extend(CERT_DATA_PCK_CHAIN.to_le_bytes());
+        signature.extend((cert_data.len() as u32).to_le_bytes());
+        signature.extend(cert_data);
+        quote.extend((signature.len() as u32).to_le_bytes());
+        quote.extend(signature);
+        quote
+    }
+
+    #[test]
+    fn parse_quote() {
+        let raw = build_quote(b"certs");
+        let quote = Quote::parse(&raw).unwrap();
+        assert_eq!(quote.signed_data.len(), HEADER_LEN + REPORT_LEN);
+        assert_eq!(quote.report.mr_enclave, [1; 32]);
+        assert_eq!(quote.report.mr_signer, [2; 32]);
+        assert_eq!(quote.report.isv_prod_id, 7);
+        assert_eq!(quote.report.report_data, [3; 64]);
+        assert_eq!(quote.attestation_key, &[5; 64][..]);
+        assert_eq!(quote.qe_auth_data, &[8; 2][..]);
+        assert_eq!(quote.cert_data_type, CERT_DATA_PCK_CHAIN);
+        assert_eq!(quote.cert_data, b"certs");
+    }
+
+    #[test]
+    fn reject_truncated_quote() {
+        let raw = build_quote(b"certs");
+        assert!(Quote::parse(&raw[..raw.len() - 1]).is_err());
+    }
+}
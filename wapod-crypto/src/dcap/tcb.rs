@@ -0,0 +1,158 @@
+//! The TCB info and QE identity collateral, as returned by PCCS.
+
+use serde::Deserialize;
+
+use super::der::SgxExtensions;
+use super::quote::EnclaveReport;
+use super::Error;
+
+type Result<T> = core::result::Result<T, Error>;
+
+fn parse_time(time: &str) -> Result<i64> {
+    chrono::DateTime::parse_from_rfc3339(time)
+        .map(|time| time.timestamp())
+        .or(Err(Error::InvalidCollateral("invalid date")))
+}
+
+fn check_validity(issue_date: &str, next_update: &str, now: u64) -> Result<()> {
+    let now = now as i64;
+    if parse_time(issue_date)? > now || parse_time(next_update)? < now {
+        return Err(Error::CollateralExpired);
+    }
+    Ok(())
+}
+
+fn decode_hex<const N: usize>(hex_str: &str) -> Result<[u8; N]> {
+    let mut out = [0u8; N];
+    hex::decode_to_slice(hex_str, &mut out)
+        .or(Err(Error::InvalidCollateral("invalid hex value")))?;
+    Ok(out)
+}
+
+/// The status of a TCB level, e.g. "UpToDate", "SWHardeningNeeded" or "OutOfDate".
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct TcbStatus {
+    pub tcb_status: String,
+    #[serde(rename = "advisoryIDs", default)]
+    pub advisory_ids: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct TcbInfo {
+    version: u32,
+    issue_date: String,
+    next_update: String,
+    fmspc: String,
+    pce_id: String,
+    tcb_levels: Vec<TcbLevel>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TcbLevel {
+    tcb: Tcb,
+    #[serde(flatten)]
+    status: TcbStatus,
+}
+
+#[derive(Deserialize, Debug)]
+struct Tcb {
+    sgxtcbcomponents: Vec<TcbComponent>,
+    pcesvn: u16,
+}
+
+#[derive(Deserialize, Debug)]
+struct TcbComponent {
+    svn: u8,
+}
+
+impl TcbInfo {
+    /// Finds the status of the platform described by the PCK certificate.
+    pub fn platform_status(&self, platform: &SgxExtensions, now: u64) -> Result<&TcbStatus> {
+        if self.version != 3 {
+            return Err(Error::InvalidCollateral("unsupported TCB info version"));
+        }
+        check_validity(&self.issue_date, &self.next_update, now)?;
+        if decode_hex::<6>(&self.fmspc)? != platform.fmspc
+            || decode_hex::<2>(&self.pce_id)? != platform.pce_id
+        {
+            return Err(Error::InvalidCollateral("TCB info is for another platform"));
+        }
+        // Levels are sorted from the newest to the oldest; the first one the platform meets
+        // applies.
+        self.tcb_levels
+            .iter()
+            .find(|level| {
+                level.tcb.sgxtcbcomponents.len() == 16
+                    && level
+                        .tcb
+                        .sgxtcbcomponents
+                        .iter()
+                        .zip(platform.cpu_svn_components)
+                        .all(|(component, svn)| component.svn <= svn)
+                    && level.tcb.pcesvn <= platform.pce_svn
+            })
+            .map(|level| &level.status)
+            .ok_or(Error::TcbLevelNotFound)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct QeIdentity {
+    version: u32,
+    issue_date: String,
+    next_update: String,
+    miscselect: String,
+    miscselect_mask: String,
+    attributes: String,
+    attributes_mask: String,
+    mrsigner: String,
+    isvprodid: u16,
+    tcb_levels: Vec<QeTcbLevel>,
+}
+
+#[derive(Deserialize, Debug)]
+struct QeTcbLevel {
+    tcb: QeTcb,
+    #[serde(flatten)]
+    status: TcbStatus,
+}
+
+#[derive(Deserialize, Debug)]
+struct QeTcb {
+    isvsvn: u16,
+}
+
+impl QeIdentity {
+    /// Checks that the report is from a genuine quoting enclave, and finds its status.
+    pub fn qe_status(&self, report: &EnclaveReport, now: u64) -> Result<&TcbStatus> {
+        if self.version != 2 {
+            return Err(Error::InvalidCollateral("unsupported QE identity version"));
+        }
+        check_validity(&self.issue_date, &self.next_update, now)?;
+        let misc_select = u32::from_be_bytes(decode_hex(&self.miscselect)?);
+        let misc_select_mask = u32::from_be_bytes(decode_hex(&self.miscselect_mask)?);
+        let attributes: [u8; 16] = decode_hex(&self.attributes)?;
+        let attributes_mask: [u8; 16] = decode_hex(&self.attributes_mask)?;
+        let attributes_match = report
+            .attributes
+            .iter()
+            .zip(attributes_mask)
+            .zip(attributes)
+            .all(|((actual, mask), expected)| actual & mask == expected);
+        if decode_hex::<32>(&self.mrsigner)? != report.mr_signer
+            || self.isvprodid != report.isv_prod_id
+            || report.misc_select & misc_select_mask != misc_select
+            || !attributes_match
+        {
+            return Err(Error::UntrustedQuotingEnclave);
+        }
+        self.tcb_levels
+            .iter()
+            .find(|level| level.tcb.isvsvn <= report.isv_svn)
+            .map(|level| &level.status)
+            .ok_or(Error::TcbLevelNotFound)
+    }
+}
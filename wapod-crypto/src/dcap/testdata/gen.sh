@@ -0,0 +1,129 @@
+#!/bin/sh
+# Generates a test PKI shaped like the Intel SGX one, for the tests of `dcap::verify`:
+#
+# - root.der, root_crl.der: the root CA and its CRL,
+# - pck_ca.pem, pck_crl.der, pck_crl_revoked.der: the PCK platform CA and its CRLs, the
+#   second one revoking the PCK certificate,
+# - pck.pem, pck.pk8: a PCK certificate with SGX extensions and its key,
+# - tcb_signing.pem, tcb_signing.pk8: the TCB signing certificate and its key.
+#
+# The platform in the PCK certificate has all CPU SVN components at 2, PCE SVN 10,
+# PCE ID 0000 and FMSPC 00906ED50000.
+set -e
+cd "$(dirname "$0")"
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+cat > "$work/ext.cnf" <<CNF
+[ca]
+basicConstraints = critical, CA:TRUE, pathlen:1
+keyUsage = critical, keyCertSign, cRLSign
+subjectKeyIdentifier = hash
+
+[pck_ca]
+basicConstraints = critical, CA:TRUE, pathlen:0
+keyUsage = critical, keyCertSign, cRLSign
+subjectKeyIdentifier = hash
+authorityKeyIdentifier = keyid
+
+[tcb_signing]
+basicConstraints = critical, CA:FALSE
+keyUsage = critical, digitalSignature, nonRepudiation
+authorityKeyIdentifier = keyid
+
+[pck]
+basicConstraints = critical, CA:FALSE
+keyUsage = critical, digitalSignature, nonRepudiation
+authorityKeyIdentifier = keyid
+1.2.840.113741.1.13.1 = ASN1:SEQUENCE:sgx
+
+[sgx]
+ppid = SEQUENCE:ppid
+tcb = SEQUENCE:tcb
+pce_id = SEQUENCE:pce_id
+fmspc = SEQUENCE:fmspc
+
+[ppid]
+oid = OID:1.2.840.113741.1.13.1.1
+value = FORMAT:HEX,OCTETSTRING:00112233445566778899AABBCCDDEEFF
+
+[tcb]
+oid = OID:1.2.840.113741.1.13.1.2
+components = SEQUENCE:components
+
+[pce_id]
+oid = OID:1.2.840.113741.1.13.1.3
+value = FORMAT:HEX,OCTETSTRING:0000
+
+[fmspc]
+oid = OID:1.2.840.113741.1.13.1.4
+value = FORMAT:HEX,OCTETSTRING:00906ED50000
+
+[components]
+CNF
+for n in $(seq 1 17); do
+    echo "c$n = SEQUENCE:c$n" >> "$work/ext.cnf"
+done
+for n in $(seq 1 17); do
+    svn=2
+    [ "$n" = 17 ] && svn=10
+    printf '[c%s]\noid = OID:1.2.840.113741.1.13.1.2.%s\nsvn = INTEGER:%s\n' "$n" "$n" "$svn" \
+        >> "$work/ext.cnf"
+done
+
+ca_config() {
+    mkdir -p "$work/$1"
+    : > "$work/$1/index.txt"
+    echo 1000 > "$work/$1/crlnumber"
+    cat > "$work/$1/ca.cnf" <<CNF
+[ca]
+default_ca = ca
+[ca]
+database = $work/$1/index.txt
+crlnumber = $work/$1/crlnumber
+default_md = sha256
+default_crl_days = 9000
+CNF
+}
+
+key() {
+    openssl ecparam -name prime256v1 -genkey -noout -out "$work/$1.key"
+}
+
+cert() {
+    name=$1 issuer=$2 subject=$3 section=$4
+    openssl req -new -key "$work/$name.key" -subj "$subject" -out "$work/$name.csr"
+    openssl x509 -req -in "$work/$name.csr" -CA "$work/$issuer.pem" -CAkey "$work/$issuer.key" \
+        -set_serial "0x$(openssl rand -hex 16)" -days 9000 -sha256 \
+        -extfile "$work/ext.cnf" -extensions "$section" -out "$work/$name.pem"
+}
+
+crl() {
+    openssl ca -gencrl -config "$work/$1/ca.cnf" -cert "$work/$1.pem" -keyfile "$work/$1.key" \
+        -out "$work/$2.pem"
+    openssl crl -in "$work/$2.pem" -outform DER -out "$2.der"
+}
+
+key root
+openssl req -new -x509 -key "$work/root.key" -subj "/CN=Test SGX Root CA/O=wapod" -days 9000 \
+    -sha256 -config "$work/ext.cnf" -extensions ca -out "$work/root.pem"
+key pck_ca
+cert pck_ca root "/CN=Test SGX PCK Platform CA/O=wapod" pck_ca
+key tcb_signing
+cert tcb_signing root "/CN=Test SGX TCB Signing/O=wapod" tcb_signing
+key pck
+cert pck pck_ca "/CN=Test SGX PCK Certificate/O=wapod" pck
+
+ca_config root
+crl root root_crl
+ca_config pck_ca
+crl pck_ca pck_crl
+openssl ca -config "$work/pck_ca/ca.cnf" -cert "$work/pck_ca.pem" -keyfile "$work/pck_ca.key" \
+    -revoke "$work/pck.pem"
+crl pck_ca pck_crl_revoked
+
+openssl x509 -in "$work/root.pem" -outform DER -out root.der
+cp "$work/pck_ca.pem" "$work/pck.pem" "$work/tcb_signing.pem" .
+for name in pck tcb_signing; do
+    openssl pkcs8 -topk8 -nocrypt -in "$work/$name.key" -outform DER -out "$name.pk8"
+done
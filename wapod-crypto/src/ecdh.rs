@@ -22,22 +22,42 @@ impl Pair {
 
     // Encrypts a message using ECDH shared secret
     pub fn encrypt_message(&self, receiver: &[u8], message: &[u8]) -> Result<Vec<u8>, Error> {
+        self.encrypt_message_with_aad(receiver, message, &[])
+    }
+
+    // Encrypts a message using ECDH shared secret, authenticating the aad along with it
+    pub fn encrypt_message_with_aad(
+        &self,
+        receiver: &[u8],
+        message: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, Error> {
         let shared_secret = self.ecdh_agree(receiver)?;
         let iv = aead::generate_iv();
         let mut message = message.to_vec();
-        aead::encrypt(&iv, &shared_secret, &mut message)?;
+        aead::encrypt_with_aad(&iv, &shared_secret, aad, &mut message)?;
         Ok([&iv[..], &message].concat())
     }
 
     // Decrypts a message using ECDH shared secret
     pub fn decrypt_message(&self, sender: &[u8], message: &mut [u8]) -> Result<Vec<u8>, Error> {
+        self.decrypt_message_with_aad(sender, message, &[])
+    }
+
+    // Decrypts a message encrypted with the given aad using ECDH shared secret
+    pub fn decrypt_message_with_aad(
+        &self,
+        sender: &[u8],
+        message: &mut [u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, Error> {
         if message.len() < aead::IV_BYTES {
             return Err(Error::InvalidMessage);
         }
         let shared_secret = self.ecdh_agree(sender)?;
         let (iv, body) = message.split_at_mut(aead::IV_BYTES);
         let iv = iv.try_into().expect("IV length is correct");
-        let decrypted = aead::decrypt(iv, &shared_secret, body)?;
+        let decrypted = aead::decrypt_with_aad(iv, &shared_secret, aad, body)?;
         Ok(decrypted.to_vec())
     }
 }
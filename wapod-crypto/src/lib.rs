@@ -1,5 +1,6 @@
 pub use wapod_types::{self, crypto::CryptoProvider, ContentType};
 pub mod aead;
+pub mod dcap;
 pub mod sr25519;
 pub use error::Error;
 pub use provider::SpCoreHash;
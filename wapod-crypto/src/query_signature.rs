@@ -82,6 +82,18 @@ impl Signer for RootOrCertificate {
     }
 }
 
+/// Verifies an admin request signed by a root signer and returns the account of the signer.
+///
+/// EIP712 signatures are not supported for admin requests.
+pub fn verify_admin_request(
+    signer: &RootSigner,
+    request: &AdminRequest,
+    signature: &[u8],
+    sig_type: SignatureType,
+) -> Result<AccountId> {
+    non_eip712_verify(&signer.pubkey, &request.encode(), signature, sig_type, 3)
+}
+
 #[derive(Clone, Debug)]
 pub enum SignatureVerifyError {
     InvalidSignatureType,
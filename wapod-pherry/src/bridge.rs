@@ -185,6 +185,7 @@ impl BridgeState {
             .worker_init(InitArgs {
                 pnonce: pnonce.to_vec(),
                 recipient: self.config.recipient.clone(),
+                operators: vec![],
             })
             .await?;
         let update = response.decode_session_update()?;
@@ -12,7 +12,10 @@ use phaxt::{
 use serde::Deserialize;
 use tracing::info;
 use wapod_rpc::prpc::SignWorkerDescriptionArgs;
-use wapod_types::{ticket::AppManifest, Address};
+use wapod_types::{
+    ticket::{AppManifest, Mount, Schedule, ScratchDir, SecretEnvVar},
+    Address,
+};
 
 use crate::{
     chain_state::ChainClient,
@@ -30,6 +33,26 @@ struct DeployConfig {
     max_query_size: u32,
     label: String,
     deps: Vec<String>,
+    #[serde(default)]
+    schedules: Vec<Schedule>,
+    #[serde(default)]
+    mounts: Vec<MountConfig>,
+    #[serde(default)]
+    scratch: Option<ScratchDir>,
+    #[serde(default)]
+    secret_env_vars: Vec<SecretEnvVar>,
+    #[serde(default)]
+    domains: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct MountConfig {
+    /// The path to mount at in the app's filesystem.
+    path: String,
+    /// The local file to mount, relative to the config file.
+    file: PathBuf,
+    #[serde(default)]
+    format: String,
 }
 
 fn sha256_hash(data: &[u8]) -> String {
@@ -96,6 +119,18 @@ pub fn build_manifest(config_file: impl AsRef<Path>) -> Result<(Address, String)
         deps.insert(dep_hash, dep_cid);
     }
 
+    let mut mounts = vec![];
+    for mount in config.mounts {
+        let data = read_file(base_dir.join(&mount.file)).context("failed to read mount file")?;
+        let (hash, cid) = hash(&data, &blobs_dir)?;
+        mounts.push(Mount {
+            path: mount.path,
+            blob: hash.clone(),
+            format: mount.format,
+        });
+        deps.insert(hash, cid);
+    }
+
     let manifest = AppManifest {
         version: 1,
         code_hash,
@@ -106,6 +141,11 @@ pub fn build_manifest(config_file: impl AsRef<Path>) -> Result<(Address, String)
         max_query_size: config.max_query_size,
         label: config.label,
         required_blobs: deps.into_iter().collect(),
+        schedules: config.schedules,
+        mounts,
+        scratch: config.scratch,
+        secret_env_vars: config.secret_env_vars,
+        domains: config.domains,
     };
     let address = manifest.address(sp_core::hashing::blake2_256);
 
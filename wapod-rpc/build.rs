@@ -11,7 +11,14 @@ fn main() {
         "#[derive(::serde::Serialize, ::serde::Deserialize)]",
     );
     builder = builder.field_attribute(".wapod", "#[serde(default)]");
-    for t in &[".wapod.Manifest", ".wapod.StringPair"] {
+    for t in &[
+        ".wapod.Manifest",
+        ".wapod.StringPair",
+        ".wapod.Schedule",
+        ".wapod.Mount",
+        ".wapod.ScratchDir",
+        ".wapod.SecretEnvVar",
+    ] {
         builder = builder.type_attribute(t, "#[derive(::scale::Encode, ::scale::Decode)]");
     }
     builder
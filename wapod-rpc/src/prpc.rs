@@ -2,7 +2,10 @@ pub use ::prpc::*;
 pub use generated::*;
 mod generated;
 
-use wapod_types::ticket::AppManifest;
+use wapod_types::ticket::{
+    AppManifest, Mount as ManifestMount, Schedule as ManifestSchedule,
+    ScratchDir as ManifestScratchDir, SecretEnvVar as ManifestSecretEnvVar,
+};
 
 impl From<Manifest> for AppManifest {
     fn from(other: Manifest) -> Self {
@@ -16,6 +19,11 @@ impl From<Manifest> for AppManifest {
             max_query_size: other.max_query_size,
             label: other.label,
             required_blobs: other.required_blobs.into_iter().map(Into::into).collect(),
+            schedules: other.schedules.into_iter().map(Into::into).collect(),
+            mounts: other.mounts.into_iter().map(Into::into).collect(),
+            scratch: other.scratch.map(Into::into),
+            secret_env_vars: other.secret_env_vars.into_iter().map(Into::into).collect(),
+            domains: other.domains,
         }
     }
 }
@@ -32,6 +40,99 @@ impl From<AppManifest> for Manifest {
             max_query_size: other.max_query_size,
             label: other.label,
             required_blobs: other.required_blobs.into_iter().map(Into::into).collect(),
+            schedules: other.schedules.into_iter().map(Into::into).collect(),
+            mounts: other.mounts.into_iter().map(Into::into).collect(),
+            scratch: other.scratch.map(Into::into),
+            secret_env_vars: other.secret_env_vars.into_iter().map(Into::into).collect(),
+            domains: other.domains,
+        }
+    }
+}
+
+impl From<Schedule> for ManifestSchedule {
+    fn from(other: Schedule) -> Self {
+        ManifestSchedule {
+            name: other.name,
+            cron: other.cron,
+            interval_secs: other.interval_secs,
+            jitter_secs: other.jitter_secs,
+            catch_up: other.catch_up,
+            payload: other.payload,
+        }
+    }
+}
+
+impl From<ManifestSchedule> for Schedule {
+    fn from(other: ManifestSchedule) -> Self {
+        Schedule {
+            name: other.name,
+            cron: other.cron,
+            interval_secs: other.interval_secs,
+            jitter_secs: other.jitter_secs,
+            catch_up: other.catch_up,
+            payload: other.payload,
+        }
+    }
+}
+
+impl From<Mount> for ManifestMount {
+    fn from(other: Mount) -> Self {
+        ManifestMount {
+            path: other.path,
+            blob: other.blob,
+            format: other.format,
+        }
+    }
+}
+
+impl From<ManifestMount> for Mount {
+    fn from(other: ManifestMount) -> Self {
+        Mount {
+            path: other.path,
+            blob: other.blob,
+            format: other.format,
+        }
+    }
+}
+
+impl From<ScratchDir> for ManifestScratchDir {
+    fn from(other: ScratchDir) -> Self {
+        ManifestScratchDir {
+            path: other.path,
+            quota: other.quota,
+            wipe_on_restart: other.wipe_on_restart,
+        }
+    }
+}
+
+impl From<ManifestScratchDir> for ScratchDir {
+    fn from(other: ManifestScratchDir) -> Self {
+        ScratchDir {
+            path: other.path,
+            quota: other.quota,
+            wipe_on_restart: other.wipe_on_restart,
+        }
+    }
+}
+
+impl From<SecretEnvVar> for ManifestSecretEnvVar {
+    fn from(other: SecretEnvVar) -> Self {
+        ManifestSecretEnvVar {
+            name: other.name,
+            pubkey: other.pubkey,
+            encrypted_value: other.encrypted_value,
+            app_key: other.app_key,
+        }
+    }
+}
+
+impl From<ManifestSecretEnvVar> for SecretEnvVar {
+    fn from(other: ManifestSecretEnvVar) -> Self {
+        SecretEnvVar {
+            name: other.name,
+            pubkey: other.pubkey,
+            encrypted_value: other.encrypted_value,
+            app_key: other.app_key,
         }
     }
 }
@@ -17,6 +17,20 @@ pub struct Query {
     pub payload: Vec<u8>,
 }
 
+/// A request to the admin API of a worker, signed by an operator key.
+#[derive(Clone, Encode, Decode)]
+pub struct AdminRequest {
+    /// The public key of the worker the request is sent to.
+    pub worker: [u8; 32],
+    /// The path of the request, including the query string if any.
+    pub path: String,
+    /// The time the request is signed, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// A random nonce, which is never reused by the signer.
+    pub nonce: Vec<u8>,
+    /// The request body.
+    pub body: Vec<u8>,
+}
 
 /// A signature signed by a worker for query response.
 #[derive(Encode, Decode, Clone)]
@@ -47,12 +47,145 @@ pub struct AppManifest {
     ///
     /// Pair of (hash, cid).
     pub required_blobs: Vec<(String, String)>,
+    /// Scheduled queries that the worker sends to the app.
+    #[serde(default)]
+    pub schedules: Vec<Schedule>,
+    /// Blobs exposed to the app as a read-only filesystem.
+    #[serde(default)]
+    pub mounts: Vec<Mount>,
+    /// A writable directory for the app, encrypted at rest.
+    #[serde(default)]
+    pub scratch: Option<ScratchDir>,
+    /// Environment variables whose values are only readable by the worker.
+    #[serde(default)]
+    pub secret_env_vars: Vec<SecretEnvVar>,
+    /// Domains whose HTTP requests on the user port are routed to the app.
+    ///
+    /// A domain is bound when the app is deployed if a TXT record at `_wapod.<domain>` reads
+    /// `wapod-app=<hex address of the app>`.
+    #[serde(default)]
+    pub domains: Vec<String>,
+}
+
+/// The path prefix of scheduled queries. The full path is the prefix followed by the schedule name.
+pub const SCHEDULE_PATH_PREFIX: &str = "/_wapod/schedule/";
+
+/// A periodic query that the worker sends to an app.
+#[derive(Decode, Encode, TypeInfo, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    /// The name of the schedule, appended to [`SCHEDULE_PATH_PREFIX`] as the query path.
+    pub name: String,
+    /// A cron expression in UTC, e.g. "*/10 * * * *". Takes precedence over `interval_secs`.
+    #[serde(default)]
+    pub cron: String,
+    /// Run the schedule every given number of seconds if `cron` is empty.
+    #[serde(default)]
+    pub interval_secs: u64,
+    /// The maximum random delay in seconds added to each run.
+    #[serde(default)]
+    pub jitter_secs: u32,
+    /// Whether to run once more right after a run that outlasted the next scheduled time.
+    ///
+    /// If false, the runs missed during a long run are skipped.
+    #[serde(default)]
+    pub catch_up: bool,
+    /// The payload of the scheduled query.
+    #[serde(default)]
+    pub payload: Vec<u8>,
+}
+
+/// A blob mounted into the app's filesystem.
+#[derive(Decode, Encode, TypeInfo, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mount {
+    /// The absolute path to mount at, e.g. "/models/weights.bin".
+    pub path: String,
+    /// The hash or CID of the blob, which must be listed in `required_blobs`.
+    pub blob: String,
+    /// "file" (the default) to mount the blob as a single file, or "tar" or "zip" to mount the
+    /// contents of the archive as a directory.
+    #[serde(default)]
+    pub format: String,
+}
+
+/// A writable directory in the app's filesystem.
+#[derive(Decode, Encode, TypeInfo, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScratchDir {
+    /// The absolute path of the directory, e.g. "/data".
+    pub path: String,
+    /// Maximum total size of the files in bytes.
+    pub quota: u64,
+    /// Whether to start each instance with an empty directory. Otherwise the files persist
+    /// across restarts of the app.
+    #[serde(default)]
+    pub wipe_on_restart: bool,
+}
+
+/// An environment variable with the value encrypted to the worker.
+///
+/// The value is encrypted with AES-GCM using the ECDH shared secret of `pubkey` and either the
+/// worker's ECDH public key or, if `app_key` is set, the public key the worker derives for the
+/// app's code. [`AppManifest::secret_env_aad`] is the associated data, so the value can only be
+/// used by the variable and the manifest it was encrypted for.
+#[derive(Decode, Encode, TypeInfo, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SecretEnvVar {
+    /// The name of the variable.
+    pub name: String,
+    /// The sr25519 public key of the sender.
+    pub pubkey: Vec<u8>,
+    /// The 12-byte IV followed by the encrypted value.
+    pub encrypted_value: Vec<u8>,
+    /// Whether the value is encrypted to the key derived for the app's code.
+    #[serde(default)]
+    pub app_key: bool,
 }
 
 impl AppManifest {
     /// Calculate the address of the application.
+    ///
+    /// The fields added after `required_blobs` are left out of the hashed encoding if none of
+    /// them is set, so that the manifests without them keep the addresses they had before the
+    /// fields were added.
     pub fn address(&self, blake2_256_fn: fn(&[u8]) -> [u8; 32]) -> [u8; 32] {
-        blake2_256_fn(&self.encode())
+        if self.has_extensions() {
+            blake2_256_fn(&self.encode())
+        } else {
+            blake2_256_fn(&self.encode_base())
+        }
+    }
+
+    /// The associated data the value of the secret env var `name` is encrypted with.
+    ///
+    /// It binds the value to the name and to the manifest without its secret env vars, which
+    /// can't cover themselves.
+    pub fn secret_env_aad(&self, name: &str, blake2_256_fn: fn(&[u8]) -> [u8; 32]) -> Vec<u8> {
+        let manifest = AppManifest {
+            secret_env_vars: vec![],
+            ..self.clone()
+        };
+        (b"wapod/secret_env", manifest.address(blake2_256_fn), name).encode()
+    }
+
+    fn has_extensions(&self) -> bool {
+        !self.schedules.is_empty()
+            || !self.mounts.is_empty()
+            || self.scratch.is_some()
+            || !self.secret_env_vars.is_empty()
+            || !self.domains.is_empty()
+    }
+
+    /// The encoding of the fields of the first version of the manifest.
+    fn encode_base(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.version.encode_to(&mut buf);
+        self.code_hash.encode_to(&mut buf);
+        self.args.encode_to(&mut buf);
+        self.env_vars.encode_to(&mut buf);
+        self.on_demand.encode_to(&mut buf);
+        self.resizable.encode_to(&mut buf);
+        self.max_query_size.encode_to(&mut buf);
+        self.label.encode_to(&mut buf);
+        self.required_blobs.encode_to(&mut buf);
+        buf
     }
 }
 
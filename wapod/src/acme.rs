@@ -0,0 +1,128 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use rocket::serde::Deserialize;
+use tracing::{info, warn};
+use wapo_host::{AcmeConfig, AcmeHost};
+use wapod_crypto::sr25519::Pair;
+
+use crate::config::load_config_file;
+use crate::domains::DomainMap;
+use crate::registry::write_atomically;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct AcmeSettings {
+    /// Whether apps listening on an SNI can ask for a certificate issued by the ACME CA.
+    pub enabled: bool,
+    #[serde(flatten)]
+    pub config: AcmeConfig,
+}
+
+impl AcmeSettings {
+    pub fn from_config_file() -> Self {
+        let settings = load_config_file()
+            .select("acme")
+            .extract::<AcmeSettings>()
+            .unwrap_or_default();
+        info!("loaded ACME config: {settings:?}");
+        settings
+    }
+}
+
+/// Lets the apps get certificates for the domains bound to them, and keeps the ACME keys and
+/// certificates in a directory, sealed with a key derived from the worker key.
+pub(crate) struct WorkerAcmeHost {
+    domains: Arc<DomainMap>,
+    dir: PathBuf,
+    key: Pair,
+}
+
+impl WorkerAcmeHost {
+    pub fn new(domains: Arc<DomainMap>, dir: PathBuf, worker_key: &Pair) -> Self {
+        let path_hash = sp_core::hashing::blake2_256(b"wapod/acme");
+        Self {
+            domains,
+            dir,
+            key: worker_key.derive([path_hash]),
+        }
+    }
+
+    fn path_of(&self, key: &str) -> PathBuf {
+        self.dir
+            .join(hex::encode(sp_core::hashing::blake2_256(key.as_bytes())))
+    }
+
+    fn seal(&self, key: &str, data: &[u8]) -> Result<()> {
+        let sealed = self.key.encrypt_message_with_aad(
+            self.key.public().as_bytes(),
+            data,
+            key.as_bytes(),
+        )?;
+        std::fs::create_dir_all(&self.dir).context("failed to create the ACME directory")?;
+        write_atomically(&self.path_of(key), &sealed)
+    }
+
+    fn unseal(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let mut sealed = match std::fs::read(self.path_of(key)) {
+            Ok(sealed) => sealed,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context("failed to read the file"),
+        };
+        let data = self.key.decrypt_message_with_aad(
+            self.key.public().as_bytes(),
+            &mut sealed,
+            key.as_bytes(),
+        )?;
+        Ok(Some(data))
+    }
+}
+
+impl AcmeHost for WorkerAcmeHost {
+    fn is_allowed(&self, owner: &str, name: &str) -> bool {
+        self.domains
+            .app_for(name)
+            .is_some_and(|address| hex::encode(address) == owner)
+    }
+
+    fn load(&self, key: &str) -> Option<Vec<u8>> {
+        self.unseal(key).unwrap_or_else(|err| {
+            warn!("failed to load the ACME {key}: {err:?}");
+            None
+        })
+    }
+
+    fn save(&self, key: &str, data: &[u8]) {
+        if let Err(err) = self.seal(key, data) {
+            warn!("failed to save the ACME {key}: {err:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_data_is_sealed() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let domains = Arc::new(DomainMap::default());
+        domains.bind("example.com", [0xaa; 32]).unwrap();
+        let worker_key = Pair::new();
+        let host = WorkerAcmeHost::new(domains, dir.clone(), &worker_key);
+        assert!(host.is_allowed(&hex::encode([0xaa; 32]), "example.com"));
+        assert!(!host.is_allowed(&hex::encode([0xbb; 32]), "example.com"));
+        assert!(!host.is_allowed(&hex::encode([0xaa; 32]), "other.example.com"));
+
+        host.save("account", b"private key");
+        assert_eq!(host.load("account").unwrap(), b"private key");
+        assert_eq!(host.load("cert/example.com"), None);
+        let sealed = std::fs::read(host.path_of("account")).unwrap();
+        assert!(!sealed.windows(11).any(|w| w == b"private key"));
+        // Sealed to the name it was saved under.
+        std::fs::rename(host.path_of("account"), host.path_of("cert/example.com")).unwrap();
+        assert_eq!(host.load("cert/example.com"), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rocket::serde::Deserialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::info;
+
+use crate::config::load_config_file;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct AppHttpConfig {
+    /// How many HTTP requests to `/app/<id>/` each app serves at once, upgraded connections
+    /// included.
+    pub max_concurrent_requests: usize,
+    /// How many of them a single client connection can take, e.g. the requests a browser
+    /// multiplexes over one HTTP/2 connection.
+    pub max_concurrent_requests_per_connection: usize,
+    /// How long a request beyond the limits waits for a slot before it is refused, in seconds.
+    pub queue_timeout_secs: u64,
+}
+
+impl Default for AppHttpConfig {
+    fn default() -> Self {
+        Self {
+            // Below the capacity of the HTTP request channel of an instance.
+            max_concurrent_requests: 16,
+            max_concurrent_requests_per_connection: 4,
+            queue_timeout_secs: 10,
+        }
+    }
+}
+
+impl AppHttpConfig {
+    pub fn from_config_file() -> Self {
+        let config = load_config_file()
+            .select("app_http")
+            .extract::<AppHttpConfig>()
+            .unwrap_or_default();
+        info!("loaded app HTTP config: {config:?}");
+        config
+    }
+
+    pub fn queue_timeout(&self) -> Duration {
+        Duration::from_secs(self.queue_timeout_secs)
+    }
+}
+
+/// The slots of the concurrent HTTP requests to an app.
+///
+/// Each client connection takes at most its share of the slots, so one connection multiplexing
+/// many requests can't hold all of them. Its requests beyond the share wait on the connection,
+/// and the ones within it wait for the slots of the app in the order of arrival.
+pub struct HttpPermits {
+    app: Arc<Semaphore>,
+    per_connection: usize,
+    /// The slots of the client connections with requests in flight or waiting, by peer address.
+    connections: Mutex<HashMap<SocketAddr, Arc<Semaphore>>>,
+}
+
+/// A slot taken by a request, released when dropped.
+pub struct HttpPermit {
+    permits: Arc<HttpPermits>,
+    connection: Option<SocketAddr>,
+    held: Option<(Option<OwnedSemaphorePermit>, OwnedSemaphorePermit)>,
+}
+
+impl Drop for HttpPermit {
+    fn drop(&mut self) {
+        self.held = None;
+        if let Some(connection) = self.connection {
+            self.permits.forget_idle(connection);
+        }
+    }
+}
+
+impl HttpPermits {
+    pub fn new(config: &AppHttpConfig) -> Arc<Self> {
+        Arc::new(Self {
+            app: Arc::new(Semaphore::new(config.max_concurrent_requests.max(1))),
+            per_connection: config.max_concurrent_requests_per_connection.max(1),
+            connections: Default::default(),
+        })
+    }
+
+    /// Waits for a slot for a request from the client connection, if it is known.
+    ///
+    /// Returns `None` if no slot frees up within the timeout.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        connection: Option<SocketAddr>,
+        timeout: Duration,
+    ) -> Option<HttpPermit> {
+        let connection_permits = connection.map(|connection| {
+            self.connections
+                .lock()
+                .unwrap()
+                .entry(connection)
+                .or_insert_with(|| Arc::new(Semaphore::new(self.per_connection)))
+                .clone()
+        });
+        let held = tokio::time::timeout(timeout, async {
+            let connection_permit = match connection_permits {
+                Some(permits) => Some(permits.acquire_owned().await.ok()?),
+                None => None,
+            };
+            let app_permit = self.app.clone().acquire_owned().await.ok()?;
+            Some((connection_permit, app_permit))
+        })
+        .await
+        .ok()
+        .flatten();
+        // Dropping it here makes the timeout path release the connection like a permit does.
+        let permit = HttpPermit {
+            permits: self.clone(),
+            connection,
+            held,
+        };
+        permit.held.is_some().then_some(permit)
+    }
+
+    /// Forgets the connection once no request of it is in flight or waiting.
+    fn forget_idle(&self, connection: SocketAddr) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(permits) = connections.get(&connection) {
+            // Only the map holds it, as waiters and permits hold a clone.
+            if Arc::strong_count(permits) == 1 {
+                connections.remove(&connection);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn n_connections(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{http::Status, State};
+
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    fn permits(app: usize, per_connection: usize) -> Arc<HttpPermits> {
+        HttpPermits::new(&AppHttpConfig {
+            max_concurrent_requests: app,
+            max_concurrent_requests_per_connection: per_connection,
+            queue_timeout_secs: 0,
+        })
+    }
+
+    fn addr(port: u16) -> Option<SocketAddr> {
+        Some(SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    #[tokio::test]
+    async fn connections_share_the_slots() {
+        let permits = permits(4, 2);
+        let a1 = permits.acquire(addr(1), TIMEOUT).await.unwrap();
+        let _a2 = permits.acquire(addr(1), TIMEOUT).await.unwrap();
+        // The first connection used up its share, while the others can still get slots.
+        assert!(permits.acquire(addr(1), TIMEOUT).await.is_none());
+        let _b1 = permits.acquire(addr(2), TIMEOUT).await.unwrap();
+        let _unknown = permits.acquire(None, TIMEOUT).await.unwrap();
+        // The app is now full.
+        assert!(permits.acquire(addr(3), TIMEOUT).await.is_none());
+
+        let waiting = tokio::spawn({
+            let permits = permits.clone();
+            async move { permits.acquire(addr(3), Duration::from_secs(5)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(a1);
+        assert!(waiting.await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_forgotten() {
+        let permits = permits(1, 1);
+        let a = permits.acquire(addr(1), TIMEOUT).await.unwrap();
+        assert!(permits.acquire(addr(2), TIMEOUT).await.is_none());
+        assert_eq!(permits.n_connections(), 1);
+        drop(a);
+        assert_eq!(permits.n_connections(), 0);
+    }
+
+    #[rocket::get("/")]
+    async fn slow(permits: &State<Arc<HttpPermits>>, remote: Option<SocketAddr>) -> Status {
+        match permits.acquire(remote, TIMEOUT).await {
+            Some(_permit) => {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                Status::Ok
+            }
+            None => Status::ServiceUnavailable,
+        }
+    }
+
+    /// Requests multiplexed over an HTTP/2 connection come from the same peer address and share
+    /// its slots, while other connections are still served.
+    #[tokio::test]
+    async fn http2_streams_share_the_slots_of_their_connection() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = rocket::Config {
+            port,
+            log_level: rocket::config::LogLevel::Off,
+            shutdown: rocket::config::Shutdown {
+                ctrlc: false,
+                ..Default::default()
+            },
+            ..rocket::Config::debug_default()
+        };
+        let server = rocket::custom(config)
+            .manage(permits(4, 2))
+            .mount("/", rocket::routes![slow])
+            .ignite()
+            .await
+            .unwrap();
+        let shutdown = server.shutdown();
+        tokio::spawn(server.launch());
+        let url = format!("http://127.0.0.1:{port}/");
+        for _ in 0..100 {
+            if tokio::net::TcpStream::connect(("127.0.0.1", port))
+                .await
+                .is_ok()
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let client = || {
+            reqwest::Client::builder()
+                .http2_prior_knowledge()
+                .build()
+                .unwrap()
+        };
+        let get = |client: reqwest::Client| {
+            let url = url.clone();
+            async move {
+                let response = client.get(url).send().await.unwrap();
+                assert_eq!(response.version(), reqwest::Version::HTTP_2);
+                response.status().as_u16()
+            }
+        };
+        let multiplexed = client();
+        let other = client();
+        let (a1, a2, a3, b) = tokio::join!(
+            get(multiplexed.clone()),
+            get(multiplexed.clone()),
+            get(multiplexed.clone()),
+            async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                get(other).await
+            },
+        );
+        let mut statuses = [a1, a2, a3];
+        statuses.sort();
+        assert_eq!(statuses, [200, 200, 503]);
+        assert_eq!(b, 200);
+        shutdown.notify();
+    }
+}
@@ -0,0 +1,105 @@
+use rocket::serde::Deserialize;
+use tracing::info;
+use wapo_host::{ConnectionLimits, ConnectionStats, RoutedAgent, SniAgent};
+use wapod_rpc::prpc as pb;
+
+use crate::config::load_config_file;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct ConnectionLimitsConfig {
+    /// The connections accepted per second for each server name, unlimited if zero.
+    pub accept_rate: u32,
+    /// How many connections can be accepted at once beyond the rate.
+    pub accept_burst: u32,
+    /// How many connections of a server name can wait for an instance to take them.
+    pub max_pending: usize,
+}
+
+impl Default for ConnectionLimitsConfig {
+    fn default() -> Self {
+        let limits = ConnectionLimits::default();
+        Self {
+            accept_rate: limits.accept_rate,
+            accept_burst: limits.accept_burst,
+            max_pending: limits.max_pending,
+        }
+    }
+}
+
+impl ConnectionLimitsConfig {
+    pub fn from_config_file() -> Self {
+        let config = load_config_file()
+            .select("connection_limits")
+            .extract::<ConnectionLimitsConfig>()
+            .unwrap_or_default();
+        info!("loaded connection limits config: {config:?}");
+        config
+    }
+
+    pub fn limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            accept_rate: self.accept_rate,
+            accept_burst: self.accept_burst,
+            max_pending: self.max_pending,
+        }
+    }
+}
+
+/// The agents dispatching the connections of an app to its instances, shared by the instances.
+#[derive(Default)]
+pub(crate) struct AppAgents {
+    pub sni: Option<SniAgent>,
+    pub http: Option<RoutedAgent>,
+    pub tls_passthrough: Option<RoutedAgent>,
+}
+
+impl AppAgents {
+    pub fn set_limits(&self, limits: &ConnectionLimits) {
+        if let Some(agent) = &self.sni {
+            agent.set_limits(limits.clone());
+        }
+        if let Some(agent) = &self.http {
+            agent.set_limits(limits.clone());
+        }
+        if let Some(agent) = &self.tls_passthrough {
+            agent.set_limits(limits.clone());
+        }
+    }
+
+    pub fn stats(&self, address: &[u8; 32]) -> Vec<pb::ConnectionStats> {
+        let sni = self.sni.as_ref().map(|agent| ("sni", agent.stats()));
+        let http = self.http.as_ref().map(|agent| ("http", agent.stats()));
+        let tls_passthrough = self
+            .tls_passthrough
+            .as_ref()
+            .map(|agent| ("tls_passthrough", agent.stats()));
+        [sni, http, tls_passthrough]
+            .into_iter()
+            .flatten()
+            .flat_map(|(listener, stats)| {
+                stats
+                    .into_iter()
+                    .map(move |(server_names, stats)| to_pb(address, listener, server_names, stats))
+            })
+            .collect()
+    }
+}
+
+fn to_pb(
+    address: &[u8; 32],
+    listener: &str,
+    server_names: String,
+    stats: ConnectionStats,
+) -> pb::ConnectionStats {
+    pb::ConnectionStats {
+        address: address.to_vec(),
+        listener: listener.to_string(),
+        server_names,
+        accepted: stats.accepted,
+        dropped: stats.dropped,
+        timed_out: stats.timed_out,
+        handshake_failed: stats.handshake_failed,
+        pending: stats.pending,
+    }
+}
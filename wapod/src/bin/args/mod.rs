@@ -26,9 +26,10 @@ pub struct Args {
     #[builder(default, setter(strip_option))]
     pub admin_port: Option<u16>,
 
-    /// API token required for accessing the admin service. If empty, no token is required.
-    /// When provided, the token must be included in the `Authorization: Bearer` header for
-    /// each incoming request.
+    /// API token required for accessing the admin service, granted the owner role. If empty and
+    /// no tokens or operators are configured in `[admin_auth]`, no token is required. When
+    /// provided, the token must be included in the `Authorization: Bearer` header for each
+    /// incoming request, unless the request is signed by an operator key.
     #[arg(long, short='t', default_value_t = String::new())]
     #[builder(default)]
     pub admin_api_token: String,
@@ -49,6 +50,16 @@ pub struct Args {
     #[builder(default)]
     pub no_mem_pool: bool,
 
+    /// Do not persist the compiled WebAssembly modules across restarts.
+    #[arg(long)]
+    #[builder(default)]
+    pub no_module_disk_cache: bool,
+
+    /// Maximum total size of the compiled modules persisted across restarts.
+    #[arg(long, default_value = "2G", value_parser = parse_size)]
+    #[builder(default = 2 * 1024 * 1024 * 1024)]
+    pub module_disk_cache_size: u64,
+
     /// TCP port range for the worker to listen on.
     #[arg(long, short = 'l', value_parser = parse_port_range)]
     pub tcp_listen_port_range: Option<(u16, u16)>,
@@ -57,6 +68,12 @@ pub struct Args {
     #[arg(long)]
     pub tls_port: Option<u16>,
 
+    /// The TCP port to listen on plain HTTP connections which would be dispatched based on the
+    /// `Host` header.
+    #[arg(long)]
+    #[builder(default)]
+    pub http_port: Option<u16>,
+
     /// Turn off the verification of the TLS server certificate when the guest tries to listen.
     #[arg(long)]
     pub do_not_verify_tls_server_cert: bool,
@@ -64,6 +81,11 @@ pub struct Args {
     /// Time limit in seconds for on-demand instance handling.
     #[arg(long, default_value_t = 60)]
     pub on_demand_instance_time_secs: u64,
+
+    /// Maximum quota of the scratch directory of each app.
+    #[arg(long, default_value = "1G", value_parser = parse_size)]
+    #[builder(default = 1024 * 1024 * 1024)]
+    pub max_scratch_quota: u64,
 }
 
 fn parse_port_range(input: &str) -> anyhow::Result<(u16, u16)> {
@@ -97,10 +119,14 @@ impl From<Args> for WorkerArgs {
             module_cache_size: value.module_cache_size,
             no_mem_pool: value.no_mem_pool,
             use_winch: false,
+            no_module_disk_cache: value.no_module_disk_cache,
+            module_disk_cache_size: value.module_disk_cache_size,
             tcp_listen_port_range: value.tcp_listen_port_range.map_or(empty, |(f, t)| f..=t),
             tls_port: value.tls_port,
+            http_port: value.http_port,
             verify_tls_server_cert: !value.do_not_verify_tls_server_cert,
             on_demand_connection_timeout: Duration::from_secs(value.on_demand_instance_time_secs),
+            max_scratch_quota: value.max_scratch_quota,
         }
     }
 }
@@ -1,48 +1,588 @@
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use rocket::http::Status;
 use rocket::request::{FromRequest, Outcome};
+use rocket::serde::Deserialize;
 use rocket::Request;
-use tracing::warn;
+use scale::Decode;
+use sha2::{Digest, Sha256};
+use sp_core::crypto::{AccountId32, Ss58Codec};
+use tokio::io::{AsyncRead, ReadBuf};
+use tracing::{info, warn};
+use wapod::config::{load_config_file, KeyProvider, WorkerConfig};
+use wapod_crypto::query_signature::{
+    verify_admin_request, AdminRequest, RootSigner, SignatureType,
+};
+
+use crate::{Config, Worker};
+
+type AccountId = [u8; 32];
+
+/// The headers of a request signed by an operator key, in place of the `Authorization` header.
+///
+/// The signer signs the SCALE encoded `AdminRequest` made of the public key of the worker, the
+/// path of the request, the timestamp, the nonce and the body. The bodies of the blob routes are
+/// streamed, so the SHA-256 of the body is signed for them instead, sent in the body hash header
+/// and checked once the body has been read. The offset of an upload chunk is in the signed path.
+const SIGNER_HEADER: &str = "X-Wapod-Signer";
+const SIGNATURE_TYPE_HEADER: &str = "X-Wapod-Signature-Type";
+const SIGNATURE_HEADER: &str = "X-Wapod-Signature";
+const TIMESTAMP_HEADER: &str = "X-Wapod-Timestamp";
+const NONCE_HEADER: &str = "X-Wapod-Nonce";
+const BODY_HASH_HEADER: &str = "X-Wapod-Body-Hash";
+const MAX_NONCE_LEN: usize = 64;
+
+/// The methods a role can call, on top of the ones of the roles below it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "kebab-case")]
+pub enum Role {
+    ReadOnly,
+    Deployer,
+    Operator,
+    Owner,
+}
+
+const READ_ONLY_METHODS: &[&str] = &[
+    "Info",
+    "AppList",
+    "AppMetrics",
+    "BlobList",
+    "BlobExists",
+    "BlobUploadQuery",
+    "BlobGet",
+];
+const DEPLOYER_METHODS: &[&str] = &[
+    "BlobPut",
+    "BlobUploadBegin",
+    "BlobUploadAppend",
+    "BlobUploadCommit",
+    "BlobUploadAbort",
+    "AppDeploy",
+    "AppRemove",
+    "AppStart",
+    "AppStop",
+    "AppResize",
+    "AppBindDomain",
+    "AppUnbindDomain",
+    "AppQuery",
+    "AppEncryptedQuery",
+    "TopicPublish",
+];
+const OPERATOR_METHODS: &[&str] = &["BlobRemove", "BlobGc", "AppRemoveAll", "SetBenchApp"];
 
-pub struct ApiToken {
+impl Role {
+    fn allows(self, method: &str) -> bool {
+        let required = if READ_ONLY_METHODS.contains(&method) {
+            Role::ReadOnly
+        } else if DEPLOYER_METHODS.contains(&method) {
+            Role::Deployer
+        } else if OPERATOR_METHODS.contains(&method) {
+            Role::Operator
+        } else {
+            // Including the methods added later, until they are classified.
+            Role::Owner
+        };
+        self >= required
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TokenConfig {
+    /// The name recorded in the audit log for the requests with the token.
+    pub name: String,
+    pub token: String,
+    #[serde(default)]
+    pub role: Option<Role>,
+    /// The `Operation` methods allowed in addition to the ones of the role.
+    #[serde(default)]
+    pub methods: Vec<String>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct AdminAuthConfig {
+    pub tokens: Vec<TokenConfig>,
+    /// The accounts allowed to sign admin requests, in addition to the ones set at init.
+    pub operators: Vec<String>,
+    /// Whether the operator the registration info is signed for may sign admin requests.
+    pub trust_register_operator: bool,
+    /// How far the timestamp of a signed request may be from the local clock, in seconds.
+    pub max_clock_skew_secs: u64,
+}
+
+impl Default for AdminAuthConfig {
+    fn default() -> Self {
+        Self {
+            tokens: vec![],
+            operators: vec![],
+            trust_register_operator: false,
+            max_clock_skew_secs: 60,
+        }
+    }
+}
+
+impl AdminAuthConfig {
+    pub fn from_config_file() -> Self {
+        let config = load_config_file()
+            .select("admin_auth")
+            .extract::<AdminAuthConfig>()
+            .unwrap_or_default();
+        // The tokens themselves are kept out of the log.
+        for token in &config.tokens {
+            info!(name = token.name, role = ?token.role, methods = ?token.methods, "loaded admin API token");
+        }
+        info!(
+            operators = ?config.operators,
+            trust_register_operator = config.trust_register_operator,
+            max_clock_skew_secs = config.max_clock_skew_secs,
+            "loaded admin operators"
+        );
+        config
+    }
+}
+
+struct TokenEntry {
+    name: String,
     value: String,
+    role: Option<Role>,
+    methods: Vec<String>,
+}
+
+impl TokenEntry {
+    fn allows(&self, method: &str) -> bool {
+        self.role.is_some_and(|role| role.allows(method))
+            || self.methods.iter().any(|m| m == method)
+    }
+}
+
+pub struct ApiTokens {
+    tokens: Vec<Arc<TokenEntry>>,
+    operators: Vec<AccountId>,
+    trust_register_operator: bool,
+    max_clock_skew_ms: u64,
+    nonces: Mutex<SeenNonces>,
+}
+
+impl ApiTokens {
+    /// Creates the tokens configured in the config file, along with the one given on the command
+    /// line, which is granted the owner role.
+    pub fn new(cli_token: String) -> Self {
+        let config = AdminAuthConfig::from_config_file();
+        let mut configs = config.tokens;
+        if !cli_token.is_empty() {
+            configs.push(TokenConfig {
+                name: "cli".into(),
+                token: cli_token,
+                role: Some(Role::Owner),
+                methods: vec![],
+            });
+        }
+        let tokens: Vec<_> = configs
+            .into_iter()
+            .filter_map(|config| {
+                if config.token.is_empty() {
+                    warn!(name = config.name, "ignoring empty admin API token");
+                    return None;
+                }
+                Some(Arc::new(TokenEntry {
+                    name: config.name,
+                    value: format!("Bearer {}", config.token),
+                    role: config.role,
+                    methods: config
+                        .methods
+                        .iter()
+                        .map(|method| method_name(method).to_string())
+                        .collect(),
+                }))
+            })
+            .collect();
+        let operators: Vec<AccountId> = config
+            .operators
+            .iter()
+            .filter_map(|operator| match AccountId32::from_string(operator) {
+                Ok(account) => Some(account.into()),
+                Err(err) => {
+                    warn!(operator, "ignoring invalid admin operator: {err:?}");
+                    None
+                }
+            })
+            .collect();
+        if tokens.is_empty() && operators.is_empty() {
+            warn!("No API token or operator configured. No authorization required unless operators are set at init.");
+        }
+        Self {
+            tokens,
+            operators,
+            trust_register_operator: config.trust_register_operator,
+            max_clock_skew_ms: config.max_clock_skew_secs.saturating_mul(1000),
+            nonces: Default::default(),
+        }
+    }
+
+    /// The accounts allowed to sign admin requests at the moment.
+    fn operators_of(&self, worker: &Worker) -> Vec<AccountId> {
+        let mut operators = self.operators.clone();
+        operators.extend(worker.operators());
+        if self.trust_register_operator {
+            operators.extend(worker.register_operator());
+        }
+        operators
+    }
+
+    /// Verifies a signed request with its body, returning the account of the signer.
+    fn verify_signed(
+        &self,
+        request: &SignedRequest,
+        body: &[u8],
+    ) -> Result<AccountId, &'static str> {
+        let now = now_millis();
+        if request.timestamp.abs_diff(now) > self.max_clock_skew_ms {
+            return Err("timestamp out of range");
+        }
+        let admin_request = AdminRequest {
+            worker: <Config as WorkerConfig>::KeyProvider::get_key()
+                .public()
+                .to_array(),
+            path: request.path.clone(),
+            timestamp: request.timestamp,
+            nonce: request.nonce.clone(),
+            body: body.to_vec(),
+        };
+        let signer = RootSigner {
+            pubkey: request.signer.clone(),
+        };
+        let account = verify_admin_request(
+            &signer,
+            &admin_request,
+            &request.signature,
+            request.signature_type,
+        )
+        .or(Err("invalid signature"))?;
+        if !request.operators.contains(&account) {
+            return Err("not an operator");
+        }
+        let recorded = self.nonces.lock().expect("nonces lock poisoned").record(
+            account,
+            &request.nonce,
+            request.timestamp,
+            now,
+            self.max_clock_skew_ms,
+        );
+        if !recorded {
+            return Err("nonce reused");
+        }
+        Ok(account)
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
 }
 
-impl ApiToken {
-    pub fn new(token: String) -> Self {
-        let value = if token.is_empty() {
-            warn!("API token is empty. No authorization required.");
-            String::new()
+/// The nonces of the signed requests within the accepted time window, to reject the replayed
+/// ones.
+#[derive(Default)]
+struct SeenNonces {
+    nonces: HashMap<(AccountId, Vec<u8>), u64>,
+}
+
+impl SeenNonces {
+    /// Records the nonce of a request, returning false if it has been used before.
+    fn record(
+        &mut self,
+        account: AccountId,
+        nonce: &[u8],
+        timestamp: u64,
+        now: u64,
+        window: u64,
+    ) -> bool {
+        // The requests signed before the window are rejected by their timestamps instead.
+        self.nonces
+            .retain(|_, seen| seen.saturating_add(window) >= now);
+        self.nonces
+            .insert((account, nonce.to_vec()), timestamp)
+            .is_none()
+    }
+}
+
+/// A request signed by an operator key, verified along with the body.
+struct SignedRequest {
+    path: String,
+    signer: Vec<u8>,
+    signature_type: SignatureType,
+    signature: Vec<u8>,
+    timestamp: u64,
+    nonce: Vec<u8>,
+    /// The SHA-256 of the streamed body the request is signed with.
+    body_hash: Option<[u8; 32]>,
+    /// The accounts allowed to sign the request.
+    operators: Vec<AccountId>,
+}
+
+impl SignedRequest {
+    fn from_request(
+        request: &Request<'_>,
+        operators: Vec<AccountId>,
+    ) -> Result<Self, &'static str> {
+        let headers = request.headers();
+        let hex_header = |name: &str| {
+            let value = headers.get_one(name).ok_or("signature header missing")?;
+            hex::decode(value.trim_start_matches("0x")).or(Err("invalid hex in signature header"))
+        };
+        let signature_type = headers
+            .get_one(SIGNATURE_TYPE_HEADER)
+            .and_then(|value| value.parse::<u8>().ok())
+            .and_then(|value| SignatureType::decode(&mut &[value][..]).ok())
+            .ok_or("invalid signature type")?;
+        let timestamp = headers
+            .get_one(TIMESTAMP_HEADER)
+            .and_then(|value| value.parse().ok())
+            .ok_or("invalid timestamp")?;
+        let nonce = hex_header(NONCE_HEADER)?;
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+            return Err("invalid nonce");
+        }
+        let body_hash = match headers.get_one(BODY_HASH_HEADER) {
+            Some(_) => Some(
+                hex_header(BODY_HASH_HEADER)?
+                    .try_into()
+                    .or(Err("invalid body hash"))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            path: request.uri().to_string(),
+            signer: hex_header(SIGNER_HEADER)?,
+            signature_type,
+            signature: hex_header(SIGNATURE_HEADER)?,
+            timestamp,
+            nonce,
+            body_hash,
+            operators,
+        })
+    }
+}
+
+/// The method without the `Operation.` service prefix.
+fn method_name(method: &str) -> &str {
+    method.strip_prefix("Operation.").unwrap_or(method)
+}
+
+enum Credential {
+    /// No authorization is required.
+    None,
+    Token(Arc<TokenEntry>),
+    Signature(Box<SignedRequest>),
+}
+
+/// The credential a request is authenticated with.
+pub struct Authorized<'r> {
+    tokens: &'r ApiTokens,
+    credential: Credential,
+}
+
+impl Authorized<'_> {
+    /// Checks that the request may call the method, recording it in the audit log.
+    ///
+    /// The body is the one the signature of a signed request covers.
+    pub fn check(&self, method: &str, body: &[u8]) -> Result<(), Status> {
+        let method = method_name(method);
+        let token = match &self.credential {
+            Credential::None => {
+                info!(target: "wapod::audit", token = "anonymous", method, "admin request");
+                return Ok(());
+            }
+            Credential::Token(token) => token,
+            Credential::Signature(request) => {
+                return match self.tokens.verify_signed(request, body) {
+                    // The operators are the owners of the worker.
+                    Ok(account) => {
+                        info!(target: "wapod::audit", operator = hex::encode(account), method, "admin request");
+                        Ok(())
+                    }
+                    Err(reason) => {
+                        warn!(target: "wapod::audit", signer = hex::encode(&request.signer), method, reason, "admin request denied");
+                        Err(Status::Unauthorized)
+                    }
+                };
+            }
+        };
+        if token.allows(method) {
+            info!(target: "wapod::audit", token = token.name, method, "admin request");
+            Ok(())
         } else {
-            format!("Bearer {}", token)
+            warn!(target: "wapod::audit", token = token.name, method, "admin request denied");
+            Err(Status::Forbidden)
+        }
+    }
+
+    /// Checks that the request may call the method with the streamed body.
+    ///
+    /// A signed request covers the hash of the body, which the returned body checks at its end.
+    pub fn check_streamed<R>(&self, method: &str, body: R) -> Result<StreamedBody<R>, Status> {
+        let expected = match &self.credential {
+            Credential::Signature(request) => {
+                let Some(body_hash) = request.body_hash else {
+                    warn!(target: "wapod::audit", signer = hex::encode(&request.signer), method, "admin request denied, body hash missing");
+                    return Err(Status::Unauthorized);
+                };
+                self.check(method, &body_hash)?;
+                Some(body_hash)
+            }
+            _ => {
+                self.check(method, &[])?;
+                None
+            }
         };
-        Self { value }
+        Ok(StreamedBody {
+            inner: body,
+            hasher: Sha256::new(),
+            expected,
+        })
     }
 }
 
-pub struct Authorized;
+/// A streamed request body, failing at its end if it doesn't match the hash the request was
+/// signed with.
+pub struct StreamedBody<R> {
+    inner: R,
+    hasher: Sha256,
+    expected: Option<[u8; 32]>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for StreamedBody<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let Some(expected) = this.expected else {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        };
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let read = &buf.filled()[filled..];
+        if !read.is_empty() {
+            this.hasher.update(read);
+            return Poll::Ready(Ok(()));
+        }
+        this.expected = None;
+        if this.hasher.finalize_reset()[..] != expected {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "body hash mismatch",
+            )));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
 
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for Authorized {
+impl<'r> FromRequest<'r> for Authorized<'r> {
     type Error = &'static str;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        let token = request
+        let tokens = request
             .rocket()
-            .state::<ApiToken>()
+            .state::<ApiTokens>()
             .expect("Token state not available.");
-        if token.value.is_empty() {
-            return Outcome::Success(Authorized);
+        let worker = request
+            .rocket()
+            .state::<Worker>()
+            .expect("Worker state not available.");
+        let authorized = |credential| Outcome::Success(Authorized { tokens, credential });
+        let operators = tokens.operators_of(worker);
+        if request.headers().contains(SIGNER_HEADER) {
+            if operators.is_empty() {
+                return Outcome::Error((Status::Unauthorized, "no operator allowed"));
+            }
+            return match SignedRequest::from_request(request, operators) {
+                Ok(signed) => authorized(Credential::Signature(Box::new(signed))),
+                Err(err) => {
+                    warn!(target: "wapod::audit", uri = %request.uri(), "invalid admin request signature: {err}");
+                    Outcome::Error((Status::Unauthorized, err))
+                }
+            };
+        }
+        if tokens.tokens.is_empty() && operators.is_empty() {
+            if worker.operators_lost() {
+                warn!(target: "wapod::audit", uri = %request.uri(), "admin request refused, the operators were lost");
+                return Outcome::Error((Status::Unauthorized, "operators lost"));
+            }
+            return authorized(Credential::None);
         }
         match request.headers().get_one("Authorization") {
             Some(value) => {
                 // Check the Bearer token
-                if value == token.value {
-                    Outcome::Success(Authorized)
-                } else {
-                    Outcome::Error((Status::Unauthorized, "invalid token"))
+                match tokens.tokens.iter().find(|token| token.value == value) {
+                    Some(token) => authorized(Credential::Token(token.clone())),
+                    None => {
+                        warn!(target: "wapod::audit", uri = %request.uri(), "invalid admin API token");
+                        Outcome::Error((Status::Unauthorized, "invalid token"))
+                    }
                 }
             }
             _ => Outcome::Error((Status::Unauthorized, "Authorization header not found")),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_are_cumulative() {
+        assert!(Role::ReadOnly.allows("Info"));
+        assert!(!Role::ReadOnly.allows("AppDeploy"));
+        assert!(Role::Deployer.allows("AppDeploy"));
+        assert!(!Role::Deployer.allows("AppRemoveAll"));
+        assert!(!Role::Operator.allows("WorkerExit"));
+        assert!(Role::Owner.allows("WorkerExit"));
+        assert!(Role::Owner.allows("SomeFutureMethod"));
+    }
+
+    #[tokio::test]
+    async fn streamed_bodies_are_checked_at_the_end() {
+        use tokio::io::AsyncReadExt;
+
+        async fn read(body: &[u8], expected: Option<[u8; 32]>) -> io::Result<Vec<u8>> {
+            let mut streamed = StreamedBody {
+                inner: body,
+                hasher: Sha256::new(),
+                expected,
+            };
+            let mut read = vec![];
+            streamed.read_to_end(&mut read).await.map(|_| read)
+        }
+        let body = vec![7_u8; 100_000];
+        let hash: [u8; 32] = Sha256::digest(&body).into();
+        assert_eq!(read(&body, Some(hash)).await.unwrap(), body);
+        assert_eq!(read(&body, None).await.unwrap(), body);
+        let err = read(&body, Some([0; 32])).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn replayed_nonces_are_rejected() {
+        let mut nonces = SeenNonces::default();
+        let alice = [1; 32];
+        let bob = [2; 32];
+        assert!(nonces.record(alice, b"n1", 1000, 1000, 60_000));
+        assert!(!nonces.record(alice, b"n1", 1001, 1001, 60_000));
+        assert!(nonces.record(bob, b"n1", 1001, 1001, 60_000));
+        // Forgotten once out of the window, when the timestamp check rejects it instead.
+        assert!(nonces.record(alice, b"n2", 70_000, 70_000, 60_000));
+        assert_eq!(nonces.nonces.len(), 1);
+    }
+}
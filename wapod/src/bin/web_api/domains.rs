@@ -0,0 +1,31 @@
+use std::sync::Arc;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request};
+use tracing::debug;
+use wapod::DomainMap;
+
+/// Rewrites the requests to a domain bound to an app into `/app/<address>/<path>`, so that they
+/// are served by the app.
+pub struct DomainRouter(pub Arc<DomainMap>);
+
+#[rocket::async_trait]
+impl Fairing for DomainRouter {
+    fn info(&self) -> Info {
+        Info {
+            name: "Domain Router",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _data: &mut Data<'_>) {
+        let Some(host) = request.host() else {
+            return;
+        };
+        let Some(uri) = self.0.route(host.domain().as_str(), request.uri()) else {
+            return;
+        };
+        debug!(from = %request.uri(), to = %uri, "routing request by domain");
+        request.set_uri(uri);
+    }
+}
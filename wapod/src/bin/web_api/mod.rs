@@ -13,13 +13,14 @@ use rocket_cors::{AllowedHeaders, AllowedMethods, AllowedOrigins, CorsOptions};
 use tracing::{info, instrument, warn};
 use wapod::config::{load_config_file, WorkerConfig};
 
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use wapod_rpc::prpc::server::Service;
 
 use wapo_host::rocket_stream::{RequestInfo, StreamResponse};
 
 use wapod::config::KeyProvider;
-use wapod::prpc_service::{connect_vm, handle_prpc, HexBytes};
+use wapod::prpc_service::{connect_vm, handle_prpc, handle_prpc_data, read_prpc_data, HexBytes};
 
 use crate::{Args, Config, Worker};
 
@@ -27,8 +28,10 @@ type UserService = wapod::prpc_service::UserService<Config>;
 type AdminService = wapod::prpc_service::AdminService<Config>;
 
 use auth::Authorized;
+use domains::DomainRouter;
 
 mod auth;
+mod domains;
 
 struct NoEndSlash;
 
@@ -58,21 +61,23 @@ async fn redirect_connect_vm_get(id: &str, _guard: NoEndSlash) -> Redirect {
 async fn connect_vm_post<'r>(
     state: &State<Worker>,
     head: RequestInfo,
+    remote: Option<SocketAddr>,
     id: HexBytes,
     path: PathBuf,
     body: Data<'r>,
 ) -> Result<StreamResponse, (Status, String)> {
-    connect_vm(state, head, id, path, Some(body)).await
+    connect_vm(state, head, remote, id, path, Some(body)).await
 }
 
 #[get("/app/<id>/<path..>", rank = 2)]
 async fn connect_vm_get<'r>(
     state: &State<Worker>,
     head: RequestInfo,
+    remote: Option<SocketAddr>,
     id: HexBytes,
     path: PathBuf,
 ) -> Result<StreamResponse, (Status, String)> {
-    connect_vm(state, head, id, path, None).await
+    connect_vm(state, head, remote, id, path, None).await
 }
 
 #[instrument(target="prpc", name="user", fields(%id), skip_all)]
@@ -107,7 +112,7 @@ async fn prpc_get(
 #[instrument(target="prpc", name="admin", fields(%id), skip_all)]
 #[post("/<method>?<json>", data = "<data>")]
 async fn prpc_admin_post(
-    _auth: Authorized,
+    auth: Authorized<'_>,
     state: &State<Worker>,
     id: TraceId,
     limits: &Limits,
@@ -117,13 +122,16 @@ async fn prpc_admin_post(
     json: bool,
 ) -> Result<Vec<u8>, Custom<Vec<u8>>> {
     let _ = id;
-    handle_prpc::<AdminService, _>(state, method, Some(data), limits, content_type, json).await
+    let data = read_prpc_data(method, data, limits).await?;
+    auth.check(method, &data)
+        .map_err(|status| Custom(status, b"method not allowed".to_vec()))?;
+    handle_prpc_data::<AdminService, _>(state, method, data, content_type, json).await
 }
 
 #[instrument(target="prpc", name="admin", fields(%id), skip_all)]
 #[get("/<method>")]
 async fn prpc_admin_get(
-    _auth: Authorized,
+    auth: Authorized<'_>,
     state: &State<Worker>,
     id: TraceId,
     method: &str,
@@ -131,33 +139,71 @@ async fn prpc_admin_get(
     content_type: Option<&ContentType>,
 ) -> Result<Vec<u8>, Custom<Vec<u8>>> {
     let _ = id;
+    auth.check(method, &[])
+        .map_err(|status| Custom(status, b"method not allowed".to_vec()))?;
     handle_prpc::<AdminService, _>(state, method, None, limits, content_type, true).await
 }
 
 #[post("/blob/<hash>", data = "<data>")]
 async fn blob_post(
-    _auth: Authorized,
+    auth: Authorized<'_>,
     state: &State<Worker>,
     limits: &Limits,
     hash: &str,
     data: Data<'_>,
 ) -> Result<String, Custom<String>> {
-    let loader = state.blob_loader();
     let limit = limits.get("Admin.PutObject").unwrap_or(10.mebibytes());
-    let mut stream = data.open(limit);
+    let mut stream = auth
+        .check_streamed("BlobPut", data.open(limit))
+        .map_err(|status| Custom(status, "method not allowed".into()))?;
+    let loader = state.blob_loader();
     loader.put(hash, &mut stream).await.map_err(|err| {
         warn!("failed to put object: {err:?}");
         Custom(Status::InternalServerError, err.to_string())
     })
 }
 
+/// Streams a chunk of an upload started with `Operation.BlobUploadBegin`. Returns the offset
+/// to continue from.
+#[post("/blob-upload/<id>?<offset>", data = "<data>")]
+async fn blob_upload_append(
+    auth: Authorized<'_>,
+    state: &State<Worker>,
+    limits: &Limits,
+    id: &str,
+    offset: u64,
+    data: Data<'_>,
+) -> Result<String, Custom<String>> {
+    let limit = limits
+        .get("Operation.BlobUploadAppend")
+        .unwrap_or(64.mebibytes());
+    let mut stream = auth
+        .check_streamed("BlobUploadAppend", data.open(limit))
+        .map_err(|status| Custom(status, "method not allowed".into()))?;
+    let loader = state.blob_loader();
+    let status = loader
+        .append_upload(id, offset, &mut stream)
+        .await
+        .map_err(|err| {
+            warn!("failed to append upload: {err:?}");
+            Custom(Status::BadRequest, err.to_string())
+        })?;
+    Ok(status.offset.to_string())
+}
+
+/// Gets a blob by the file name, its hash in the form of `<algo>:<hex>` or its IPFS CID.
 #[get("/blob/<id>")]
 async fn blob_get(
-    _auth: Authorized,
+    auth: Authorized<'_>,
     state: &State<Worker>,
-    id: HexBytes,
+    id: &str,
 ) -> Result<NamedFile, Custom<&'static str>> {
-    let path = state.blob_loader().path(&id.0);
+    auth.check("BlobGet", &[])
+        .map_err(|status| Custom(status, "method not allowed"))?;
+    let path = state
+        .blob_loader()
+        .path_of(id.trim_start_matches("0x"))
+        .ok_or(Custom(Status::NotFound, "Object not found"))?;
     NamedFile::open(&path)
         .await
         .map_err(|_| Custom(Status::NotFound, "Object not found"))
@@ -201,6 +247,7 @@ pub async fn serve_user(state: Worker, args: Args) -> Result<()> {
         sign_http_response::<<Config as WorkerConfig>::KeyProvider>,
     );
     let _rocket = rocket::custom(figment)
+        .attach(DomainRouter(state.domains()))
         .attach(
             cors_options()
                 .to_cors()
@@ -242,9 +289,12 @@ pub async fn serve_admin(state: Worker, args: Args) -> Result<()> {
         )
         .attach(RequestTracer::default())
         .attach(TimeMeter)
-        .manage(auth::ApiToken::new(args.admin_api_token))
+        .manage(auth::ApiTokens::new(args.admin_api_token))
         .manage(state)
-        .mount("/", routes![blob_post, blob_get, console])
+        .mount(
+            "/",
+            routes![blob_post, blob_upload_append, blob_get, console],
+        )
         .mount("/prpc", routes![prpc_admin_post, prpc_admin_get])
         .launch()
         .await?;
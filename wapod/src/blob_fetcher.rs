@@ -0,0 +1,404 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use rocket::serde::Deserialize;
+use tokio::{io::AsyncWriteExt as _, sync::watch};
+use tracing::{info, warn};
+use wapo_host::blobs::BlobLoader;
+use wapod_rpc::prpc as pb;
+
+use crate::config::load_config_file;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PeerConfig {
+    /// The admin API endpoint of the peer worker.
+    pub url: String,
+    /// The admin API token of the peer worker.
+    #[serde(default)]
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct BlobFetchConfig {
+    /// URL templates to download the blobs from, e.g. `https://ipfs.io/ipfs/{cid}`.
+    /// `{cid}` and `{hash}` are replaced with the IDs of the blob. Templates that need an ID the
+    /// blob doesn't have are skipped.
+    pub sources: Vec<String>,
+    /// Peer workers to download the blobs from.
+    pub peers: Vec<PeerConfig>,
+    /// Maximum size of a blob in bytes.
+    pub max_size: u64,
+    /// Time limit to download a blob from a source, in seconds.
+    pub timeout_secs: u64,
+    /// Time to wait before retrying the failed fetches of an app, in seconds. Doubled after each
+    /// failure up to `max_retry_interval_secs`.
+    pub retry_interval_secs: u64,
+    pub max_retry_interval_secs: u64,
+}
+
+impl Default for BlobFetchConfig {
+    fn default() -> Self {
+        Self {
+            sources: vec![],
+            peers: vec![],
+            max_size: 1024 * 1024 * 1024 * 4,
+            timeout_secs: 3600,
+            retry_interval_secs: 10,
+            max_retry_interval_secs: 3600,
+        }
+    }
+}
+
+impl BlobFetchConfig {
+    pub fn from_config_file() -> Self {
+        let config = load_config_file()
+            .select("blob_fetch")
+            .extract::<BlobFetchConfig>()
+            .unwrap_or_default();
+        info!("loaded blob fetch config: {config:?}");
+        config
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum FetchState {
+    Downloading { received: u64, size: Option<u64> },
+    Done,
+    Failed(String),
+}
+
+/// Downloads the blobs required by the deployed apps that are missing from the local store.
+pub(crate) struct BlobFetcher {
+    config: BlobFetchConfig,
+    client: reqwest::Client,
+    blob_loader: BlobLoader,
+    fetches: Mutex<HashMap<String, watch::Receiver<FetchState>>>,
+}
+
+impl BlobFetcher {
+    pub fn new(config: BlobFetchConfig, blob_loader: BlobLoader) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::builder()
+                .connect_timeout(Duration::from_secs(30))
+                .build()
+                .expect("failed to create http client"),
+            blob_loader,
+            fetches: Default::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.config.sources.is_empty() || !self.config.peers.is_empty()
+    }
+
+    /// The time to wait before the `attempt`-th retry, counting from 0.
+    pub fn retry_interval(&self, attempt: u32) -> Duration {
+        let secs = self
+            .config
+            .retry_interval_secs
+            .saturating_mul(1 << attempt.min(32))
+            .min(self.config.max_retry_interval_secs);
+        Duration::from_secs(secs)
+    }
+
+    fn is_ready(&self, hash: &str, cid: Option<&str>) -> bool {
+        self.blob_loader.exists(hash) || cid.is_some_and(|cid| self.blob_loader.exists(cid))
+    }
+
+    /// Reports the state of a blob for `AppList`.
+    pub fn status(&self, hash: &str, cid: &str) -> pb::BlobStatus {
+        let mut status = pb::BlobStatus {
+            hash: hash.to_string(),
+            cid: cid.to_string(),
+            ..Default::default()
+        };
+        if self.blob_loader.exists(hash) || self.blob_loader.exists(cid) {
+            status.state = "ready".into();
+            return status;
+        }
+        let state = self
+            .fetches
+            .lock()
+            .unwrap()
+            .get(hash)
+            .map(|rx| rx.borrow().clone());
+        match state {
+            None => status.state = "missing".into(),
+            Some(FetchState::Done) => status.state = "ready".into(),
+            Some(FetchState::Downloading { received, size }) => {
+                status.state = "downloading".into();
+                status.received = received;
+                status.size = size.unwrap_or_default();
+            }
+            Some(FetchState::Failed(error)) => {
+                status.state = "failed".into();
+                status.error = error;
+            }
+        }
+        status
+    }
+
+    /// Downloads the blob unless it is already being downloaded, and waits for it to finish.
+    pub async fn fetch(self: &Arc<Self>, hash: &str, cid: Option<&str>) -> Result<()> {
+        if self.is_ready(hash, cid) {
+            return Ok(());
+        }
+        let mut rx = {
+            let mut fetches = self.fetches.lock().unwrap();
+            match fetches.get(hash) {
+                Some(rx) if !matches!(*rx.borrow(), FetchState::Failed(_)) => rx.clone(),
+                _ => {
+                    let (tx, rx) = watch::channel(FetchState::Downloading {
+                        received: 0,
+                        size: None,
+                    });
+                    fetches.insert(hash.to_string(), rx.clone());
+                    let this = self.clone();
+                    let hash = hash.to_string();
+                    let cid = cid.map(ToString::to_string);
+                    tokio::spawn(async move {
+                        match this.download(&hash, cid.as_deref(), &tx).await {
+                            Ok(()) => {
+                                info!(hash, "blob downloaded");
+                                tx.send_replace(FetchState::Done);
+                                this.fetches.lock().unwrap().remove(&hash);
+                            }
+                            Err(err) => {
+                                warn!(hash, "failed to download blob: {err:?}");
+                                tx.send_replace(FetchState::Failed(format!("{err:?}")));
+                            }
+                        }
+                    });
+                    rx
+                }
+            }
+        };
+        loop {
+            match &*rx.borrow_and_update() {
+                FetchState::Done => return Ok(()),
+                FetchState::Failed(error) => bail!("{error}"),
+                FetchState::Downloading { .. } => {}
+            }
+            rx.changed().await.context("blob download aborted")?;
+        }
+    }
+
+    /// Returns the URLs to try in order, along with the token to access them.
+    fn candidates(&self, hash: &str, cid: Option<&str>) -> Vec<(String, &str)> {
+        let mut candidates = vec![];
+        for template in &self.config.sources {
+            let url = if template.contains("{cid}") {
+                let Some(cid) = cid else {
+                    continue;
+                };
+                template.replace("{cid}", cid)
+            } else if template.contains("{hash}") {
+                template.replace("{hash}", hash)
+            } else {
+                continue;
+            };
+            candidates.push((url, ""));
+        }
+        for peer in &self.config.peers {
+            let url = format!("{}/blob/{hash}", peer.url.trim_end_matches('/'));
+            candidates.push((url, peer.token.as_str()));
+        }
+        candidates
+    }
+
+    async fn download(
+        &self,
+        hash: &str,
+        cid: Option<&str>,
+        tx: &watch::Sender<FetchState>,
+    ) -> Result<()> {
+        let candidates = self.candidates(hash, cid);
+        if candidates.is_empty() {
+            bail!("no source to download the blob from");
+        }
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        let mut errors = vec![];
+        for (url, token) in candidates {
+            info!(hash, url, "downloading blob");
+            let result = tokio::time::timeout(timeout, self.download_from(&url, token, hash, tx))
+                .await
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+            match result {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    warn!(hash, url, "failed to download blob: {err:?}");
+                    errors.push(format!("{url}: {err}"));
+                }
+            }
+        }
+        bail!("all sources failed: {}", errors.join("; "))
+    }
+
+    async fn download_from(
+        &self,
+        url: &str,
+        token: &str,
+        hash: &str,
+        tx: &watch::Sender<FetchState>,
+    ) -> Result<()> {
+        let mut request = self.client.get(url);
+        if !token.is_empty() {
+            request = request.bearer_auth(token);
+        }
+        let mut response = request.send().await?.error_for_status()?;
+        let size = response.content_length();
+        if size.unwrap_or_default() > self.config.max_size {
+            bail!("blob too large, max={}", self.config.max_size);
+        }
+        let path = self.blob_loader.temp_path()?;
+        let _guard = scopeguard::guard((), |_| {
+            let _ = std::fs::remove_file(&path);
+        });
+        let mut file = tokio::fs::File::create(&path)
+            .await
+            .context("failed to create temporary file")?;
+        let mut received = 0_u64;
+        tx.send_replace(FetchState::Downloading { received, size });
+        while let Some(chunk) = response.chunk().await? {
+            received += chunk.len() as u64;
+            if received > self.config.max_size {
+                bail!("blob too large, max={}", self.config.max_size);
+            }
+            file.write_all(&chunk).await?;
+            tx.send_replace(FetchState::Downloading { received, size });
+        }
+        file.sync_all().await?;
+        drop(file);
+        self.blob_loader
+            .put_file(hash, &path)
+            .await
+            .context("failed to verify the downloaded blob")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt as _;
+
+    const DATA: &[u8] = b"hello world\n";
+    const SHA256: &str = "sha256:a948904f2f0f479b8f8197694b30184b0d2ed1c1cd2a1ec0fb85d299a192a447";
+
+    fn test_fetcher(config: BlobFetchConfig) -> (Arc<BlobFetcher>, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let fetcher = BlobFetcher::new(config, BlobLoader::new(&dir));
+        (Arc::new(fetcher), dir)
+    }
+
+    /// Serves `body` to every request on a local port. Returns the base URL.
+    async fn serve(body: &'static [u8]) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).await;
+                let header = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(header.as_bytes()).await;
+                let _ = stream.write_all(body).await;
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    #[test]
+    fn candidates_expand_the_templates() {
+        let (fetcher, dir) = test_fetcher(BlobFetchConfig {
+            sources: vec![
+                "https://ipfs.io/ipfs/{cid}".into(),
+                "https://blobs.example.com/{hash}".into(),
+                "https://static.example.com/file".into(),
+            ],
+            peers: vec![PeerConfig {
+                url: "http://10.0.0.2:8001/".into(),
+                token: "secret".into(),
+            }],
+            ..Default::default()
+        });
+        let urls = |cid| {
+            fetcher
+                .candidates("sha256:00", cid)
+                .into_iter()
+                .map(|(url, token)| format!("{url} {token}"))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            urls(Some("Qm00")),
+            [
+                "https://ipfs.io/ipfs/Qm00 ",
+                "https://blobs.example.com/sha256:00 ",
+                "http://10.0.0.2:8001/blob/sha256:00 secret",
+            ]
+        );
+        // Templates that need the CID are skipped for the blobs without one.
+        assert_eq!(
+            urls(None),
+            [
+                "https://blobs.example.com/sha256:00 ",
+                "http://10.0.0.2:8001/blob/sha256:00 secret",
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn downloads_are_verified() {
+        let good = serve(DATA).await;
+        let (fetcher, dir) = test_fetcher(BlobFetchConfig {
+            sources: vec![format!("{good}/{{hash}}")],
+            ..Default::default()
+        });
+        fetcher.fetch(SHA256, None).await.unwrap();
+        assert_eq!(fetcher.status(SHA256, "").state, "ready");
+        assert_eq!(fetcher.blob_loader.get(SHA256).unwrap().unwrap(), DATA);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn corrupted_downloads_are_rejected() {
+        let bad = serve(b"hello world!").await;
+        let (fetcher, dir) = test_fetcher(BlobFetchConfig {
+            sources: vec![format!("{bad}/{{hash}}")],
+            ..Default::default()
+        });
+        let err = fetcher.fetch(SHA256, None).await.unwrap_err();
+        assert!(err.to_string().contains("failed to verify"), "{err}");
+        assert!(!fetcher.blob_loader.exists(SHA256));
+        assert_eq!(fetcher.status(SHA256, "").state, "failed");
+        // Nothing is left in the temporary directory.
+        assert_eq!(std::fs::read_dir(dir.join(".tmp")).unwrap().count(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn retries_back_off() {
+        let (fetcher, dir) = test_fetcher(BlobFetchConfig {
+            retry_interval_secs: 10,
+            max_retry_interval_secs: 60,
+            ..Default::default()
+        });
+        let intervals: Vec<_> = (0..5)
+            .map(|attempt| fetcher.retry_interval(attempt).as_secs())
+            .collect();
+        assert_eq!(intervals, [10, 20, 40, 60, 60]);
+        assert_eq!(fetcher.retry_interval(u32::MAX).as_secs(), 60);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use rocket::serde::Deserialize;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tracing::{info, warn};
+use wapo_host::blobs::{BlobFile, BlobLoader};
+
+use crate::config::load_config_file;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct BlobGcConfig {
+    /// Whether to remove unreferenced blobs periodically.
+    pub enabled: bool,
+    /// Interval between two collections, in seconds.
+    pub interval_secs: u64,
+    /// How long a blob must stay unreferenced before it is removed, in seconds.
+    pub grace_period_secs: u64,
+    /// Chunked uploads idle for longer than this are discarded, in seconds.
+    pub upload_timeout_secs: u64,
+}
+
+impl Default for BlobGcConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 3600,
+            grace_period_secs: 3600 * 24,
+            upload_timeout_secs: 3600,
+        }
+    }
+}
+
+impl BlobGcConfig {
+    pub fn from_config_file() -> Self {
+        let config = load_config_file()
+            .select("blob_gc")
+            .extract::<BlobGcConfig>()
+            .unwrap_or_default();
+        info!("loaded blob gc config: {config:?}");
+        config
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period_secs)
+    }
+}
+
+/// Mark-and-sweep collector for the blobs directory.
+///
+/// The roots are the `code_hash` and `required_blobs` of the deployed apps. A blob is removed
+/// only after it has been seen unreferenced for the whole grace period, so blobs uploaded ahead
+/// of a deployment, or those of an app being redeployed, are kept.
+pub(crate) struct BlobGc {
+    pub config: BlobGcConfig,
+    unreferenced_since: Mutex<HashMap<String, Instant>>,
+    /// Read while the roots change, and written from taking the roots until the garbage is
+    /// removed, so that the blobs of an app being deployed are not removed under it.
+    ///
+    /// An async lock, as deployments wait on it while a collection sweeps the disk.
+    roots: RwLock<()>,
+}
+
+impl BlobGc {
+    pub fn new(config: BlobGcConfig) -> Self {
+        Self {
+            config,
+            unreferenced_since: Default::default(),
+            roots: RwLock::new(()),
+        }
+    }
+
+    /// Blocks the collections while the roots are changed.
+    pub async fn changing_roots(&self) -> RwLockReadGuard<'_, ()> {
+        self.roots.read().await
+    }
+
+    /// Blocks the changes of the roots during a collection.
+    pub async fn collecting(&self) -> RwLockWriteGuard<'_, ()> {
+        self.roots.write().await
+    }
+
+    /// Returns how long the blob has been observed unreferenced.
+    pub fn unreferenced_for(&self, key: &str) -> Option<Duration> {
+        let since = *self.unreferenced_since.lock().unwrap().get(key)?;
+        Some(since.elapsed())
+    }
+
+    /// Marks the unreferenced blobs and returns the ones that are due for removal.
+    pub fn sweep<'a>(
+        &self,
+        files: &'a [BlobFile],
+        referenced: &HashSet<String>,
+        now: Instant,
+    ) -> Vec<&'a BlobFile> {
+        let grace_period = self.config.grace_period();
+        let mut unreferenced_since = self.unreferenced_since.lock().unwrap();
+        let mut garbage = vec![];
+        let mut seen = HashSet::new();
+        for file in files {
+            if referenced.contains(&file.key) {
+                continue;
+            }
+            seen.insert(&file.key);
+            let since = *unreferenced_since.entry(file.key.clone()).or_insert(now);
+            let modified_age = SystemTime::now()
+                .duration_since(file.modified)
+                .unwrap_or_default();
+            if now.duration_since(since) >= grace_period && modified_age >= grace_period {
+                garbage.push(file);
+            }
+        }
+        unreferenced_since.retain(|key, _| seen.contains(key));
+        garbage
+    }
+}
+
+/// Discards the chunked uploads that have been idle for longer than `timeout`.
+pub(crate) async fn cleanup_uploads(blob_loader: BlobLoader, timeout: Duration) {
+    let interval = (timeout / 10).clamp(Duration::from_secs(10), Duration::from_secs(600));
+    loop {
+        tokio::time::sleep(interval).await;
+        match blob_loader.cleanup_uploads(timeout) {
+            Ok(0) => {}
+            Ok(removed) => info!(removed, "stale uploads removed"),
+            Err(err) => warn!("failed to clean up uploads: {err:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(key: &str) -> BlobFile {
+        BlobFile {
+            key: key.into(),
+            size: 1,
+            modified: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn sweep_respects_grace_period() {
+        let gc = BlobGc::new(BlobGcConfig {
+            grace_period_secs: 60,
+            ..Default::default()
+        });
+        let files = [file("aa"), file("bb")];
+        let referenced = HashSet::from(["aa".to_string()]);
+        let t0 = Instant::now();
+
+        assert!(gc.sweep(&files, &referenced, t0).is_empty());
+        let garbage = gc.sweep(&files, &referenced, t0 + Duration::from_secs(60));
+        assert_eq!(garbage.len(), 1);
+        assert_eq!(garbage[0].key, "bb");
+
+        // Referenced again, the mark is cleared.
+        let referenced = HashSet::from(["aa".to_string(), "bb".to_string()]);
+        assert!(gc.sweep(&files, &referenced, t0).is_empty());
+        assert!(gc.unreferenced_for("bb").is_none());
+    }
+}
@@ -30,31 +30,49 @@ impl AddressGenerator for DefaultWorkerConfig {
 
 pub trait KeyProvider {
     fn get_key() -> &'static Pair;
+    /// Whether the key was generated by this run of the worker rather than loaded from an earlier
+    /// one.
+    fn is_new_key() -> bool {
+        false
+    }
 }
 
 pub struct DefaultKerProvider<P>(PhantomData<P>);
 
-impl<P: Paths> KeyProvider for DefaultKerProvider<P> {
-    fn get_key() -> &'static Pair {
-        static KEY: OnceLock<Pair> = OnceLock::new();
+impl<P: Paths> DefaultKerProvider<P> {
+    fn key() -> &'static (Pair, bool) {
+        static KEY: OnceLock<(Pair, bool)> = OnceLock::new();
 
         KEY.get_or_init(|| {
             let keyfile = P::secret_data_dir().join("worker.key");
             match std::fs::read(&keyfile) {
-                Ok(secret) => Pair::decode(&mut &secret[..]).expect("failed to load keypair"),
+                Ok(secret) => (
+                    Pair::decode(&mut &secret[..]).expect("failed to load keypair"),
+                    false,
+                ),
                 Err(err) => {
                     if err.kind() != std::io::ErrorKind::NotFound {
                         panic!("Failed to read keypair: {err}");
                     }
                     let pair = Pair::new();
                     std::fs::write(&keyfile, pair.encode()).expect("failed to write keypair");
-                    pair
+                    (pair, true)
                 }
             }
         })
     }
 }
 
+impl<P: Paths> KeyProvider for DefaultKerProvider<P> {
+    fn get_key() -> &'static Pair {
+        &Self::key().0
+    }
+
+    fn is_new_key() -> bool {
+        Self::key().1
+    }
+}
+
 pub trait Paths {
     fn data_dir() -> PathBuf;
     fn storage_dir() -> PathBuf {
@@ -97,3 +115,56 @@ impl WorkerConfig for DefaultWorkerConfig {
     type KeyProvider = DefaultKerProvider<Self>;
     type Paths = Self;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wapod_crypto::wapod_types::ticket::Schedule;
+
+    fn base_manifest() -> AppManifest {
+        AppManifest {
+            version: 1,
+            code_hash: "sha256:00".into(),
+            args: vec!["a".into()],
+            env_vars: vec![("K".into(), "V".into())],
+            on_demand: true,
+            resizable: false,
+            max_query_size: 1024,
+            label: "app".into(),
+            required_blobs: vec![("sha256:00".into(), "".into())],
+            schedules: vec![],
+            mounts: vec![],
+            scratch: None,
+            secret_env_vars: vec![],
+            domains: vec![],
+        }
+    }
+
+    #[test]
+    fn address_of_base_manifest_is_stable() {
+        // The address of the manifest before the schedules, mounts, scratch and secret
+        // environment variables were added.
+        let address = DefaultWorkerConfig::generate_address(&base_manifest());
+        assert_eq!(
+            hex::encode(address),
+            "1cbc815614edee80858118cde6937698bcc570e4942c78882bd34c88b3868701"
+        );
+    }
+
+    #[test]
+    fn extensions_change_the_address() {
+        let mut manifest = base_manifest();
+        manifest.schedules.push(Schedule {
+            name: "tick".into(),
+            cron: "".into(),
+            interval_secs: 60,
+            jitter_secs: 0,
+            catch_up: false,
+            payload: vec![],
+        });
+        assert_ne!(
+            DefaultWorkerConfig::generate_address(&manifest),
+            DefaultWorkerConfig::generate_address(&base_manifest())
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use anyhow::{bail, Context, Result};
+use rocket::http::uri::Origin;
+use tracing::{info, warn};
+use wapo_host::ShortId;
+
+use crate::Address;
+
+/// The apps that the HTTP requests to each domain on the user port are routed to.
+///
+/// It is looked up on every request to the user port, so it is kept behind its own lock rather
+/// than the worker one. Changes are made with the worker locked, which is taken first.
+#[derive(Default)]
+pub struct DomainMap {
+    domains: RwLock<HashMap<String, Address>>,
+}
+
+impl DomainMap {
+    pub(crate) fn bind(&self, domain: &str, address: Address) -> Result<()> {
+        let domain = normalize_domain(domain)?;
+        let mut domains = self.domains.write().unwrap();
+        match domains.get(&domain) {
+            Some(bound) if *bound != address => bail!("domain already bound to another app"),
+            _ => {}
+        }
+        info!(%domain, app=%ShortId(address), "domain bound");
+        domains.insert(domain, address);
+        Ok(())
+    }
+
+    pub(crate) fn unbind(&self, domain: &str, address: Address) -> Result<()> {
+        let domain = normalize_domain(domain)?;
+        let mut domains = self.domains.write().unwrap();
+        if domains.get(&domain) != Some(&address) {
+            bail!("domain not bound to the app");
+        }
+        info!(%domain, app=%ShortId(address), "domain unbound");
+        domains.remove(&domain);
+        Ok(())
+    }
+
+    /// Restores a binding from the registry, taking the domain as already normalized.
+    pub(crate) fn restore(&self, domain: String, address: Address) {
+        self.domains.write().unwrap().insert(domain, address);
+    }
+
+    pub(crate) fn remove_app(&self, address: Address) {
+        self.domains
+            .write()
+            .unwrap()
+            .retain(|_, bound| *bound != address);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.domains.write().unwrap().clear();
+    }
+
+    /// The domains bound to the app, sorted.
+    pub(crate) fn domains_of(&self, address: &Address) -> Vec<String> {
+        let mut domains: Vec<_> = self
+            .domains
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, bound)| *bound == address)
+            .map(|(domain, _)| domain.clone())
+            .collect();
+        domains.sort();
+        domains
+    }
+
+    /// The app the domain is bound to.
+    pub fn app_for(&self, domain: &str) -> Option<Address> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        self.domains.read().unwrap().get(&domain).copied()
+    }
+
+    /// Rewrites a request to a domain bound to an app into `/app/<address>/<path>`, keeping the
+    /// query. Returns `None` if the domain is not bound.
+    pub fn route(&self, host: &str, uri: &Origin<'_>) -> Option<Origin<'static>> {
+        let address = self.app_for(host)?;
+        let mut rewritten = format!("/app/{}{}", hex::encode(address), uri.path());
+        if let Some(query) = uri.query() {
+            rewritten.push('?');
+            rewritten.push_str(query.as_str());
+        }
+        match Origin::parse_owned(rewritten) {
+            Ok(uri) => Some(uri),
+            Err(err) => {
+                warn!("failed to rewrite the request uri: {err}");
+                None
+            }
+        }
+    }
+}
+
+/// Checks that the owner of the domain routes it to the app, by a TXT record at `_wapod.<domain>`
+/// reading `wapod-app=<hex address>`.
+///
+/// Domains declared in a manifest are only bound after this, as anyone can deploy an app.
+pub(crate) async fn verify_ownership(domain: &str, address: &Address) -> Result<()> {
+    let domain = normalize_domain(domain)?;
+    let resolver = hickory_resolver::TokioAsyncResolver::tokio_from_system_conf()
+        .context("failed to create the DNS resolver")?;
+    let records = resolver
+        .txt_lookup(format!("_wapod.{domain}."))
+        .await
+        .context("failed to look up the ownership record")?;
+    if !records
+        .iter()
+        .any(|record| names_app(&record.to_string(), address))
+    {
+        bail!("no ownership record of {domain} names the app");
+    }
+    Ok(())
+}
+
+/// Whether the TXT record is a `wapod-app=<hex address>` naming the app.
+fn names_app(record: &str, address: &Address) -> bool {
+    let Some(value) = record.trim().strip_prefix("wapod-app=") else {
+        return false;
+    };
+    let value = value.trim();
+    let value = value.strip_prefix("0x").unwrap_or(value);
+    value.eq_ignore_ascii_case(&hex::encode(address))
+}
+
+/// Lowercases the domain and checks that it is a valid DNS name.
+pub(crate) fn normalize_domain(domain: &str) -> Result<String> {
+    let domain = domain.trim().trim_end_matches('.').to_ascii_lowercase();
+    let valid_label = |label: &str| {
+        (1..=63).contains(&label.len())
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    };
+    if domain.len() > 253 || !domain.contains('.') || !domain.split('.').all(valid_label) {
+        bail!("invalid domain: {domain}");
+    }
+    Ok(domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APP_A: Address = [0xaa; 32];
+    const APP_B: Address = [0xbb; 32];
+
+    #[test]
+    fn domains_are_normalized() {
+        assert_eq!(
+            normalize_domain(" App.Example.COM. ").unwrap(),
+            "app.example.com"
+        );
+        assert_eq!(
+            normalize_domain("a-1.example.com").unwrap(),
+            "a-1.example.com"
+        );
+        for invalid in [
+            "",
+            "localhost",
+            "example..com",
+            "-a.example.com",
+            "a-.example.com",
+            "a_b.example.com",
+            "*.example.com",
+            "example.com/path",
+            "example.com:8080",
+        ] {
+            assert!(normalize_domain(invalid).is_err(), "{invalid:?}");
+        }
+        let long_label = format!("{}.com", "a".repeat(64));
+        assert!(normalize_domain(&long_label).is_err());
+        let long_domain = format!("{}com", "a.".repeat(126));
+        assert!(normalize_domain(&long_domain).is_err());
+    }
+
+    #[test]
+    fn ownership_records_name_the_app() {
+        let hex_a = hex::encode(APP_A);
+        assert!(names_app(&format!("wapod-app={hex_a}"), &APP_A));
+        assert!(names_app(
+            &format!(" wapod-app=0x{} ", hex_a.to_uppercase()),
+            &APP_A
+        ));
+        assert!(!names_app(&format!("wapod-app={hex_a}"), &APP_B));
+        assert!(!names_app(&format!("wapod-app={}", &hex_a[2..]), &APP_A));
+        assert!(!names_app(&hex_a, &APP_A));
+        assert!(!names_app("v=spf1 -all", &APP_A));
+    }
+
+    #[test]
+    fn bind_and_unbind() {
+        let map = DomainMap::default();
+        map.bind("App.Example.com", APP_A).unwrap();
+        // Binding again to the same app is a no-op, to another one is refused.
+        map.bind("app.example.com.", APP_A).unwrap();
+        assert!(map.bind("app.example.com", APP_B).is_err());
+        map.bind("b.example.com", APP_B).unwrap();
+        map.bind("a2.example.com", APP_A).unwrap();
+        assert_eq!(map.app_for("APP.example.com."), Some(APP_A));
+        assert_eq!(
+            map.domains_of(&APP_A),
+            ["a2.example.com", "app.example.com"]
+        );
+
+        assert!(map.unbind("app.example.com", APP_B).is_err());
+        assert!(map.unbind("none.example.com", APP_A).is_err());
+        map.unbind("APP.example.com", APP_A).unwrap();
+        assert_eq!(map.app_for("app.example.com"), None);
+
+        map.remove_app(APP_A);
+        assert!(map.domains_of(&APP_A).is_empty());
+        assert_eq!(map.app_for("b.example.com"), Some(APP_B));
+        map.clear();
+        assert_eq!(map.app_for("b.example.com"), None);
+    }
+
+    #[test]
+    fn requests_are_routed_to_the_bound_app() {
+        let map = DomainMap::default();
+        map.bind("app.example.com", APP_A).unwrap();
+        let route = |host, uri| {
+            map.route(host, &Origin::parse(uri).unwrap())
+                .map(|uri| uri.to_string())
+        };
+        let app = format!("/app/{}", hex::encode(APP_A));
+        assert_eq!(route("app.example.com", "/"), Some(format!("{app}/")));
+        assert_eq!(
+            route("App.Example.com", "/a/b%20c?x=1&y=%2F"),
+            Some(format!("{app}/a/b%20c?x=1&y=%2F"))
+        );
+        assert_eq!(route("app.example.com", "/a?"), Some(format!("{app}/a?")));
+        assert_eq!(route("other.example.com", "/a?x=1"), None);
+    }
+}
@@ -1,3 +1,4 @@
+pub use domains::DomainMap;
 pub use state::{Worker, WorkerArgs};
 pub use wapod_crypto as crypto;
 pub use wapod_rpc as rpc;
@@ -7,10 +8,21 @@ pub use wapod_types as types;
 pub mod config;
 pub mod prpc_service;
 
+mod acme;
 mod allocator;
+mod app_http;
+mod app_listeners;
+mod blob_fetcher;
+mod blob_gc;
+mod domains;
+mod proxy_protocol;
+mod pubsub;
+mod registry;
+mod scheduler;
 mod sgx;
 mod state;
 mod tcp_acl;
+mod time_service;
 
 pub mod ext {
     pub use wapo_host::rocket_stream::{connect, RequestInfo, StreamResponse};
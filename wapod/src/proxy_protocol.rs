@@ -0,0 +1,40 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use rocket::serde::Deserialize;
+use tracing::{info, warn};
+use wapo_host::ProxyProtocol;
+
+use crate::config::load_config_file;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct ProxyProtocolConfig {
+    /// The upstreams to read the PROXY protocol header from, as IP addresses or CIDRs.
+    pub trusted_upstreams: Vec<String>,
+}
+
+impl ProxyProtocolConfig {
+    pub fn from_config_file() -> Self {
+        let config = load_config_file()
+            .select("proxy_protocol")
+            .extract::<ProxyProtocolConfig>()
+            .unwrap_or_default();
+        info!("loaded PROXY protocol config: {config:?}");
+        config
+    }
+
+    pub fn proxy_protocol(&self) -> ProxyProtocol {
+        let trusted = self.trusted_upstreams.iter().filter_map(|upstream| {
+            let upstream = upstream.trim();
+            let net = upstream
+                .parse::<IpNet>()
+                .or_else(|_| upstream.parse::<IpAddr>().map(IpNet::from));
+            if net.is_err() {
+                warn!("ignoring invalid trusted upstream: {upstream}");
+            }
+            net.ok()
+        });
+        ProxyProtocol::new(trusted)
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    net::SocketAddr,
     ops::Deref,
     path::PathBuf,
     time::{SystemTime, UNIX_EPOCH},
@@ -26,6 +27,7 @@ use scale::Encode;
 use sp_core::crypto::{AccountId32, Ss58Codec};
 use tracing::{error, field::Empty, info, warn};
 use wapo_host::{
+    blobs::UploadStatus,
     rocket_stream::{RequestInfo, StreamResponse},
     MetricsToken, ShortId,
 };
@@ -109,7 +111,11 @@ impl<T: WorkerConfig> OperationRpc for Call<T> {
             bail!("the salt is too long");
         }
         let account = AccountId32::from_string(&request.recipient).context("invalid account")?;
+        let operators = request.decode_operators()?;
         let update = self.init(&request.pnonce, account.into())?;
+        if !operators.is_empty() {
+            self.set_operators(operators);
+        }
         let signature = T::KeyProvider::get_key()
             .sign(wapod_types::ContentType::SessionUpdate, update.encode());
         let pubkey = T::KeyProvider::get_key().public();
@@ -144,7 +150,59 @@ impl<T: WorkerConfig> OperationRpc for Call<T> {
         let loader = self.blob_loader();
         loader
             .remove(&request.hash)
-            .context("failed to remove object")
+            .context("failed to remove object")?;
+        self.evict_module_if_unused(&request.hash);
+        Ok(())
+    }
+
+    async fn blob_upload_begin(
+        self,
+        request: pb::BlobUploadBeginArgs,
+    ) -> Result<pb::BlobUploadStatus> {
+        let status = self
+            .blob_loader()
+            .begin_upload(&request.hash, request.size)
+            .context("failed to begin upload")?;
+        Ok(upload_status_to_pb(status))
+    }
+
+    async fn blob_upload_append(
+        self,
+        request: pb::BlobUploadAppendArgs,
+    ) -> Result<pb::BlobUploadStatus> {
+        let status = self
+            .blob_loader()
+            .append_upload(&request.upload_id, request.offset, &mut &request.data[..])
+            .await
+            .context("failed to append upload")?;
+        Ok(upload_status_to_pb(status))
+    }
+
+    async fn blob_upload_query(self, request: pb::BlobUploadId) -> Result<pb::BlobUploadStatus> {
+        let status = self.blob_loader().upload_status(&request.upload_id)?;
+        Ok(upload_status_to_pb(status))
+    }
+
+    async fn blob_upload_commit(self, request: pb::BlobUploadId) -> Result<pb::Blob> {
+        let hash = self
+            .blob_loader()
+            .commit_upload(&request.upload_id)
+            .context("failed to commit upload")?;
+        Ok(pb::Blob { hash, body: vec![] })
+    }
+
+    async fn blob_upload_abort(self, request: pb::BlobUploadId) -> Result<()> {
+        self.blob_loader().abort_upload(&request.upload_id)
+    }
+
+    async fn blob_list(self) -> Result<pb::BlobListResponse> {
+        self.list_blobs().context("failed to list blobs")
+    }
+
+    async fn blob_gc(self) -> Result<pb::BlobGcResponse> {
+        self.collect_blobs()
+            .await
+            .context("failed to collect blobs")
     }
 
     #[tracing::instrument(name="app.deploy", skip_all, fields(addr=Empty))]
@@ -182,6 +240,14 @@ impl<T: WorkerConfig> OperationRpc for Call<T> {
         Ok(())
     }
 
+    async fn app_bind_domain(self, request: pb::DomainBinding) -> Result<()> {
+        self.bind_domain(&request.domain, request.decode_address()?)
+    }
+
+    async fn app_unbind_domain(self, request: pb::DomainBinding) -> Result<()> {
+        self.unbind_domain(&request.domain, request.decode_address()?)
+    }
+
     async fn app_metrics(self, request: pb::Addresses) -> Result<pb::AppMetricsResponse> {
         let addresses = request.decode_addresses()?;
         let addresses = if addresses.is_empty() {
@@ -216,6 +282,8 @@ impl<T: WorkerConfig> OperationRpc for Call<T> {
                 memory_used: 0,
             });
         });
+        // The metrics restored after a restart must not fall below the ones reported.
+        self.save_registry();
         let metrics = rpc::types::VersionedAppsMetrics::V0(metrics);
         let encoded_metrics = metrics.encode();
         let signature =
@@ -252,6 +320,10 @@ impl<T: WorkerConfig> OperationRpc for Call<T> {
                     info.last_query_elapsed_secs,
                     info.reuse_instances,
                     Some(info.manifest),
+                    info.schedule_runs,
+                    info.blobs,
+                    info.domains,
+                    info.subscriptions,
                 )
             })
             .collect();
@@ -313,6 +385,8 @@ impl<T: WorkerConfig> OperationRpc for Call<T> {
         request: pb::SignRegisterInfoArgs,
     ) -> Result<pb::SignRegisterInfoResponse> {
         let pubkey = T::KeyProvider::get_key().public().to_array();
+        let operator = request.decode_operator()?;
+        self.set_register_operator(operator);
         let runtime_info = rpc::types::WorkerRegistrationInfoV2 {
             version: compat_app_version(),
             machine_id: vec![],
@@ -320,7 +394,7 @@ impl<T: WorkerConfig> OperationRpc for Call<T> {
             ecdh_pubkey: pubkey,
             genesis_block_hash: request.decode_genesis_block_hash()?,
             features: vec![],
-            operator: request.decode_operator()?,
+            operator,
             para_id: request.para_id,
             max_consensus_version: 0,
         };
@@ -385,6 +459,24 @@ impl<T: WorkerConfig> OperationRpc for Call<T> {
         }
         Ok(())
     }
+
+    async fn topic_publish(self, request: pb::PublishArgs) -> Result<pb::PublishResponse> {
+        let result = self
+            .worker
+            .publish(None, &request.topic, &request.payload)?;
+        Ok(pb::PublishResponse {
+            delivered: result.delivered,
+            dropped: result.dropped,
+        })
+    }
+}
+
+fn upload_status_to_pb(status: UploadStatus) -> pb::BlobUploadStatus {
+    pb::BlobUploadStatus {
+        upload_id: status.id,
+        offset: status.offset,
+        size: status.size,
+    }
 }
 
 fn compat_app_version() -> u32 {
@@ -404,6 +496,26 @@ impl<T: WorkerConfig> UserRpc for Call<T> {
     async fn encrypted_query(self, request: pb::EncryptedQueryArgs) -> Result<pb::QueryResponse> {
         OperationRpc::app_encrypted_query(self, request).await
     }
+
+    async fn publish(self, request: pb::PublishArgs) -> Result<pb::PublishResponse> {
+        let result = self
+            .worker
+            .publish_public(&request.topic, &request.payload)?;
+        Ok(pb::PublishResponse {
+            delivered: result.delivered,
+            dropped: result.dropped,
+        })
+    }
+
+    async fn secret_env_key(
+        self,
+        request: pb::SecretEnvKeyArgs,
+    ) -> Result<pb::SecretEnvKeyResponse> {
+        let key = crate::state::secret_env_key::<T>(&request.code_hash);
+        Ok(pb::SecretEnvKeyResponse {
+            pubkey: key.public().to_array().to_vec(),
+        })
+    }
 }
 
 pub async fn handle_prpc<S, T>(
@@ -419,16 +531,37 @@ where
     T: WorkerConfig,
 {
     let data = match data {
-        Some(data) => {
-            let limit = limit_for_method(method, limits);
-            read_data(data, limit).await?
-        }
+        Some(data) => read_prpc_data(method, data, limits).await?,
         None => vec![],
     };
+    handle_prpc_data::<S, T>(worker, method, data, content_type, json).await
+}
+
+/// Reads the body of a prpc request, up to the limit of the method.
+pub async fn read_prpc_data(
+    method: &str,
+    data: Data<'_>,
+    limits: &Limits,
+) -> Result<Vec<u8>, Custom<Vec<u8>>> {
+    let limit = limit_for_method(method, limits);
+    Ok(read_data(data, limit).await?)
+}
+
+/// Dispatches a prpc request whose body has been read.
+pub async fn handle_prpc_data<S, T>(
+    worker: &State<Worker<T>>,
+    method: &str,
+    data: Vec<u8>,
+    content_type: Option<&ContentType>,
+    json: bool,
+) -> Result<Vec<u8>, Custom<Vec<u8>>>
+where
+    S: From<Call<T>> + PrpcService,
+    T: WorkerConfig,
+{
     let json = json || content_type.map(|t| t.is_json()).unwrap_or(false);
     let worker = (*worker).clone();
     let call = Call::new(worker);
-    let data = data.to_vec();
     let result = dispatch_prpc(method.into(), data, json, S::from(call)).await;
     let (status_code, output) = result;
     if status_code == 200 {
@@ -486,9 +619,12 @@ async fn dispatch_prpc(
     (code, data)
 }
 
+/// Forwards an HTTP request to the app, `remote` being the peer address of the client
+/// connection it came on.
 pub async fn connect_vm<'r, T: WorkerConfig>(
     state: &State<Worker<T>>,
     head: RequestInfo,
+    remote: Option<SocketAddr>,
     id: HexBytes,
     path: PathBuf,
     body: Option<Data<'r>>,
@@ -496,6 +632,14 @@ pub async fn connect_vm<'r, T: WorkerConfig>(
     let address =
         id.0.try_into()
             .map_err(|_| (Status::BadRequest, "invalid address".to_string()))?;
+    let permit = state
+        .acquire_http_permit(address, remote)
+        .await
+        .map_err(|err| (Status::NotFound, err.to_string()))?
+        .ok_or((
+            Status::ServiceUnavailable,
+            "too many concurrent requests".to_string(),
+        ))?;
     let guard = state
         .prepare_instance_for_query(address, 0)
         .await
@@ -503,13 +647,14 @@ pub async fn connect_vm<'r, T: WorkerConfig>(
             warn!("failed to prepare query: {err:?}");
             (Status::NotFound, err.to_string())
         })?;
-    let command_tx = state
-        .sender_for(address, 0)
-        .ok_or((Status::NotFound, Default::default()))?;
+    let command_tx = guard
+        .command_sender()
+        .map_err(|err| (Status::NotFound, err.to_string()))?;
     let path = path
         .to_str()
         .ok_or((Status::BadRequest, "invalid path".to_string()))?;
-    let result = wapo_host::rocket_stream::connect(head, path, body, command_tx, guard).await;
+    let result =
+        wapo_host::rocket_stream::connect(head, path, body, command_tx, (guard, permit)).await;
     match result {
         Ok(response) => Ok(response),
         Err(err) => Err((Status::InternalServerError, err.to_string())),
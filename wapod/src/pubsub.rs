@@ -0,0 +1,306 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use rocket::serde::Deserialize;
+use scale::Encode;
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+use tracing::{debug, info, warn};
+use wapo_host::{ShortId, TopicMessage};
+use wapod_rpc::prpc as pb;
+
+use crate::config::load_config_file;
+use crate::Address;
+
+const MAX_TOPIC_LEN: usize = 128;
+const MAX_MESSAGE_SIZE: usize = 1024 * 64;
+const MAX_TOPICS: usize = 1024;
+const MAX_SUBSCRIBERS_PER_TOPIC: usize = 1024;
+/// How often the drops of a subscriber are logged at most.
+const DROP_WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct PubSubConfig {
+    /// The topics anyone can publish to with the user `Publish` RPC. A trailing `*` matches the
+    /// topics starting with the rest of the pattern.
+    pub public_topics: Vec<String>,
+}
+
+impl PubSubConfig {
+    pub fn from_config_file() -> Self {
+        let config = load_config_file()
+            .select("pubsub")
+            .extract::<PubSubConfig>()
+            .unwrap_or_default();
+        info!("loaded pubsub config: {config:?}");
+        config
+    }
+
+    /// Whether the user RPC may publish to the topic.
+    pub fn is_public(&self, topic: &str) -> bool {
+        self.public_topics
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => topic.starts_with(prefix),
+                None => topic == pattern,
+            })
+    }
+}
+
+struct Subscriber {
+    address: Address,
+    tx: Sender<Vec<u8>>,
+    dropped: u64,
+    last_warned: Option<Instant>,
+}
+
+impl Subscriber {
+    fn record_drop(&mut self, topic: &str) {
+        self.dropped += 1;
+        if self
+            .last_warned
+            .is_some_and(|t| t.elapsed() < DROP_WARN_INTERVAL)
+        {
+            return;
+        }
+        self.last_warned = Some(Instant::now());
+        warn!(
+            topic,
+            app = %ShortId(self.address),
+            dropped = self.dropped,
+            "topic queue full, messages dropped"
+        );
+    }
+}
+
+/// The result of publishing a message to a topic.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PublishResult {
+    /// Number of subscribers the message was queued to.
+    pub delivered: u32,
+    /// Number of subscribers that dropped the message because their queue was full.
+    pub dropped: u32,
+}
+
+/// Fans out topic messages to the subscribed instances.
+///
+/// Each instance has a bounded queue. Messages to a full queue are dropped and counted.
+#[derive(Default)]
+pub(crate) struct TopicBus {
+    topics: HashMap<String, Vec<Subscriber>>,
+    config: PubSubConfig,
+}
+
+impl TopicBus {
+    pub fn new(config: PubSubConfig) -> Self {
+        Self {
+            topics: Default::default(),
+            config,
+        }
+    }
+
+    /// Publishes a message on behalf of a user, to one of the topics open to them.
+    pub fn publish_public(&mut self, topic: &str, payload: &[u8]) -> Result<PublishResult> {
+        if !self.config.is_public(topic) {
+            bail!("publishing to the topic is not allowed");
+        }
+        self.publish(topic, None, payload)
+    }
+
+    pub fn subscribe(&mut self, topic: &str, address: Address, tx: Sender<Vec<u8>>) -> bool {
+        if topic.len() > MAX_TOPIC_LEN {
+            return false;
+        }
+        if !self.topics.contains_key(topic) && self.topics.len() >= MAX_TOPICS {
+            return false;
+        }
+        let subscribers = self.topics.entry(topic.to_string()).or_default();
+        subscribers.retain(|sub| !sub.tx.is_closed());
+        if subscribers.iter().any(|sub| sub.tx.same_channel(&tx)) {
+            return true;
+        }
+        if subscribers.len() >= MAX_SUBSCRIBERS_PER_TOPIC {
+            return false;
+        }
+        subscribers.push(Subscriber {
+            address,
+            tx,
+            dropped: 0,
+            last_warned: None,
+        });
+        true
+    }
+
+    pub fn unsubscribe(&mut self, topic: &str, tx: &Sender<Vec<u8>>) -> bool {
+        let Some(subscribers) = self.topics.get_mut(topic) else {
+            return false;
+        };
+        let before = subscribers.len();
+        subscribers.retain(|sub| !sub.tx.same_channel(tx) && !sub.tx.is_closed());
+        let removed = subscribers.len() < before;
+        if subscribers.is_empty() {
+            self.topics.remove(topic);
+        }
+        removed
+    }
+
+    pub fn publish(
+        &mut self,
+        topic: &str,
+        publisher: Option<Address>,
+        payload: &[u8],
+    ) -> Result<PublishResult> {
+        if topic.is_empty() || topic.len() > MAX_TOPIC_LEN {
+            bail!("invalid topic");
+        }
+        if payload.len() > MAX_MESSAGE_SIZE {
+            bail!("message too large, max={MAX_MESSAGE_SIZE}");
+        }
+        let mut result = PublishResult::default();
+        let Some(subscribers) = self.topics.get_mut(topic) else {
+            debug!(topic, "no subscriber for the topic");
+            return Ok(result);
+        };
+        let message = TopicMessage {
+            topic: topic.to_string(),
+            publisher,
+            payload: payload.to_vec(),
+        }
+        .encode();
+        subscribers.retain_mut(|sub| {
+            match sub.tx.try_send(message.clone()) {
+                Ok(()) => result.delivered += 1,
+                Err(TrySendError::Full(_)) => {
+                    sub.record_drop(topic);
+                    result.dropped += 1;
+                }
+                Err(TrySendError::Closed(_)) => return false,
+            }
+            true
+        });
+        if subscribers.is_empty() {
+            self.topics.remove(topic);
+        }
+        Ok(result)
+    }
+
+    /// The topics the instances of an app are subscribed to, with the messages they dropped.
+    pub fn subscriptions_of(&self, address: &Address) -> Vec<pb::TopicSubscription> {
+        let mut subscriptions = BTreeMap::<&str, pb::TopicSubscription>::new();
+        for (topic, subscribers) in &self.topics {
+            for sub in subscribers {
+                if sub.address != *address || sub.tx.is_closed() {
+                    continue;
+                }
+                let entry = subscriptions
+                    .entry(topic)
+                    .or_insert_with(|| pb::TopicSubscription {
+                        topic: topic.clone(),
+                        subscribers: 0,
+                        dropped: 0,
+                    });
+                entry.subscribers += 1;
+                entry.dropped += sub.dropped;
+            }
+        }
+        subscriptions.into_values().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scale::Decode;
+    use tokio::sync::mpsc::channel;
+
+    const APP: Address = [1; 32];
+
+    #[test]
+    fn messages_fan_out_to_subscribers() {
+        let mut bus = TopicBus::default();
+        let (tx1, mut rx1) = channel(4);
+        let (tx2, mut rx2) = channel(4);
+        assert!(bus.subscribe("prices", APP, tx1.clone()));
+        assert!(bus.subscribe("prices", [2; 32], tx2));
+        // Subscribing twice with the same queue is a no-op.
+        assert!(bus.subscribe("prices", APP, tx1));
+
+        let result = bus.publish("prices", None, b"42").unwrap();
+        assert_eq!((result.delivered, result.dropped), (2, 0));
+        for rx in [&mut rx1, &mut rx2] {
+            let message = TopicMessage::decode(&mut &rx.try_recv().unwrap()[..]).unwrap();
+            assert_eq!(message.topic, "prices");
+            assert_eq!(message.payload, b"42");
+            assert!(rx.try_recv().is_err());
+        }
+        let result = bus.publish("other", None, b"42").unwrap();
+        assert_eq!((result.delivered, result.dropped), (0, 0));
+    }
+
+    #[test]
+    fn full_queues_drop_messages() {
+        let mut bus = TopicBus::default();
+        let (tx, mut rx) = channel(1);
+        assert!(bus.subscribe("prices", APP, tx));
+        assert_eq!(bus.publish("prices", None, b"1").unwrap().delivered, 1);
+        for _ in 0..3 {
+            assert_eq!(bus.publish("prices", None, b"2").unwrap().dropped, 1);
+        }
+        let subscriptions = bus.subscriptions_of(&APP);
+        assert_eq!(subscriptions.len(), 1);
+        assert_eq!(subscriptions[0].topic, "prices");
+        assert_eq!(subscriptions[0].subscribers, 1);
+        assert_eq!(subscriptions[0].dropped, 3);
+        assert!(bus.subscriptions_of(&[2; 32]).is_empty());
+
+        rx.try_recv().unwrap();
+        assert_eq!(bus.publish("prices", None, b"3").unwrap().delivered, 1);
+    }
+
+    #[test]
+    fn closed_and_unsubscribed_queues_are_removed() {
+        let mut bus = TopicBus::default();
+        let (tx1, rx1) = channel(1);
+        let (tx2, _rx2) = channel(1);
+        assert!(bus.subscribe("a", APP, tx1));
+        assert!(bus.subscribe("b", APP, tx2.clone()));
+        drop(rx1);
+        assert_eq!(bus.publish("a", None, b"").unwrap().delivered, 0);
+        assert!(!bus.topics.contains_key("a"));
+
+        assert!(bus.unsubscribe("b", &tx2));
+        assert!(!bus.unsubscribe("b", &tx2));
+        assert!(bus.topics.is_empty());
+    }
+
+    #[test]
+    fn users_publish_to_public_topics_only() {
+        let mut bus = TopicBus::new(PubSubConfig {
+            public_topics: vec!["prices".into(), "feeds/*".into()],
+        });
+        let (tx, _rx) = channel(4);
+        assert!(bus.subscribe("prices", APP, tx.clone()));
+        assert!(bus.subscribe("feeds/eth", APP, tx.clone()));
+        assert!(bus.subscribe("admin", APP, tx));
+        assert_eq!(bus.publish_public("prices", b"1").unwrap().delivered, 1);
+        assert_eq!(bus.publish_public("feeds/eth", b"1").unwrap().delivered, 1);
+        assert!(bus.publish_public("prices/eth", b"1").is_err());
+        assert!(bus.publish_public("feeds", b"1").is_err());
+        assert!(bus.publish_public("admin", b"1").is_err());
+        assert_eq!(bus.publish("admin", None, b"1").unwrap().delivered, 1);
+    }
+
+    #[test]
+    fn invalid_topics_and_messages_are_rejected() {
+        let mut bus = TopicBus::default();
+        let (tx, _rx) = channel(1);
+        let long_topic = "x".repeat(MAX_TOPIC_LEN + 1);
+        assert!(!bus.subscribe(&long_topic, APP, tx));
+        assert!(bus.publish("", None, b"").is_err());
+        assert!(bus.publish(&long_topic, None, b"").is_err());
+        assert!(bus
+            .publish("prices", None, &vec![0; MAX_MESSAGE_SIZE + 1])
+            .is_err());
+    }
+}
@@ -0,0 +1,337 @@
+//! The deployed apps and the admin operators, persisted in the secret data directory so that they
+//! survive restarts.
+//!
+//! The secret data directory holds protected files, which the host can't read or forge but can
+//! replace with an older copy of themselves: nothing detects the rollback of the registry. A worker
+//! restarted from an older copy redeploys the apps as they were then, and reissues the metrics
+//! sequence numbers issued after the copy was saved. The tokens carrying them stay distinct by
+//! their random nonces, but their consumers can't tell from the sequence numbers alone that the
+//! worker went back in time.
+
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use scale::{Decode, DecodeAll, Encode};
+use tracing::warn;
+use wapo_host::Metrics;
+use wapod_crypto::wapod_types::ticket::AppManifest;
+
+use crate::config::{Paths, WorkerConfig};
+
+type Address = [u8; 32];
+
+/// How many metrics sequence numbers to reserve each time the registry is saved for them.
+///
+/// The restored worker continues from the reserved ones, so the tokens it issues stay above the
+/// ones issued before the restart, as long as the registry is not rolled back (see the module
+/// docs).
+pub(crate) const METRICS_SN_RESERVE: u64 = 1024;
+
+/// The magic the registry files start with, followed by a [`VersionedRegistry`].
+const MAGIC: &[u8; 8] = b"wapodreg";
+
+/// The encodings of the registry, a new version is added whenever the layout of [`Registry`] or
+/// of its records changes.
+#[derive(Encode, Decode)]
+enum VersionedRegistry {
+    #[codec(index = 1)]
+    V1(Registry),
+}
+
+#[derive(Encode, Decode, Default, Debug)]
+pub(crate) struct Registry {
+    /// The session of the worker the apps are deployed in.
+    pub session: Option<[u8; 32]>,
+    /// The metrics sequence numbers reserved in the session.
+    pub metrics_sn_reserved: u64,
+    pub apps: Vec<AppRecord>,
+    /// The accounts allowed to sign admin requests.
+    pub operators: Vec<[u8; 32]>,
+    pub register_operator: Option<[u8; 32]>,
+    /// Whether the operators were lost with an earlier registry, which keeps the admin API from
+    /// being open to anyone until the worker is initialized again.
+    pub operators_lost: bool,
+}
+
+impl Registry {
+    fn encode_versioned(self) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        VersionedRegistry::V1(self).encode_to(&mut data);
+        data
+    }
+
+    fn decode_versioned(data: &[u8]) -> Result<Self> {
+        let mut versioned = data.strip_prefix(MAGIC).context("not a registry file")?;
+        match VersionedRegistry::decode_all(&mut versioned)? {
+            VersionedRegistry::V1(registry) => Ok(registry),
+        }
+    }
+}
+
+#[derive(Encode, Decode, Debug)]
+pub(crate) struct AppRecord {
+    pub address: Address,
+    pub session: [u8; 32],
+    pub manifest: AppManifest,
+    pub auto_restart: bool,
+    pub reuse_instances: bool,
+    /// The number of instances the app was last started or resized to.
+    pub desired_instances: u32,
+    pub hist_metrics: MetricsRecord,
+    pub domains: Vec<String>,
+}
+
+#[derive(Encode, Decode, Default, Debug)]
+pub(crate) struct MetricsRecord {
+    pub gas_consumed: u64,
+    pub net_egress: u64,
+    pub net_ingress: u64,
+    pub storage_read: u64,
+    pub storage_written: u64,
+    pub storage_used: u64,
+    pub memory_used: u64,
+    pub starts: u64,
+    pub tip: u64,
+    pub duration_ms: u64,
+}
+
+impl From<&Metrics> for MetricsRecord {
+    fn from(metrics: &Metrics) -> Self {
+        Self {
+            gas_consumed: metrics.gas_consumed,
+            net_egress: metrics.net_egress,
+            net_ingress: metrics.net_ingress,
+            storage_read: metrics.storage_read,
+            storage_written: metrics.storage_written,
+            storage_used: metrics.storage_used,
+            memory_used: metrics.memory_used,
+            starts: metrics.starts,
+            tip: metrics.tip,
+            duration_ms: metrics.duration.as_millis() as u64,
+        }
+    }
+}
+
+impl From<MetricsRecord> for Metrics {
+    fn from(record: MetricsRecord) -> Self {
+        Self {
+            gas_consumed: record.gas_consumed,
+            net_egress: record.net_egress,
+            net_ingress: record.net_ingress,
+            storage_read: record.storage_read,
+            storage_written: record.storage_written,
+            storage_used: record.storage_used,
+            memory_used: record.memory_used,
+            starts: record.starts,
+            tip: record.tip,
+            duration: Duration::from_millis(record.duration_ms),
+        }
+    }
+}
+
+pub(crate) fn registry_file<T: WorkerConfig>() -> PathBuf {
+    T::Paths::secret_data_dir().join("apps.registry")
+}
+
+/// Loads the registry, or `None` if none has been saved.
+pub(crate) fn load<T: WorkerConfig>() -> Result<Option<Registry>> {
+    load_from(&registry_file::<T>())
+}
+
+fn load_from(path: &Path) -> Result<Option<Registry>> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).context("failed to read the app registry"),
+    };
+    Registry::decode_versioned(&data)
+        .map(Some)
+        .context("failed to decode the app registry")
+}
+
+/// Replaces the file with the data, so that a crash leaves either the old or the new content.
+pub(crate) fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file =
+        std::fs::File::create(&tmp_path).context("failed to create the temporary file")?;
+    file.write_all(data)
+        .context("failed to write the temporary file")?;
+    file.sync_all()
+        .context("failed to sync the temporary file")?;
+    drop(file);
+    std::fs::rename(&tmp_path, path).context("failed to replace the file")?;
+    if let Some(dir) = path.parent() {
+        std::fs::File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .context("failed to sync the directory")?;
+    }
+    Ok(())
+}
+
+/// Saves the registry on a thread of its own, so that the worker lock is not held for the disk
+/// writes.
+///
+/// Snapshots queued while one is being written are coalesced into the latest one.
+pub(crate) struct RegistryWriter {
+    tx: mpsc::Sender<(u64, Vec<u8>)>,
+    queued: AtomicU64,
+    written: Arc<(Mutex<u64>, Condvar)>,
+}
+
+impl RegistryWriter {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        let (tx, rx) = mpsc::channel::<(u64, Vec<u8>)>();
+        let written = Arc::new((Mutex::new(0), Condvar::new()));
+        let thread_written = written.clone();
+        std::thread::spawn(move || {
+            while let Ok(mut snapshot) = rx.recv() {
+                while let Ok(newer) = rx.try_recv() {
+                    snapshot = newer;
+                }
+                let (seq, data) = snapshot;
+                if let Err(err) = write_atomically(&path, &data) {
+                    warn!("failed to save the app registry: {err:?}");
+                }
+                let (written, cond) = &*thread_written;
+                *written.lock().unwrap() = seq;
+                cond.notify_all();
+            }
+        });
+        Self {
+            tx,
+            queued: AtomicU64::new(0),
+            written,
+        }
+    }
+
+    /// Queues the registry to be saved, returning the sequence number to [`Self::wait`] for.
+    ///
+    /// The snapshots must be queued in the order they were taken, e.g. with the worker locked.
+    pub(crate) fn save(&self, registry: Registry) -> u64 {
+        let seq = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.tx.send((seq, registry.encode_versioned())).is_err() {
+            warn!("the app registry writer is gone");
+        }
+        seq
+    }
+
+    /// Waits until the registry queued with the sequence number, or a later one, has been written
+    /// or failed to.
+    pub(crate) fn wait(&self, seq: u64) {
+        let (written, cond) = &*self.written;
+        let _written = cond
+            .wait_while(written.lock().unwrap(), |written| *written < seq)
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(n_apps: u8) -> Registry {
+        let manifest = AppManifest {
+            version: 1,
+            code_hash: format!("sha256:{}", hex::encode([1; 32])),
+            args: vec!["--flag".into()],
+            env_vars: vec![("KEY".into(), "value".into())],
+            on_demand: false,
+            resizable: true,
+            max_query_size: 1024,
+            label: "app".into(),
+            required_blobs: vec![],
+            schedules: vec![],
+            mounts: vec![],
+            scratch: None,
+            secret_env_vars: vec![],
+            domains: vec![],
+        };
+        Registry {
+            session: Some([2; 32]),
+            metrics_sn_reserved: 2048,
+            apps: (0..n_apps)
+                .map(|i| AppRecord {
+                    address: [i; 32],
+                    session: [3; 32],
+                    manifest: manifest.clone(),
+                    auto_restart: true,
+                    reuse_instances: false,
+                    desired_instances: 2,
+                    hist_metrics: MetricsRecord {
+                        gas_consumed: 100,
+                        duration_ms: 5000,
+                        ..Default::default()
+                    },
+                    domains: vec!["app.example.com".into()],
+                })
+                .collect(),
+            operators: vec![[4; 32]],
+            register_operator: Some([4; 32]),
+            operators_lost: false,
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn registry_round_trips() {
+        let dir = temp_dir();
+        let path = dir.join("apps.registry");
+        assert!(load_from(&path).unwrap().is_none());
+
+        let saved = registry(2);
+        write_atomically(&path, &registry(2).encode_versioned()).unwrap();
+        let mut loaded = load_from(&path).unwrap().unwrap();
+        assert_eq!(loaded.encode(), saved.encode());
+        let app = &loaded.apps[1];
+        assert_eq!(app.address, [1; 32]);
+        assert_eq!(app.manifest, saved.apps[1].manifest);
+        assert_eq!(app.desired_instances, 2);
+        assert_eq!(app.domains, ["app.example.com"]);
+        let metrics = Metrics::from(std::mem::take(&mut loaded.apps[0].hist_metrics));
+        assert_eq!(metrics.gas_consumed, 100);
+        assert_eq!(metrics.duration, Duration::from_secs(5));
+        assert_eq!(loaded.operators, [[4; 32]]);
+        assert!(!path.with_extension("tmp").exists());
+
+        std::fs::write(&path, b"garbage").unwrap();
+        assert!(load_from(&path).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn only_known_versions_are_decoded() {
+        let decoded = Registry::decode_versioned(&registry(1).encode_versioned()).unwrap();
+        assert_eq!(decoded.apps.len(), 1);
+
+        assert!(Registry::decode_versioned(&registry(1).encode()).is_err());
+        let mut truncated = registry(1).encode_versioned();
+        truncated.pop();
+        assert!(Registry::decode_versioned(&truncated).is_err());
+        let mut unknown_version = registry(1).encode_versioned();
+        unknown_version[MAGIC.len()] = 2;
+        assert!(Registry::decode_versioned(&unknown_version).is_err());
+    }
+
+    #[test]
+    fn writer_saves_the_latest_registry() {
+        let dir = temp_dir();
+        let path = dir.join("apps.registry");
+        let writer = RegistryWriter::new(path.clone());
+        let mut last = 0;
+        for n_apps in 0..=5 {
+            last = writer.save(registry(n_apps));
+        }
+        writer.wait(last);
+        assert_eq!(load_from(&path).unwrap().unwrap().apps.len(), 5);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
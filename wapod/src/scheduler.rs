@@ -0,0 +1,151 @@
+use std::{
+    collections::HashSet,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use rand::Rng as _;
+use wapod_rpc::prpc as pb;
+use wapod_types::ticket::Schedule;
+
+const MAX_SCHEDULES: usize = 16;
+const MAX_NAME_LEN: usize = 64;
+const MIN_INTERVAL_SECS: u64 = 10;
+
+/// Computes the fire times of a schedule.
+pub(crate) enum Trigger {
+    Cron(Box<cron::Schedule>),
+    Interval(Duration),
+}
+
+impl Trigger {
+    pub fn parse(schedule: &Schedule) -> Result<Self> {
+        let cron = schedule.cron.trim();
+        if !cron.is_empty() {
+            // The cron crate requires the seconds field, which is rarely used in practice.
+            let expr = if cron.split_whitespace().count() == 5 {
+                format!("0 {cron}")
+            } else {
+                cron.to_string()
+            };
+            let parsed = cron::Schedule::from_str(&expr).context("invalid cron expression")?;
+            return Ok(Self::Cron(Box::new(parsed)));
+        }
+        if schedule.interval_secs < MIN_INTERVAL_SECS {
+            bail!("schedule interval must be at least {MIN_INTERVAL_SECS} seconds");
+        }
+        Ok(Self::Interval(Duration::from_secs(schedule.interval_secs)))
+    }
+
+    /// Returns the first fire time strictly after `after`.
+    pub fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        match self {
+            Self::Cron(schedule) => schedule
+                .after(&DateTime::<Utc>::from(after))
+                .next()
+                .map(Into::into),
+            Self::Interval(interval) => after.checked_add(*interval),
+        }
+    }
+}
+
+/// Returns a random delay up to `jitter_secs`.
+pub(crate) fn jitter(jitter_secs: u32) -> Duration {
+    if jitter_secs == 0 {
+        return Duration::ZERO;
+    }
+    Duration::from_millis(rand::thread_rng().gen_range(0..=jitter_secs as u64 * 1000))
+}
+
+pub(crate) fn validate(schedules: &[Schedule]) -> Result<()> {
+    if schedules.len() > MAX_SCHEDULES {
+        bail!("too many schedules, max={MAX_SCHEDULES}");
+    }
+    let mut names = HashSet::new();
+    for schedule in schedules {
+        if schedule.name.is_empty() || schedule.name.len() > MAX_NAME_LEN {
+            bail!("invalid schedule name: {:?}", schedule.name);
+        }
+        if !names.insert(&schedule.name) {
+            bail!("duplicate schedule name: {}", schedule.name);
+        }
+        Trigger::parse(schedule).with_context(|| format!("invalid schedule {}", schedule.name))?;
+    }
+    Ok(())
+}
+
+/// The record of the last run of a schedule.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ScheduleRun {
+    pub started_at: Option<SystemTime>,
+    pub duration: Duration,
+    pub runs: u64,
+    pub skipped: u64,
+    pub error: Option<String>,
+}
+
+impl ScheduleRun {
+    pub fn to_pb(&self, name: &str) -> pb::ScheduleRun {
+        pb::ScheduleRun {
+            name: name.to_string(),
+            started_at: self
+                .started_at
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |d| d.as_secs()),
+            duration_ms: self.duration.as_millis() as u64,
+            runs: self.runs,
+            skipped: self.skipped,
+            error: self.error.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Aborts the wrapped task when dropped.
+pub(crate) struct TaskGuard(pub tokio::task::AbortHandle);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(cron: &str, interval_secs: u64) -> Schedule {
+        Schedule {
+            name: "test".into(),
+            cron: cron.into(),
+            interval_secs,
+            jitter_secs: 0,
+            catch_up: false,
+            payload: vec![],
+        }
+    }
+
+    #[test]
+    fn trigger_works() {
+        let t0 = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let every_10_minutes = Trigger::parse(&schedule("*/10 * * * *", 0)).unwrap();
+        let next = every_10_minutes.next_after(t0).unwrap();
+        let secs = next.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        assert_eq!(secs % 600, 0);
+        assert!(next > t0 && next <= t0 + Duration::from_secs(600));
+
+        let interval = Trigger::parse(&schedule("", 60)).unwrap();
+        assert_eq!(interval.next_after(t0), Some(t0 + Duration::from_secs(60)));
+
+        assert!(Trigger::parse(&schedule("", 1)).is_err());
+        assert!(Trigger::parse(&schedule("not a cron", 60)).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_duplicated_names() {
+        let schedules = vec![schedule("", 60), schedule("", 120)];
+        assert!(validate(&schedules).is_err());
+    }
+}
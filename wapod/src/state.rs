@@ -1,31 +1,49 @@
 use anyhow::{anyhow, bail, Context, Result};
 
 use rand::Rng as _;
-use scale::Encode;
-use tokio::sync::{broadcast, oneshot};
-use tracing::{field::display, info, warn, Instrument};
-use wapo_host::{blobs::BlobLoader, Metrics};
-use wapo_host::{MetricsToken, ShortId, SniTlsListener, VmStatus, VmStatusReceiver};
+use scale::{Decode, Encode};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tracing::{error, field::display, info, warn, Instrument};
+use wapo_host::{blobs::BlobLoader, AppQueryFuture, BlobMount, DiskCacheConfig, Metrics};
+use wapo_host::{
+    ConnectionLimits, MetricsToken, ProxyProtocol, RoutingListener, ScratchConfig, ShortId,
+    SniTlsListener, VmStatus, VmStatusReceiver,
+};
 use wapod_crypto::wapod_types::session::SessionUpdate;
-use wapod_crypto::wapod_types::ticket::AppManifest;
-use wapod_crypto::{ContentType, SpCoreHash};
+use wapod_crypto::wapod_types::ticket::{
+    AppManifest, Schedule, ScratchDir, SecretEnvVar, SCHEDULE_PATH_PREFIX,
+};
+use wapod_crypto::{sr25519::Pair, ContentType, SpCoreHash};
 use wapod_rpc::prpc::{self as pb};
 
 use std::collections::{BTreeMap, HashMap, HashSet};
 
 use std::marker::PhantomData;
+use std::net::SocketAddr;
 use std::ops::{Add, RangeInclusive};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard, Weak};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use service::{Command, CommandSender, ServiceHandle};
 
 use wapo_host::service::{self, Report, VmHandle};
 use wapod_rpc::prpc::Manifest;
 
+use crate::acme::{AcmeSettings, WorkerAcmeHost};
+use crate::app_http::{AppHttpConfig, HttpPermit, HttpPermits};
+use crate::app_listeners::{AppAgents, ConnectionLimitsConfig};
+use crate::blob_fetcher::{BlobFetchConfig, BlobFetcher};
+use crate::blob_gc::{self, BlobGc, BlobGcConfig};
 use crate::config::{AddressGenerator, KeyProvider, Paths, WorkerConfig};
+use crate::domains::{self, DomainMap};
+use crate::proxy_protocol::ProxyProtocolConfig;
+use crate::pubsub::{PubSubConfig, PublishResult, TopicBus};
+use crate::registry::{self, AppRecord, Registry, RegistryWriter, METRICS_SN_RESERVE};
+use crate::scheduler::{self, ScheduleRun, TaskGuard, Trigger};
 use crate::tcp_acl::HostFilter;
+use crate::time_service::{TimeConfig, TimeService};
 
 type Address = [u8; 32];
 #[derive(Clone, Debug, typed_builder::TypedBuilder)]
@@ -42,14 +60,26 @@ pub struct WorkerArgs {
     /// Disable memory pool for instances.
     #[builder(default)]
     pub use_winch: bool,
+    /// Do not persist the compiled modules across restarts.
+    #[builder(default)]
+    pub no_module_disk_cache: bool,
+    /// Maximum total size of the compiled modules persisted across restarts.
+    #[builder(default = 2 * 1024 * 1024 * 1024)]
+    pub module_disk_cache_size: u64,
     /// The port range to allow the worker to listen on.
     pub tcp_listen_port_range: RangeInclusive<u16>,
     /// The tcp port that SNI TLS listener to use.
     pub tls_port: Option<u16>,
+    /// The tcp port of the listener routing plain HTTP connections by `Host` header.
+    #[builder(default)]
+    pub http_port: Option<u16>,
     /// Whether to verify the TLS server certificate when the app tries to listen on an SNI.
     pub verify_tls_server_cert: bool,
     /// The maximum instance execution time for handling a on-demand connection.
     pub on_demand_connection_timeout: Duration,
+    /// The maximum quota of an app's scratch directory in bytes.
+    #[builder(default = 1024 * 1024 * 1024)]
+    pub max_scratch_quota: u64,
 }
 
 struct Instance {
@@ -72,6 +102,10 @@ pub struct AppInfo {
     pub last_query_elapsed_secs: u64,
     pub reuse_instances: bool,
     pub manifest: Manifest,
+    pub schedule_runs: Vec<pb::ScheduleRun>,
+    pub blobs: Vec<pb::BlobStatus>,
+    pub domains: Vec<String>,
+    pub subscriptions: Vec<pb::TopicSubscription>,
 }
 
 pub struct AppState {
@@ -84,6 +118,13 @@ pub struct AppState {
     last_query_done: Instant,
     auto_restart: bool,
     reuse_instance: bool,
+    /// The number of instances the app was last started or resized to.
+    desired_instances: usize,
+    schedule_runs: BTreeMap<String, ScheduleRun>,
+    _schedule_tasks: Vec<TaskGuard>,
+    agents: AppAgents,
+    /// The slots of the concurrent HTTP requests to the app.
+    http_permits: Arc<HttpPermits>,
 }
 
 impl AppState {
@@ -105,7 +146,26 @@ impl AppState {
         self.on_going_queries
     }
 
-    fn info(&self, address: Address) -> AppInfo {
+    fn blob_statuses(&self, blob_fetcher: &BlobFetcher) -> Vec<pb::BlobStatus> {
+        let manifest = &self.manifest;
+        let mut statuses: Vec<_> = manifest
+            .required_blobs
+            .iter()
+            .map(|(hash, cid)| blob_fetcher.status(hash, cid))
+            .collect();
+        if !statuses.iter().any(|s| s.hash == manifest.code_hash) {
+            statuses.insert(0, blob_fetcher.status(&manifest.code_hash, ""));
+        }
+        statuses
+    }
+
+    fn info(
+        &self,
+        address: Address,
+        blob_fetcher: &BlobFetcher,
+        domains: Vec<String>,
+        subscriptions: Vec<pb::TopicSubscription>,
+    ) -> AppInfo {
         AppInfo {
             address,
             sn: self.sn,
@@ -114,6 +174,14 @@ impl AppState {
             last_query_elapsed_secs: self.last_query_done.elapsed().as_secs(),
             reuse_instances: self.reuse_instance,
             manifest: self.manifest.clone().into(),
+            schedule_runs: self
+                .schedule_runs
+                .iter()
+                .map(|(name, run)| run.to_pb(name))
+                .collect(),
+            blobs: self.blob_statuses(blob_fetcher),
+            domains,
+            subscriptions,
         }
     }
 }
@@ -125,6 +193,30 @@ pub struct QueryGuard<T: WorkerConfig> {
     instance_id: Option<u64>,
 }
 
+impl<T: WorkerConfig> QueryGuard<T> {
+    /// The command sender of the instance to serve the query.
+    pub fn command_sender(&self) -> Result<CommandSender> {
+        let state = self.worker.lock();
+        let app = state
+            .apps
+            .get(&self.address)
+            .ok_or(anyhow::Error::msg("App not found"))?;
+        let instance = match self.instance_id {
+            Some(id) => app
+                .instances
+                .get(&id)
+                .ok_or(anyhow::Error::msg("Instance not found"))?,
+            None => match app.instances.values().next() {
+                Some(instance) => instance,
+                None => {
+                    bail!("instance not found");
+                }
+            },
+        };
+        Ok(instance.vm_handle.command_sender().clone())
+    }
+}
+
 impl<T: WorkerConfig> Drop for QueryGuard<T> {
     fn drop(&mut self) {
         if let Err(err) = self.worker.end_query(self.address, self.instance_id) {
@@ -143,10 +235,28 @@ struct WorkerState<T> {
     blob_loader: BlobLoader,
     session: Option<[u8; 32]>,
     sni_tls_listener: Option<SniTlsListener>,
+    http_listener: Option<RoutingListener>,
+    proxy_protocol: ProxyProtocol,
+    connection_limits: ConnectionLimits,
+    app_http: AppHttpConfig,
+    domains: Arc<DomainMap>,
     host_filter: Arc<HostFilter>,
+    time_service: Arc<TimeService>,
+    blob_gc: Arc<BlobGc>,
+    blob_fetcher: Arc<BlobFetcher>,
     metrics_sn: u64,
+    /// The metrics sequence numbers reserved in the registry.
+    metrics_sn_reserved: u64,
+    registry: Arc<RegistryWriter>,
+    /// The accounts allowed to sign admin requests, set at init.
+    operators: Vec<Address>,
+    /// The operator the registration info of the worker was last signed for.
+    register_operator: Option<Address>,
+    /// Whether the operators were lost with the registry, see [`Worker::operators_lost`].
+    operators_lost: bool,
     bench_app: Option<Address>,
     bench_instances: u64,
+    topics: TopicBus,
 }
 
 pub struct Worker<T> {
@@ -169,18 +279,31 @@ impl<T: WorkerConfig> Worker<T> {
             .try_into()
             .context("invalid memory size")?;
         SniTlsListener::install_ring_provider();
+        let proxy_protocol = ProxyProtocolConfig::from_config_file().proxy_protocol();
         let sni_tcp_listener = match args.tls_port {
             Some(port) => Some({
-                SniTlsListener::bind("0.0.0.0", port, args.verify_tls_server_cert)
+                let listener = SniTlsListener::bind("0.0.0.0", port, args.verify_tls_server_cert)
+                    .await
+                    .context("failed to bind sni tls listener")?;
+                listener.set_proxy_protocol(proxy_protocol.clone());
+                listener
+            }),
+            None => None,
+        };
+        let http_listener = match args.http_port {
+            Some(port) => Some({
+                let listener = RoutingListener::bind_http("0.0.0.0", port)
                     .await
-                    .context("failed to bind sni tls listener")?
+                    .context("failed to bind http listener")?;
+                listener.set_proxy_protocol(proxy_protocol.clone());
+                listener
             }),
             None => None,
         };
         let (run, spawner) = service::service(
             n_threads,
             args.module_cache_size,
-            &T::Paths::blobs_dir(),
+            BlobLoader::new(T::Paths::blobs_dir()),
             max_memory,
             if args.no_mem_pool {
                 0
@@ -188,6 +311,7 @@ impl<T: WorkerConfig> Worker<T> {
                 args.max_instances
             },
             args.use_winch,
+            module_disk_cache::<T>(args.no_module_disk_cache, args.module_disk_cache_size),
         )
         .context("failed to create service")?;
         std::thread::spawn(move || {
@@ -197,28 +321,88 @@ impl<T: WorkerConfig> Worker<T> {
                 }
             });
         });
-        Ok(Self::new(spawner, args, sni_tcp_listener))
+        // Ephemeral scratch directories left by a previous run.
+        let scratch_tmp_dir = scratch_tmp_dir::<T>();
+        if scratch_tmp_dir.exists() {
+            std::fs::remove_dir_all(&scratch_tmp_dir)
+                .context("failed to clean up the ephemeral scratch directories")?;
+        }
+        let acme_listener = sni_tcp_listener.clone();
+        let worker = Self::new(
+            spawner,
+            args,
+            sni_tcp_listener,
+            http_listener,
+            proxy_protocol,
+        );
+        let acme = AcmeSettings::from_config_file();
+        if let (true, Some(listener)) = (acme.enabled, acme_listener) {
+            let host = WorkerAcmeHost::new(
+                worker.domains(),
+                T::Paths::storage_dir().join("acme"),
+                T::KeyProvider::get_key(),
+            );
+            listener
+                .enable_acme(acme.config, Arc::new(host))
+                .context("failed to enable ACME")?;
+        }
+        worker
+            .restore_apps()
+            .context("failed to restore the app registry")?;
+        let time_service = worker.lock().time_service.clone();
+        tokio::spawn(async move { time_service.run().await });
+        let gc_config = worker.lock().blob_gc.config.clone();
+        if gc_config.enabled {
+            let interval = Duration::from_secs(gc_config.interval_secs.max(60));
+            tokio::spawn(Self::run_blob_gc(Arc::downgrade(&worker.inner), interval));
+        }
+        tokio::spawn(blob_gc::cleanup_uploads(
+            worker.blob_loader(),
+            Duration::from_secs(gc_config.upload_timeout_secs),
+        ));
+        Ok(worker)
     }
 
     pub fn new(
         service: ServiceHandle,
         args: WorkerArgs,
         sni_tls_listener: Option<SniTlsListener>,
+        http_listener: Option<RoutingListener>,
+        proxy_protocol: ProxyProtocol,
     ) -> Self {
+        // Shared with the instances, which verify reads against the chunk hashes recorded in it.
+        let blob_loader = service.module_loader().blob_loader().clone();
         Self {
             inner: Arc::new_cyclic(|weak_self| {
                 Mutex::new(WorkerState {
                     weak_self: weak_self.clone(),
-                    blob_loader: BlobLoader::new(T::Paths::blobs_dir()),
+                    blob_fetcher: Arc::new(BlobFetcher::new(
+                        BlobFetchConfig::from_config_file(),
+                        blob_loader.clone(),
+                    )),
+                    blob_loader,
                     apps: HashMap::new(),
                     service,
                     args,
                     session: None,
                     sni_tls_listener,
+                    http_listener,
+                    proxy_protocol,
+                    connection_limits: ConnectionLimitsConfig::from_config_file().limits(),
+                    app_http: AppHttpConfig::from_config_file(),
+                    domains: Default::default(),
                     host_filter: Arc::new(HostFilter::from_config_file()),
+                    time_service: Arc::new(TimeService::new(TimeConfig::from_config_file())),
+                    blob_gc: Arc::new(BlobGc::new(BlobGcConfig::from_config_file())),
                     metrics_sn: 0,
+                    metrics_sn_reserved: 0,
+                    registry: Arc::new(RegistryWriter::new(registry::registry_file::<T>())),
+                    operators: vec![],
+                    register_operator: None,
+                    operators_lost: false,
                     bench_app: None,
                     bench_instances: 0,
+                    topics: TopicBus::new(PubSubConfig::from_config_file()),
                 })
             }),
         }
@@ -228,17 +412,24 @@ impl<T: WorkerConfig> Worker<T> {
         self.inner.lock().expect("worker lock poisoned")
     }
 
-    pub fn sender_for(&self, vmid: Address, index: usize) -> Option<CommandSender> {
-        let handle = self
-            .lock()
-            .apps
-            .get(&vmid)?
-            .instances
-            .get(&(index as u64))?
-            .vm_handle
-            .command_sender()
-            .clone();
-        Some(handle)
+    /// Waits for a slot among the concurrent HTTP requests of the app, for a request from the
+    /// client connection with the given peer address. See [`HttpPermits`].
+    ///
+    /// Returns `Ok(None)` if no slot frees up within the queue timeout.
+    pub async fn acquire_http_permit(
+        &self,
+        address: Address,
+        connection: Option<SocketAddr>,
+    ) -> Result<Option<HttpPermit>> {
+        let (permits, timeout) = {
+            let state = self.lock();
+            let app = state
+                .apps
+                .get(&address)
+                .ok_or(anyhow::Error::msg("App not found"))?;
+            (app.http_permits.clone(), state.app_http.queue_timeout())
+        };
+        Ok(permits.acquire(connection, timeout).await)
     }
 
     pub fn blob_loader(&self) -> BlobLoader {
@@ -254,6 +445,14 @@ impl<T: WorkerConfig> Worker<T> {
 
 impl<T: WorkerConfig> Worker<T> {
     pub fn info(&self, admin: bool) -> pb::WorkerInfo {
+        let blobs_disk_usage = if admin {
+            self.blob_loader().disk_usage().unwrap_or_else(|err| {
+                warn!("failed to get blobs disk usage: {err:?}");
+                0
+            })
+        } else {
+            0
+        };
         let worker = self.lock();
         let max_instances = worker.args.max_instances as u64;
         let deployed_apps = worker.apps.len() as u64;
@@ -297,6 +496,16 @@ impl<T: WorkerConfig> Worker<T> {
             },
             bench_app_address: worker.bench_app.map(|a| a.to_vec()).unwrap_or_default(),
             bench_app_instances: worker.bench_instances,
+            blobs_disk_usage,
+            connection_stats: if admin {
+                worker
+                    .apps
+                    .iter()
+                    .flat_map(|(address, app)| app.agents.stats(address))
+                    .collect()
+            } else {
+                vec![]
+            },
         }
     }
 
@@ -389,6 +598,19 @@ impl<T: WorkerConfig> Worker<T> {
         address: Address,
         path: String,
         payload: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        if path.starts_with(SCHEDULE_PATH_PREFIX) {
+            bail!("the path is reserved for scheduled queries");
+        }
+        self.send_query(origin, address, path, payload).await
+    }
+
+    async fn send_query(
+        &self,
+        origin: Option<[u8; 32]>,
+        address: Address,
+        path: String,
+        payload: Vec<u8>,
     ) -> Result<Vec<u8>> {
         info!(address=%ShortId(address), "incomming query");
         let query_size = payload.len() + path.as_bytes().len();
@@ -396,26 +618,7 @@ impl<T: WorkerConfig> Worker<T> {
             .prepare_instance_for_query(address, query_size)
             .await
             .context("failed to prepare query")?;
-        let cmd_sender = {
-            let state = self.lock();
-            let app = state
-                .apps
-                .get(&address)
-                .ok_or(anyhow::Error::msg("App not found"))?;
-            let instance = match guard.instance_id {
-                Some(id) => app
-                    .instances
-                    .get(&id)
-                    .ok_or(anyhow::Error::msg("Instance not found"))?,
-                None => match app.instances.values().next() {
-                    Some(instance) => instance,
-                    None => {
-                        bail!("instance not found");
-                    }
-                },
-            };
-            instance.vm_handle.command_sender().clone()
-        };
+        let cmd_sender = guard.command_sender()?;
         let (reply_tx, rx) = oneshot::channel();
         cmd_sender
             .send(Command::PushQuery {
@@ -506,6 +709,11 @@ impl<T: WorkerConfig> Worker<T> {
         if manifest.label.len() > 64 {
             bail!("label too long");
         }
+        scheduler::validate(&manifest.schedules)?;
+        blob_mounts(&manifest)?;
+        validate_scratch(&manifest, self.lock().args.max_scratch_quota)?;
+        validate_secret_envs(&manifest)?;
+        validate_domains(&manifest)?;
         const MAX_MANIFEST_SIZE: usize = 1024 * 16;
         if manifest.size_hint() > MAX_MANIFEST_SIZE {
             bail!(
@@ -516,40 +724,199 @@ impl<T: WorkerConfig> Worker<T> {
         let address = T::AddressGenerator::generate_address(&manifest);
         tracing::Span::current().record("addr", display(ShortId(&address)));
         let on_demand = manifest.on_demand;
-        {
+        let instances = if on_demand { 0 } else { 1 };
+        let declared_domains = manifest.domains.clone();
+        let blob_gc = self.lock().blob_gc.clone();
+        let missing_blobs = {
+            let _changing_roots = blob_gc.changing_roots().await;
             let mut worker = self.lock();
             if worker.apps.contains_key(&address) {
                 bail!("app already exists")
             }
-            let session: [u8; 32] = rand::thread_rng().gen();
-
-            static NEXT_APP_SN: AtomicU64 = AtomicU64::new(0);
-            let state = AppState {
-                sn: NEXT_APP_SN.fetch_add(1, Ordering::Relaxed),
-                session,
-                manifest,
-                hist_metrics: Default::default(),
-                instances: Default::default(),
-                on_going_queries: 0,
-                last_query_done: Instant::now(),
-                auto_restart,
-                reuse_instance: reuse_instances,
+            let missing_blobs = if worker.blob_fetcher.is_enabled() {
+                worker.missing_blobs(&manifest)
+            } else {
+                vec![]
             };
+            let mut state = self.new_app(&worker, address, manifest, auto_restart, reuse_instances);
+            state.desired_instances = instances;
             worker.apps.insert(address, state);
-        }
-        if !on_demand {
+            worker.persist();
+            missing_blobs
+        };
+        if !missing_blobs.is_empty() {
+            info!(?missing_blobs, "fetching missing blobs");
+            tokio::spawn(
+                self.clone()
+                    .fetch_blobs_and_start(address, missing_blobs, instances)
+                    .in_current_span(),
+            );
+        } else if !on_demand {
             self.resize_app_instances(address, 1, false).await?;
         }
+        if !declared_domains.is_empty() {
+            tokio::spawn(
+                self.clone()
+                    .bind_declared_domains(address, declared_domains)
+                    .in_current_span(),
+            );
+        }
         let worker = self.lock();
         let app = worker
             .apps
             .get(&address)
             .ok_or(anyhow!("BUG: App not found after deployed"))?;
-        Ok(app.info(address))
+        Ok(app.info(address, &worker.blob_fetcher, vec![], vec![]))
+    }
+
+    fn new_app(
+        &self,
+        worker: &WorkerState<T>,
+        address: Address,
+        manifest: AppManifest,
+        auto_restart: bool,
+        reuse_instances: bool,
+    ) -> AppState {
+        static NEXT_APP_SN: AtomicU64 = AtomicU64::new(0);
+        let schedules = manifest.schedules.clone();
+        AppState {
+            sn: NEXT_APP_SN.fetch_add(1, Ordering::Relaxed),
+            session: rand::thread_rng().gen(),
+            manifest,
+            hist_metrics: Default::default(),
+            instances: Default::default(),
+            on_going_queries: 0,
+            last_query_done: Instant::now(),
+            auto_restart,
+            reuse_instance: reuse_instances,
+            desired_instances: 0,
+            schedule_runs: Default::default(),
+            _schedule_tasks: self.spawn_schedules(address, schedules),
+            agents: worker.app_agents(address, reuse_instances),
+            http_permits: HttpPermits::new(&worker.app_http),
+        }
+    }
+
+    /// Redeploys the apps saved in the registry, and restores the admin operators.
+    ///
+    /// The apps are restored in the worker session they were deployed in, along with their own
+    /// sessions and metrics, so that the metrics reported after the restart carry on from the ones
+    /// reported before.
+    ///
+    /// A registry that can't be loaded fails the restore rather than being dropped, as the
+    /// operators in it are what keeps the admin API closed.
+    fn restore_apps(&self) -> Result<()> {
+        let Some(registry) = registry::load::<T>()? else {
+            let (registry, seq) = {
+                let mut worker = self.lock();
+                if !T::KeyProvider::is_new_key() {
+                    error!("the app registry of the worker is missing, the admin API requires a configured token or operator until the worker is initialized again");
+                    worker.operators_lost = true;
+                }
+                // Saved right away, so that the registry of a worker that has run is only missing
+                // if it was lost.
+                (worker.registry.clone(), worker.persist())
+            };
+            registry.wait(seq);
+            return Ok(());
+        };
+        let mut starts = vec![];
+        {
+            let mut worker = self.lock();
+            worker.operators = registry.operators;
+            worker.register_operator = registry.register_operator;
+            worker.operators_lost = registry.operators_lost;
+            worker.session = registry.session;
+            worker.metrics_sn = registry.metrics_sn_reserved;
+            worker.metrics_sn_reserved = registry.metrics_sn_reserved;
+            for record in registry.apps {
+                let address = record.address;
+                let missing_blobs = if worker.blob_fetcher.is_enabled() {
+                    worker.missing_blobs(&record.manifest)
+                } else {
+                    vec![]
+                };
+                let mut state = self.new_app(
+                    &worker,
+                    address,
+                    record.manifest,
+                    record.auto_restart,
+                    record.reuse_instances,
+                );
+                state.session = record.session;
+                state.hist_metrics = record.hist_metrics.into();
+                state.desired_instances = record.desired_instances as usize;
+                for domain in record.domains {
+                    worker.domains.restore(domain, address);
+                }
+                starts.push((address, missing_blobs, state.desired_instances));
+                worker.apps.insert(address, state);
+            }
+            info!(apps = worker.apps.len(), "restored the deployed apps");
+        }
+        for (address, missing_blobs, instances) in starts {
+            let span = tracing::info_span!("restore", id = %ShortId(address));
+            tokio::spawn(
+                self.clone()
+                    .fetch_blobs_and_start(address, missing_blobs, instances)
+                    .instrument(span),
+            );
+        }
+        Ok(())
+    }
+
+    /// Saves the deployed apps and their current metrics to the registry, returning once they
+    /// have been written.
+    pub fn save_registry(&self) {
+        let (registry, seq) = {
+            let worker = self.lock();
+            (worker.registry.clone(), worker.persist())
+        };
+        registry.wait(seq);
+    }
+
+    /// Downloads the missing blobs of an app and starts the instances once they are all ready.
+    /// Failed downloads are retried with backoff until they succeed or the app is removed.
+    async fn fetch_blobs_and_start(
+        self,
+        address: Address,
+        missing_blobs: Vec<(String, Option<String>)>,
+        instances: usize,
+    ) {
+        let fetcher = self.lock().blob_fetcher.clone();
+        for attempt in 0.. {
+            let mut fetches = tokio::task::JoinSet::new();
+            for (hash, cid) in missing_blobs.clone() {
+                let fetcher = fetcher.clone();
+                fetches.spawn(async move { fetcher.fetch(&hash, cid.as_deref()).await });
+            }
+            let mut failed = false;
+            while let Some(result) = fetches.join_next().await {
+                if !matches!(result, Ok(Ok(()))) {
+                    failed = true;
+                }
+            }
+            if !failed {
+                break;
+            }
+            let retry_in = fetcher.retry_interval(attempt);
+            warn!(?retry_in, "failed to fetch the blobs of the app");
+            tokio::time::sleep(retry_in).await;
+            if !self.lock().apps.contains_key(&address) {
+                return;
+            }
+        }
+        info!("all blobs of the app are ready");
+        if instances == 0 || !self.lock().apps.contains_key(&address) {
+            return;
+        }
+        if let Err(err) = self.resize_app_instances(address, instances, false).await {
+            warn!("failed to start app: {err:?}");
+        }
     }
 
     pub async fn remove_app(&self, address: Address) -> Result<()> {
-        let Some(app) = self.lock().apps.remove(&address) else {
+        let Some(app) = self.lock().remove_app(address) else {
             bail!("app not found")
         };
         let n = app.instances.len();
@@ -562,9 +929,34 @@ impl<T: WorkerConfig> Worker<T> {
                 }
             }
         }
+        if app.manifest.scratch.is_some() {
+            let dir = scratch_dir::<T>().join(hex::encode(address));
+            if let Err(err) = tokio::fs::remove_dir_all(&dir).await {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    warn!("failed to remove the scratch directory: {err:?}");
+                }
+            }
+        }
+        self.evict_module_if_unused(&app.manifest.code_hash);
         Ok(())
     }
 
+    /// Drops the compiled module of the code from the caches unless an app still runs it.
+    pub fn evict_module_if_unused(&self, code_hash: &str) {
+        let module_loader = {
+            let state = self.lock();
+            if state
+                .apps
+                .values()
+                .any(|app| app.manifest.code_hash == code_hash)
+            {
+                return;
+            }
+            state.service.module_loader().clone()
+        };
+        module_loader.evict(code_hash);
+    }
+
     pub fn for_each_app<F>(&self, addresses: Option<&[Address]>, mut f: F)
     where
         F: FnMut(Address, &AppState),
@@ -591,6 +983,36 @@ impl<T: WorkerConfig> Worker<T> {
         self.lock().init(pnonce, recipient)
     }
 
+    /// The accounts allowed to sign admin requests, set at init.
+    pub fn operators(&self) -> Vec<Address> {
+        self.lock().operators.clone()
+    }
+
+    /// Whether the operators were lost along with the registry of the worker, in which case the
+    /// admin API must not be open to anyone until the worker is initialized again.
+    pub fn operators_lost(&self) -> bool {
+        self.lock().operators_lost
+    }
+
+    pub fn set_operators(&self, operators: Vec<Address>) {
+        let mut worker = self.lock();
+        worker.operators = operators;
+        worker.persist();
+    }
+
+    /// The operator the registration info of the worker was last signed for.
+    pub fn register_operator(&self) -> Option<Address> {
+        self.lock().register_operator
+    }
+
+    pub fn set_register_operator(&self, operator: Option<Address>) {
+        let mut worker = self.lock();
+        if worker.register_operator != operator {
+            worker.register_operator = operator;
+            worker.persist();
+        }
+    }
+
     pub fn num_instances_of(&self, address: Address) -> Option<usize> {
         self.lock()
             .apps
@@ -605,17 +1027,439 @@ impl<T: WorkerConfig> Worker<T> {
             .iter()
             .skip(start)
             .take(count)
-            .map(|(address, state)| state.info(*address))
+            .map(|(address, state)| {
+                state.info(
+                    *address,
+                    &inner.blob_fetcher,
+                    inner.domains.domains_of(address),
+                    inner.topics.subscriptions_of(address),
+                )
+            })
             .collect()
     }
 
     pub fn clear(&self) {
-        self.lock().apps.clear();
+        let (module_loader, code_hashes) = {
+            let mut state = self.lock();
+            let code_hashes: HashSet<_> = state
+                .apps
+                .drain()
+                .map(|(_, app)| app.manifest.code_hash)
+                .collect();
+            state.domains.clear();
+            state.persist();
+            (state.service.module_loader().clone(), code_hashes)
+        };
+        for code_hash in code_hashes {
+            module_loader.evict(&code_hash);
+        }
+    }
+
+    /// Binds the domains declared in the manifest of the app whose ownership records name it.
+    ///
+    /// A domain without the record is left unbound, to be bound by an operator or by deploying
+    /// the app again once the record is in place.
+    async fn bind_declared_domains(self, address: Address, declared: Vec<String>) {
+        for domain in declared {
+            let bound = match domains::verify_ownership(&domain, &address).await {
+                Ok(()) => self.bind_domain(&domain, address),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = bound {
+                warn!(%domain, "declared domain not bound: {err:?}");
+            }
+        }
+    }
+
+    /// Routes the HTTP requests to the domain on the user port to the app.
+    pub fn bind_domain(&self, domain: &str, address: Address) -> Result<()> {
+        let state = self.lock();
+        if !state.apps.contains_key(&address) {
+            bail!("app not found");
+        }
+        state.domains.bind(domain, address)?;
+        state.persist();
+        Ok(())
+    }
+
+    pub fn unbind_domain(&self, domain: &str, address: Address) -> Result<()> {
+        let state = self.lock();
+        state.domains.unbind(domain, address)?;
+        state.persist();
+        Ok(())
+    }
+
+    /// The domains bound to the apps, to route the requests on the user port without locking the
+    /// worker.
+    pub fn domains(&self) -> Arc<DomainMap> {
+        self.lock().domains.clone()
     }
 
     pub fn bump_metrics_sn(&self) -> u64 {
         self.lock().bump_metrics_sn()
     }
+
+    /// Returns the blob keys referenced by the deployed apps, along with the referrers.
+    fn blob_referrers(&self) -> HashMap<String, Vec<Address>> {
+        // Resolving the keys reads the blob indexes, so it is done out of the lock.
+        let (blob_loader, blob_ids) = {
+            let state = self.lock();
+            let blob_ids: Vec<_> = state
+                .apps
+                .iter()
+                .map(|(address, app)| {
+                    let manifest = &app.manifest;
+                    let ids: Vec<_> = std::iter::once(&manifest.code_hash)
+                        .chain(
+                            manifest
+                                .required_blobs
+                                .iter()
+                                .flat_map(|(hash, cid)| [hash, cid]),
+                        )
+                        .cloned()
+                        .collect();
+                    (*address, ids)
+                })
+                .collect();
+            (state.blob_loader.clone(), blob_ids)
+        };
+        let mut referrers = HashMap::<String, Vec<Address>>::new();
+        for (address, ids) in blob_ids {
+            let keys: HashSet<_> = ids
+                .iter()
+                .filter_map(|id| blob_loader.resolve_key(id))
+                .collect();
+            for key in keys {
+                referrers.entry(key).or_default().push(address);
+            }
+        }
+        referrers
+    }
+
+    pub fn list_blobs(&self) -> Result<pb::BlobListResponse> {
+        let (blob_loader, blob_gc) = {
+            let state = self.lock();
+            (state.blob_loader.clone(), state.blob_gc.clone())
+        };
+        let mut referrers = self.blob_referrers();
+        let files = blob_loader.list()?;
+        let total_size = files.iter().map(|file| file.size).sum();
+        let blobs = files
+            .into_iter()
+            .map(|file| pb::BlobInfo {
+                referrers: referrers
+                    .remove(&file.key)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|address| address.to_vec())
+                    .collect(),
+                unreferenced_secs: blob_gc
+                    .unreferenced_for(&file.key)
+                    .map_or(0, |elapsed| elapsed.as_secs()),
+                size: file.size,
+                hash: file.key,
+            })
+            .collect();
+        Ok(pb::BlobListResponse { blobs, total_size })
+    }
+
+    /// Removes the blobs that have been unreferenced for longer than the grace period.
+    ///
+    /// The disk is swept on a blocking thread, while the deployments wait for it asynchronously.
+    pub async fn collect_blobs(&self) -> Result<pb::BlobGcResponse> {
+        let (blob_loader, blob_gc) = {
+            let state = self.lock();
+            (state.blob_loader.clone(), state.blob_gc.clone())
+        };
+        let _collecting = blob_gc.collecting().await;
+        let referenced = self.blob_referrers().into_keys().collect();
+        let sweep_gc = blob_gc.clone();
+        tokio::task::spawn_blocking(move || {
+            let files = blob_loader.list()?;
+            let mut result = pb::BlobGcResponse::default();
+            for file in sweep_gc.sweep(&files, &referenced, Instant::now()) {
+                match blob_loader.remove_key(&file.key) {
+                    Ok(()) => {
+                        info!(key = file.key, size = file.size, "blob removed");
+                        result.removed += 1;
+                        result.freed_bytes += file.size;
+                    }
+                    Err(err) => warn!(key = file.key, "failed to remove blob: {err:?}"),
+                }
+            }
+            Ok(result)
+        })
+        .await
+        .context("the blob collection panicked")?
+    }
+
+    async fn run_blob_gc(weak_self: WeakWorker<T>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            let Some(inner) = weak_self.upgrade() else {
+                break;
+            };
+            if let Err(err) = (Worker { inner }).collect_blobs().await {
+                warn!("failed to collect blobs: {err:?}");
+            }
+        }
+    }
+
+    fn spawn_schedules(&self, address: Address, schedules: Vec<Schedule>) -> Vec<TaskGuard> {
+        schedules
+            .into_iter()
+            .map(|schedule| {
+                let span = tracing::info_span!("schedule", id = %ShortId(address));
+                let task = Self::run_schedule(Arc::downgrade(&self.inner), address, schedule);
+                TaskGuard(tokio::spawn(task.instrument(span)).abort_handle())
+            })
+            .collect()
+    }
+
+    async fn run_schedule(weak_self: WeakWorker<T>, address: Address, schedule: Schedule) {
+        let trigger = match Trigger::parse(&schedule) {
+            Ok(trigger) => trigger,
+            Err(err) => {
+                warn!(name = schedule.name, "invalid schedule: {err:?}");
+                return;
+            }
+        };
+        let path = format!("{SCHEDULE_PATH_PREFIX}{}", schedule.name);
+        let mut next = trigger.next_after(SystemTime::now());
+        while let Some(at) = next {
+            let delay = at
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+                .saturating_add(scheduler::jitter(schedule.jitter_secs));
+            tokio::time::sleep(delay).await;
+            let Some(inner) = weak_self.upgrade() else {
+                break;
+            };
+            let worker = Worker { inner };
+            info!(name = schedule.name, "running scheduled query");
+            let started_at = SystemTime::now();
+            let start = Instant::now();
+            let result = worker
+                .send_query(None, address, path.clone(), schedule.payload.clone())
+                .await;
+            let duration = start.elapsed();
+            if let Err(err) = &result {
+                warn!(name = schedule.name, "scheduled query failed: {err:?}");
+            }
+
+            // Find out the runs missed while the query was running.
+            let now = SystemTime::now();
+            let mut skipped = 0;
+            next = trigger.next_after(at);
+            while let Some(t) = next.filter(|t| *t < now) {
+                skipped += 1;
+                next = trigger.next_after(t);
+            }
+            if skipped > 0 && schedule.catch_up {
+                skipped -= 1;
+                next = Some(now);
+            }
+            if skipped > 0 {
+                info!(name = schedule.name, skipped, "skipped missed runs");
+            }
+
+            let mut state = worker.lock();
+            let Some(app) = state.apps.get_mut(&address) else {
+                break;
+            };
+            let run = app.schedule_runs.entry(schedule.name.clone()).or_default();
+            run.started_at = Some(started_at);
+            run.duration = duration;
+            run.runs += 1;
+            run.skipped += skipped;
+            run.error = result.err().map(|err| format!("{err:?}"));
+        }
+    }
+
+    pub fn publish(
+        &self,
+        publisher: Option<Address>,
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<PublishResult> {
+        self.lock().topics.publish(topic, publisher, payload)
+    }
+
+    /// Publishes a message from the user RPC, to one of the topics the config opens to the public.
+    pub fn publish_public(&self, topic: &str, payload: &[u8]) -> Result<PublishResult> {
+        self.lock().topics.publish_public(topic, payload)
+    }
+}
+
+fn module_disk_cache<T: WorkerConfig>(disabled: bool, max_size: u64) -> Option<DiskCacheConfig> {
+    if disabled {
+        return None;
+    }
+    let path_hash = sp_core::hashing::blake2_256(b"wapod/module_cache");
+    let seed = T::KeyProvider::get_key().derive([path_hash]).dump();
+    Some(DiskCacheConfig {
+        dir: T::Paths::storage_dir().join("module_cache"),
+        key: seed[..32].try_into().expect("BUG: invalid key length"),
+        max_size,
+    })
+}
+
+/// Parses the mounts of the manifest.
+fn blob_mounts(manifest: &AppManifest) -> Result<Vec<BlobMount>> {
+    const MAX_MOUNTS: usize = 64;
+    if manifest.mounts.len() > MAX_MOUNTS {
+        bail!("too many mounts, max={MAX_MOUNTS}");
+    }
+    let mut mounts = vec![];
+    for mount in &manifest.mounts {
+        if !mount.path.starts_with('/') || mount.path.split('/').any(|part| part == "..") {
+            bail!("invalid mount path: {:?}", mount.path);
+        }
+        let required = !mount.blob.is_empty()
+            && manifest
+                .required_blobs
+                .iter()
+                .any(|(hash, cid)| &mount.blob == hash || &mount.blob == cid);
+        if !required {
+            bail!("mounted blob {} is not in required_blobs", mount.blob);
+        }
+        mounts.push(BlobMount {
+            path: mount.path.clone(),
+            blob: mount.blob.clone(),
+            format: mount.format.parse()?,
+        });
+    }
+    Ok(mounts)
+}
+
+fn validate_scratch(manifest: &AppManifest, max_quota: u64) -> Result<()> {
+    let Some(scratch) = &manifest.scratch else {
+        return Ok(());
+    };
+    let path = scratch.path.trim_end_matches('/');
+    if !scratch.path.starts_with('/') || path.is_empty() || path.split('/').any(|p| p == "..") {
+        bail!("invalid scratch path: {:?}", scratch.path);
+    }
+    if scratch.quota > max_quota {
+        bail!("scratch quota too large, max={max_quota}");
+    }
+    let overlapped = manifest.mounts.iter().any(|mount| {
+        let mount_path = mount.path.trim_end_matches('/');
+        mount_path == path
+            || mount_path.starts_with(&format!("{path}/"))
+            || path.starts_with(&format!("{mount_path}/"))
+    });
+    if overlapped {
+        bail!("scratch path {:?} overlaps with a mount", scratch.path);
+    }
+    Ok(())
+}
+
+fn validate_secret_envs(manifest: &AppManifest) -> Result<()> {
+    const MAX_SECRET_ENV_VARS: usize = 64;
+    if manifest.secret_env_vars.len() > MAX_SECRET_ENV_VARS {
+        bail!("too many secret env vars, max={MAX_SECRET_ENV_VARS}");
+    }
+    let mut names = HashSet::new();
+    for (name, _) in &manifest.env_vars {
+        names.insert(name.as_str());
+    }
+    for var in &manifest.secret_env_vars {
+        if var.name.is_empty() || var.name.contains(['=', '\0']) {
+            bail!("invalid secret env name: {:?}", var.name);
+        }
+        if !names.insert(var.name.as_str()) {
+            bail!("duplicate env name: {}", var.name);
+        }
+        if var.pubkey.len() != 32 {
+            bail!("invalid pubkey of secret env {}", var.name);
+        }
+    }
+    Ok(())
+}
+
+fn validate_domains(manifest: &AppManifest) -> Result<()> {
+    const MAX_DOMAINS: usize = 16;
+    if manifest.domains.len() > MAX_DOMAINS {
+        bail!("too many domains, max={MAX_DOMAINS}");
+    }
+    for domain in &manifest.domains {
+        domains::normalize_domain(domain)?;
+    }
+    Ok(())
+}
+
+/// Returns the key that secret env vars of the given code can be encrypted to.
+pub(crate) fn secret_env_key<T: WorkerConfig>(code_hash: &str) -> Pair {
+    derive_secret_env_key(T::KeyProvider::get_key(), code_hash)
+}
+
+fn derive_secret_env_key(worker_key: &Pair, code_hash: &str) -> Pair {
+    let path_hash = sp_core::hashing::blake2_256(b"wapod/secret_env");
+    let code_hash = sp_core::hashing::blake2_256(code_hash.as_bytes());
+    worker_key.derive([path_hash, code_hash])
+}
+
+/// Decrypts a secret env var with the associated data given by
+/// [`AppManifest::secret_env_aad`].
+fn decrypt_secret_env(
+    worker_key: &Pair,
+    code_hash: &str,
+    aad: &[u8],
+    var: &SecretEnvVar,
+) -> Result<String> {
+    let mut encrypted = var.encrypted_value.clone();
+    let decrypted = if var.app_key {
+        derive_secret_env_key(worker_key, code_hash).decrypt_message_with_aad(
+            &var.pubkey,
+            &mut encrypted,
+            aad,
+        )
+    } else {
+        worker_key.decrypt_message_with_aad(&var.pubkey, &mut encrypted, aad)
+    };
+    let value = decrypted.context("failed to decrypt the value")?;
+    String::from_utf8(value).context("the value is not valid utf-8")
+}
+
+/// The directory of the persistent scratch directories of the apps.
+fn scratch_dir<T: WorkerConfig>() -> PathBuf {
+    T::Paths::storage_dir().join("scratch")
+}
+
+/// The directory of the scratch directories wiped on restart, cleaned up at worker start.
+fn scratch_tmp_dir<T: WorkerConfig>() -> PathBuf {
+    T::Paths::storage_dir().join("scratch_tmp")
+}
+
+fn scratch_config<T: WorkerConfig>(address: &Address, scratch: &ScratchDir) -> ScratchConfig {
+    let base_dir = if scratch.wipe_on_restart {
+        scratch_tmp_dir::<T>()
+    } else {
+        scratch_dir::<T>()
+    };
+    ScratchConfig {
+        dir: base_dir.join(hex::encode(address)),
+        guest_path: scratch.path.clone(),
+        quota: scratch.quota,
+        ephemeral: scratch.wipe_on_restart,
+    }
+}
+
+/// Starts an instance of the app to take a connection waiting in the listeners.
+fn create_instance_fn<T: WorkerConfig>(
+    weak_self: &WeakWorker<T>,
+    address: Address,
+) -> impl Fn() + Send + Sync + 'static {
+    let weak_self = weak_self.clone();
+    move || {
+        let Some(inner) = weak_self.upgrade() else {
+            return;
+        };
+        let Ok(Some(_info)) = inner.lock().unwrap().try_inc_instances(address) else {
+            return;
+        };
+    }
 }
 
 fn to_pages(size: u64) -> u64 {
@@ -624,6 +1468,49 @@ fn to_pages(size: u64) -> u64 {
 }
 
 impl<T: WorkerConfig> WorkerState<T> {
+    /// Returns the blobs of the manifest that are not in the store, as `(hash, cid)` pairs.
+    fn missing_blobs(&self, manifest: &AppManifest) -> Vec<(String, Option<String>)> {
+        let mut missing = vec![];
+        for (hash, cid) in &manifest.required_blobs {
+            if !self.blob_loader.exists(hash) && !self.blob_loader.exists(cid) {
+                let cid = (!cid.is_empty()).then(|| cid.clone());
+                missing.push((hash.clone(), cid));
+            }
+        }
+        let listed = manifest
+            .required_blobs
+            .iter()
+            .any(|(hash, _)| hash == &manifest.code_hash);
+        if !listed && !self.blob_loader.exists(&manifest.code_hash) {
+            missing.push((manifest.code_hash.clone(), None));
+        }
+        missing
+    }
+
+    /// Creates the agents of the app on the listeners, shared by all of its instances.
+    fn app_agents(&self, address: Address, reuse_instances: bool) -> AppAgents {
+        let connect_timeout = Duration::from_secs(5);
+        let agents = AppAgents {
+            sni: self.sni_tls_listener.as_ref().map(|l| {
+                let create_instance_fn = create_instance_fn(&self.weak_self, address);
+                let agent = l.agent(create_instance_fn, reuse_instances, connect_timeout);
+                agent.set_acme_owner(&hex::encode(address));
+                agent
+            }),
+            http: self.http_listener.as_ref().map(|l| {
+                let create_instance_fn = create_instance_fn(&self.weak_self, address);
+                l.agent(create_instance_fn, reuse_instances, connect_timeout)
+            }),
+            tls_passthrough: self.sni_tls_listener.as_ref().map(|l| {
+                let create_instance_fn = create_instance_fn(&self.weak_self, address);
+                l.passthrough()
+                    .agent(create_instance_fn, reuse_instances, connect_timeout)
+            }),
+        };
+        agents.set_limits(&self.connection_limits);
+        agents
+    }
+
     fn start_app(
         &mut self,
         address: Address,
@@ -638,15 +1525,19 @@ impl<T: WorkerConfig> WorkerState<T> {
         if !app.manifest.resizable && !app.instances.is_empty() && time_limit.is_none() {
             return Err(anyhow!("Instance already started"));
         }
-        let runtime_calls =
-            AppRuntimeCalls::<T>::new(address, self.host_filter.clone(), self.weak_self.clone());
+        let runtime_calls = AppRuntimeCalls::<T>::new(
+            address,
+            self.host_filter.clone(),
+            self.time_service.clone(),
+            self.weak_self.clone(),
+        );
         let event_rx = runtime_calls.event_tx.subscribe();
         let config = service::InstanceStartConfig::builder()
             .auto_restart(app.auto_restart)
             .max_memory_pages(to_pages(self.args.instance_memory_size) as _)
             .id(address)
             .weight(1)
-            .blobs_dir(T::Paths::blobs_dir())
+            .blob_loader(self.blob_loader.clone())
             .runtime_calls(runtime_calls)
             .args(
                 [app_name]
@@ -655,22 +1546,31 @@ impl<T: WorkerConfig> WorkerState<T> {
                     .collect(),
             )
             .envs(app.manifest.env_vars.to_vec())
+            .secret_envs(
+                app.manifest
+                    .secret_env_vars
+                    .iter()
+                    .map(|var| {
+                        let aad = app
+                            .manifest
+                            .secret_env_aad(&var.name, sp_core::hashing::blake2_256);
+                        let encrypted = (&app.manifest.code_hash, aad, var).encode();
+                        (var.name.clone(), encrypted)
+                    })
+                    .collect(),
+            )
+            .mounts(blob_mounts(&app.manifest)?)
+            .scratch(
+                app.manifest
+                    .scratch
+                    .as_ref()
+                    .map(|scratch| scratch_config::<T>(&address, scratch)),
+            )
             .tcp_listen_port_range(self.args.tcp_listen_port_range.clone())
-            .sni_tls_listener({
-                let weak_self = self.weak_self.clone();
-                let create_instance_fn = move || {
-                    let Some(inner) = weak_self.upgrade() else {
-                        return;
-                    };
-                    let Ok(Some(_info)) = inner.lock().unwrap().try_inc_instances(address) else {
-                        return;
-                    };
-                };
-                self.sni_tls_listener.as_ref().map(|l| {
-                    let connect_timeout = Duration::from_secs(5);
-                    l.agent(create_instance_fn, app.reuse_instance, connect_timeout)
-                })
-            })
+            .sni_tls_listener(app.agents.sni.clone())
+            .http_listener(app.agents.http.clone())
+            .tls_passthrough_listener(app.agents.tls_passthrough.clone())
+            .proxy_protocol(self.proxy_protocol.clone())
             .time_limit(time_limit)
             .build();
         let (vm_handle, join_handle) = self
@@ -765,6 +1665,9 @@ impl<T: WorkerConfig> WorkerState<T> {
             .apps
             .get_mut(&address)
             .ok_or(anyhow!("App not found"))?;
+        if on_demand_timeout.is_none() {
+            app.desired_instances = count;
+        }
         let current = app.instances.len();
         let max_allowed = if app.manifest.resizable { count } else { 1 };
         info!(current, count, max_allowed, "changing number of instances");
@@ -797,6 +1700,9 @@ impl<T: WorkerConfig> WorkerState<T> {
                 }
             }
         }
+        if on_demand_timeout.is_none() {
+            self.persist();
+        }
         Ok((created, removed))
     }
 
@@ -836,11 +1742,56 @@ impl<T: WorkerConfig> WorkerState<T> {
         let update = SessionUpdate::new::<SpCoreHash>(cnonce, pnonce, recipient);
         self.session = Some(update.session);
         self.metrics_sn = 0;
+        self.metrics_sn_reserved = 0;
+        self.operators_lost = false;
+        self.persist();
         Ok(update)
     }
 
+    fn remove_app(&mut self, address: Address) -> Option<AppState> {
+        self.domains.remove_app(address);
+        let app = self.apps.remove(&address);
+        self.persist();
+        app
+    }
+
+    /// Queues the deployed apps to be saved to the registry, returning the sequence number to wait
+    /// for them to be written.
+    fn persist(&self) -> u64 {
+        let apps = self
+            .apps
+            .iter()
+            .map(|(address, app)| AppRecord {
+                address: *address,
+                session: app.session,
+                manifest: app.manifest.clone(),
+                auto_restart: app.auto_restart,
+                reuse_instances: app.reuse_instance,
+                desired_instances: app.desired_instances as u32,
+                hist_metrics: (&app.metrics()).into(),
+                domains: self.domains.domains_of(address),
+            })
+            .collect();
+        let registry = Registry {
+            session: self.session,
+            metrics_sn_reserved: self.metrics_sn_reserved,
+            apps,
+            operators: self.operators.clone(),
+            register_operator: self.register_operator,
+            operators_lost: self.operators_lost,
+        };
+        self.registry.save(registry)
+    }
+
     fn bump_metrics_sn(&mut self) -> u64 {
         self.metrics_sn += 1;
+        if self.metrics_sn > self.metrics_sn_reserved {
+            self.metrics_sn_reserved = self.metrics_sn + METRICS_SN_RESERVE;
+            // The reservation must be on disk before the sequence numbers are issued. It only
+            // happens once per reserve, so the wait under the worker lock is rare.
+            let seq = self.persist();
+            self.registry.wait(seq);
+        }
         self.metrics_sn
     }
 }
@@ -859,6 +1810,7 @@ struct AppRuntimeCalls<T> {
     event_tx: broadcast::Sender<Event>,
     address: Address,
     host_filter: Arc<HostFilter>,
+    time_service: Arc<TimeService>,
     worker: WeakWorker<T>,
     shared: Arc<Mutex<SharedState>>,
     _phantom: PhantomData<fn() -> T>,
@@ -870,6 +1822,7 @@ impl<T> Clone for AppRuntimeCalls<T> {
             event_tx: self.event_tx.clone(),
             address: self.address,
             host_filter: self.host_filter.clone(),
+            time_service: self.time_service.clone(),
             worker: self.worker.clone(),
             shared: self.shared.clone(),
             _phantom: self._phantom,
@@ -878,11 +1831,17 @@ impl<T> Clone for AppRuntimeCalls<T> {
 }
 
 impl<T: WorkerConfig> AppRuntimeCalls<T> {
-    fn new(address: Address, host_filter: Arc<HostFilter>, worker: WeakWorker<T>) -> Self {
+    fn new(
+        address: Address,
+        host_filter: Arc<HostFilter>,
+        time_service: Arc<TimeService>,
+        worker: WeakWorker<T>,
+    ) -> Self {
         Self {
             event_tx: broadcast::channel(1).0,
             address,
             host_filter,
+            time_service,
             worker,
             shared: Default::default(),
             _phantom: PhantomData,
@@ -944,6 +1903,18 @@ impl<T: WorkerConfig + 'static> wapo_host::RuntimeCalls for AppRuntimeCalls<T> {
         self.event_tx.send(Event::QueryListened).ok();
     }
 
+    fn decrypt_env(&self, encrypted: &[u8]) -> Option<String> {
+        let (code_hash, aad, var) =
+            <(String, Vec<u8>, SecretEnvVar)>::decode(&mut &encrypted[..]).ok()?;
+        match decrypt_secret_env(T::KeyProvider::get_key(), &code_hash, &aad, &var) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                warn!(name = var.name, "failed to decrypt secret env: {err:?}");
+                None
+            }
+        }
+    }
+
     fn try_lock(&self, path: &str) -> bool {
         if path.as_bytes().len() > 64 {
             return false;
@@ -962,4 +1933,144 @@ impl<T: WorkerConfig + 'static> wapo_host::RuntimeCalls for AppRuntimeCalls<T> {
     fn unlock(&self, path: &str) -> bool {
         self.shared.lock().unwrap().locks.remove(path)
     }
+
+    fn app_query(
+        &self,
+        address: [u8; 32],
+        path: String,
+        payload: Vec<u8>,
+    ) -> Option<AppQueryFuture> {
+        let inner = self.worker.upgrade()?;
+        let worker = Worker::<T> { inner };
+        let origin = self.address;
+        Some(Box::pin(
+            async move {
+                worker
+                    .query(Some(origin), address, path, payload)
+                    .await
+                    .map_err(|err| format!("{err:?}"))
+            }
+            .instrument(tracing::info_span!("app_query", to = %ShortId(address))),
+        ))
+    }
+
+    fn subscribe(&self, topic: &str, tx: mpsc::Sender<Vec<u8>>) -> bool {
+        let Some(inner) = self.worker.upgrade() else {
+            return false;
+        };
+        let mut inner = inner.lock().expect("worker lock poisoned");
+        inner.topics.subscribe(topic, self.address, tx)
+    }
+
+    fn unsubscribe(&self, topic: &str, tx: &mpsc::Sender<Vec<u8>>) -> bool {
+        let Some(inner) = self.worker.upgrade() else {
+            return false;
+        };
+        let mut inner = inner.lock().expect("worker lock poisoned");
+        inner.topics.unsubscribe(topic, tx)
+    }
+
+    fn publish(&self, topic: &str, payload: &[u8]) -> bool {
+        let Some(inner) = self.worker.upgrade() else {
+            return false;
+        };
+        let mut inner = inner.lock().expect("worker lock poisoned");
+        match inner.topics.publish(topic, Some(self.address), payload) {
+            Ok(_) => true,
+            Err(err) => {
+                warn!("failed to publish message: {err:?}");
+                false
+            }
+        }
+    }
+
+    fn trusted_now(&self) -> Option<(SystemTime, Duration)> {
+        self.time_service.trusted_now()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> AppManifest {
+        AppManifest {
+            version: 1,
+            code_hash: "sha256:00".into(),
+            args: vec![],
+            env_vars: vec![],
+            on_demand: false,
+            resizable: false,
+            max_query_size: 1024,
+            label: "app".into(),
+            required_blobs: vec![],
+            schedules: vec![],
+            mounts: vec![],
+            scratch: None,
+            secret_env_vars: vec![],
+            domains: vec![],
+        }
+    }
+
+    fn encrypt(
+        worker_key: &Pair,
+        manifest: &AppManifest,
+        name: &str,
+        value: &str,
+        app_key: bool,
+    ) -> SecretEnvVar {
+        let sender = Pair::new();
+        let receiver = if app_key {
+            derive_secret_env_key(worker_key, &manifest.code_hash).public()
+        } else {
+            worker_key.public()
+        };
+        let aad = manifest.secret_env_aad(name, sp_core::hashing::blake2_256);
+        SecretEnvVar {
+            name: name.into(),
+            pubkey: sender.public().as_bytes().to_vec(),
+            encrypted_value: sender
+                .encrypt_message_with_aad(receiver.as_bytes(), value.as_bytes(), &aad)
+                .unwrap(),
+            app_key,
+        }
+    }
+
+    fn decrypt(worker_key: &Pair, manifest: &AppManifest, var: &SecretEnvVar) -> Result<String> {
+        let aad = manifest.secret_env_aad(&var.name, sp_core::hashing::blake2_256);
+        decrypt_secret_env(worker_key, &manifest.code_hash, &aad, var)
+    }
+
+    #[test]
+    fn secret_envs_round_trip() {
+        let worker_key = Pair::new();
+        let mut manifest = manifest();
+        for app_key in [false, true] {
+            let var = encrypt(&worker_key, &manifest, "TOKEN", "secret", app_key);
+            // Adding the var to the manifest doesn't change the associated data.
+            manifest.secret_env_vars = vec![var.clone()];
+            assert_eq!(decrypt(&worker_key, &manifest, &var).unwrap(), "secret");
+        }
+    }
+
+    #[test]
+    fn secret_envs_are_bound_to_the_manifest_and_name() {
+        let worker_key = Pair::new();
+        let manifest = manifest();
+        for app_key in [false, true] {
+            let var = encrypt(&worker_key, &manifest, "TOKEN", "secret", app_key);
+
+            let mut other = manifest.clone();
+            other.args = vec!["--leak".into()];
+            assert!(decrypt(&worker_key, &other, &var).is_err());
+
+            let renamed = SecretEnvVar {
+                name: "OTHER".into(),
+                ..var.clone()
+            };
+            assert!(decrypt(&worker_key, &manifest, &renamed).is_err());
+
+            assert!(decrypt(&Pair::new(), &manifest, &var).is_err());
+        }
+    }
 }
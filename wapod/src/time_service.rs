@@ -0,0 +1,132 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Result;
+use rocket::serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::config::load_config_file;
+
+/// Assumed maximum drift of the local monotonic clock, in parts per million.
+const MAX_DRIFT_PPM: u32 = 100;
+/// The shortest interval the sources are resampled at, whatever the config says.
+const MIN_SYNC_INTERVAL_SECS: u64 = 10;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct TimeConfig {
+    /// HTTPS servers whose `Date` header is sampled.
+    pub sources: Vec<String>,
+    /// Maximum disagreement allowed between the sources, in seconds.
+    pub tolerance_secs: u64,
+    /// Interval to resample the sources, in seconds.
+    pub sync_interval_secs: u64,
+    /// Timeout for each request, in seconds.
+    pub timeout_secs: u64,
+}
+
+impl Default for TimeConfig {
+    fn default() -> Self {
+        Self {
+            sources: vec![],
+            tolerance_secs: 2,
+            sync_interval_secs: 600,
+            timeout_secs: 5,
+        }
+    }
+}
+
+impl TimeConfig {
+    pub fn from_config_file() -> Self {
+        let config = load_config_file()
+            .select("time")
+            .extract::<TimeConfig>()
+            .unwrap_or_default();
+        info!("loaded trusted time config: {config:?}");
+        config
+    }
+
+    pub fn sync_interval(&self) -> Duration {
+        Duration::from_secs(self.sync_interval_secs.max(MIN_SYNC_INTERVAL_SECS))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    /// The trusted time at `instant`.
+    time: SystemTime,
+    instant: Instant,
+    uncertainty: Duration,
+}
+
+/// Maintains a trusted wall clock cross-checked against multiple HTTPS servers, so that the
+/// apps don't need to trust the host's clock.
+pub struct TimeService {
+    config: TimeConfig,
+    last_sample: Mutex<Option<Sample>>,
+}
+
+impl TimeService {
+    pub fn new(config: TimeConfig) -> Self {
+        Self {
+            config,
+            last_sample: Mutex::new(None),
+        }
+    }
+
+    /// Returns the current trusted time and its uncertainty bound.
+    pub fn trusted_now(&self) -> Option<(SystemTime, Duration)> {
+        let sample = (*self.last_sample.lock().unwrap())?;
+        let elapsed = sample.instant.elapsed();
+        let drift = elapsed * MAX_DRIFT_PPM / 1_000_000;
+        Some((sample.time + elapsed, sample.uncertainty + drift))
+    }
+
+    /// Periodically resample the time sources.
+    pub async fn run(&self) {
+        if self.config.sources.is_empty() {
+            info!("no trusted time source configured");
+            return;
+        }
+        loop {
+            match self.sync().await {
+                Ok(()) => {
+                    let (now, uncertainty) = self.trusted_now().expect("BUG: no sample after sync");
+                    info!(?now, ?uncertainty, "trusted time synced");
+                }
+                Err(err) => warn!("failed to sync trusted time: {err:?}"),
+            }
+            tokio::time::sleep(self.config.sync_interval()).await;
+        }
+    }
+
+    async fn sync(&self) -> Result<()> {
+        let quorum = self.config.sources.len() / 2 + 1;
+        let tolerance = Duration::from_secs(self.config.tolerance_secs);
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        let result =
+            httptime::get_consensus_time(&self.config.sources, quorum, tolerance, timeout).await;
+        let consensus = match result {
+            Ok(consensus) => consensus,
+            Err(err) => {
+                // Stop serving a time that the sources no longer agree on.
+                *self.last_sample.lock().unwrap() = None;
+                return Err(err);
+            }
+        };
+        if consensus.rejected > 0 {
+            warn!(
+                rejected = consensus.rejected,
+                "some time sources failed or disagreed"
+            );
+        }
+        *self.last_sample.lock().unwrap() = Some(Sample {
+            time: consensus.time,
+            instant: consensus.instant,
+            uncertainty: consensus.error_bound,
+        });
+        Ok(())
+    }
+}